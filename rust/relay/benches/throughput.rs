@@ -0,0 +1,170 @@
+//! Measures how many channel-data packets the relay can process per second.
+//!
+//! Run with `cargo bench --bench throughput`.
+//!
+//! The first part drives [`Server`] directly with synthetic channel-data, this is the upper bound of what a single worker can relay.
+//! The second part sends the same load over loopback, once datagram by datagram and once batched with `sendmmsg` / `recvmmsg`.
+
+use anyhow::Result;
+use firezone_relay::{
+    Allocate, BufferPool, ChannelBind, ChannelData, ClientMessage, ClientSocket, Command, Server,
+    UdpSocket, MAX_BATCH_SIZE,
+};
+use rand::rngs::mock::StepRng;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+use stun_codec::rfc5389::attributes::Username;
+use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress};
+use stun_codec::TransactionId;
+use uuid::Uuid;
+
+const NUM_PACKETS: usize = 1_000_000;
+const PAYLOAD_SIZE: usize = 1200;
+
+fn main() -> Result<()> {
+    let payload = vec![0u8; PAYLOAD_SIZE];
+    let channel_data = ChannelData::new(0x4000, &payload).to_bytes();
+
+    report("server", NUM_PACKETS, relay_through_server(&channel_data));
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let num_datagrams = NUM_PACKETS / 10 / MAX_BATCH_SIZE * MAX_BATCH_SIZE;
+
+    report(
+        "udp loopback, one datagram per syscall",
+        num_datagrams,
+        runtime.block_on(loopback_single(&channel_data, num_datagrams))?,
+    );
+    report(
+        "udp loopback, batched",
+        num_datagrams,
+        runtime.block_on(loopback_batched(&channel_data, num_datagrams))?,
+    );
+
+    Ok(())
+}
+
+fn relay_through_server(channel_data: &[u8]) -> Duration {
+    let mut server = Server::new(
+        Ipv4Addr::new(35, 124, 91, 37),
+        StepRng::new(0, 0),
+        49152,
+        65535,
+    );
+    let secret = server.auth_secret().clone();
+    let nonce = Uuid::from_u128(0);
+    server.add_nonce(nonce);
+
+    let client = ClientSocket::udp(SocketAddr::from(([1, 1, 1, 1], 5000)));
+    let peer = SocketAddr::from(([8, 8, 8, 8], 9000));
+    let now = SystemTime::now();
+    let username = username(now);
+
+    server.handle_client_message(
+        ClientMessage::Allocate(Allocate::new_authenticated_udp_implicit_ip4(
+            TransactionId::new([0; 12]),
+            Some(Lifetime::new(Duration::from_secs(3600)).unwrap()),
+            username.clone(),
+            &secret,
+            nonce,
+        )),
+        client,
+        now,
+    );
+    server.handle_client_message(
+        ClientMessage::ChannelBind(ChannelBind::new(
+            TransactionId::new([1; 12]),
+            ChannelNumber::new(0x4000).unwrap(),
+            XorPeerAddress::new(peer),
+            username,
+            &secret,
+            nonce,
+        )),
+        client,
+        now,
+    );
+    while server.next_command().is_some() {}
+
+    let start = Instant::now();
+    let mut forwarded = 0;
+
+    for _ in 0..NUM_PACKETS {
+        server.handle_client_input(channel_data, client, now);
+
+        while let Some(command) = server.next_command() {
+            if matches!(command, Command::ForwardData { .. }) {
+                forwarded += 1;
+            }
+        }
+    }
+
+    let elapsed = start.elapsed();
+    assert_eq!(forwarded, NUM_PACKETS, "every packet should be relayed");
+
+    elapsed
+}
+
+// Both loopback benchmarks keep at most one batch in flight, otherwise the sender outruns the receiver and the kernel drops datagrams.
+
+async fn loopback_single(datagram: &[u8], num_datagrams: usize) -> Result<Duration> {
+    let (mut sender, mut receiver, receiver_addr) = loopback_pair()?;
+
+    let start = Instant::now();
+
+    for _ in 0..num_datagrams / MAX_BATCH_SIZE {
+        for _ in 0..MAX_BATCH_SIZE {
+            sender.send_to(datagram, receiver_addr).await?;
+        }
+        for _ in 0..MAX_BATCH_SIZE {
+            receiver.recv().await?;
+        }
+    }
+
+    Ok(start.elapsed())
+}
+
+async fn loopback_batched(datagram: &[u8], num_datagrams: usize) -> Result<Duration> {
+    let (mut sender, mut receiver, receiver_addr) = loopback_pair()?;
+    let pool = BufferPool::default();
+    let batch = vec![(datagram.to_vec(), receiver_addr); MAX_BATCH_SIZE];
+
+    let start = Instant::now();
+
+    for _ in 0..num_datagrams / MAX_BATCH_SIZE {
+        sender.send_batch(&batch).await?;
+
+        let mut received = 0;
+        while received < MAX_BATCH_SIZE {
+            received += receiver.recv_batch(&pool).await?.len();
+        }
+    }
+
+    Ok(start.elapsed())
+}
+
+fn loopback_pair() -> Result<(UdpSocket, UdpSocket, SocketAddr)> {
+    let sender = UdpSocket::bind(Ipv4Addr::LOCALHOST.into(), 0)?;
+    let receiver = UdpSocket::bind(Ipv4Addr::LOCALHOST.into(), 0)?;
+    let receiver_addr = receiver.local_addr()?;
+
+    Ok((sender, receiver, receiver_addr))
+}
+
+fn username(now: SystemTime) -> Username {
+    let expiry = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 3600;
+
+    Username::new(format!("{expiry}:benchmark")).unwrap()
+}
+
+fn report(name: &str, num_packets: usize, elapsed: Duration) {
+    let packets_per_second = num_packets as f64 / elapsed.as_secs_f64();
+
+    println!("{name}: {num_packets} packets in {elapsed:?} ({packets_per_second:.0} packets/s)");
+}
@@ -61,15 +61,17 @@ async fn main() -> Result<()> {
 }
 
 async fn new_turn_client() -> Result<Client, Error> {
+    let relay_addr = std::env::var("RELAY_ADDR").unwrap_or_else(|_| "localhost:3478".to_owned());
+
     let client = Client::new(ClientConfig {
-        stun_serv_addr: "localhost:3478".to_owned(),
-        turn_serv_addr: "localhost:3478".to_owned(),
+        stun_serv_addr: relay_addr.clone(),
+        turn_serv_addr: relay_addr,
         username: "2000000000:client".to_owned(), // 2000000000 expires in 2033, plenty of time
         password: "+Qou8TSjw9q3JMnWET7MbFsQh/agwz/LURhpfX7a0hE".to_owned(),
         realm: "firezone".to_owned(),
         software: String::new(),
         rto_in_ms: 0,
-        conn: Arc::new(UdpSocket::bind("127.0.0.1:0").await?),
+        conn: Arc::new(UdpSocket::bind("0.0.0.0:0").await?),
         vnet: None,
     })
     .await?;
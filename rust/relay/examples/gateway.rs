@@ -9,7 +9,8 @@ use webrtc::turn::Error;
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let bind_addr = std::env::var("GATEWAY_ADDR").unwrap_or_else(|_| "127.0.0.1:0".to_owned());
+    let socket = UdpSocket::bind(bind_addr).await?;
     let listening_addr = socket.local_addr()?;
 
     println!("Our listening address is {listening_addr}");
@@ -0,0 +1,71 @@
+#!/bin/bash
+# Runs the smoke test with channel data relayed by the XDP program.
+#
+# The relay runs in its own network namespace, connected to a second namespace with the client, the gateway and redis via a veth pair.
+# Requires root, clang, libbpf headers, bpftool, ethtool and redis-server.
+set -e
+
+cargo build --package firezone-relay --bin firezone-relay --example client --example gateway
+
+target_directory=$(cargo metadata --format-version 1 | jq -r '.target_directory')
+client="$target_directory/debug/examples/client"
+gateway="$target_directory/debug/examples/gateway"
+relay="$target_directory/debug/firezone-relay"
+xdp_object="$target_directory/channel_data.o"
+
+clang -O2 -g -target bpf -c xdp/channel_data.c -o "$xdp_object"
+
+cleanup() {
+  pkill -P $$ || true # Kill all child-processes of the current process.
+  ip netns delete relay 2> /dev/null || true
+  ip netns delete peers 2> /dev/null || true
+}
+trap cleanup EXIT
+
+ip netns add relay
+ip netns add peers
+ip link add veth-relay netns relay type veth peer name veth-peers netns peers
+
+ip -n relay addr add 10.0.0.1/24 dev veth-relay
+ip -n peers addr add 10.0.0.2/24 dev veth-peers
+ip -n relay link set veth-relay up
+ip -n peers link set veth-peers up
+ip -n relay link set lo up
+ip -n peers link set lo up
+
+# The receiving end of a veth pair only accepts frames sent via `XDP_TX` if it runs NAPI.
+ip netns exec peers ethtool -K veth-peers gro on > /dev/null
+
+mount_bpffs="mount -t bpf bpf /sys/fs/bpf"
+
+RED=$(echo -e '\033[0;31m')
+GREEN=$(echo -e '\033[0;32m')
+BLUE=$(echo -e '\033[0;34m')
+NC=$(echo -e '\033[0m')
+
+export RNG_SEED=0;
+export RUST_LOG=relay=debug,firezone_relay=debug;
+
+ip netns exec peers redis-server --port 6379 > /dev/null &
+
+# Client and relay run in the background.
+ip netns exec relay unshare --mount sh -c "$mount_bpffs && exec $relay --public-ip4-addr 10.0.0.1 --xdp-interface veth-relay --xdp-object $xdp_object" 2>&1 | sed "s/^/${GREEN}[  relay]${NC} /" &
+sleep 1
+RELAY_ADDR=10.0.0.1:3478 ip netns exec peers "$client" 2>&1 | sed "s/^/${RED}[ client]${NC} /" &
+
+GATEWAY_ADDR=10.0.0.2:0 ip netns exec peers "$gateway" 2>&1 | sed "s/^/${BLUE}[gateway]${NC} /"
+gateway_status="${PIPESTATUS[0]}"
+
+if [ "$gateway_status" -ne 0 ]; then
+  exit "$gateway_status"
+fi
+
+# The XDP program counts the packets it relayed in both directions.
+relayed_packets=$(bpftool --json map dump name relay_stats | jq '[.[].formatted.value] | add // 0')
+
+if [ "$relayed_packets" -eq 0 ]; then
+  echo "Channel data was relayed by the relay process instead of the XDP program"
+  exit 1
+fi
+
+echo "The XDP program relayed $relayed_packets packets"
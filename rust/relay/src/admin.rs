@@ -0,0 +1,199 @@
+//! A read-only view on the allocations of a [`Server`](crate::Server) plus the ability to forcibly free them or drain the relay.
+//!
+//! The [`Server`](crate::Server) is owned by the eventloop, thus the HTTP handlers talk to it via [`AdminRequest`]s.
+//! Every request must carry the admin token as `Authorization: Bearer <token>`.
+
+use crate::AllocationId;
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router, Server};
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// A request from the admin API to the eventloop.
+pub enum AdminRequest {
+    /// Reply with the current state of all allocations.
+    ListAllocations {
+        reply: oneshot::Sender<Vec<AllocationInfo>>,
+    },
+    /// Free the given allocation, replying whether it existed.
+    FreeAllocation {
+        id: AllocationId,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Stop accepting new allocations and exit once the existing ones are gone.
+    Drain { reply: oneshot::Sender<()> },
+}
+
+#[derive(Debug, Serialize)]
+pub struct AllocationInfo {
+    pub id: AllocationId,
+    pub client: SocketAddr,
+    pub client_transport: &'static str,
+    pub relay_addresses: Vec<SocketAddr>,
+    pub transport: &'static str,
+    pub channels: Vec<ChannelInfo>,
+    pub permissions: Vec<PermissionInfo>,
+    pub client_to_peer_bytes: u64,
+    pub peer_to_client_bytes: u64,
+    /// Seconds since the UNIX epoch.
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelInfo {
+    pub number: u16,
+    pub peer: SocketAddr,
+    pub bound: bool,
+    /// Seconds since the UNIX epoch.
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PermissionInfo {
+    pub peer: IpAddr,
+    /// Seconds since the UNIX epoch.
+    pub expires_at: u64,
+}
+
+/// The routes of the admin API.
+///
+/// - `GET /allocations`: Lists all allocations.
+/// - `DELETE /allocations/:id`: Frees an allocation.
+/// - `POST /drain`: Drains the relay.
+pub fn router(requests: mpsc::Sender<AdminRequest>, token: SecretString) -> Router {
+    Router::new()
+        .route("/allocations", get(list_allocations))
+        .route("/allocations/:id", delete(free_allocation))
+        .route("/drain", post(drain))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(token),
+            require_token,
+        ))
+        .with_state(requests)
+}
+
+/// Serves the admin API on its own listener, separate from the health-check endpoints.
+pub async fn serve(
+    addr: impl Into<SocketAddr>,
+    requests: mpsc::Sender<AdminRequest>,
+    token: SecretString,
+) -> Result<()> {
+    Server::try_bind(&addr.into())?
+        .serve(router(requests, token).into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+async fn require_token<B>(
+    State(token): State<Arc<SecretString>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    if !is_authorized(authorization, &token) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Whether the given `Authorization` header carries our token.
+///
+/// Compares in constant time to not leak the token through timing.
+fn is_authorized(authorization: Option<&str>, token: &SecretString) -> bool {
+    let Some(candidate) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let token = token.expose_secret().as_bytes();
+    let candidate = candidate.as_bytes();
+
+    if candidate.len() != token.len() {
+        return false;
+    }
+
+    candidate
+        .iter()
+        .zip(token)
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+async fn list_allocations(
+    State(mut requests): State<mpsc::Sender<AdminRequest>>,
+) -> Result<Json<Vec<AllocationInfo>>, StatusCode> {
+    let (reply, response) = oneshot::channel();
+
+    requests
+        .send(AdminRequest::ListAllocations { reply })
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    let allocations = response
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    Ok(Json(allocations))
+}
+
+async fn free_allocation(
+    State(mut requests): State<mpsc::Sender<AdminRequest>>,
+    Path(id): Path<AllocationId>,
+) -> StatusCode {
+    let (reply, response) = oneshot::channel();
+
+    if requests
+        .send(AdminRequest::FreeAllocation { id, reply })
+        .await
+        .is_err()
+    {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    match response.await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn drain(State(mut requests): State<mpsc::Sender<AdminRequest>>) -> StatusCode {
+    let (reply, response) = oneshot::channel();
+
+    if requests.send(AdminRequest::Drain { reply }).await.is_err() {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    match response.await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_matching_bearer_token() {
+        let token = SecretString::from("north".to_owned());
+
+        assert!(is_authorized(Some("Bearer north"), &token));
+        assert!(!is_authorized(Some("Bearer south"), &token));
+        assert!(!is_authorized(Some("Bearer nort"), &token));
+        assert!(!is_authorized(Some("north"), &token));
+        assert!(!is_authorized(None, &token));
+    }
+}
@@ -1,11 +1,14 @@
 use crate::server::AllocationId;
-use crate::udp_socket::UdpSocket;
-use crate::AddressFamily;
-use anyhow::{bail, Result};
+use crate::stream::{bind_tcp_listener, bind_tcp_socket};
+use crate::udp_socket::{BufferPool, PooledBuffer, UdpSocket, MAX_BATCH_SIZE};
+use crate::IpAddrExt;
+use anyhow::{bail, Context as _, Result};
 use futures::channel::mpsc;
 use futures::{SinkExt, StreamExt};
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpStream;
 use tokio::task;
 
 /// The maximum amount of items that can be buffered in the channel to the allocation task.
@@ -22,10 +25,12 @@ pub struct Allocation {
 }
 
 impl Allocation {
+    /// Creates an allocation whose socket receives into buffers of the given pool.
     pub fn new(
-        relay_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr, AllocationId)>,
+        relay_data_sender: mpsc::Sender<(PooledBuffer, SocketAddr, AllocationId)>,
+        pool: BufferPool,
         id: AllocationId,
-        family: AddressFamily,
+        addr: IpAddr,
         port: u16,
     ) -> Self {
         let (client_to_peer_sender, client_to_peer_receiver) = mpsc::channel(MAX_BUFFERED_ITEMS);
@@ -34,8 +39,9 @@ impl Allocation {
             let Err(e) = forward_incoming_relay_data(
                 relay_data_sender,
                 client_to_peer_receiver,
+                pool,
                 id,
-                family,
+                addr,
                 port,
             )
             .await
@@ -43,7 +49,7 @@ impl Allocation {
                 unreachable!()
             };
 
-            tracing::warn!(allocation = %id, %family, "Allocation task failed: {e:#}");
+            tracing::warn!(allocation = %id, family = %addr.family(), "Allocation task failed: {e:#}");
 
             // With the task stopping, the channel will be closed and any attempt to send data to it will fail.
         });
@@ -88,24 +94,104 @@ impl Drop for Allocation {
     }
 }
 
+/// The TCP counterpart of [`Allocation`], see <https://www.rfc-editor.org/rfc/rfc6062>.
+///
+/// Accepts connections from peers on the relay port and hands them to the main task.
+pub struct TcpAllocation {
+    addr: IpAddr,
+    port: u16,
+
+    /// The handle to the task that is accepting peer connections.
+    ///
+    /// Stored here to make resource-cleanup easy.
+    handle: task::JoinHandle<()>,
+}
+
+impl TcpAllocation {
+    pub fn new(
+        peer_connection_sender: mpsc::Sender<(TcpStream, SocketAddr, AllocationId)>,
+        id: AllocationId,
+        addr: IpAddr,
+        port: u16,
+    ) -> Self {
+        let task = tokio::spawn(async move {
+            let Err(e) = accept_peer_connections(peer_connection_sender, id, addr, port).await
+            else {
+                unreachable!()
+            };
+
+            tracing::warn!(allocation = %id, family = %addr.family(), "TCP allocation task failed: {e:#}");
+        });
+
+        Self {
+            addr,
+            port,
+            handle: task,
+        }
+    }
+
+    /// Connect to a peer from the relay address of this allocation.
+    pub fn connect(&self, peer: SocketAddr) -> impl Future<Output = Result<TcpStream>> {
+        let socket = bind_tcp_socket(self.addr, self.port);
+
+        async move { Ok(socket?.connect(peer).await?) }
+    }
+}
+
+impl Drop for TcpAllocation {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn accept_peer_connections(
+    mut peer_connection_sender: mpsc::Sender<(TcpStream, SocketAddr, AllocationId)>,
+    id: AllocationId,
+    addr: IpAddr,
+    port: u16,
+) -> Result<Infallible> {
+    let listener = bind_tcp_listener(addr, port)?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+
+        peer_connection_sender.send((stream, peer, id)).await?;
+    }
+}
+
+/// Receives and sends the datagrams of an allocation in batches, like the main UDP socket task does for our listen port.
 async fn forward_incoming_relay_data(
-    mut relayed_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr, AllocationId)>,
+    mut relayed_data_sender: mpsc::Sender<(PooledBuffer, SocketAddr, AllocationId)>,
     mut client_to_peer_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    pool: BufferPool,
     id: AllocationId,
-    family: AddressFamily,
+    addr: IpAddr,
     port: u16,
 ) -> Result<Infallible> {
-    let mut socket = UdpSocket::bind(family, port)?;
+    let mut socket = UdpSocket::bind(addr, port)?;
+    let mut outbound_batch = Vec::with_capacity(MAX_BATCH_SIZE);
 
     loop {
         tokio::select! {
-            result = socket.recv() => {
-                let (data, sender) = result?;
-                relayed_data_sender.send((data.to_vec(), sender, id)).await?;
+            result = socket.recv_batch(&pool) => {
+                for (data, sender) in result? {
+                    relayed_data_sender.send((data, sender, id)).await?;
+                }
             }
 
-            Some((data, recipient)) = client_to_peer_receiver.next() => {
-                socket.send_to(&data, recipient).await?;
+            maybe_item = client_to_peer_receiver.next() => {
+                outbound_batch.push(maybe_item.context("Channel to allocation closed")?);
+
+                while outbound_batch.len() < MAX_BATCH_SIZE {
+                    let Ok(Some(item)) = client_to_peer_receiver.try_next() else {
+                        break;
+                    };
+
+                    outbound_batch.push(item);
+                }
+
+                socket.send_batch(&outbound_batch).await?;
+                outbound_batch.clear();
             }
         }
     }
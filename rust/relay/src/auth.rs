@@ -1,7 +1,11 @@
-use base64::prelude::BASE64_STANDARD_NO_PAD;
+use crate::rfc8489::{MessageIntegritySha256, PasswordAlgorithm, SecurityFeatures, Userhash};
+use base64::prelude::{BASE64_STANDARD, BASE64_STANDARD_NO_PAD};
 use base64::Engine;
+use hmac::Hmac;
 use once_cell::sync::Lazy;
 use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 use sha2::digest::FixedOutput;
 use sha2::Sha256;
 use std::borrow::ToOwned;
@@ -23,7 +27,41 @@ pub trait MessageIntegrityExt {
     ) -> Result<(), Error>;
 }
 
-impl MessageIntegrityExt for MessageIntegrity {
+/// The integrity protection of an authenticated request.
+///
+/// Requests are either protected with MESSAGE-INTEGRITY, keyed with the MD5 of the long-term credential,
+/// or with MESSAGE-INTEGRITY-SHA256, keyed with the long-term credential hashed by the negotiated [`PasswordAlgorithm`].
+#[derive(Debug, Clone, Copy)]
+pub enum Integrity<'a> {
+    Sha1(&'a MessageIntegrity),
+    Sha256(&'a MessageIntegritySha256, PasswordAlgorithm),
+}
+
+impl Integrity<'_> {
+    pub fn check_long_term_credential(
+        &self,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+    ) -> Result<(), Error> {
+        match self {
+            Integrity::Sha1(message_integrity) => message_integrity
+                .check_long_term_credential(username, realm, password)
+                .map_err(|_| Error::InvalidPassword),
+            Integrity::Sha256(message_integrity, algorithm) => message_integrity
+                .check_long_term_credential(username, realm, password, *algorithm)
+                .map_err(|_| Error::InvalidPassword),
+        }
+    }
+}
+
+impl<'a> From<&'a MessageIntegrity> for Integrity<'a> {
+    fn from(message_integrity: &'a MessageIntegrity) -> Self {
+        Integrity::Sha1(message_integrity)
+    }
+}
+
+impl MessageIntegrityExt for Integrity<'_> {
     fn verify(
         &self,
         relay_secret: &SecretString,
@@ -44,13 +82,177 @@ impl MessageIntegrityExt for MessageIntegrity {
                 .map_err(|_| Error::InvalidUsername)?,
             &FIREZONE,
             &password,
-        )
-        .map_err(|_| Error::InvalidPassword)?;
+        )?;
 
         Ok(())
     }
 }
 
+/// Verifies the credentials of authenticated TURN requests.
+///
+/// The [`Server`](crate::Server) checks nonces itself and delegates the verification of the [`Integrity`] to an [`Authenticator`].
+pub trait Authenticator: Send {
+    /// Verifies the [`Integrity`] of a request made with the given username.
+    ///
+    /// `relay_secret` is the secret the relay shares with the portal, backends that don't integrate with the portal ignore it.
+    /// On success, returns the user the credentials belong to, used to enforce per-user limits.
+    fn verify(
+        &self,
+        message_integrity: &Integrity<'_>,
+        username: &str,
+        relay_secret: &SecretString,
+        now: SystemTime,
+    ) -> Result<String, Error>;
+
+    /// The realm we send in challenges, clients key their credentials with it.
+    ///
+    /// Credentials issued by the portal are always keyed with [`FIREZONE`].
+    fn realm(&self) -> &Realm {
+        &FIREZONE
+    }
+
+    /// Whether this authenticator can resolve a [`Userhash`] to a username.
+    ///
+    /// Only then do we allow clients to send a USERHASH instead of their username.
+    fn supports_userhash(&self) -> bool {
+        false
+    }
+
+    /// Resolves a [`Userhash`] to the username it was computed from.
+    fn resolve_userhash(&self, _userhash: &Userhash) -> Option<String> {
+        None
+    }
+}
+
+/// The credentials handed out by the Firezone portal.
+///
+/// Usernames are of the form `expiry:salt` and passwords are derived from the relay secret, see [`generate_password`].
+/// The salt identifies the user.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Firezone;
+
+impl Authenticator for Firezone {
+    fn verify(
+        &self,
+        message_integrity: &Integrity<'_>,
+        username: &str,
+        relay_secret: &SecretString,
+        now: SystemTime,
+    ) -> Result<String, Error> {
+        message_integrity.verify(relay_secret, username, now)?;
+
+        let (_, salt) = split_username(username)?;
+
+        Ok(salt.to_owned())
+    }
+}
+
+/// A fixed set of usernames and passwords.
+///
+/// As we know all usernames upfront, clients may also identify themselves with a [`Userhash`].
+pub struct StaticCredentials {
+    passwords: HashMap<String, SecretString>,
+    usernames_by_hash: HashMap<Userhash, String>,
+    realm: Realm,
+}
+
+impl StaticCredentials {
+    pub fn new(
+        credentials: impl IntoIterator<Item = (String, SecretString)>,
+        realm: Realm,
+    ) -> Self {
+        let passwords = credentials.into_iter().collect::<HashMap<_, _>>();
+        let usernames_by_hash = passwords
+            .keys()
+            .map(|username| (Userhash::new(username, &realm), username.clone()))
+            .collect();
+
+        Self {
+            passwords,
+            usernames_by_hash,
+            realm,
+        }
+    }
+}
+
+impl Authenticator for StaticCredentials {
+    fn verify(
+        &self,
+        message_integrity: &Integrity<'_>,
+        username: &str,
+        _: &SecretString,
+        _: SystemTime,
+    ) -> Result<String, Error> {
+        let password = self.passwords.get(username).ok_or(Error::InvalidUsername)?;
+
+        message_integrity.check_long_term_credential(
+            &Username::new(username.to_owned()).map_err(|_| Error::InvalidUsername)?,
+            &self.realm,
+            password.expose_secret(),
+        )?;
+
+        Ok(username.to_owned())
+    }
+
+    fn realm(&self) -> &Realm {
+        &self.realm
+    }
+
+    fn supports_userhash(&self) -> bool {
+        true
+    }
+
+    fn resolve_userhash(&self, userhash: &Userhash) -> Option<String> {
+        self.usernames_by_hash.get(userhash).cloned()
+    }
+}
+
+/// The time-limited credentials of the "TURN REST API", as implemented by coturn's `use-auth-secret`.
+///
+/// Usernames are of the form `expiry:user` (or just `expiry`) and the password is the base64-encoded HMAC-SHA1 of the username, keyed with a shared secret.
+/// See <https://datatracker.ietf.org/doc/html/draft-uberti-behave-turn-rest-00>.
+pub struct TurnRestApi {
+    secret: SecretString,
+    realm: Realm,
+}
+
+impl TurnRestApi {
+    pub fn new(secret: SecretString, realm: Realm) -> Self {
+        Self { secret, realm }
+    }
+}
+
+impl Authenticator for TurnRestApi {
+    fn verify(
+        &self,
+        message_integrity: &Integrity<'_>,
+        username: &str,
+        _: &SecretString,
+        now: SystemTime,
+    ) -> Result<String, Error> {
+        let (expiry, user) = username.split_once(':').unwrap_or((username, username));
+        let expiry_unix_timestamp = expiry.parse::<u64>().map_err(|_| Error::InvalidUsername)?;
+
+        if systemtime_from_unix(expiry_unix_timestamp) < now {
+            return Err(Error::Expired);
+        }
+
+        let password = generate_turn_rest_password(&self.secret, username);
+
+        message_integrity.check_long_term_credential(
+            &Username::new(username.to_owned()).map_err(|_| Error::InvalidUsername)?,
+            &self.realm,
+            &password,
+        )?;
+
+        Ok(user.to_owned())
+    }
+
+    fn realm(&self) -> &Realm {
+        &self.realm
+    }
+}
+
 /// Tracks valid nonces for the TURN relay.
 ///
 /// The semantic nature of nonces is an implementation detail of the relay in TURN.
@@ -58,30 +260,61 @@ impl MessageIntegrityExt for MessageIntegrity {
 ///
 /// For simplicity reasons, we use a count-based strategy.
 /// Each nonce can be used for a certain number of requests before it is invalid.
-#[derive(Default)]
+///
+/// We also remember the [`SecurityFeatures`] we advertised with a nonce.
+/// A request must present the nonce with exactly these, otherwise an attacker could strip them to bid down the client, see <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.4>.
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct Nonces {
     inner: HashMap<Uuid, u64>,
+    /// The features of nonces that advertise any, see [`Nonces::add_new_with_features`].
+    #[serde(default)]
+    security_features: HashMap<Uuid, SecurityFeatures>,
 }
 
 impl Nonces {
     /// How many requests a client can perform with the same nonce.
     const NUM_REQUESTS: u64 = 10;
 
+    /// Adds a nonce that doesn't advertise any [`SecurityFeatures`], e.g. one issued by the portal.
     pub fn add_new(&mut self, nonce: Uuid) {
         self.inner.insert(nonce, Self::NUM_REQUESTS);
     }
 
-    /// Record the usage of a nonce in a request.
-    pub fn handle_nonce_used(&mut self, nonce: Uuid) -> Result<(), Error> {
+    /// Adds a nonce that we hand out with the given [`SecurityFeatures`].
+    pub fn add_new_with_features(&mut self, nonce: Uuid, security_features: SecurityFeatures) {
+        self.add_new(nonce);
+
+        if security_features != SecurityFeatures::default() {
+            self.security_features.insert(nonce, security_features);
+        }
+    }
+
+    /// Record the usage of a nonce in a request that presented it with the given [`SecurityFeatures`].
+    pub fn handle_nonce_used(
+        &mut self,
+        nonce: Uuid,
+        security_features: SecurityFeatures,
+    ) -> Result<(), Error> {
         let mut entry = match self.inner.entry(nonce) {
             Entry::Vacant(_) => return Err(Error::InvalidNonce),
             Entry::Occupied(entry) => entry,
         };
 
+        let issued_security_features = self
+            .security_features
+            .get(&nonce)
+            .copied()
+            .unwrap_or_default();
+
+        if security_features != issued_security_features {
+            return Err(Error::InvalidNonce);
+        }
+
         let remaining_requests = entry.get_mut();
 
         if *remaining_requests == 0 {
             entry.remove();
+            self.security_features.remove(&nonce);
 
             return Err(Error::InvalidNonce);
         }
@@ -137,6 +370,16 @@ pub(crate) fn generate_password(
     BASE64_STANDARD_NO_PAD.encode(array.as_slice())
 }
 
+pub(crate) fn generate_turn_rest_password(secret: &SecretString, username: &str) -> String {
+    use hmac::Mac as _;
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+
+    BASE64_STANDARD.encode(mac.finalize().into_bytes())
+}
+
 pub(crate) fn systemtime_from_unix(seconds: u64) -> SystemTime {
     SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
 }
@@ -181,7 +424,7 @@ mod tests {
             "n23JJ2wKKtt30oXi",
         );
 
-        let result = message_integrity.verify(
+        let result = Integrity::from(&message_integrity).verify(
             &RELAY_SECRET_1.parse().unwrap(),
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000 - 1000),
@@ -198,7 +441,7 @@ mod tests {
             "n23JJ2wKKtt30oXi",
         );
 
-        let result = message_integrity.verify(
+        let result = Integrity::from(&message_integrity).verify(
             &RELAY_SECRET_1.parse().unwrap(),
             "1685199000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000),
@@ -215,7 +458,7 @@ mod tests {
             "n23JJ2wKKtt30oXi",
         );
 
-        let result = message_integrity.verify(
+        let result = Integrity::from(&message_integrity).verify(
             &RELAY_SECRET_1.parse().unwrap(),
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(168520000 + 1000),
@@ -232,7 +475,7 @@ mod tests {
             "n23JJ2wKKtt30oXi",
         );
 
-        let result = message_integrity.verify(
+        let result = Integrity::from(&message_integrity).verify(
             &RELAY_SECRET_1.parse().unwrap(),
             "foobar",
             systemtime_from_unix(168520000 + 1000),
@@ -241,6 +484,218 @@ mod tests {
         assert_eq!(result.unwrap_err(), Error::InvalidUsername)
     }
 
+    #[test]
+    fn firezone_authenticator_identifies_user_by_salt() {
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_1.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+
+        let user = Firezone
+            .verify(
+                &Integrity::from(&message_integrity),
+                "1685200000:n23JJ2wKKtt30oXi",
+                &RELAY_SECRET_1.parse().unwrap(),
+                systemtime_from_unix(1685200000 - 1000),
+            )
+            .unwrap();
+
+        assert_eq!(user, "n23JJ2wKKtt30oXi")
+    }
+
+    #[test]
+    fn static_credentials_smoke() {
+        let authenticator = static_credentials("alice", "secret");
+        let message_integrity = long_term_credential("alice", "secret");
+
+        let user = authenticator
+            .verify(
+                &Integrity::from(&message_integrity),
+                "alice",
+                &RELAY_SECRET_1.parse().unwrap(),
+                SystemTime::now(),
+            )
+            .unwrap();
+
+        assert_eq!(user, "alice")
+    }
+
+    #[test]
+    fn static_credentials_wrong_password_is_invalid() {
+        let authenticator = static_credentials("alice", "secret");
+        let message_integrity = long_term_credential("alice", "guess");
+
+        let result = authenticator.verify(
+            &Integrity::from(&message_integrity),
+            "alice",
+            &RELAY_SECRET_1.parse().unwrap(),
+            SystemTime::now(),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
+    #[test]
+    fn static_credentials_unknown_user_is_invalid() {
+        let authenticator = static_credentials("alice", "secret");
+        let message_integrity = long_term_credential("bob", "secret");
+
+        let result = authenticator.verify(
+            &Integrity::from(&message_integrity),
+            "bob",
+            &RELAY_SECRET_1.parse().unwrap(),
+            SystemTime::now(),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidUsername)
+    }
+
+    #[test]
+    fn static_credentials_sha256_smoke() {
+        let authenticator = static_credentials("alice", "secret");
+        let message_integrity = long_term_credential_sha256("alice", "secret");
+
+        let user = authenticator
+            .verify(
+                &Integrity::Sha256(&message_integrity, PasswordAlgorithm::Sha256),
+                "alice",
+                &RELAY_SECRET_1.parse().unwrap(),
+                SystemTime::now(),
+            )
+            .unwrap();
+
+        assert_eq!(user, "alice")
+    }
+
+    #[test]
+    fn sha256_integrity_with_wrong_algorithm_is_invalid() {
+        let authenticator = static_credentials("alice", "secret");
+        let message_integrity = long_term_credential_sha256("alice", "secret");
+
+        let result = authenticator.verify(
+            &Integrity::Sha256(&message_integrity, PasswordAlgorithm::Md5),
+            "alice",
+            &RELAY_SECRET_1.parse().unwrap(),
+            SystemTime::now(),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
+    #[test]
+    fn static_credentials_resolve_userhash() {
+        let authenticator = static_credentials("alice", "secret");
+
+        assert!(authenticator.supports_userhash());
+        assert_eq!(
+            authenticator.resolve_userhash(&Userhash::new("alice", &FIREZONE)),
+            Some("alice".to_owned())
+        );
+        assert_eq!(
+            authenticator.resolve_userhash(&Userhash::new("bob", &FIREZONE)),
+            None
+        );
+    }
+
+    #[test]
+    fn turn_rest_password_test_vector() {
+        let password = generate_turn_rest_password(&"north".parse().unwrap(), "1685200000:alice");
+
+        assert_eq!(password, "Y4ewOTc4Ej1d9nTSCAgGsGm9DgE=")
+    }
+
+    #[test]
+    fn turn_rest_api_smoke() {
+        let authenticator = TurnRestApi::new("north".parse().unwrap(), FIREZONE.clone());
+        let message_integrity =
+            long_term_credential("1685200000:alice", "Y4ewOTc4Ej1d9nTSCAgGsGm9DgE=");
+
+        let user = authenticator
+            .verify(
+                &Integrity::from(&message_integrity),
+                "1685200000:alice",
+                &RELAY_SECRET_1.parse().unwrap(),
+                systemtime_from_unix(1685200000 - 1000),
+            )
+            .unwrap();
+
+        assert_eq!(user, "alice")
+    }
+
+    #[test]
+    fn turn_rest_api_expired_is_not_valid() {
+        let authenticator = TurnRestApi::new("north".parse().unwrap(), FIREZONE.clone());
+        let message_integrity =
+            long_term_credential("1685200000:alice", "Y4ewOTc4Ej1d9nTSCAgGsGm9DgE=");
+
+        let result = authenticator.verify(
+            &Integrity::from(&message_integrity),
+            "1685200000:alice",
+            &RELAY_SECRET_1.parse().unwrap(),
+            systemtime_from_unix(1685200000 + 1),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::Expired)
+    }
+
+    #[test]
+    fn turn_rest_api_different_secret_makes_password_invalid() {
+        let authenticator = TurnRestApi::new("south".parse().unwrap(), FIREZONE.clone());
+        let message_integrity =
+            long_term_credential("1685200000:alice", "Y4ewOTc4Ej1d9nTSCAgGsGm9DgE=");
+
+        let result = authenticator.verify(
+            &Integrity::from(&message_integrity),
+            "1685200000:alice",
+            &RELAY_SECRET_1.parse().unwrap(),
+            systemtime_from_unix(1685200000 - 1000),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
+    #[test]
+    fn turn_rest_api_uses_configured_realm() {
+        let realm = Realm::new("example.com".to_owned()).unwrap();
+        let authenticator = TurnRestApi::new("north".parse().unwrap(), realm.clone());
+        let message_integrity = MessageIntegrity::new_long_term_credential(
+            &sample_message(),
+            &Username::new("1685200000:alice".to_owned()).unwrap(),
+            &realm,
+            "Y4ewOTc4Ej1d9nTSCAgGsGm9DgE=",
+        )
+        .unwrap();
+
+        assert_eq!(authenticator.realm().text(), "example.com");
+        assert_eq!(
+            authenticator.verify(
+                &Integrity::from(&message_integrity),
+                "1685200000:alice",
+                &RELAY_SECRET_1.parse().unwrap(),
+                systemtime_from_unix(1685200000 - 1000),
+            ),
+            Ok("alice".to_owned())
+        );
+    }
+
+    #[test]
+    fn turn_rest_api_rejects_credentials_of_other_realm() {
+        let realm = Realm::new("example.com".to_owned()).unwrap();
+        let authenticator = TurnRestApi::new("north".parse().unwrap(), realm);
+        let message_integrity =
+            long_term_credential("1685200000:alice", "Y4ewOTc4Ej1d9nTSCAgGsGm9DgE=");
+
+        let result = authenticator.verify(
+            &Integrity::from(&message_integrity),
+            "1685200000:alice",
+            &RELAY_SECRET_1.parse().unwrap(),
+            systemtime_from_unix(1685200000 - 1000),
+        );
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
     #[test]
     fn nonces_are_valid_for_10_requests() {
         let mut nonces = Nonces::default();
@@ -249,11 +704,15 @@ mod tests {
         nonces.add_new(nonce);
 
         for _ in 0..10 {
-            nonces.handle_nonce_used(nonce).unwrap();
+            nonces
+                .handle_nonce_used(nonce, SecurityFeatures::default())
+                .unwrap();
         }
 
         assert_eq!(
-            nonces.handle_nonce_used(nonce).unwrap_err(),
+            nonces
+                .handle_nonce_used(nonce, SecurityFeatures::default())
+                .unwrap_err(),
             Error::InvalidNonce
         );
     }
@@ -264,11 +723,33 @@ mod tests {
         let nonce = Uuid::new_v4();
 
         assert_eq!(
-            nonces.handle_nonce_used(nonce).unwrap_err(),
+            nonces
+                .handle_nonce_used(nonce, SecurityFeatures::default())
+                .unwrap_err(),
             Error::InvalidNonce
         );
     }
 
+    #[test]
+    fn nonces_must_be_used_with_issued_security_features() {
+        let mut nonces = Nonces::default();
+        let nonce = Uuid::new_v4();
+        let security_features = SecurityFeatures {
+            password_algorithms: true,
+            username_anonymity: false,
+        };
+
+        nonces.add_new_with_features(nonce, security_features);
+
+        assert_eq!(
+            nonces
+                .handle_nonce_used(nonce, SecurityFeatures::default())
+                .unwrap_err(),
+            Error::InvalidNonce
+        );
+        nonces.handle_nonce_used(nonce, security_features).unwrap();
+    }
+
     fn message_integrity(
         relay_secret: &SecretString,
         username_expiry: u64,
@@ -290,6 +771,34 @@ mod tests {
         .unwrap()
     }
 
+    fn static_credentials(username: &str, password: &str) -> StaticCredentials {
+        StaticCredentials::new(
+            [(username.to_owned(), password.parse().unwrap())],
+            FIREZONE.clone(),
+        )
+    }
+
+    fn long_term_credential(username: &str, password: &str) -> MessageIntegrity {
+        MessageIntegrity::new_long_term_credential(
+            &sample_message(),
+            &Username::new(username.to_owned()).unwrap(),
+            &FIREZONE,
+            password,
+        )
+        .unwrap()
+    }
+
+    fn long_term_credential_sha256(username: &str, password: &str) -> MessageIntegritySha256 {
+        MessageIntegritySha256::new_long_term_credential(
+            &sample_message(),
+            &Username::new(username.to_owned()).unwrap(),
+            &FIREZONE,
+            password,
+            PasswordAlgorithm::Sha256,
+        )
+        .unwrap()
+    }
+
     fn sample_message() -> Message<Attribute> {
         Message::new(
             MessageClass::Request,
@@ -0,0 +1,199 @@
+use std::time::{Duration, SystemTime};
+
+/// A rate limit for relayed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    /// The sustained rate at which data may be relayed.
+    pub bytes_per_second: u64,
+    /// How many bytes may be relayed in a single burst, i.e. after being idle for a while.
+    pub burst_bytes: u64,
+}
+
+impl BandwidthLimit {
+    /// A limit that allows bursts of one second worth of traffic.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            burst_bytes: bytes_per_second,
+        }
+    }
+}
+
+/// The bandwidth limits enforced by the [`Server`](crate::Server).
+///
+/// Data exceeding any of these limits is dropped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    /// The limit for each allocation, covering data in both directions.
+    pub per_allocation: Option<BandwidthLimit>,
+    /// The limit for all allocations of the same user, as identified by the [`Authenticator`](crate::Authenticator).
+    pub per_user: Option<BandwidthLimit>,
+}
+
+/// A token bucket, tracking how many bytes may be relayed at a given point in time.
+///
+/// The bucket holds up to `burst_bytes` tokens and is refilled at `bytes_per_second`.
+/// Relaying a packet consumes one token per byte.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    limit: BandwidthLimit,
+    tokens: u64,
+    /// Tokens refilled since the last whole one, in billionths of a token.
+    remainder: u128,
+    last_refill: SystemTime,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: BandwidthLimit, now: SystemTime) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst_bytes,
+            remainder: 0,
+            last_refill: now,
+        }
+    }
+
+    /// Whether `bytes` could be consumed from this bucket at the given point in time.
+    pub(crate) fn has_capacity(&mut self, bytes: usize, now: SystemTime) -> bool {
+        self.refill(now);
+
+        self.tokens >= bytes as u64
+    }
+
+    /// Consumes `bytes` from this bucket.
+    ///
+    /// Must be preceded by a call to [`TokenBucket::has_capacity`].
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.tokens = self.tokens.saturating_sub(bytes as u64);
+    }
+
+    /// Consumes `bytes` from this bucket even if they exceed its capacity, e.g. because they have already been relayed.
+    ///
+    /// Returns until when no more data may be relayed to get back within the limit, if it was exceeded.
+    pub(crate) fn consume_overdrawn(
+        &mut self,
+        bytes: usize,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        self.refill(now);
+
+        let missing = (bytes as u64).saturating_sub(self.tokens);
+        self.consume(bytes);
+
+        if missing == 0 {
+            return None;
+        }
+
+        let nanos = missing as u128 * 1_000_000_000 / self.limit.bytes_per_second.max(1) as u128;
+        let delay = Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+
+        // Tokens are only refilled again once the missing ones would have been.
+        self.remainder = 0;
+        self.last_refill = self.last_refill.max(now) + delay;
+
+        Some(self.last_refill)
+    }
+
+    fn refill(&mut self, now: SystemTime) {
+        let Ok(elapsed) = now.duration_since(self.last_refill) else {
+            return; // Time went backwards, don't refill.
+        };
+
+        let refilled = elapsed.as_nanos() * self.limit.bytes_per_second as u128 + self.remainder;
+        let new_tokens = u64::try_from(refilled / 1_000_000_000).unwrap_or(u64::MAX);
+
+        self.tokens = self
+            .tokens
+            .saturating_add(new_tokens)
+            .min(self.limit.burst_bytes);
+        self.remainder = if self.tokens == self.limit.burst_bytes {
+            0 // A full bucket doesn't keep fractional tokens either.
+        } else {
+            refilled % 1_000_000_000
+        };
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_with_full_burst() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(limit(1000, 1500), now);
+
+        assert!(bucket.has_capacity(1500, now));
+        assert!(!bucket.has_capacity(1501, now));
+    }
+
+    #[test]
+    fn refills_at_configured_rate() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(limit(1000, 1000), now);
+
+        bucket.consume(1000);
+
+        assert!(!bucket.has_capacity(1, now));
+        assert!(bucket.has_capacity(500, now + Duration::from_millis(500)));
+        assert!(!bucket.has_capacity(501, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn does_not_refill_beyond_burst() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(limit(1000, 1000), now);
+
+        bucket.consume(1000);
+
+        assert!(!bucket.has_capacity(1001, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn accumulates_fractional_refills() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(limit(1, 1), now);
+
+        bucket.consume(1);
+
+        assert!(!bucket.has_capacity(1, now + Duration::from_millis(500)));
+        assert!(bucket.has_capacity(1, now + Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn keeps_fractional_tokens_across_refills() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(limit(2, 10), now);
+
+        bucket.consume(10);
+
+        assert!(bucket.has_capacity(1, now + Duration::from_millis(750)));
+        assert!(bucket.has_capacity(3, now + Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn overdrawing_pauses_until_missing_tokens_are_refilled() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(limit(1000, 1000), now);
+
+        assert_eq!(bucket.consume_overdrawn(1000, now), None);
+        assert_eq!(
+            bucket.consume_overdrawn(500, now),
+            Some(now + Duration::from_millis(500))
+        );
+        assert_eq!(
+            bucket.consume_overdrawn(500, now),
+            Some(now + Duration::from_secs(1))
+        );
+        assert!(!bucket.has_capacity(1, now + Duration::from_secs(1)));
+        assert!(bucket.has_capacity(1, now + Duration::from_millis(1001)));
+    }
+
+    fn limit(bytes_per_second: u64, burst_bytes: u64) -> BandwidthLimit {
+        BandwidthLimit {
+            bytes_per_second,
+            burst_bytes,
+        }
+    }
+}
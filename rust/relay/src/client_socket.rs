@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+
+/// The address of a client together with the transport it uses to talk to us.
+///
+/// Combined with our own address, this is the 5-tuple that identifies the allocation of a client, see <https://www.rfc-editor.org/rfc/rfc8656#name-allocations-2>.
+/// A client connected via TCP may use the same address as a client talking to us via UDP, thus the address alone is not enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ClientSocket {
+    pub transport: ClientTransport,
+    pub addr: SocketAddr,
+}
+
+/// The transport between a client and the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ClientTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+impl ClientSocket {
+    pub fn udp(addr: SocketAddr) -> Self {
+        Self {
+            transport: ClientTransport::Udp,
+            addr,
+        }
+    }
+
+    pub fn tcp(addr: SocketAddr) -> Self {
+        Self {
+            transport: ClientTransport::Tcp,
+            addr,
+        }
+    }
+
+    pub fn tls(addr: SocketAddr) -> Self {
+        Self {
+            transport: ClientTransport::Tls,
+            addr,
+        }
+    }
+
+    /// Whether the client is connected via TCP or TLS.
+    pub fn is_stream(&self) -> bool {
+        self.transport != ClientTransport::Udp
+    }
+}
+
+impl ClientTransport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientTransport::Udp => "udp",
+            ClientTransport::Tcp => "tcp",
+            ClientTransport::Tls => "tls",
+        }
+    }
+}
+
+impl fmt::Display for ClientSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.transport.as_str())
+    }
+}
@@ -0,0 +1,294 @@
+//! The optional configuration file of the relay.
+//!
+//! The file is written in TOML and mirrors a subset of our command-line options, grouped into sections.
+//! Settings in the file take precedence over the corresponding options.
+//! See `README.md` for an example.
+
+use crate::{Args, LogFormat};
+use anyhow::{bail, Context, Result};
+use ip_network::IpNetwork;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Deserializer};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    network: Network,
+    auth: Auth,
+    limits: Limits,
+    log: Log,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Network {
+    public_ip4_addr: Option<Ipv4Addr>,
+    public_ip6_addr: Option<Ipv6Addr>,
+    lowest_port: Option<u16>,
+    highest_port: Option<u16>,
+}
+
+/// Replaces the authentication backend given on the command line, if `static_credentials` or `turn_rest_secret` is present.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Auth {
+    static_credentials: Option<Vec<String>>,
+    turn_rest_secret: Option<String>,
+    realm: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Limits {
+    allocation_bandwidth_limit: Option<u64>,
+    user_bandwidth_limit: Option<u64>,
+    #[serde(deserialize_with = "deserialize_networks")]
+    allowed_peer_networks: Option<Vec<IpNetwork>>,
+    #[serde(deserialize_with = "deserialize_networks")]
+    denied_peer_networks: Option<Vec<IpNetwork>>,
+    max_stream_connections: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Log {
+    format: Option<LogFormat>,
+    /// Directives in the syntax of `RUST_LOG`.
+    filter: Option<String>,
+}
+
+impl Config {
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Self::parse(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let config = toml::from_str::<Self>(content)?;
+
+        if config.auth.static_credentials.is_some() && config.auth.turn_rest_secret.is_some() {
+            bail!("`static_credentials` and `turn_rest_secret` cannot be used together")
+        }
+        if let (Some(lowest), Some(highest)) =
+            (config.network.lowest_port, config.network.highest_port)
+        {
+            if lowest > highest {
+                bail!("`lowest_port` ({lowest}) must not be higher than `highest_port` ({highest})")
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Overrides the given [`Args`] with all settings present in this file.
+    pub fn apply(self, args: &mut Args) {
+        let Config {
+            network,
+            auth,
+            limits,
+            log,
+        } = self;
+
+        override_with(&mut args.public_ip4_addr, network.public_ip4_addr);
+        override_with(&mut args.public_ip6_addr, network.public_ip6_addr);
+        if let Some(port) = network.lowest_port {
+            args.lowest_port = port;
+        }
+        if let Some(port) = network.highest_port {
+            args.highest_port = port;
+        }
+
+        if let Some(credentials) = auth.static_credentials {
+            args.static_credentials = credentials;
+            args.turn_rest_secret = None;
+        }
+        if let Some(secret) = auth.turn_rest_secret {
+            args.static_credentials = Vec::new();
+            args.turn_rest_secret = Some(SecretString::from(secret));
+        }
+        if let Some(realm) = auth.realm {
+            args.realm = realm;
+        }
+
+        override_with(
+            &mut args.allocation_bandwidth_limit,
+            limits.allocation_bandwidth_limit,
+        );
+        override_with(&mut args.user_bandwidth_limit, limits.user_bandwidth_limit);
+        if let Some(networks) = limits.allowed_peer_networks {
+            args.allowed_peer_networks = networks;
+        }
+        override_with(&mut args.denied_peer_networks, limits.denied_peer_networks);
+        if let Some(max) = limits.max_stream_connections {
+            args.max_stream_connections = max;
+        }
+
+        if let Some(format) = log.format {
+            args.log_format = format;
+        }
+        override_with(&mut args.log_filter, log.filter);
+    }
+}
+
+/// The settings that differ between the running and the reloaded [`Args`] but can only be changed by restarting the relay.
+pub fn settings_requiring_restart(running: &Args, reloaded: &Args) -> Vec<&'static str> {
+    let mut settings = Vec::new();
+
+    macro_rules! compare {
+        ($($field:ident),+) => {
+            $(
+                if running.$field != reloaded.$field {
+                    settings.push(stringify!($field));
+                }
+            )+
+        };
+    }
+
+    compare!(
+        public_ip4_addr,
+        public_ip6_addr,
+        lowest_port,
+        highest_port,
+        max_stream_connections,
+        static_credentials,
+        realm,
+        log_format
+    );
+
+    if running.turn_rest_secret.as_ref().map(|s| s.expose_secret())
+        != reloaded
+            .turn_rest_secret
+            .as_ref()
+            .map(|s| s.expose_secret())
+    {
+        settings.push("turn_rest_secret");
+    }
+
+    settings
+}
+
+pub fn override_with<T>(setting: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *setting = value;
+    }
+}
+
+/// Deserializes a list of networks in CIDR notation, i.e. `10.0.0.0/8`.
+pub fn deserialize_networks<'de, D>(deserializer: D) -> Result<Option<Vec<IpNetwork>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(networks) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    networks
+        .iter()
+        .map(|network| network.parse().map_err(serde::de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const EXAMPLE: &str = r#"
+        [network]
+        public_ip4_addr = "203.0.113.1"
+        lowest_port = 50000
+        highest_port = 60000
+
+        [auth]
+        turn_rest_secret = "north"
+        realm = "example.com"
+
+        [limits]
+        user_bandwidth_limit = 1000000
+        denied_peer_networks = ["10.0.0.0/8", "fd00::/8"]
+
+        [log]
+        format = "google-cloud"
+        filter = "info,relay=debug"
+    "#;
+
+    #[test]
+    fn example_overrides_args() {
+        let mut args = Args::parse_from(["firezone-relay", "--static-credentials", "foo:bar"]);
+
+        Config::parse(EXAMPLE).unwrap().apply(&mut args);
+
+        assert_eq!(args.public_ip4_addr, Some(Ipv4Addr::new(203, 0, 113, 1)));
+        assert_eq!(args.lowest_port, 50000);
+        assert_eq!(args.highest_port, 60000);
+        assert!(args.static_credentials.is_empty());
+        assert_eq!(
+            args.turn_rest_secret.unwrap().expose_secret().as_str(),
+            "north"
+        );
+        assert_eq!(args.realm, "example.com");
+        assert_eq!(args.user_bandwidth_limit, Some(1_000_000));
+        assert_eq!(
+            args.denied_peer_networks,
+            Some(vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "fd00::/8".parse().unwrap()
+            ])
+        );
+        assert_eq!(args.log_format, LogFormat::GoogleCloud);
+        assert_eq!(args.log_filter.as_deref(), Some("info,relay=debug"));
+    }
+
+    #[test]
+    fn missing_settings_keep_args() {
+        let mut args = Args::parse_from(["firezone-relay", "--allocation-bandwidth-limit", "500"]);
+
+        Config::parse("").unwrap().apply(&mut args);
+
+        assert_eq!(args.allocation_bandwidth_limit, Some(500));
+        assert_eq!(args.lowest_port, 49152);
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(Config::parse("[limits]\nbandwidth = 1").is_err());
+    }
+
+    #[test]
+    fn both_auth_backends_are_rejected() {
+        assert!(Config::parse(
+            "[auth]\nstatic_credentials = [\"foo:bar\"]\nturn_rest_secret = \"north\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn inverted_port_range_is_rejected() {
+        assert!(Config::parse("[network]\nlowest_port = 60000\nhighest_port = 50000").is_err());
+    }
+
+    #[test]
+    fn only_restart_settings_are_reported() {
+        let running = Args::parse_from(["firezone-relay"]);
+        let mut reloaded = running.clone();
+
+        Config::parse(EXAMPLE).unwrap().apply(&mut reloaded);
+
+        assert_eq!(
+            settings_requiring_restart(&running, &reloaded),
+            [
+                "public_ip4_addr",
+                "lowest_port",
+                "highest_port",
+                "realm",
+                "log_format",
+                "turn_rest_secret"
+            ]
+        );
+    }
+}
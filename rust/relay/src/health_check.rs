@@ -1,16 +1,146 @@
 use anyhow::Result;
+use axum::extract::State;
+use axum::http::StatusCode;
 use axum::routing::get;
 use axum::{Router, Server};
+use prometheus::{Encoder as _, TextEncoder};
+use std::iter;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-pub async fn serve(addr: impl Into<SocketAddr>) -> Result<()> {
+/// Tracks whether the relay should receive new clients.
+///
+/// Updated by the eventloops of all shards and reported at `/readyz`.
+#[derive(Debug)]
+pub struct Readiness {
+    portal_disconnected: AtomicBool,
+    draining: AtomicBool,
+    shards: Vec<ShardReadiness>,
+}
+
+/// The readiness flags that are tracked per [`Shard`](crate::Shard).
+///
+/// The relay is not ready as soon as one of its shards is not.
+#[derive(Debug, Default)]
+struct ShardReadiness {
+    port_pool_exhausted: AtomicBool,
+    udp_channel_full: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new(num_shards: usize) -> Self {
+        Self {
+            portal_disconnected: AtomicBool::default(),
+            draining: AtomicBool::default(),
+            shards: iter::repeat_with(ShardReadiness::default)
+                .take(num_shards)
+                .collect(),
+        }
+    }
+
+    pub fn set_portal_disconnected(&self, value: bool) {
+        self.portal_disconnected.store(value, Ordering::Relaxed);
+    }
+
+    pub fn set_draining(&self, value: bool) {
+        self.draining.store(value, Ordering::Relaxed);
+    }
+
+    pub fn set_port_pool_exhausted(&self, shard: usize, value: bool) {
+        self.shards[shard]
+            .port_pool_exhausted
+            .store(value, Ordering::Relaxed);
+    }
+
+    pub fn set_udp_channel_full(&self, shard: usize, value: bool) {
+        self.shards[shard]
+            .udp_channel_full
+            .store(value, Ordering::Relaxed);
+    }
+
+    /// The reasons why we are not ready, empty if we are.
+    fn not_ready_reasons(&self) -> Vec<&'static str> {
+        let any_shard = |flag: fn(&ShardReadiness) -> &AtomicBool| {
+            self.shards
+                .iter()
+                .any(|shard| flag(shard).load(Ordering::Relaxed))
+        };
+
+        [
+            (
+                self.portal_disconnected.load(Ordering::Relaxed),
+                "portal connection is down",
+            ),
+            (self.draining.load(Ordering::Relaxed), "relay is draining"),
+            (
+                any_shard(|shard| &shard.port_pool_exhausted),
+                "port pool is exhausted",
+            ),
+            (
+                any_shard(|shard| &shard.udp_channel_full),
+                "channel to UDP socket is full",
+            ),
+        ]
+        .into_iter()
+        .filter(|(not_ready, _)| *not_ready)
+        .map(|(_, reason)| reason)
+        .collect()
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+/// Serves our health-check and readiness endpoints.
+///
+/// If a [`prometheus::Registry`] is given, its metrics are served at `/metrics`.
+pub async fn serve(
+    addr: impl Into<SocketAddr>,
+    readiness: Arc<Readiness>,
+    prometheus_registry: Option<prometheus::Registry>,
+) -> Result<()> {
     let addr = addr.into();
 
-    let service = Router::new()
+    let mut router = Router::new()
         .route("/healthz", get(|| async { "" }))
-        .into_make_service();
+        .route("/readyz", get(readyz))
+        .with_state(readiness);
+
+    if let Some(registry) = prometheus_registry {
+        router = router.merge(
+            Router::new()
+                .route("/metrics", get(metrics))
+                .with_state(registry),
+        );
+    }
 
-    Server::try_bind(&addr)?.serve(service).await?;
+    Server::try_bind(&addr)?
+        .serve(router.into_make_service())
+        .await?;
 
     Ok(())
 }
+
+async fn readyz(State(readiness): State<Arc<Readiness>>) -> (StatusCode, String) {
+    let reasons = readiness.not_ready_reasons();
+
+    if reasons.is_empty() {
+        return (StatusCode::OK, String::new());
+    }
+
+    (StatusCode::SERVICE_UNAVAILABLE, reasons.join("\n"))
+}
+
+async fn metrics(State(registry): State<prometheus::Registry>) -> Result<String, StatusCode> {
+    let mut buffer = Vec::new();
+
+    TextEncoder::new()
+        .encode(&registry.gather(), &mut buffer)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    String::from_utf8(buffer).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
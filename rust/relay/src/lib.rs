@@ -1,28 +1,64 @@
 mod allocation;
 mod auth;
+mod bandwidth;
+mod client_socket;
+mod load_shedding;
 mod net_ext;
+mod peer_filter;
+mod rfc5780;
+mod rfc6062;
+mod rfc8016;
+mod rfc8489;
 mod server;
+mod shard;
 mod sleep;
+mod stream;
 mod time_events;
 mod udp_socket;
+mod usage;
+mod xdp;
 
+pub mod admin;
 pub mod health_check;
 #[cfg(feature = "proptest")]
 pub mod proptest;
 
-pub use allocation::Allocation;
+pub use allocation::{Allocation, TcpAllocation};
+pub use auth::{
+    Authenticator, Error as AuthError, Firezone, Integrity, StaticCredentials, TurnRestApi,
+};
+pub use bandwidth::{BandwidthLimit, BandwidthLimits};
+pub use client_socket::{ClientSocket, ClientTransport};
+pub use load_shedding::LoadShedding;
 pub use net_ext::{IpAddrExt, SocketAddrExt};
+pub use peer_filter::PeerFilter;
+pub use rfc5780::{ChangeRequest, DiscoveryOrigin, NatDiscovery, OtherAddress, ResponseOrigin};
+pub use rfc6062::{
+    connection_bind_target, ConnectionId, CONNECT, CONNECTION_ATTEMPT, CONNECTION_BIND,
+};
+pub use rfc8016::MobilityTicket;
+pub use rfc8489::{
+    MessageIntegritySha256, PasswordAlgorithm, PasswordAlgorithms, SecurityFeatures, Userhash,
+};
 pub use server::{
     Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    Connect, ConnectionBind, CreatePermission, Refresh, SendIndication, Server, Snapshot,
+    AUTH_SECRET_GRACE_PERIOD,
 };
+pub use shard::Shard;
 pub use sleep::Sleep;
+pub use stream::{
+    bind_tcp_listener, bind_tcp_socket, pad_for_stream, StreamConnectionLimit,
+    StreamConnectionPermit, StreamFramer,
+};
 pub use stun_codec::rfc8656::attributes::AddressFamily;
-pub use udp_socket::UdpSocket;
+pub use udp_socket::{BufferPool, PooledBuffer, UdpSocket, MAX_BATCH_SIZE};
+pub use usage::{ChannelUsage, Traffic, Usage, UsageRecord};
+pub use xdp::{FastPath, FastPathUsage};
 
 pub(crate) use time_events::TimeEvents;
 
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// Describes the IP stack of a relay server.
 #[derive(Debug, Copy, Clone)]
@@ -48,6 +84,14 @@ impl IpStack {
             IpStack::Dual { ip6, .. } => Some(ip6),
         }
     }
+
+    /// The address of the given [`AddressFamily`], if we have one.
+    pub fn for_family(&self, family: AddressFamily) -> Option<IpAddr> {
+        match family {
+            AddressFamily::V4 => self.as_v4().map(|ip4| IpAddr::from(*ip4)),
+            AddressFamily::V6 => self.as_v6().map(|ip6| IpAddr::from(*ip6)),
+        }
+    }
 }
 
 impl From<Ipv4Addr> for IpStack {
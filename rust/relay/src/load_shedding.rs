@@ -0,0 +1,119 @@
+use std::net::SocketAddr;
+
+/// Redirects new allocations to sibling relays once this relay is too busy.
+///
+/// The load of a relay is the fraction of its port range that is in use by allocations.
+/// Once it reaches the threshold, clients are sent to one of the alternate servers instead, see <https://www.rfc-editor.org/rfc/rfc8489#section-10>.
+/// Without any alternate servers, a relay keeps accepting allocations until its port range is exhausted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadShedding {
+    alternate_servers: Vec<SocketAddr>,
+    threshold: f64,
+    /// The index of the alternate server to try first next time, to spread clients evenly across our siblings.
+    next: usize,
+}
+
+impl LoadShedding {
+    /// The threshold used unless configured otherwise: Only redirect clients once all ports are in use.
+    pub const DEFAULT_THRESHOLD: f64 = 1.0;
+
+    pub fn new(alternate_servers: Vec<SocketAddr>, threshold: f64) -> Self {
+        Self {
+            alternate_servers,
+            threshold: threshold.clamp(0.0, 1.0),
+            next: 0,
+        }
+    }
+
+    pub(crate) fn set_alternate_servers(&mut self, alternate_servers: Vec<SocketAddr>) {
+        self.alternate_servers = alternate_servers;
+        self.next = 0;
+    }
+
+    /// Picks an alternate server for the given client if our `load` is at or beyond the threshold.
+    ///
+    /// Only servers of the same address family as the client are considered because that is how the client reached us.
+    pub(crate) fn alternate_server(&mut self, client: SocketAddr, load: f64) -> Option<SocketAddr> {
+        if load < self.threshold {
+            return None;
+        }
+
+        let num_servers = self.alternate_servers.len();
+
+        let (index, server) = (0..num_servers)
+            .map(|offset| (self.next + offset) % num_servers)
+            .map(|index| (index, self.alternate_servers[index]))
+            .find(|(_, server)| server.is_ipv4() == client.is_ipv4())?;
+
+        self.next = index + 1;
+
+        Some(server)
+    }
+}
+
+impl Default for LoadShedding {
+    fn default() -> Self {
+        Self::new(Vec::new(), Self::DEFAULT_THRESHOLD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn does_not_redirect_below_threshold() {
+        let mut load_shedding = LoadShedding::new(vec!["10.0.0.2:3478".parse().unwrap()], 0.8);
+
+        assert_eq!(
+            load_shedding.alternate_server("1.1.1.1:1000".parse().unwrap(), 0.5),
+            None
+        );
+    }
+
+    #[test]
+    fn cycles_through_alternate_servers() {
+        let mut load_shedding = LoadShedding::new(
+            vec![
+                "10.0.0.2:3478".parse().unwrap(),
+                "10.0.0.3:3478".parse().unwrap(),
+            ],
+            0.8,
+        );
+        let client = "1.1.1.1:1000".parse().unwrap();
+
+        assert_eq!(
+            load_shedding.alternate_server(client, 0.8),
+            Some("10.0.0.2:3478".parse().unwrap())
+        );
+        assert_eq!(
+            load_shedding.alternate_server(client, 0.9),
+            Some("10.0.0.3:3478".parse().unwrap())
+        );
+        assert_eq!(
+            load_shedding.alternate_server(client, 1.0),
+            Some("10.0.0.2:3478".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn only_redirects_to_servers_of_the_same_address_family() {
+        let mut load_shedding = LoadShedding::new(
+            vec![
+                "10.0.0.2:3478".parse().unwrap(),
+                "[2001:db8::2]:3478".parse().unwrap(),
+            ],
+            0.8,
+        );
+
+        assert_eq!(
+            load_shedding.alternate_server("[2001:db8::1]:1000".parse().unwrap(), 1.0),
+            Some("[2001:db8::2]:3478".parse().unwrap())
+        );
+        assert_eq!(
+            LoadShedding::new(vec!["10.0.0.2:3478".parse().unwrap()], 0.8)
+                .alternate_server("[2001:db8::1]:1000".parse().unwrap(), 1.0),
+            None
+        );
+    }
+}
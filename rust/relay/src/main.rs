@@ -1,43 +1,104 @@
+use crate::config::Config;
+use crate::messages::{
+    AlternateServersUpdated, ConfigUpdated, DrainRequested, EgressMessages, IngressMessages, Init,
+    JoinMessage, NoncesIssued, SecretRotated, UsageReported, UsernameSaltRevoked,
+};
 use anyhow::{anyhow, bail, Context, Result};
+use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
+use firezone_relay::admin::{AdminRequest, AllocationInfo};
+use firezone_relay::health_check::Readiness;
 use firezone_relay::{
-    AddressFamily, Allocation, AllocationId, Command, IpStack, Server, Sleep, SocketAddrExt,
-    UdpSocket,
+    bind_tcp_listener, connection_bind_target, pad_for_stream, AddressFamily, Allocation,
+    AllocationId, BandwidthLimit, BandwidthLimits, BufferPool, ClientSocket, Command, ConnectionId,
+    DiscoveryOrigin, FastPath, FastPathUsage, IpStack, LoadShedding, NatDiscovery, PeerFilter,
+    PooledBuffer, Server, Shard, Sleep, Snapshot, SocketAddrExt, StaticCredentials,
+    StreamConnectionLimit, StreamFramer, TcpAllocation, TurnRestApi, UdpSocket, UsageRecord,
+    MAX_BATCH_SIZE,
 };
-use futures::channel::mpsc;
-use futures::{future, FutureExt, SinkExt, StreamExt};
+use futures::channel::{mpsc, oneshot};
+use futures::future::BoxFuture;
+use futures::{future, FutureExt, SinkExt, StreamExt, TryFutureExt};
+use ip_network::IpNetwork;
 use opentelemetry::{sdk, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
-use phoenix_channel::{Error, Event, PhoenixChannel, SecureUrl};
+use phoenix_channel::{Error, Event, OutboundRequestId, PhoenixChannel, SecureUrl};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use secrecy::{Secret, SecretString};
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Poll;
-use std::time::SystemTime;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use stun_codec::rfc5389::attributes::{Realm, Software};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::{rustls, TlsAcceptor};
 use tracing::{level_filters::LevelFilter, Instrument, Subscriber};
 use tracing_core::Dispatch;
 use tracing_stackdriver::CloudTraceConfiguration;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer};
 use url::Url;
+use uuid::Uuid;
+
+mod config;
+mod messages;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 struct Args {
+    /// Path to a TOML file with further settings.
+    ///
+    /// Settings in this file take precedence over command-line options and environment variables.
+    /// On SIGHUP, the file is read again and changes to bandwidth limits, peer networks and the log filter are applied.
+    #[arg(long, env)]
+    config_file: Option<PathBuf>,
     /// The public (i.e. internet-reachable) IPv4 address of the relay server.
     #[arg(long, env)]
     public_ip4_addr: Option<Ipv4Addr>,
     /// The public (i.e. internet-reachable) IPv6 address of the relay server.
     #[arg(long, env)]
     public_ip6_addr: Option<Ipv6Addr>,
+    /// The local IPv4 address to bind our sockets to.
+    ///
+    /// Defaults to all interfaces. Set this on machines with multiple interfaces.
+    /// If `public_ip4_addr` is omitted, this address is also advertised to clients.
+    #[arg(long, env)]
+    listen_ip4_addr: Option<Ipv4Addr>,
+    /// The local IPv6 address to bind our sockets to.
+    ///
+    /// Defaults to all interfaces. Set this on machines with multiple interfaces.
+    /// If `public_ip6_addr` is omitted, this address is also advertised to clients.
+    #[arg(long, env)]
+    listen_ip6_addr: Option<Ipv6Addr>,
+    /// The port on which we accept STUN & TURN traffic over UDP and TCP.
+    #[arg(long, env, default_value = "3478")]
+    listen_port: u16,
     /// The address of the local interface where we should serve our health-check endpoint.
     ///
     /// The actual health-check endpoint will be at `http://<health_check_addr>/healthz`.
     #[arg(long, env, default_value = "0.0.0.0:8080")]
     health_check_addr: SocketAddr,
+    /// The address of the local interface where we should serve the admin API.
+    ///
+    /// Only served if `admin_token` is set.
+    #[arg(long, env, default_value = "127.0.0.1:8081")]
+    admin_listen_addr: SocketAddr,
+    /// The token that requests to the admin API must carry as `Authorization: Bearer <token>`.
+    ///
+    /// If omitted, we don't serve the admin API.
+    #[arg(long, env)]
+    admin_token: Option<SecretString>,
     // See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
     /// The lowest port used for TURN allocations.
     #[arg(long, env, default_value = "49152")]
@@ -45,6 +106,122 @@ struct Args {
     /// The highest port used for TURN allocations.
     #[arg(long, env, default_value = "65535")]
     highest_port: u16,
+    /// Path to a PEM-encoded certificate chain for serving TURN over TLS.
+    ///
+    /// TLS listeners are only started if both, a certificate and a private key are provided.
+    #[arg(long, env, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// Path to the PEM-encoded private key belonging to `tls_cert_file`.
+    #[arg(long, env, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
+    /// The ports on which we accept TURN over TLS.
+    ///
+    /// The standard port for TURNS is 5349.
+    /// Add 443 to reach clients behind firewalls that only allow HTTPS.
+    #[arg(long, env, value_delimiter = ',', default_value = "5349")]
+    tls_ports: Vec<u16>,
+    /// The maximum number of concurrent TCP and TLS connections of clients.
+    ///
+    /// Further connections are closed right after accepting them, until existing ones are closed.
+    #[arg(long, env, default_value = "10000")]
+    max_stream_connections: usize,
+    /// The maximum rate in bytes per second at which data is relayed for a single allocation.
+    ///
+    /// Data exceeding this rate is dropped.
+    #[arg(long, env)]
+    allocation_bandwidth_limit: Option<u64>,
+    /// The maximum rate in bytes per second at which data is relayed for all allocations of a single user.
+    ///
+    /// Data exceeding this rate is dropped.
+    #[arg(long, env)]
+    user_bandwidth_limit: Option<u64>,
+    /// Networks that clients may relay data to, even if they are part of a denied network.
+    #[arg(long, env, value_delimiter = ',')]
+    allowed_peer_networks: Vec<IpNetwork>,
+    /// Networks that clients may not relay data to.
+    ///
+    /// Defaults to "this" network, loopback, link-local and cloud metadata addresses.
+    #[arg(long, env, value_delimiter = ',')]
+    denied_peer_networks: Option<Vec<IpNetwork>>,
+    /// Sibling relays that clients are redirected to once this relay is loaded beyond `load_shedding_threshold`.
+    ///
+    /// If connected to the portal, this list is replaced by the relays the portal tells us about.
+    #[arg(long, env, value_delimiter = ',')]
+    alternate_servers: Vec<SocketAddr>,
+    /// The fraction of the port range in use at which new allocations are redirected to an alternate server.
+    #[arg(long, env, default_value = "1.0")]
+    load_shedding_threshold: f64,
+    /// The name the relay identifies itself with in the SOFTWARE attribute of its responses.
+    ///
+    /// The version of the relay is appended to it. Set to an empty string to omit the attribute.
+    #[arg(long, env, default_value = "firezone-relay")]
+    software: String,
+    /// The number of worker threads that relay traffic.
+    ///
+    /// Each worker serves a shard of the clients with its own sockets on the listen port, shared via `SO_REUSEPORT`.
+    /// It owns the allocations of its clients and an equal part of the port range.
+    /// Mobility (RFC 8016) is only offered with a single worker.
+    #[arg(long, env, default_value = "1")]
+    workers: NonZeroUsize,
+    /// The network interface to attach the XDP program in `xdp_object` to.
+    ///
+    /// If set, channel data between IPv4 clients and peers is relayed in the kernel and never reaches the relay process.
+    /// The kernel cannot enforce bandwidth limits, thus the channels of rate-limited allocations are still relayed by us.
+    #[arg(long, env, requires = "xdp_object")]
+    xdp_interface: Option<String>,
+    /// Path to the compiled XDP program, see `xdp/channel_data.c`.
+    #[arg(long, env)]
+    xdp_object: Option<PathBuf>,
+    /// Replace an XDP program that is already attached to `xdp_interface`.
+    ///
+    /// By default, we refuse to start if the interface already has an XDP program, which may belong to another application.
+    #[arg(long, env, requires = "xdp_interface")]
+    xdp_replace: bool,
+    /// The alternate port for NAT behaviour discovery as per RFC 5780.
+    ///
+    /// If set, we also answer STUN Binding requests on this port and honor their CHANGE-REQUEST attribute.
+    /// This allows clients to find out whether they are behind a NAT with endpoint-dependent mapping or filtering.
+    #[arg(long, env)]
+    nat_discovery_port: Option<u16>,
+    /// The alternate public IPv4 address for NAT behaviour discovery.
+    ///
+    /// Must be assigned to a local interface, we bind our sockets to it.
+    /// Without an alternate address, clients can only ask us to respond from the alternate port.
+    #[arg(long, env, requires = "nat_discovery_port")]
+    nat_discovery_ip4_addr: Option<Ipv4Addr>,
+    /// The alternate public IPv6 address for NAT behaviour discovery.
+    ///
+    /// Must be assigned to a local interface, we bind our sockets to it.
+    #[arg(long, env, requires = "nat_discovery_port")]
+    nat_discovery_ip6_addr: Option<Ipv6Addr>,
+    /// Path to a file for keeping allocations across restarts of the relay.
+    ///
+    /// On SIGTERM, the relay writes its state to this file before exiting.
+    /// On startup, the relay restores the state from this file if it exists.
+    /// The file contains the auth secret, keep it private.
+    #[arg(long, env)]
+    state_file: Option<PathBuf>,
+    /// How long to wait for existing allocations to go away once draining, in seconds.
+    ///
+    /// Draining is started by SIGQUIT, `POST /drain` on the health-check listener or the portal.
+    /// The relay then refuses new allocations and exits once all existing ones expired or this timeout passed.
+    #[arg(long, env, default_value = "3600")]
+    drain_timeout: u64,
+    /// Authenticate clients with these static credentials instead of the ones issued by the portal.
+    ///
+    /// Each credential is of the form `username:password`.
+    #[arg(long, env, value_delimiter = ',', conflicts_with = "turn_rest_secret")]
+    static_credentials: Vec<String>,
+    /// Authenticate clients with credentials of the TURN REST API instead of the ones issued by the portal.
+    ///
+    /// This is the shared secret used to derive the passwords, compatible with coturn's `static-auth-secret`.
+    #[arg(long, env)]
+    turn_rest_secret: Option<SecretString>,
+    /// The realm clients authenticate in with static credentials or credentials of the TURN REST API.
+    ///
+    /// Credentials issued by the portal always use the `firezone` realm.
+    #[arg(long, env, default_value = "firezone")]
+    realm: String,
     /// Firezone admin portal websocket URL
     #[arg(long, env, default_value = "wss://api.firezone.dev")]
     portal_url: Url,
@@ -63,12 +240,24 @@ struct Args {
     #[arg(long, env, default_value = "human")]
     log_format: LogFormat,
 
+    /// Which logs to emit, in the syntax of `RUST_LOG`.
+    ///
+    /// Defaults to the value of `RUST_LOG` or `info` if that is unset.
+    #[arg(long)]
+    log_filter: Option<String>,
+
     /// Which OTLP collector we should connect to.
     ///
     /// If set, we will report traces and metrics to this collector via gRPC.
     #[arg(long, env)]
     otlp_grpc_endpoint: Option<SocketAddr>,
 
+    /// Serve metrics in the Prometheus format at `/metrics` on the health-check listener.
+    ///
+    /// Metrics are still reported to the OTLP collector if `otlp_grpc_endpoint` is set.
+    #[arg(long, env)]
+    prometheus_metrics: bool,
+
     /// The Google Project ID to embed in spans.
     ///
     /// Set this if you are running on Google Cloud but using the OTLP trace collector.
@@ -77,7 +266,8 @@ struct Args {
     google_cloud_project_id: Option<String>,
 }
 
-#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
 enum LogFormat {
     Human,
     Json,
@@ -86,11 +276,23 @@ enum LogFormat {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let cli_args = Args::parse();
+    let mut args = cli_args.clone();
+    if let Some(path) = cli_args.config_file.as_deref() {
+        Config::read(path)?.apply(&mut args);
+    }
 
-    setup_tracing(&args).await?;
+    let reload_log_filter = setup_tracing(&args).await?;
+    let prometheus_registry = setup_metrics(&args)?;
 
-    let public_addr = match (args.public_ip4_addr, args.public_ip6_addr) {
+    let public_ip4_addr = args
+        .public_ip4_addr
+        .or(args.listen_ip4_addr.filter(|ip| !ip.is_unspecified()));
+    let public_ip6_addr = args
+        .public_ip6_addr
+        .or(args.listen_ip6_addr.filter(|ip| !ip.is_unspecified()));
+
+    let public_addr = match (public_ip4_addr, public_ip6_addr) {
         (Some(ip4), Some(ip6)) => IpStack::Dual { ip4, ip6 },
         (Some(ip4), None) => IpStack::Ip4(ip4),
         (None, Some(ip6)) => IpStack::Ip6(ip6),
@@ -98,58 +300,543 @@ async fn main() -> Result<()> {
             bail!("Must listen on at least one of IPv4 or IPv6")
         }
     };
+    let listen_ip4_addr = args.listen_ip4_addr.unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let listen_ip6_addr = args.listen_ip6_addr.unwrap_or(Ipv6Addr::UNSPECIFIED);
+    let listen_addr = match public_addr {
+        IpStack::Ip4(_) => IpStack::Ip4(listen_ip4_addr),
+        IpStack::Ip6(_) => IpStack::Ip6(listen_ip6_addr),
+        IpStack::Dual { .. } => IpStack::Dual {
+            ip4: listen_ip4_addr,
+            ip6: listen_ip6_addr,
+        },
+    };
+
+    if args.lowest_port > args.highest_port {
+        bail!(
+            "The lowest port ({}) must not be higher than the highest port ({})",
+            args.lowest_port,
+            args.highest_port
+        )
+    }
+
+    let num_workers = args.workers.get();
+    if usize::from(args.highest_port - args.lowest_port) + 1 < num_workers {
+        bail!("Cannot split the port range between {num_workers} workers")
+    }
+    if num_workers > 1 {
+        tracing::warn!(
+            "Mobility (RFC 8016) is disabled with {num_workers} workers; clients changing their address will have to allocate again"
+        );
+    }
+
+    let fast_path = match (args.xdp_interface.as_deref(), args.xdp_object.as_deref()) {
+        (Some(interface), Some(object)) => Some(FastPath::attach(
+            interface,
+            object,
+            args.listen_port,
+            args.xdp_replace,
+        )?),
+        _ => None,
+    };
+
+    let mut servers = Shard::all(num_workers)
+        .map(|shard| make_server(&args, public_addr, shard))
+        .collect::<Result<Vec<_>>>()?;
+
+    let auth_secret = servers[0].auth_secret().clone();
+    for server in &mut servers[1..] {
+        server.set_auth_secret(auth_secret.clone());
+    }
+
+    let mut unreported_usage = Vec::new();
+    if let Some((snapshots, usage)) = args
+        .state_file
+        .as_deref()
+        .map(read_state)
+        .transpose()?
+        .flatten()
+    {
+        unreported_usage = usage;
+
+        if snapshots.len() == num_workers {
+            for (server, snapshot) in servers.iter_mut().zip(snapshots) {
+                server.restore(snapshot, SystemTime::now());
+            }
+        } else {
+            tracing::warn!(
+                "State file was written by {} workers but we run {num_workers}, not restoring it",
+                snapshots.len()
+            );
+        }
+    }
+
+    let portal = args.portal_token.clone().map(|token| Portal {
+        public_addr,
+        token,
+        url: args.portal_url.clone(),
+        stamp_secret: servers[0].auth_secret().clone(),
+    });
+
+    let channel = if let Some(portal) = portal.as_ref() {
+        let span = tracing::error_span!("connect_to_portal", config_url = %portal.url);
+
+        Some(connect_to_portal(portal).instrument(span).await?)
+    } else {
+        tracing::warn!("No portal token supplied, starting standalone mode");
+
+        None
+    };
+
+    let tls_acceptor = match (args.tls_cert_file.as_deref(), args.tls_key_file.as_deref()) {
+        (Some(cert), Some(key)) => Some(load_tls_acceptor(cert, key)?),
+        _ => None,
+    };
+
+    let (admin_request_sender, admin_request_receiver) = mpsc::channel(10);
+    let (usage_sender, usage_receiver) = mpsc::unbounded();
+    let (drained_sender, drained_receiver) = mpsc::unbounded();
+    let readiness = Arc::new(Readiness::new(num_workers));
+
+    let (stream_event_senders, stream_event_receivers): (Vec<_>, Vec<_>) =
+        (0..num_workers).map(|_| mpsc::channel(10)).unzip();
+    let stream_event_senders = Arc::<[_]>::from(stream_event_senders);
+    let stream_connection_limit = StreamConnectionLimit::new(args.max_stream_connections);
+
+    let mut shard_command_senders = Vec::with_capacity(num_workers);
+    let mut workers = Vec::with_capacity(num_workers);
+
+    for ((shard, server), stream_event_receiver) in Shard::all(num_workers)
+        .zip(servers)
+        .zip(stream_event_receivers)
+    {
+        let (shard_command_sender, shard_command_receiver) = mpsc::unbounded();
+
+        // Bind the sockets of all workers in order, the kernel picks the socket for a client based on that.
+        let udp_sockets = [AddressFamily::V4, AddressFamily::V6]
+            .into_iter()
+            .filter_map(|family| listen_addr.for_family(family))
+            .map(|addr| UdpSocket::bind_shared(addr, args.listen_port))
+            .collect::<Result<Vec<_>>>()?;
+        let nat_discovery_sockets = bind_nat_discovery_sockets(&args, listen_addr)?;
+
+        workers.push(spawn_worker(
+            shard,
+            server,
+            WorkerConfig {
+                udp_sockets,
+                nat_discovery_sockets,
+                listen_address: listen_addr,
+                listen_port: args.listen_port,
+                tls_acceptor: tls_acceptor.clone(),
+                tls_ports: args.tls_ports.clone(),
+                stream_connection_limit: stream_connection_limit.clone(),
+                stream_event_senders: stream_event_senders.clone(),
+                stream_event_receiver,
+                shard_command_receiver,
+                readiness: readiness.clone(),
+                fast_path: fast_path.clone(),
+                usage_sender: usage_sender.clone(),
+                drained_sender: drained_sender.clone(),
+            },
+        )?);
+        shard_command_senders.push(shard_command_sender);
+    }
+
+    let mut control_plane = ControlPlane {
+        unsent_usage: unreported_usage.into(),
+        unacknowledged_usage: HashMap::new(),
+        args: args.clone(),
+        config_update: None,
+        portal,
+        channel,
+        portal_reconnect: None,
+        admin_request_receiver,
+        usage_receiver,
+        shards: shard_command_senders,
+        readiness: readiness.clone(),
+        drain_timeout: Duration::from_secs(args.drain_timeout),
+        drain_deadline: None,
+        drained_receiver,
+        num_drained_shards: 0,
+    };
+    control_plane.send_usage();
+
+    tokio::spawn(firezone_relay::health_check::serve(
+        args.health_check_addr,
+        readiness,
+        prometheus_registry,
+    ));
+
+    match args.admin_token.clone() {
+        Some(token) => {
+            tokio::spawn(firezone_relay::admin::serve(
+                args.admin_listen_addr,
+                admin_request_sender,
+                token,
+            ));
+        }
+        None => {
+            tracing::info!("No admin token configured, not serving the admin API");
+        }
+    }
+
+    tracing::info!(
+        "Listening for incoming traffic on UDP and TCP port {} with {num_workers} workers",
+        args.listen_port
+    );
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigquit = signal(SignalKind::quit())?;
+    let mut workers = future::select_all(workers);
+    let mut drained = false;
+
+    loop {
+        tokio::select! {
+            result = future::poll_fn(|cx| control_plane.poll(cx)) => {
+                result.context("control plane failed")?;
+                drained = true;
+                break;
+            }
+            (result, index, _) = &mut workers => {
+                result
+                    .unwrap_or_else(|_| Err(anyhow!("Worker thread panicked")))
+                    .with_context(|| format!("Worker {index} failed"))?;
+                break;
+            }
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM, shutting down");
+                break;
+            }
+            _ = sighup.recv() => {
+                reload_config(&cli_args, &mut args, &mut control_plane, &reload_log_filter);
+            }
+            _ = sigquit.recv() => {
+                tracing::info!("Received SIGQUIT");
+
+                control_plane.start_draining();
+            }
+        }
+    }
+
+    // A drained relay is out of rotation, there is nothing to restore.
+    if let Some(path) = args.state_file.as_deref().filter(|_| !drained) {
+        let snapshots = control_plane.stop().await?;
+        let usage = control_plane.flush_usage().await;
+        write_state(snapshots, usage, path)?;
+
+        tracing::info!(path = %path.display(), "Saved state for restart");
+    } else {
+        control_plane.shut_down().await;
+        let usage = control_plane.flush_usage().await;
+
+        if !usage.is_empty() {
+            tracing::warn!(
+                "Failed to report {} usage records before shutting down",
+                usage.len()
+            );
+        }
+    }
+
+    if let Some(fast_path) = fast_path {
+        fast_path.detach()?;
+    }
 
-    let server = Server::new(
+    Ok(())
+}
+
+/// Creates and configures the [`Server`] of one [`Shard`].
+fn make_server(args: &Args, public_addr: IpStack, shard: Shard) -> Result<Server<StdRng>> {
+    let mut server = Server::new_shard(
         public_addr,
-        make_rng(args.rng_seed),
+        make_rng(
+            args.rng_seed
+                .map(|seed| seed.wrapping_add(shard.index() as u64)),
+        ),
         args.lowest_port,
         args.highest_port,
+        shard,
     );
+    server.set_bandwidth_limits(bandwidth_limits(args), SystemTime::now());
+    server.set_peer_filter(peer_filter(args));
+    server.set_load_shedding(LoadShedding::new(
+        args.alternate_servers.clone(),
+        args.load_shedding_threshold,
+    ));
+    if !args.software.is_empty() {
+        server.set_software(
+            Software::new(format!("{}/{}", args.software, env!("CARGO_PKG_VERSION")))
+                .map_err(|e| anyhow!("Invalid software name: {e}"))?,
+        );
+    }
+    if args.xdp_interface.is_some() {
+        server.enable_fast_path();
+    }
+    if args.portal_token.is_some() {
+        server.enable_usage_reports(USAGE_REPORT_INTERVAL);
+    }
+    // After moving to a new 5-tuple, a client's traffic would most likely reach a worker that doesn't know its allocation.
+    if shard.count() == 1 {
+        server.enable_mobility();
+    }
+    if let Some(alternate_port) = args.nat_discovery_port {
+        server.enable_nat_discovery(NatDiscovery {
+            primary_port: args.listen_port,
+            alternate_port,
+            alternate_address: nat_discovery_address(args),
+        });
+    }
+    if let Some(secret) = args.turn_rest_secret.clone() {
+        server.set_authenticator(TurnRestApi::new(secret, parse_realm(&args.realm)?));
+    }
+    if !args.static_credentials.is_empty() {
+        server.set_authenticator(parse_static_credentials(
+            &args.static_credentials,
+            parse_realm(&args.realm)?,
+        )?);
+    }
 
-    let channel = if let Some(token) = args.portal_token.as_ref() {
-        let base_url = args.portal_url.clone();
-        let stamp_secret = server.auth_secret();
+    Ok(server)
+}
 
-        let span = tracing::error_span!("connect_to_portal", config_url = %base_url);
+fn bandwidth_limits(args: &Args) -> BandwidthLimits {
+    BandwidthLimits {
+        per_allocation: args.allocation_bandwidth_limit.map(BandwidthLimit::new),
+        per_user: args.user_bandwidth_limit.map(BandwidthLimit::new),
+    }
+}
 
-        connect_to_portal(&args, token, base_url, stamp_secret)
-            .instrument(span)
-            .await?
-    } else {
-        tracing::warn!("No portal token supplied, starting standalone mode");
+fn peer_filter(args: &Args) -> PeerFilter {
+    PeerFilter::new(
+        args.allowed_peer_networks.clone(),
+        args.denied_peer_networks
+            .clone()
+            .unwrap_or_else(PeerFilter::default_denied),
+    )
+}
 
-        None
+/// Reads the config file again and applies the settings that can be changed while the relay is running.
+///
+/// Settings the portal pushed since take precedence, see [`ControlPlane::reconfigure`].
+///
+/// `args` are the settings the relay currently runs with, `cli_args` the ones given on the command line.
+/// Changes to all other settings are logged as errors and only take effect after a restart.
+fn reload_config(
+    cli_args: &Args,
+    args: &mut Args,
+    control_plane: &mut ControlPlane,
+    reload_log_filter: &ReloadLogFilter,
+) {
+    let Some(path) = cli_args.config_file.as_deref() else {
+        tracing::warn!("Received SIGHUP but no config file is set, ignoring");
+        return;
     };
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr)?;
+    let mut reloaded = cli_args.clone();
+    match Config::read(path) {
+        Ok(config) => config.apply(&mut reloaded),
+        Err(e) => {
+            tracing::error!("Failed to reload config, keeping the current one: {e:#}");
+            return;
+        }
+    }
 
-    tokio::spawn(firezone_relay::health_check::serve(args.health_check_addr));
+    for setting in config::settings_requiring_restart(args, &reloaded) {
+        tracing::error!("Cannot change `{setting}` without restarting the relay");
+    }
 
-    tracing::info!("Listening for incoming traffic on UDP port 3478");
+    args.allocation_bandwidth_limit = reloaded.allocation_bandwidth_limit;
+    args.user_bandwidth_limit = reloaded.user_bandwidth_limit;
+    args.allowed_peer_networks = reloaded.allowed_peer_networks;
+    args.denied_peer_networks = reloaded.denied_peer_networks;
 
-    future::poll_fn(|cx| eventloop.poll(cx))
-        .await
-        .context("event loop failed")?;
+    control_plane.args = args.clone();
+    control_plane.reconfigure();
+
+    match reload_log_filter(reloaded.log_filter.as_deref()) {
+        Ok(()) => args.log_filter = reloaded.log_filter,
+        Err(e) => tracing::error!("Failed to reload log filter: {e:#}"),
+    }
+
+    tracing::info!(path = %path.display(), "Reloaded config");
+}
+
+/// The alternate public address for NAT behaviour discovery, if any.
+fn nat_discovery_address(args: &Args) -> Option<IpStack> {
+    match (args.nat_discovery_ip4_addr, args.nat_discovery_ip6_addr) {
+        (Some(ip4), Some(ip6)) => Some(IpStack::Dual { ip4, ip6 }),
+        (Some(ip4), None) => Some(IpStack::Ip4(ip4)),
+        (None, Some(ip6)) => Some(IpStack::Ip6(ip6)),
+        (None, None) => None,
+    }
+}
+
+/// Binds a socket for each combination of our primary and alternate address and port, except for the primary one.
+///
+/// Like the sockets on our listen port, these are shared between all workers via `SO_REUSEPORT`.
+fn bind_nat_discovery_sockets(
+    args: &Args,
+    listen_addr: IpStack,
+) -> Result<Vec<(DiscoveryOrigin, std::net::UdpSocket)>> {
+    let Some(nat_discovery_port) = args.nat_discovery_port else {
+        return Ok(Vec::new());
+    };
+    let alternate_address = nat_discovery_address(args);
+
+    let mut sockets = Vec::new();
+
+    for family in [AddressFamily::V4, AddressFamily::V6] {
+        // We can only serve clients of the address families we listen on.
+        if listen_addr.for_family(family).is_none() {
+            continue;
+        }
+
+        for (alternate_ip, alternate_port) in [(false, true), (true, false), (true, true)] {
+            let address = if alternate_ip {
+                alternate_address.and_then(|address| address.for_family(family))
+            } else {
+                listen_addr.for_family(family)
+            };
+            let Some(address) = address else {
+                continue;
+            };
+            let port = if alternate_port {
+                nat_discovery_port
+            } else {
+                args.listen_port
+            };
+
+            sockets.push((
+                DiscoveryOrigin {
+                    alternate_ip,
+                    alternate_port,
+                },
+                UdpSocket::bind_shared(address, port)?,
+            ));
+        }
+    }
+
+    Ok(sockets)
+}
+
+fn parse_realm(realm: &str) -> Result<Realm> {
+    Realm::new(realm.to_owned()).map_err(|e| anyhow!("Invalid realm: {e}"))
+}
+
+fn parse_static_credentials(credentials: &[String], realm: Realm) -> Result<StaticCredentials> {
+    let credentials = credentials
+        .iter()
+        .map(|credential| {
+            let (username, password) = credential
+                .split_once(':')
+                .context("Static credentials must be of the form `username:password`")?;
+
+            Ok((username.to_owned(), SecretString::from(password.to_owned())))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(StaticCredentials::new(credentials, realm))
+}
+
+/// The content of the state file: One [`Snapshot`] per worker.
+///
+/// A relay with a single worker writes its [`Snapshot`] as is, compatible with relays that predate workers.
+/// Only if the portal did not acknowledge all usage records before we stopped, they are written alongside the snapshots.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum State {
+    Single(Snapshot),
+    Sharded(Vec<Snapshot>),
+    WithUsage {
+        snapshots: Vec<Snapshot>,
+        usage: Vec<UsageRecord>,
+    },
+}
+
+/// Reads the [`Snapshot`]s and unreported usage records left behind by a previous relay process, if any.
+///
+/// The file is deleted afterwards to not restore the same state twice.
+fn read_state(path: &Path) -> Result<Option<(Vec<Snapshot>, Vec<UsageRecord>)>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read state file"),
+    };
+
+    let state = serde_json::from_slice(&bytes).context("Failed to parse state file")?;
+    std::fs::remove_file(path).context("Failed to remove state file")?;
+
+    let state = match state {
+        State::Single(snapshot) => (vec![snapshot], Vec::new()),
+        State::Sharded(snapshots) => (snapshots, Vec::new()),
+        State::WithUsage { snapshots, usage } => (snapshots, usage),
+    };
+
+    Ok(Some(state))
+}
+
+fn write_state(mut snapshots: Vec<Snapshot>, usage: Vec<UsageRecord>, path: &Path) -> Result<()> {
+    use std::io::Write as _;
+    use std::os::unix::fs::OpenOptionsExt as _;
+
+    let state = if !usage.is_empty() {
+        State::WithUsage { snapshots, usage }
+    } else if snapshots.len() == 1 {
+        State::Single(snapshots.remove(0))
+    } else {
+        State::Sharded(snapshots)
+    };
+
+    // The snapshot contains our auth secret, only we should be able to read it.
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .context("Failed to open state file")?;
+
+    serde_json::to_writer(&mut file, &state).context("Failed to serialize state")?;
+    file.flush()?;
 
     Ok(())
 }
 
+/// Replaces the filter directives of our log layers, see [`env_filter`].
+type ReloadLogFilter = Box<dyn Fn(Option<&str>) -> Result<()> + Send + Sync>;
+
 /// Sets up our tracing infrastructure.
 ///
 /// See [`log_layer`] for details on the base log layer.
+/// The returned [`ReloadLogFilter`] changes which logs are emitted at runtime.
 ///
 /// ## Integration with OTLP
 ///
 /// If the user has specified [`TraceCollector::Otlp`], we will set up an OTLP-exporter that connects to an OTLP collector specified at `Args.otlp_grpc_endpoint`.
-async fn setup_tracing(args: &Args) -> Result<()> {
+async fn setup_tracing(args: &Args) -> Result<ReloadLogFilter> {
+    let directives = args.log_filter.as_deref();
+
     // Use `tracing_core` directly for the temp logger because that one does not initialize a `log` logger.
     // A `log` Logger cannot be unset once set, so we can't use that for our temp logger during the setup.
     let temp_logger_guard = tracing_core::dispatcher::set_default(
-        &tracing_subscriber::registry().with(log_layer(args)).into(),
+        &tracing_subscriber::registry()
+            .with(log_layer(args).with_filter(env_filter(directives)))
+            .into(),
     );
 
-    let dispatch: Dispatch = match args.otlp_grpc_endpoint {
-        None => tracing_subscriber::registry().with(log_layer(args)).into(),
+    let (log_filter, log_filter_handle) = reload::Layer::new(env_filter(directives));
+
+    let (dispatch, reload_log_filter): (Dispatch, ReloadLogFilter) = match args.otlp_grpc_endpoint {
+        None => (
+            tracing_subscriber::registry()
+                .with(log_layer(args).with_filter(log_filter))
+                .into(),
+            Box::new(move |directives: Option<&str>| -> Result<()> {
+                log_filter_handle.reload(env_filter(directives))?;
+
+                Ok(())
+            }),
+        ),
         Some(endpoint) => {
             let grpc_endpoint = format!("http://{endpoint}");
 
@@ -157,7 +844,7 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 
             let exporter = opentelemetry_otlp::new_exporter()
                 .tonic()
-                .with_endpoint(grpc_endpoint.clone());
+                .with_endpoint(grpc_endpoint);
 
             let tracer =
                 opentelemetry_otlp::new_pipeline()
@@ -171,26 +858,26 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 
             tracing::trace!("Successfully initialized trace provider on tokio runtime");
 
-            let exporter = opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(grpc_endpoint);
-
-            opentelemetry_otlp::new_pipeline()
-                .metrics(opentelemetry::runtime::Tokio)
-                .with_exporter(exporter)
-                .build()
-                .context("Failed to create OTLP metrics pipeline")?;
-
-            tracing::trace!("Successfully initialized metric controller on tokio runtime");
+            let (otel_filter, otel_filter_handle) = reload::Layer::new(env_filter(directives));
 
-            tracing_subscriber::registry()
-                .with(log_layer(args))
+            let dispatch = tracing_subscriber::registry()
+                .with(log_layer(args).with_filter(log_filter))
                 .with(
                     tracing_opentelemetry::layer()
                         .with_tracer(tracer)
-                        .with_filter(env_filter()),
+                        .with_filter(otel_filter),
                 )
-                .into()
+                .into();
+
+            (
+                dispatch,
+                Box::new(move |directives: Option<&str>| -> Result<()> {
+                    log_filter_handle.reload(env_filter(directives))?;
+                    otel_filter_handle.reload(env_filter(directives))?;
+
+                    Ok(())
+                }),
+            )
         }
     };
 
@@ -200,7 +887,53 @@ async fn setup_tracing(args: &Args) -> Result<()> {
         .try_init()
         .context("Failed to initialize tracing")?;
 
-    Ok(())
+    Ok(reload_log_filter)
+}
+
+/// Installs a global meter provider that reports all metrics to the OTLP collector and / or records them into the returned [`prometheus::Registry`].
+///
+/// Without either of them, metrics are not collected at all.
+fn setup_metrics(args: &Args) -> Result<Option<prometheus::Registry>> {
+    if args.otlp_grpc_endpoint.is_none() && !args.prometheus_metrics {
+        return Ok(None);
+    }
+
+    let mut provider =
+        sdk::metrics::MeterProvider::builder().with_resource(sdk::Resource::new(vec![
+            KeyValue::new("service.name", "relay"),
+        ]));
+
+    if let Some(endpoint) = args.otlp_grpc_endpoint {
+        let exporter = opentelemetry_otlp::MetricsExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(format!("http://{endpoint}")),
+        )
+        .build_metrics_exporter(
+            Box::new(sdk::metrics::reader::DefaultTemporalitySelector::new()),
+            Box::new(sdk::metrics::reader::DefaultAggregationSelector::new()),
+        )
+        .context("Failed to create OTLP metrics exporter")?;
+
+        provider = provider.with_reader(
+            sdk::metrics::PeriodicReader::builder(exporter, opentelemetry::runtime::Tokio).build(),
+        );
+    }
+
+    let registry = args.prometheus_metrics.then(prometheus::Registry::new);
+
+    if let Some(registry) = &registry {
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .context("Failed to create Prometheus exporter")?;
+
+        provider = provider.with_reader(exporter);
+    }
+
+    opentelemetry::global::set_meter_provider(provider.build());
+
+    Ok(registry)
 }
 
 /// Constructs the base log layer.
@@ -214,7 +947,7 @@ fn log_layer<T>(args: &Args) -> Box<dyn Layer<T> + Send + Sync>
 where
     T: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
 {
-    let log_layer = match (args.log_format, args.google_cloud_project_id.clone()) {
+    match (args.log_format, args.google_cloud_project_id.clone()) {
         (LogFormat::Human, _) => tracing_subscriber::fmt::layer().boxed(),
         (LogFormat::Json, _) => tracing_subscriber::fmt::layer().json().boxed(),
         (LogFormat::GoogleCloud, None) => {
@@ -225,25 +958,40 @@ where
         (LogFormat::GoogleCloud, Some(project_id)) => tracing_stackdriver::layer()
             .with_cloud_trace(CloudTraceConfiguration { project_id })
             .boxed(),
-    };
+    }
+}
 
-    log_layer.with_filter(env_filter()).boxed()
+/// Filters logs by the given directives, falling back to `RUST_LOG` if there are none.
+fn env_filter(directives: Option<&str>) -> EnvFilter {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+
+    match directives {
+        Some(directives) => builder.parse_lossy(directives),
+        None => builder.from_env_lossy(),
+    }
 }
 
-fn env_filter() -> EnvFilter {
-    EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env_lossy()
+/// Everything we need to (re-)connect to the portal.
+#[derive(Clone)]
+struct Portal {
+    public_addr: IpStack,
+    token: SecretString,
+    url: Url,
+    /// The auth secret we join with, kept up to date with the secrets rotated by the portal.
+    stamp_secret: SecretString,
 }
 
-async fn connect_to_portal(
-    args: &Args,
-    token: &SecretString,
-    mut url: Url,
-    stamp_secret: &SecretString,
-) -> Result<Option<PhoenixChannel<(), ()>>> {
+async fn connect_to_portal(portal: &Portal) -> Result<PhoenixChannel<IngressMessages, ()>> {
     use secrecy::ExposeSecret;
 
+    let Portal {
+        public_addr,
+        token,
+        url,
+        stamp_secret,
+    } = portal;
+    let mut url = url.clone();
+
     if !url.path().is_empty() {
         tracing::warn!("Overwriting path component of portal URL with '/relay/websocket'");
     }
@@ -252,11 +1000,11 @@ async fn connect_to_portal(
     url.query_pairs_mut()
         .append_pair("token", token.expose_secret().as_str());
 
-    if let Some(public_ip4_addr) = args.public_ip4_addr {
+    if let Some(public_ip4_addr) = public_addr.as_v4() {
         url.query_pairs_mut()
             .append_pair("ipv4", &public_ip4_addr.to_string());
     }
-    if let Some(public_ip6_addr) = args.public_ip6_addr {
+    if let Some(public_ip6_addr) = public_addr.as_v6() {
         url.query_pairs_mut()
             .append_pair("ipv6", &public_ip6_addr.to_string());
     }
@@ -271,15 +1019,53 @@ async fn connect_to_portal(
     )
     .await??;
 
-    Ok(Some(channel))
+    Ok(channel)
 }
 
-#[derive(serde::Deserialize, Debug)]
-struct Init {}
+/// Connects to the portal again after the connection failed, retrying with exponential backoff until we succeed.
+async fn reconnect_to_portal(portal: Portal) -> Result<PhoenixChannel<IngressMessages, ()>> {
+    backoff::future::retry_notify(
+        ExponentialBackoffBuilder::default()
+            .with_max_elapsed_time(None)
+            .build(),
+        || connect_to_portal(&portal).map_err(backoff::Error::transient),
+        |error, t| {
+            tracing::warn!(retry_in = ?t, "Failed to reconnect to portal: {error:#}");
+        },
+    )
+    .await
+}
 
-#[derive(serde::Serialize, PartialEq, Debug)]
-struct JoinMessage {
-    stamp_secret: String,
+/// Loads the certificate chain and private key for our TLS listeners.
+fn load_tls_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_file).with_context(|| format!("Failed to open {}", cert_file.display()))?,
+    ))
+    .context("Failed to parse TLS certificates")?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect::<Vec<_>>();
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(
+        File::open(key_file).with_context(|| format!("Failed to open {}", key_file.display()))?,
+    ))
+    .context("Failed to parse TLS private key")?
+    .into_iter()
+    .find_map(|item| match item {
+        rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+        _ => None,
+    })
+    .with_context(|| format!("No private key found in {}", key_file.display()))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
 }
 
 #[cfg(debug_assertions)]
@@ -302,91 +1088,871 @@ fn make_rng(seed: Option<u64>) -> StdRng {
     StdRng::from_entropy()
 }
 
-struct Eventloop<R> {
-    inbound_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
-    outbound_ip4_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
-    outbound_ip6_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
-    server: Server<R>,
-    channel: Option<PhoenixChannel<(), ()>>,
-    allocations: HashMap<(AllocationId, AddressFamily), Allocation>,
-    relay_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr, AllocationId)>,
-    relay_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr, AllocationId)>,
-    sleep: Sleep,
+/// Everything a worker needs besides its [`Server`] to run the [`Eventloop`] of its [`Shard`].
+struct WorkerConfig {
+    /// The sockets on our listen port, bound via [`UdpSocket::bind_shared`].
+    udp_sockets: Vec<std::net::UdpSocket>,
+    /// The sockets on our alternate addresses and ports for NAT behaviour discovery, if enabled.
+    nat_discovery_sockets: Vec<(DiscoveryOrigin, std::net::UdpSocket)>,
+    listen_address: IpStack,
+    listen_port: u16,
+    tls_acceptor: Option<TlsAcceptor>,
+    tls_ports: Vec<u16>,
+    /// Shared by the TCP and TLS listeners of all workers.
+    stream_connection_limit: StreamConnectionLimit,
+    /// The channels to the [`Eventloop`]s of all shards for events of stream connections, indexed by [`Shard::index`].
+    ///
+    /// Our TCP listeners may accept the data connection of a TCP allocation owned by another shard, see [`stream_connection_task`].
+    stream_event_senders: Arc<[mpsc::Sender<StreamEvent>]>,
+    stream_event_receiver: mpsc::Receiver<StreamEvent>,
+    shard_command_receiver: mpsc::UnboundedReceiver<ShardCommand>,
+    readiness: Arc<Readiness>,
+    fast_path: Option<FastPath>,
+    usage_sender: mpsc::UnboundedSender<UsageRecord>,
+    /// Where to send the index of our [`Shard`] once it is drained.
+    drained_sender: mpsc::UnboundedSender<usize>,
 }
 
-impl<R> Eventloop<R>
+/// Runs the [`Eventloop`] of a [`Shard`] on a dedicated thread with its own single-threaded runtime.
+///
+/// All sockets and tasks of the shard live on this runtime, thus relayed data never crosses threads.
+/// The returned receiver resolves once the [`Eventloop`] stops.
+fn spawn_worker<R>(
+    shard: Shard,
+    server: Server<R>,
+    config: WorkerConfig,
+) -> Result<oneshot::Receiver<Result<()>>>
 where
-    R: Rng,
+    R: Rng + Send + 'static,
 {
-    fn new(
-        server: Server<R>,
-        channel: Option<PhoenixChannel<(), ()>>,
-        public_address: IpStack,
-    ) -> Result<Self> {
-        let (relay_data_sender, relay_data_receiver) = mpsc::channel(1);
-        let (inbound_data_sender, inbound_data_receiver) = mpsc::channel(10);
-        let (outbound_ip4_data_sender, outbound_ip4_data_receiver) =
-            mpsc::channel::<(Vec<u8>, SocketAddr)>(10);
-        let (outbound_ip6_data_sender, outbound_ip6_data_receiver) =
-            mpsc::channel::<(Vec<u8>, SocketAddr)>(10);
+    let (result_sender, result_receiver) = oneshot::channel();
 
-        if public_address.as_v4().is_some() {
-            tokio::spawn(main_udp_socket_task(
-                AddressFamily::V4,
-                inbound_data_sender.clone(),
-                outbound_ip4_data_receiver,
-            ));
-        }
-        if public_address.as_v6().is_some() {
-            tokio::spawn(main_udp_socket_task(
-                AddressFamily::V6,
-                inbound_data_sender,
-                outbound_ip6_data_receiver,
-            ));
-        }
+    thread::Builder::new()
+        .name(format!("relay-worker-{}", shard.index()))
+        .spawn(move || {
+            let result = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .context("Failed to build runtime")
+                .and_then(|runtime| {
+                    runtime.block_on(async move {
+                        let mut eventloop = Eventloop::new(shard, server, config)?;
 
-        Ok(Self {
-            inbound_data_receiver,
-            outbound_ip4_data_sender,
-            outbound_ip6_data_sender,
-            server,
-            channel,
-            allocations: Default::default(),
-            relay_data_sender,
-            relay_data_receiver,
-            sleep: Sleep::default(),
+                        future::poll_fn(|cx| eventloop.poll(cx)).await
+                    })
+                });
+
+            let _ = result_sender.send(result);
         })
-    }
+        .context("Failed to spawn worker thread")?;
+
+    Ok(result_receiver)
+}
+
+/// Commands from the [`ControlPlane`] to the [`Eventloop`] of a [`Shard`].
+enum ShardCommand {
+    RotateAuthSecret(SecretString),
+    SetAlternateServers(Vec<SocketAddr>),
+    AddNonces(Vec<Uuid>),
+    /// Revoke the credentials of a user until the given time, see [`Server::revoke_user`].
+    RevokeUser(String, SystemTime),
+    Drain,
+    /// Apply the settings of a reloaded config file or pushed by the portal.
+    Reconfigure {
+        bandwidth_limits: BandwidthLimits,
+        peer_filter: PeerFilter,
+    },
+    Admin(AdminRequest),
+    /// Stop the [`Eventloop`], replying with a [`Snapshot`] of the shard for restoring it in the next process.
+    Stop {
+        reply: oneshot::Sender<Snapshot>,
+    },
+    /// Stop the [`Eventloop`] for good, reporting the usage of all allocations one last time before replying.
+    ShutDown {
+        reply: oneshot::Sender<()>,
+    },
+}
 
+/// Owns the parts of the relay that are shared between all shards: The portal connection and the admin API.
+///
+/// Portal messages are broadcast to the [`Eventloop`]s of all shards, keeping their configuration consistent.
+/// Admin requests are answered by combining the replies of all shards.
+struct ControlPlane {
+    /// The settings from the command line and config file the relay currently runs with.
+    args: Args,
+    /// The last configuration pushed by the portal, overriding the corresponding settings of `args`.
+    config_update: Option<ConfigUpdated>,
+    /// How to connect to the portal, if we have a token.
+    portal: Option<Portal>,
+    channel: Option<PhoenixChannel<IngressMessages, ()>>,
+    /// Re-establishes the portal connection after it failed, see [`reconnect_to_portal`].
+    portal_reconnect: Option<BoxFuture<'static, Result<PhoenixChannel<IngressMessages, ()>>>>,
+    admin_request_receiver: mpsc::Receiver<AdminRequest>,
+    /// The usage records of all shards, to be reported to the portal.
+    usage_receiver: mpsc::UnboundedReceiver<UsageRecord>,
+    /// Usage records that we have yet to send, e.g. because the portal is not connected.
+    unsent_usage: VecDeque<UsageRecord>,
+    /// Usage records that the portal has yet to acknowledge, by the request that carried them.
+    ///
+    /// If the connection fails, we send them again after reconnecting.
+    unacknowledged_usage: HashMap<OutboundRequestId, Vec<UsageRecord>>,
+    /// The command channels to each shard, indexed by [`Shard::index`].
+    ///
+    /// These are unbounded because control messages must never be dropped and are rare compared to relayed data.
+    shards: Vec<mpsc::UnboundedSender<ShardCommand>>,
+    readiness: Arc<Readiness>,
+    /// How long we wait for the shards to drain.
+    drain_timeout: Duration,
+    /// When we stop, regardless of whether all shards drained, if we are draining.
+    drain_deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+    drained_receiver: mpsc::UnboundedReceiver<usize>,
+    num_drained_shards: usize,
+}
+
+impl ControlPlane {
+    /// Resolves with `Ok(())` once draining is complete, see [`ControlPlane::start_draining`].
     fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
-        let span = tracing::error_span!("Eventloop::poll");
+        let span = tracing::error_span!("ControlPlane::poll");
         let _guard = span.enter();
 
         loop {
-            let now = SystemTime::now();
+            // Priority 1: Handle admin requests
+            if let Poll::Ready(Some(request)) = self.admin_request_receiver.poll_next_unpin(cx) {
+                self.handle_admin_request(request);
+                continue;
+            }
 
-            // Priority 1: Execute the pending commands of the server.
+            // Priority 2: Handle portal messages
+            if let Some(Poll::Ready(result)) =
+                self.portal_reconnect.as_mut().map(|r| r.poll_unpin(cx))
+            {
+                self.portal_reconnect = None;
+
+                match result {
+                    Ok(channel) => {
+                        tracing::info!("Reconnected to portal");
+
+                        self.channel = Some(channel);
+                        self.readiness.set_portal_disconnected(false);
+                        self.send_usage();
+                    }
+                    Err(e) => return Poll::Ready(Err(e.context("Failed to reconnect to portal"))),
+                }
+                continue;
+            }
+
+            match self.channel.as_mut().map(|c| c.poll(cx)) {
+                Some(Poll::Ready(Err(Error::Serde(e)))) => {
+                    tracing::warn!("Failed to deserialize portal message: {e}");
+                    continue; // This is not a hard-error, we can continue.
+                }
+                Some(Poll::Ready(Err(e))) => {
+                    // Keep serving existing allocations but signal that we should not receive new clients until we are reconnected.
+                    tracing::error!("Portal connection failed: {e}");
+
+                    self.channel = None;
+                    self.readiness.set_portal_disconnected(true);
+                    self.resend_unacknowledged_usage();
+                    self.portal_reconnect = self
+                        .portal
+                        .clone()
+                        .map(|portal| reconnect_to_portal(portal).boxed());
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::SuccessResponse {
+                    req_id, res: (), ..
+                }))) => {
+                    self.unacknowledged_usage.remove(&req_id);
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::JoinedRoom { topic }))) => {
+                    tracing::info!("Successfully joined room '{topic}'");
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::ErrorResponse {
+                    topic,
+                    req_id,
+                    reason,
+                }))) => {
+                    self.reject_usage(&req_id, &reason);
+
+                    tracing::warn!("Request with ID {req_id} on topic {topic} failed: {reason}");
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::HeartbeatSent))) => {
+                    tracing::debug!("Heartbeat sent to portal");
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundMessage {
+                    msg: IngressMessages::SecretRotated(SecretRotated { secret }),
+                    ..
+                }))) => {
+                    self.broadcast(|| ShardCommand::RotateAuthSecret(secret.clone()));
+                    if let Some(portal) = self.portal.as_mut() {
+                        portal.stamp_secret = secret;
+                    }
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundMessage {
+                    msg:
+                        IngressMessages::AlternateServersUpdated(AlternateServersUpdated { servers }),
+                    ..
+                }))) => {
+                    tracing::info!(?servers, "Received new alternate servers from portal");
+
+                    self.broadcast(|| ShardCommand::SetAlternateServers(servers.clone()));
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundMessage {
+                    msg: IngressMessages::NoncesIssued(NoncesIssued { nonces }),
+                    ..
+                }))) => {
+                    // We don't know which shard the client of a nonce will reach, thus every shard needs to accept it.
+                    // A client always reaches the same shard, so this doesn't give it more requests per nonce.
+                    self.broadcast(|| ShardCommand::AddNonces(nonces.clone()));
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundMessage {
+                    msg:
+                        IngressMessages::UsernameSaltRevoked(UsernameSaltRevoked {
+                            username_salt,
+                            expires_at,
+                        }),
+                    ..
+                }))) => {
+                    tracing::info!(%username_salt, %expires_at, "Portal revoked username salt");
+
+                    let until = SystemTime::UNIX_EPOCH + Duration::from_secs(expires_at);
+                    self.broadcast(|| ShardCommand::RevokeUser(username_salt.clone(), until));
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundMessage {
+                    msg: IngressMessages::DrainRequested(DrainRequested {}),
+                    ..
+                }))) => {
+                    tracing::info!("Portal requested to drain");
+
+                    self.start_draining();
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundMessage {
+                    msg: IngressMessages::ConfigUpdated(config),
+                    ..
+                }))) => {
+                    tracing::info!(?config, "Received new configuration from portal");
+
+                    self.config_update = Some(config);
+                    self.reconfigure();
+                    continue;
+                }
+                Some(Poll::Ready(Ok(Event::InboundReq { req, .. }))) => {
+                    tracing::warn!("Unexpected request from portal: {req:?}");
+                    continue;
+                }
+                Some(Poll::Pending) | None => {}
+            }
+
+            // Priority 3: Report usage records to the portal, batching all that are ready
+            if let Poll::Ready(Some(record)) = self.usage_receiver.poll_next_unpin(cx) {
+                self.buffer_usage(record);
+                while let Ok(Some(record)) = self.usage_receiver.try_next() {
+                    self.buffer_usage(record);
+                }
+
+                self.send_usage();
+                continue;
+            }
+
+            // Priority 4: Stop once all shards are drained or we ran out of time
+            if let Poll::Ready(Some(shard)) = self.drained_receiver.poll_next_unpin(cx) {
+                tracing::info!(%shard, "Shard has no allocations left");
+
+                self.num_drained_shards += 1;
+                continue;
+            }
+
+            if self.num_drained_shards == self.shards.len() {
+                tracing::info!("Drained all allocations");
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(deadline) = self.drain_deadline.as_mut() {
+                if deadline.poll_unpin(cx).is_ready() {
+                    tracing::warn!("Drain timeout passed, dropping remaining allocations");
+
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            return Poll::Pending;
+        }
+    }
+
+    /// Takes the relay out of rotation.
+    ///
+    /// All shards refuse new allocations but keep serving existing ones.
+    /// Once these are gone or the drain timeout passed, [`ControlPlane::poll`] resolves.
+    fn start_draining(&mut self) {
+        if self.drain_deadline.is_some() {
+            return;
+        }
+
+        tracing::info!(timeout = ?self.drain_timeout, "Draining");
+
+        self.drain_deadline = Some(Box::pin(tokio::time::sleep(self.drain_timeout)));
+        self.readiness.set_draining(true);
+        self.broadcast(|| ShardCommand::Drain);
+    }
+
+    /// Applies the bandwidth limits and peer networks of [`ControlPlane::args`] to all shards, overridden by the ones the portal pushed last.
+    fn reconfigure(&self) {
+        let mut args = self.args.clone();
+        if let Some(config_update) = self.config_update.as_ref() {
+            config_update.apply(&mut args);
+        }
+
+        let limits = bandwidth_limits(&args);
+        let filter = peer_filter(&args);
+        self.broadcast(|| ShardCommand::Reconfigure {
+            bandwidth_limits: limits,
+            peer_filter: filter.clone(),
+        });
+    }
+
+    /// Sends a command to all shards.
+    ///
+    /// A shard that stopped is detected by `main` through the result of its worker, thus we don't handle it here.
+    fn broadcast(&self, make_command: impl Fn() -> ShardCommand) {
+        for shard in &self.shards {
+            let _ = shard.unbounded_send(make_command());
+        }
+    }
+
+    /// Answers an [`AdminRequest`] by asking all shards.
+    ///
+    /// Allocation IDs are unique across shards, thus at most one shard frees a given allocation.
+    fn handle_admin_request(&mut self, request: AdminRequest) {
+        match request {
+            AdminRequest::ListAllocations { reply } => {
+                let replies = self
+                    .ask_all(|reply| ShardCommand::Admin(AdminRequest::ListAllocations { reply }));
+
+                tokio::spawn(async move {
+                    let allocations = future::join_all(replies)
+                        .await
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .collect::<Vec<AllocationInfo>>();
+
+                    let _ = reply.send(allocations);
+                });
+            }
+            AdminRequest::FreeAllocation { id, reply } => {
+                let replies = self.ask_all(|reply| {
+                    ShardCommand::Admin(AdminRequest::FreeAllocation { id, reply })
+                });
+
+                tokio::spawn(async move {
+                    let freed = future::join_all(replies)
+                        .await
+                        .into_iter()
+                        .any(|freed| matches!(freed, Ok(true)));
+
+                    let _ = reply.send(freed);
+                });
+            }
+            AdminRequest::Drain { reply } => {
+                self.start_draining();
+
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    /// Stops all shards, returning their [`Snapshot`]s in order.
+    ///
+    /// The usage that the allocations of a shard did not report yet is part of its [`Snapshot`].
+    async fn stop(&self) -> Result<Vec<Snapshot>> {
+        let replies = self.ask_all(|reply| ShardCommand::Stop { reply });
+
+        future::try_join_all(replies)
+            .await
+            .context("Worker stopped before handing over its state")
+    }
+
+    /// Stops all shards without handing them over, their allocations report their usage one last time.
+    async fn shut_down(&self) {
+        let replies = self.ask_all(|reply| ShardCommand::ShutDown { reply });
+
+        // A worker that already stopped has nothing left to report.
+        future::join_all(replies).await;
+    }
+
+    /// Queues a usage record for the portal, dropping the oldest ones if we cannot report them for too long.
+    fn buffer_usage(&mut self, record: UsageRecord) {
+        self.unsent_usage.push_back(record);
+
+        let num_pending = self.unsent_usage.len()
+            + self
+                .unacknowledged_usage
+                .values()
+                .map(Vec::len)
+                .sum::<usize>();
+
+        if num_pending > MAX_PENDING_USAGE_RECORDS {
+            self.unsent_usage.pop_front();
+
+            tracing::warn!("Too many unreported usage records, dropping the oldest one");
+        }
+    }
+
+    /// Sends all queued usage records to the portal, if we are connected.
+    fn send_usage(&mut self) {
+        let Some(channel) = self.channel.as_mut() else {
+            return;
+        };
+        if self.unsent_usage.is_empty() {
+            return;
+        }
+
+        let records = self.unsent_usage.drain(..).collect::<Vec<_>>();
+        let req_id = channel.send(
+            "relay",
+            EgressMessages::UsageReported(UsageReported {
+                records: records.clone(),
+            }),
+        );
+
+        self.unacknowledged_usage.insert(req_id, records);
+    }
+
+    /// Queues the usage records that the portal did not acknowledge before the connection failed again.
+    fn resend_unacknowledged_usage(&mut self) {
+        for (_, records) in self.unacknowledged_usage.drain() {
+            for record in records.into_iter().rev() {
+                self.unsent_usage.push_front(record);
+            }
+        }
+    }
+
+    /// Drops the usage records of a request that the portal rejected, sending them again would only fail again.
+    fn reject_usage(&mut self, req_id: &OutboundRequestId, reason: &str) {
+        if let Some(records) = self.unacknowledged_usage.remove(req_id) {
+            tracing::warn!(
+                "Portal rejected {} usage records, dropping them: {reason}",
+                records.len()
+            );
+        }
+    }
+
+    /// Reports all pending usage records, waiting at most [`USAGE_FLUSH_TIMEOUT`] for the portal to acknowledge them.
+    ///
+    /// Returns the records that we could not report.
+    async fn flush_usage(&mut self) -> Vec<UsageRecord> {
+        while let Ok(Some(record)) = self.usage_receiver.try_next() {
+            self.buffer_usage(record);
+        }
+        self.send_usage();
+
+        if tokio::time::timeout(
+            USAGE_FLUSH_TIMEOUT,
+            future::poll_fn(|cx| self.poll_usage_acknowledged(cx)),
+        )
+        .await
+        .is_err()
+        {
+            tracing::warn!("Portal did not acknowledge all usage records in time");
+        }
+
+        self.resend_unacknowledged_usage();
+
+        self.unsent_usage.drain(..).collect()
+    }
+
+    /// Resolves once the portal acknowledged all usage records we sent or the connection failed.
+    fn poll_usage_acknowledged(&mut self, cx: &mut std::task::Context<'_>) -> Poll<()> {
+        loop {
+            if self.unacknowledged_usage.is_empty() {
+                return Poll::Ready(());
+            }
+            let Some(channel) = self.channel.as_mut() else {
+                return Poll::Ready(());
+            };
+
+            match channel.poll(cx) {
+                Poll::Ready(Ok(Event::SuccessResponse { req_id, .. })) => {
+                    self.unacknowledged_usage.remove(&req_id);
+                }
+                Poll::Ready(Ok(Event::ErrorResponse { req_id, reason, .. })) => {
+                    self.reject_usage(&req_id, &reason);
+                }
+                Poll::Ready(Ok(_)) | Poll::Ready(Err(Error::Serde(_))) => {} // We are shutting down, nothing else matters.
+                Poll::Ready(Err(e)) => {
+                    tracing::warn!("Portal connection failed while reporting usage: {e}");
+
+                    self.channel = None;
+                    return Poll::Ready(());
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn ask_all<T>(
+        &self,
+        make_command: impl Fn(oneshot::Sender<T>) -> ShardCommand,
+    ) -> Vec<oneshot::Receiver<T>> {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let (reply, receiver) = oneshot::channel();
+                let _ = shard.unbounded_send(make_command(reply));
+
+                receiver
+            })
+            .collect()
+    }
+}
+
+struct Eventloop<R> {
+    shard: Shard,
+    inbound_data_receiver: mpsc::Receiver<(PooledBuffer, SocketAddr)>,
+    outbound_ip4_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    outbound_ip6_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    nat_discovery_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr, DiscoveryOrigin)>,
+    nat_discovery_data_senders:
+        HashMap<(AddressFamily, DiscoveryOrigin), mpsc::Sender<(Vec<u8>, SocketAddr)>>,
+    server: Server<R>,
+    shard_command_receiver: mpsc::UnboundedReceiver<ShardCommand>,
+    allocations: HashMap<(AllocationId, AddressFamily), Allocation>,
+    relay_data_sender: mpsc::Sender<(PooledBuffer, SocketAddr, AllocationId)>,
+    relay_data_receiver: mpsc::Receiver<(PooledBuffer, SocketAddr, AllocationId)>,
+    /// The receive buffers of our listen sockets and allocations.
+    buffer_pool: BufferPool,
+    stream_event_receiver: mpsc::Receiver<StreamEvent>,
+    /// Clients connected via TCP or TLS.
+    streams: HashMap<ClientSocket, mpsc::Sender<StreamCommand>>,
+    tcp_allocations: HashMap<(AllocationId, AddressFamily), TcpAllocation>,
+    peer_connection_sender: mpsc::Sender<(TcpStream, SocketAddr, AllocationId)>,
+    peer_connection_receiver: mpsc::Receiver<(TcpStream, SocketAddr, AllocationId)>,
+    peer_connect_result_sender: mpsc::Sender<(ConnectionId, Result<TcpStream>)>,
+    peer_connect_result_receiver: mpsc::Receiver<(ConnectionId, Result<TcpStream>)>,
+    /// Peer connections of TCP allocations that are not yet bound to a data connection of a client.
+    peer_streams: HashMap<ConnectionId, TcpStream>,
+    /// The data connections of clients that peer connections are bound to.
+    bound_connections: HashMap<ConnectionId, ClientSocket>,
+    readiness: Arc<Readiness>,
+    /// The local addresses we bind our sockets to.
+    listen_address: IpStack,
+    fast_path: Option<FastPath>,
+    /// The channel data relayed by the fast path that we did not report yet.
+    fast_path_usage: FastPathUsage,
+    usage_sender: mpsc::UnboundedSender<UsageRecord>,
+    drained_sender: mpsc::UnboundedSender<usize>,
+    /// Whether we already told the [`ControlPlane`] that our shard is drained.
+    reported_drained: bool,
+    sleep: Sleep,
+}
+
+/// How long we try to connect to a peer on behalf of a TCP allocation.
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client may take to complete the TLS handshake before we close its connection.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often we report the usage of allocations to the portal.
+const USAGE_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many usage records we keep at most while we cannot report them to the portal.
+const MAX_PENDING_USAGE_RECORDS: usize = 100_000;
+
+/// How long we wait for the portal to acknowledge the remaining usage records when shutting down.
+const USAGE_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Events from the tasks handling TCP and TLS connections of clients.
+enum StreamEvent {
+    Opened {
+        peer: ClientSocket,
+        sender: mpsc::Sender<StreamCommand>,
+    },
+    Data {
+        peer: ClientSocket,
+        data: Vec<u8>,
+    },
+    /// Bytes of the client have been spliced to its peer connection.
+    SplicedFromClient {
+        peer: ClientSocket,
+        num_bytes: usize,
+    },
+    /// Bytes of the peer have been spliced to the data connection of the client.
+    SplicedToClient {
+        peer: ClientSocket,
+        num_bytes: usize,
+    },
+    Closed {
+        peer: ClientSocket,
+    },
+}
+
+/// Commands for the tasks handling TCP and TLS connections of clients.
+enum StreamCommand {
+    Send(Vec<u8>),
+    /// Stop processing STUN messages and forward all bytes between the client and the given peer.
+    Splice(TcpStream),
+    /// Stop forwarding bytes between the client and its peer until the given deadline.
+    Pause(tokio::time::Instant),
+}
+
+impl<R> Eventloop<R>
+where
+    R: Rng,
+{
+    fn new(shard: Shard, server: Server<R>, config: WorkerConfig) -> Result<Self> {
+        let WorkerConfig {
+            udp_sockets,
+            nat_discovery_sockets,
+            listen_address,
+            listen_port,
+            tls_acceptor,
+            tls_ports,
+            stream_connection_limit,
+            stream_event_senders,
+            stream_event_receiver,
+            shard_command_receiver,
+            readiness,
+            fast_path,
+            usage_sender,
+            drained_sender,
+        } = config;
+
+        let buffer_pool = BufferPool::default();
+        let (relay_data_sender, relay_data_receiver) = mpsc::channel(1);
+        let (inbound_data_sender, inbound_data_receiver) = mpsc::channel(10);
+        let (peer_connection_sender, peer_connection_receiver) = mpsc::channel(10);
+        let (peer_connect_result_sender, peer_connect_result_receiver) = mpsc::channel(10);
+        let (outbound_ip4_data_sender, outbound_ip4_data_receiver) =
+            mpsc::channel::<(Vec<u8>, SocketAddr)>(10);
+        let (outbound_ip6_data_sender, outbound_ip6_data_receiver) =
+            mpsc::channel::<(Vec<u8>, SocketAddr)>(10);
+
+        let mut outbound_ip4_data_receiver = Some(outbound_ip4_data_receiver);
+        let mut outbound_ip6_data_receiver = Some(outbound_ip6_data_receiver);
+
+        for socket in udp_sockets {
+            let outbound_data_receiver = match socket.local_addr()?.family() {
+                AddressFamily::V4 => outbound_ip4_data_receiver.take(),
+                AddressFamily::V6 => outbound_ip6_data_receiver.take(),
+            }
+            .context("Expected at most one UDP socket per address family")?;
+
+            tokio::spawn(main_udp_socket_task(
+                UdpSocket::from_std(socket)?,
+                buffer_pool.clone(),
+                inbound_data_sender.clone(),
+                outbound_data_receiver,
+            ));
+        }
+
+        let (nat_discovery_data_sender, nat_discovery_data_receiver) = mpsc::channel(10);
+        let mut nat_discovery_data_senders = HashMap::new();
+
+        for (origin, socket) in nat_discovery_sockets {
+            let (outbound_data_sender, outbound_data_receiver) = mpsc::channel(10);
+            nat_discovery_data_senders.insert(
+                (socket.local_addr()?.family(), origin),
+                outbound_data_sender,
+            );
+
+            tokio::spawn(nat_discovery_socket_task(
+                UdpSocket::from_std(socket)?,
+                origin,
+                nat_discovery_data_sender.clone(),
+                outbound_data_receiver,
+            ));
+        }
+
+        let addresses = [
+            listen_address.for_family(AddressFamily::V4),
+            listen_address.for_family(AddressFamily::V6),
+        ];
+
+        for addr in addresses.into_iter().flatten() {
+            tokio::spawn(tcp_listener_task(
+                addr,
+                listen_port,
+                None,
+                stream_connection_limit.clone(),
+                shard,
+                stream_event_senders.clone(),
+            ));
+
+            for port in tls_ports.iter().filter(|_| tls_acceptor.is_some()) {
+                tokio::spawn(tcp_listener_task(
+                    addr,
+                    *port,
+                    tls_acceptor.clone(),
+                    stream_connection_limit.clone(),
+                    shard,
+                    stream_event_senders.clone(),
+                ));
+            }
+        }
+
+        Ok(Self {
+            shard,
+            inbound_data_receiver,
+            outbound_ip4_data_sender,
+            outbound_ip6_data_sender,
+            nat_discovery_data_receiver,
+            nat_discovery_data_senders,
+            server,
+            shard_command_receiver,
+            allocations: Default::default(),
+            relay_data_sender,
+            relay_data_receiver,
+            buffer_pool,
+            stream_event_receiver,
+            streams: Default::default(),
+            tcp_allocations: Default::default(),
+            peer_connection_sender,
+            peer_connection_receiver,
+            peer_connect_result_sender,
+            peer_connect_result_receiver,
+            peer_streams: Default::default(),
+            bound_connections: Default::default(),
+            readiness,
+            listen_address,
+            fast_path,
+            fast_path_usage: FastPathUsage::default(),
+            usage_sender,
+            drained_sender,
+            reported_drained: false,
+            sleep: Sleep::default(),
+        })
+    }
+
+    /// The local address to bind the sockets of an allocation of the given [`AddressFamily`] to.
+    ///
+    /// The [`Server`] only creates allocations for the families it has a public address for, which are the same ones we listen on.
+    fn listen_addr(&self, family: AddressFamily) -> IpAddr {
+        self.listen_address
+            .for_family(family)
+            .expect("to only create allocations for address families we listen on")
+    }
+
+    /// Hands the usage records that the [`Server`] has yet to emit to the [`ControlPlane`], dropping all other commands.
+    ///
+    /// Only meant to be called when stopping, when all other commands are moot.
+    fn forward_pending_usage(&mut self) {
+        while let Some(command) = self.server.next_command() {
+            if let Command::ReportUsage(record) = command {
+                self.report_usage(record);
+            }
+        }
+    }
+
+    /// Hands a usage record to the [`ControlPlane`], adding the channel data relayed by the fast path.
+    ///
+    /// With the fast path enabled, the [`Server`] reports all allocations periodically; records that remain empty are dropped.
+    fn report_usage(&mut self, mut record: UsageRecord) {
+        if let Some(fast_path) = &self.fast_path {
+            self.fast_path_usage
+                .add_to_record(&mut record, |client, channel| {
+                    fast_path.channel_usage(client, channel)
+                });
+        }
+
+        if !record.is_final && record.usage.is_empty() {
+            return;
+        }
+
+        // The control plane only stops once the relay shuts down.
+        let _ = self.usage_sender.unbounded_send(record);
+    }
+
+    fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
+        let span = tracing::error_span!("Eventloop::poll", shard = %self.shard.index());
+        let _guard = span.enter();
+
+        loop {
+            let now = SystemTime::now();
+
+            // Priority 1: Execute the pending commands of the server.
             if let Some(next_command) = self.server.next_command() {
                 match next_command {
                     Command::SendMessage { payload, recipient } => {
                         let span = tracing::error_span!("Command::SendMessage");
                         let _guard = span.enter();
 
+                        if recipient.is_stream() {
+                            let Some(stream) = self.streams.get_mut(&recipient) else {
+                                tracing::debug!(%recipient, "Unknown stream connection");
+                                continue;
+                            };
+
+                            if let Err(e) =
+                                stream.try_send(StreamCommand::Send(pad_for_stream(payload)))
+                            {
+                                if e.is_disconnected() {
+                                    tracing::debug!(%recipient, "Stream connection has been closed");
+                                    self.streams.remove(&recipient);
+                                }
+
+                                if e.is_full() {
+                                    tracing::warn!(%recipient, "Dropping message because channel to stream connection is full");
+                                }
+                            }
+
+                            continue;
+                        }
+
+                        let recipient = recipient.addr;
                         let sender = match recipient.family() {
                             AddressFamily::V4 => &mut self.outbound_ip4_data_sender,
                             AddressFamily::V6 => &mut self.outbound_ip6_data_sender,
                         };
 
-                        if let Err(e) = sender.try_send((payload, recipient)) {
-                            if e.is_disconnected() {
+                        match sender.try_send((payload, recipient)) {
+                            Ok(()) => self
+                                .readiness
+                                .set_udp_channel_full(self.shard.index(), false),
+                            Err(e) if e.is_disconnected() => {
                                 return Poll::Ready(Err(anyhow!(
                                     "Channel to primary UDP socket task has been closed"
                                 )));
                             }
+                            Err(e) => {
+                                if e.is_full() {
+                                    tracing::warn!(%recipient, "Dropping message because channel to primary UDP socket task is full");
+                                    self.readiness
+                                        .set_udp_channel_full(self.shard.index(), true);
+                                }
+                            }
+                        }
+                    }
+                    Command::SendMessageFrom {
+                        payload,
+                        recipient,
+                        origin,
+                    } => {
+                        let Some(sender) = self
+                            .nat_discovery_data_senders
+                            .get_mut(&(recipient.family(), origin))
+                        else {
+                            tracing::debug!(%recipient, ?origin, "No socket to send NAT discovery response from");
+                            continue;
+                        };
 
-                            if e.is_full() {
-                                tracing::warn!(%recipient, "Dropping message because channel to primary UDP socket task is full");
+                        if let Err(e) = sender.try_send((payload, recipient)) {
+                            if e.is_disconnected() {
+                                return Poll::Ready(Err(anyhow!(
+                                    "Channel to NAT discovery socket task has been closed"
+                                )));
                             }
+
+                            tracing::debug!(%recipient, ?origin, "Dropping NAT discovery response because channel to socket task is full");
                         }
                     }
                     Command::CreateAllocation { id, family, port } => {
@@ -396,19 +1962,54 @@ where
 
                         self.allocations.insert(
                             (id, family),
-                            Allocation::new(self.relay_data_sender.clone(), id, family, port),
+                            Allocation::new(
+                                self.relay_data_sender.clone(),
+                                self.buffer_pool.clone(),
+                                id,
+                                self.listen_addr(family),
+                                port,
+                            ),
+                        );
+                        self.readiness.set_port_pool_exhausted(
+                            self.shard.index(),
+                            self.server.is_port_pool_exhausted(),
+                        );
+                    }
+                    Command::CreateTcpAllocation { id, family, port } => {
+                        let span = tracing::error_span!("Command::CreateTcpAllocation", %id, %family, %port);
+                        let _guard = span.enter();
+
+                        self.tcp_allocations.insert(
+                            (id, family),
+                            TcpAllocation::new(
+                                self.peer_connection_sender.clone(),
+                                id,
+                                self.listen_addr(family),
+                                port,
+                            ),
+                        );
+                        self.readiness.set_port_pool_exhausted(
+                            self.shard.index(),
+                            self.server.is_port_pool_exhausted(),
                         );
                     }
                     Command::FreeAllocation { id, family } => {
                         let span = tracing::error_span!("Command::FreeAllocation", %id, %family);
                         let _guard = span.enter();
 
-                        if self.allocations.remove(&(id, family)).is_none() {
+                        if self.allocations.remove(&(id, family)).is_none()
+                            && self.tcp_allocations.remove(&(id, family)).is_none()
+                        {
                             tracing::debug!("Unknown allocation {id}");
                             continue;
                         };
 
                         tracing::info!("Freeing addresses of allocation {id}");
+
+                        self.readiness.set_port_pool_exhausted(
+                            self.shard.index(),
+                            self.server.is_port_pool_exhausted(),
+                        );
                     }
                     Command::Wake { deadline } => {
                         let span = tracing::error_span!("Command::Wake", ?deadline);
@@ -447,6 +2048,127 @@ where
                             allocation.remove();
                         }
                     }
+                    Command::CreateChannelBinding {
+                        allocation,
+                        client,
+                        channel,
+                        peer,
+                        port,
+                    } => {
+                        let Some(fast_path) = &self.fast_path else {
+                            continue;
+                        };
+
+                        match fast_path.create_channel_binding(client, channel, peer, port) {
+                            Ok(()) => {
+                                self.fast_path_usage
+                                    .binding_created(allocation, client, channel, peer);
+                            }
+                            Err(e) => {
+                                tracing::warn!(%client, %channel, %peer, "Failed to add channel binding to XDP program: {e:#}");
+                            }
+                        }
+                    }
+                    Command::DeleteChannelBinding {
+                        allocation: _,
+                        client,
+                        channel,
+                        peer,
+                        port,
+                    } => {
+                        let Some(fast_path) = &self.fast_path else {
+                            continue;
+                        };
+
+                        match fast_path.delete_channel_binding(client, channel, peer, port) {
+                            Ok(totals) => {
+                                self.fast_path_usage
+                                    .binding_deleted(client, channel, totals);
+                            }
+                            Err(e) => {
+                                tracing::warn!(%client, %channel, %peer, "Failed to remove channel binding from XDP program: {e:#}");
+                            }
+                        }
+                    }
+                    Command::ReportUsage(record) => {
+                        self.report_usage(record);
+                    }
+                    Command::ConnectToPeer {
+                        connection,
+                        allocation,
+                        peer,
+                    } => {
+                        let span = tracing::error_span!("Command::ConnectToPeer", %connection, %allocation, %peer);
+                        let _guard = span.enter();
+
+                        let Some(tcp_allocation) =
+                            self.tcp_allocations.get(&(allocation, peer.family()))
+                        else {
+                            tracing::debug!("Unknown TCP allocation");
+                            self.server.handle_peer_connection_failed(connection);
+                            continue;
+                        };
+
+                        let connect = tcp_allocation.connect(peer);
+                        let mut result_sender = self.peer_connect_result_sender.clone();
+
+                        tokio::spawn(async move {
+                            let result = tokio::time::timeout(PEER_CONNECT_TIMEOUT, connect)
+                                .await
+                                .context("Timed out while connecting to peer")
+                                .and_then(|result| result);
+
+                            let _ = result_sender.send((connection, result)).await;
+                        });
+                    }
+                    Command::BindConnection { connection, client } => {
+                        let span =
+                            tracing::error_span!("Command::BindConnection", %connection, %client);
+                        let _guard = span.enter();
+
+                        let Some(peer_stream) = self.peer_streams.remove(&connection) else {
+                            tracing::debug!("Unknown peer connection");
+                            continue;
+                        };
+                        let Some(stream) = self.streams.get_mut(&client) else {
+                            tracing::debug!("Unknown data connection");
+                            continue;
+                        };
+
+                        if stream.try_send(StreamCommand::Splice(peer_stream)).is_err() {
+                            tracing::warn!("Failed to hand peer connection to data connection");
+                            continue;
+                        }
+
+                        self.bound_connections.insert(connection, client);
+                    }
+                    Command::CloseConnection { connection } => {
+                        let span = tracing::error_span!("Command::CloseConnection", %connection);
+                        let _guard = span.enter();
+
+                        self.peer_streams.remove(&connection);
+
+                        // Dropping the sender stops the task of the data connection.
+                        if let Some(client) = self.bound_connections.remove(&connection) {
+                            self.streams.remove(&client);
+                        }
+                    }
+                    Command::PauseConnection { client, until } => {
+                        let span =
+                            tracing::error_span!("Command::PauseConnection", %client, ?until);
+                        let _guard = span.enter();
+
+                        let Some(stream) = self.streams.get_mut(&client) else {
+                            tracing::debug!("Unknown data connection");
+                            continue;
+                        };
+                        let deadline = tokio::time::Instant::now()
+                            + until.duration_since(now).unwrap_or_default();
+
+                        if stream.try_send(StreamCommand::Pause(deadline)).is_err() {
+                            tracing::debug!("Failed to pause data connection");
+                        }
+                    }
                 }
 
                 continue; // Attempt to process more commands.
@@ -462,7 +2184,38 @@ where
             if let Poll::Ready(Some((data, sender, allocation))) =
                 self.relay_data_receiver.poll_next_unpin(cx)
             {
-                self.server.handle_relay_input(&data, sender, allocation);
+                self.server
+                    .handle_relay_input(&data, sender, allocation, now);
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 3 (continued): Handle connections of peers to TCP allocations.
+            if let Poll::Ready(Some((stream, peer, allocation))) =
+                self.peer_connection_receiver.poll_next_unpin(cx)
+            {
+                if let Some(connection) = self
+                    .server
+                    .handle_peer_connection_accepted(allocation, peer, now)
+                {
+                    self.peer_streams.insert(connection, stream);
+                }
+                continue; // Handle potentially new commands.
+            }
+
+            if let Poll::Ready(Some((connection, result))) =
+                self.peer_connect_result_receiver.poll_next_unpin(cx)
+            {
+                match result {
+                    Ok(stream) => {
+                        self.peer_streams.insert(connection, stream);
+                        self.server
+                            .handle_peer_connection_established(connection, now);
+                    }
+                    Err(e) => {
+                        tracing::debug!(%connection, "Failed to connect to peer: {e:#}");
+                        self.server.handle_peer_connection_failed(connection);
+                    }
+                }
                 continue; // Handle potentially new commands.
             }
 
@@ -470,43 +2223,103 @@ where
             if let Poll::Ready(Some((buffer, sender))) =
                 self.inbound_data_receiver.poll_next_unpin(cx)
             {
-                self.server.handle_client_input(&buffer, sender, now);
+                self.server
+                    .handle_client_input(&buffer, ClientSocket::udp(sender), now);
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 5: Handle portal messages
-            match self.channel.as_mut().map(|c| c.poll(cx)) {
-                Some(Poll::Ready(Err(Error::Serde(e)))) => {
-                    tracing::warn!("Failed to deserialize portal message: {e}");
-                    continue; // This is not a hard-error, we can continue.
-                }
-                Some(Poll::Ready(Err(e))) => {
-                    return Poll::Ready(Err(anyhow!("Portal connection failed: {e}")));
-                }
-                Some(Poll::Ready(Ok(Event::SuccessResponse { res: (), .. }))) => {
-                    continue;
-                }
-                Some(Poll::Ready(Ok(Event::JoinedRoom { topic }))) => {
-                    tracing::info!("Successfully joined room '{topic}'");
-                    continue;
-                }
-                Some(Poll::Ready(Ok(Event::ErrorResponse {
-                    topic,
-                    req_id,
-                    reason,
-                }))) => {
-                    tracing::warn!("Request with ID {req_id} on topic {topic} failed: {reason}");
-                    continue;
+            // Priority 4 (continued): Answer NAT behaviour discovery requests on our alternate addresses and ports.
+            if let Poll::Ready(Some((data, sender, origin))) =
+                self.nat_discovery_data_receiver.poll_next_unpin(cx)
+            {
+                self.server
+                    .handle_nat_discovery_input(&data, sender, origin);
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 4 (continued): Same as above but for clients connected via TCP or TLS.
+            if let Poll::Ready(Some(event)) = self.stream_event_receiver.poll_next_unpin(cx) {
+                match event {
+                    StreamEvent::Opened { peer, sender } => {
+                        tracing::debug!(%peer, "New stream connection");
+
+                        self.streams.insert(peer, sender);
+                    }
+                    StreamEvent::Data { peer, data } => {
+                        self.server.handle_client_input(&data, peer, now);
+                    }
+                    StreamEvent::SplicedFromClient { peer, num_bytes } => {
+                        self.server.handle_spliced_client_data(peer, num_bytes, now);
+                    }
+                    StreamEvent::SplicedToClient { peer, num_bytes } => {
+                        self.server.handle_spliced_peer_data(peer, num_bytes, now);
+                    }
+                    StreamEvent::Closed { peer } => {
+                        tracing::debug!(%peer, "Stream connection closed");
+
+                        self.streams.remove(&peer);
+                        self.server.handle_stream_closed(peer);
+                    }
                 }
-                Some(Poll::Ready(Ok(Event::HeartbeatSent))) => {
-                    tracing::debug!("Heartbeat sent to portal");
-                    continue;
+
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 5: Handle commands from the control plane
+            if let Poll::Ready(Some(command)) = self.shard_command_receiver.poll_next_unpin(cx) {
+                match command {
+                    ShardCommand::RotateAuthSecret(secret) => {
+                        self.server.rotate_auth_secret(secret, now);
+                    }
+                    ShardCommand::SetAlternateServers(servers) => {
+                        self.server.set_alternate_servers(servers);
+                    }
+                    ShardCommand::AddNonces(nonces) => {
+                        for nonce in nonces {
+                            self.server.add_nonce(nonce);
+                        }
+                    }
+                    ShardCommand::RevokeUser(user, until) => {
+                        self.server.revoke_user(&user, until);
+                    }
+                    ShardCommand::Drain => {
+                        self.server.start_draining();
+                    }
+                    ShardCommand::Reconfigure {
+                        bandwidth_limits,
+                        peer_filter,
+                    } => {
+                        self.server.reconfigure(bandwidth_limits, peer_filter, now);
+                    }
+                    ShardCommand::Admin(AdminRequest::ListAllocations { reply }) => {
+                        let _ = reply.send(self.server.allocations());
+                    }
+                    ShardCommand::Admin(AdminRequest::FreeAllocation { id, reply }) => {
+                        let _ = reply.send(self.server.free_allocation(id));
+                    }
+                    ShardCommand::Stop { reply } => {
+                        self.forward_pending_usage();
+                        let _ = reply.send(self.server.snapshot());
+
+                        return Poll::Ready(Ok(()));
+                    }
+                    ShardCommand::ShutDown { reply } => {
+                        self.server.report_final_usage();
+                        self.forward_pending_usage();
+                        let _ = reply.send(());
+
+                        return Poll::Ready(Ok(()));
+                    }
                 }
-                Some(Poll::Ready(Ok(
-                    Event::InboundMessage { msg: (), .. } | Event::InboundReq { req: (), .. },
-                )))
-                | Some(Poll::Pending)
-                | None => {}
+
+                continue; // Handle potentially new commands.
+            }
+
+            if !self.reported_drained && self.server.is_drained() {
+                self.reported_drained = true;
+
+                // The control plane only stops once the relay shuts down.
+                let _ = self.drained_sender.unbounded_send(self.shard.index());
             }
 
             return Poll::Pending;
@@ -514,23 +2327,340 @@ where
     }
 }
 
+/// Receives and sends the datagrams of our listen port in batches.
+///
+/// Received datagrams are handed to the [`Eventloop`] in the buffers they were received into, which return to the pool once handled.
 async fn main_udp_socket_task(
-    family: AddressFamily,
-    mut inbound_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    mut socket: UdpSocket,
+    pool: BufferPool,
+    mut inbound_data_sender: mpsc::Sender<(PooledBuffer, SocketAddr)>,
     mut outbound_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
 ) -> Result<Infallible> {
-    let mut socket = UdpSocket::bind(family, 3478)?;
+    let mut outbound_batch = Vec::with_capacity(MAX_BATCH_SIZE);
 
+    loop {
+        tokio::select! {
+            result = socket.recv_batch(&pool) => {
+                for datagram in result? {
+                    inbound_data_sender.send(datagram).await?;
+                }
+            }
+            maybe_item = outbound_data_receiver.next() => {
+                outbound_batch.push(maybe_item.context("Outbound data channel closed")?);
+
+                // Send whatever else the eventloop already queued with the same syscall.
+                while outbound_batch.len() < MAX_BATCH_SIZE {
+                    let Ok(Some(item)) = outbound_data_receiver.try_next() else {
+                        break;
+                    };
+
+                    outbound_batch.push(item);
+                }
+
+                socket.send_batch(&outbound_batch).await?;
+                outbound_batch.clear();
+            }
+        }
+    }
+}
+
+/// Serves one of our alternate addresses or ports for NAT behaviour discovery.
+///
+/// Clients only send a handful of Binding requests here, thus we don't bother with batching.
+async fn nat_discovery_socket_task(
+    mut socket: UdpSocket,
+    origin: DiscoveryOrigin,
+    mut inbound_data_sender: mpsc::Sender<(Vec<u8>, SocketAddr, DiscoveryOrigin)>,
+    mut outbound_data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+) -> Result<Infallible> {
     loop {
         tokio::select! {
             result = socket.recv() => {
                 let (data, sender) = result?;
-                inbound_data_sender.send((data.to_vec(), sender)).await?;
+
+                inbound_data_sender.send((data.to_vec(), sender, origin)).await?;
             }
             maybe_item = outbound_data_receiver.next() => {
-                let (data, recipient) = maybe_item.context("Outbound data channel closed")?;
-                socket.send_to(data.as_ref(), recipient).await?;
+                let (payload, recipient) = maybe_item.context("Outbound data channel closed")?;
+
+                socket.send_to(&payload, recipient).await?;
+            }
+        }
+    }
+}
+
+async fn tcp_listener_task(
+    addr: IpAddr,
+    port: u16,
+    tls_acceptor: Option<TlsAcceptor>,
+    connection_limit: StreamConnectionLimit,
+    shard: Shard,
+    stream_event_senders: Arc<[mpsc::Sender<StreamEvent>]>,
+) -> Result<Infallible> {
+    let listener = bind_tcp_listener(addr, port)?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+
+        let Some(permit) = connection_limit.try_acquire() else {
+            tracing::debug!(%peer, "Too many stream connections, closing new one");
+            continue;
+        };
+        let stream_event_senders = stream_event_senders.clone();
+
+        let Some(tls_acceptor) = tls_acceptor.clone() else {
+            tokio::spawn(async move {
+                let _permit = permit;
+
+                stream_connection_task(stream, ClientSocket::tcp(peer), shard, stream_event_senders)
+                    .await
+            });
+            continue;
+        };
+
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    stream_connection_task(
+                        stream,
+                        ClientSocket::tls(peer),
+                        shard,
+                        stream_event_senders,
+                    )
+                    .await
+                }
+                Ok(Err(e)) => tracing::debug!(%peer, "TLS handshake failed: {e}"),
+                Err(_) => tracing::debug!(%peer, "TLS handshake timed out"),
+            }
+        });
+    }
+}
+
+/// Handles a single TCP or TLS connection of a client.
+///
+/// Incoming bytes are split into STUN or channel data messages and forwarded to the [`Eventloop`].
+/// Messages from the [`Eventloop`] are written back to the stream.
+///
+/// The connection is handled by the [`Eventloop`] of the shard that accepted it, unless its first message is a CONNECTION-BIND request.
+/// Those are data connections of TCP allocations, which we hand to the shard owning the peer connection to bind to.
+/// The peer connection then gets spliced into this task, thus its bytes are relayed on our runtime.
+async fn stream_connection_task<S>(
+    mut stream: S,
+    peer: ClientSocket,
+    shard: Shard,
+    stream_event_senders: Arc<[mpsc::Sender<StreamEvent>]>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framer = StreamFramer::default();
+
+    let first_frame = match read_frame(&mut stream, &mut framer).await {
+        Ok(Some(frame)) => frame,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::debug!(%peer, "Stream connection failed: {e:#}");
+            return;
+        }
+    };
+
+    let owner =
+        connection_bind_target(&first_frame).map_or(shard, |connection| shard.owner_of(connection));
+    let mut stream_event_sender = stream_event_senders[owner.index()].clone();
+    let (stream_command_sender, stream_command_receiver) = mpsc::channel(10);
+
+    if stream_event_sender
+        .send(StreamEvent::Opened {
+            peer,
+            sender: stream_command_sender,
+        })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    if let Err(e) = relay_stream(
+        stream,
+        peer,
+        framer,
+        first_frame,
+        &mut stream_event_sender,
+        stream_command_receiver,
+    )
+    .await
+    {
+        tracing::debug!(%peer, "Stream connection failed: {e:#}");
+    }
+
+    let _ = stream_event_sender.send(StreamEvent::Closed { peer }).await;
+}
+
+/// Reads from the stream until the framer yields a frame, returning `None` if the stream ends before.
+async fn read_frame<S>(stream: &mut S, framer: &mut StreamFramer) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0u8; 65536];
+
+    loop {
+        if let Some(frame) = framer.next_frame()? {
+            return Ok(Some(frame));
+        }
+
+        let num_read = stream.read(&mut buffer).await?;
+
+        if num_read == 0 {
+            return Ok(None);
+        }
+
+        framer.push(&buffer[..num_read]);
+    }
+}
+
+async fn relay_stream<S>(
+    mut stream: S,
+    peer: ClientSocket,
+    mut framer: StreamFramer,
+    first_frame: Vec<u8>,
+    stream_event_sender: &mut mpsc::Sender<StreamEvent>,
+    mut stream_command_receiver: mpsc::Receiver<StreamCommand>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; 65536];
+
+    stream_event_sender
+        .send(StreamEvent::Data {
+            peer,
+            data: first_frame,
+        })
+        .await?;
+
+    while let Some(data) = framer.next_frame()? {
+        stream_event_sender
+            .send(StreamEvent::Data { peer, data })
+            .await?;
+    }
+
+    loop {
+        tokio::select! {
+            result = stream.read(&mut buffer) => {
+                let num_read = result?;
+
+                if num_read == 0 {
+                    return Ok(());
+                }
+
+                framer.push(&buffer[..num_read]);
+
+                while let Some(data) = framer.next_frame()? {
+                    stream_event_sender.send(StreamEvent::Data { peer, data }).await?;
+                }
+            }
+            maybe_command = stream_command_receiver.next() => {
+                match maybe_command.context("Stream command channel closed")? {
+                    StreamCommand::Send(data) => {
+                        stream.write_all(&data).await?;
+                    }
+                    StreamCommand::Splice(mut peer_stream) => {
+                        let buffered = framer.take_buffered();
+                        peer_stream.write_all(&buffered).await?;
+                        stream_event_sender
+                            .send(StreamEvent::SplicedFromClient { peer, num_bytes: buffered.len() })
+                            .await?;
+
+                        splice(
+                            &mut stream,
+                            &mut peer_stream,
+                            peer,
+                            stream_event_sender,
+                            &mut stream_command_receiver,
+                        )
+                        .await?;
+
+                        return Ok(());
+                    }
+                }
             }
         }
     }
 }
+
+/// Forwards all bytes between the data connection of a client and its peer connection until both are closed.
+///
+/// Every forwarded chunk is reported to the eventloop so it can be accounted for.
+/// Forwarding stops early once the eventloop closes the connection.
+async fn splice<S>(
+    stream: &mut S,
+    peer_stream: &mut TcpStream,
+    peer: ClientSocket,
+    stream_event_sender: &mut mpsc::Sender<StreamEvent>,
+    stream_command_receiver: &mut mpsc::Receiver<StreamCommand>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client_buffer = vec![0u8; 65536];
+    let mut peer_buffer = vec![0u8; 65536];
+    let mut client_open = true;
+    let mut peer_open = true;
+
+    while client_open || peer_open {
+        tokio::select! {
+            result = stream.read(&mut client_buffer), if client_open => {
+                let num_bytes = result?;
+
+                if num_bytes == 0 {
+                    client_open = false;
+                    peer_stream.shutdown().await?;
+                    continue;
+                }
+
+                peer_stream.write_all(&client_buffer[..num_bytes]).await?;
+                stream_event_sender.send(StreamEvent::SplicedFromClient { peer, num_bytes }).await?;
+            }
+            result = peer_stream.read(&mut peer_buffer), if peer_open => {
+                let num_bytes = result?;
+
+                if num_bytes == 0 {
+                    peer_open = false;
+                    stream.shutdown().await?;
+                    continue;
+                }
+
+                stream.write_all(&peer_buffer[..num_bytes]).await?;
+                stream_event_sender.send(StreamEvent::SplicedToClient { peer, num_bytes }).await?;
+            }
+            maybe_command = stream_command_receiver.next() => {
+                match maybe_command {
+                    Some(StreamCommand::Pause(deadline)) => {
+                        // Exceeded a bandwidth limit, don't forward anything until we are within it again.
+                        let sleep = tokio::time::sleep_until(deadline);
+                        tokio::pin!(sleep);
+
+                        loop {
+                            tokio::select! {
+                                () = &mut sleep => break,
+                                maybe_command = stream_command_receiver.next() => match maybe_command {
+                                    Some(StreamCommand::Pause(deadline)) => {
+                                        let deadline = deadline.max(sleep.deadline());
+                                        sleep.as_mut().reset(deadline);
+                                    }
+                                    Some(StreamCommand::Send(_) | StreamCommand::Splice(_)) => {}
+                                    None => return Ok(()),
+                                },
+                            }
+                        }
+                    }
+                    Some(StreamCommand::Send(_) | StreamCommand::Splice(_)) => {} // Not applicable to spliced connections.
+                    None => return Ok(()), // The connection got closed by the eventloop.
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
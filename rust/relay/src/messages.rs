@@ -0,0 +1,278 @@
+//! The messages exchanged with the portal.
+
+use crate::config::override_with;
+use crate::Args;
+use firezone_relay::UsageRecord;
+use ip_network::IpNetwork;
+use secrecy::{ExposeSecret as _, SecretString};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+#[derive(Deserialize, Debug)]
+pub struct Init {}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct JoinMessage {
+    pub stamp_secret: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum IngressMessages {
+    /// The portal rotated our auth secret.
+    ///
+    /// Credentials derived from the previous secret remain valid for a grace period.
+    SecretRotated(SecretRotated),
+    /// The portal told us about the relays that we may redirect clients to when we are too busy.
+    AlternateServersUpdated(AlternateServersUpdated),
+    /// The portal handed out nonces to clients that they may use without asking us for one first.
+    NoncesIssued(NoncesIssued),
+    /// The portal revoked the credentials of a user, we must free its allocations.
+    UsernameSaltRevoked(UsernameSaltRevoked),
+    /// The portal takes us out of rotation, we must stop accepting new allocations.
+    DrainRequested(DrainRequested),
+    /// The portal changed the settings that can be applied while we are running.
+    ConfigUpdated(ConfigUpdated),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SecretRotated {
+    pub secret: SecretString,
+}
+
+impl PartialEq for SecretRotated {
+    fn eq(&self, other: &Self) -> bool {
+        self.secret.expose_secret() == other.secret.expose_secret()
+    }
+}
+
+impl Eq for SecretRotated {}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct AlternateServersUpdated {
+    pub servers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct NoncesIssued {
+    pub nonces: Vec<Uuid>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct UsernameSaltRevoked {
+    pub username_salt: String,
+    /// When the last credentials with this salt expire, as seconds since the UNIX epoch.
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct DrainRequested {}
+
+/// Overrides the corresponding settings of the config file and command-line options, absent settings keep them.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct ConfigUpdated {
+    #[serde(default)]
+    pub allocation_bandwidth_limit: Option<u64>,
+    #[serde(default)]
+    pub user_bandwidth_limit: Option<u64>,
+    #[serde(default, deserialize_with = "crate::config::deserialize_networks")]
+    pub allowed_peer_networks: Option<Vec<IpNetwork>>,
+    #[serde(default, deserialize_with = "crate::config::deserialize_networks")]
+    pub denied_peer_networks: Option<Vec<IpNetwork>>,
+}
+
+impl ConfigUpdated {
+    /// Overrides the given [`Args`] with all settings present in this update.
+    pub fn apply(&self, args: &mut Args) {
+        override_with(
+            &mut args.allocation_bandwidth_limit,
+            self.allocation_bandwidth_limit,
+        );
+        override_with(&mut args.user_bandwidth_limit, self.user_bandwidth_limit);
+        if let Some(networks) = self.allowed_peer_networks.clone() {
+            args.allowed_peer_networks = networks;
+        }
+        override_with(
+            &mut args.denied_peer_networks,
+            self.denied_peer_networks.clone(),
+        );
+    }
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum EgressMessages {
+    /// The data relayed through our allocations since their previous report.
+    UsageReported(UsageReported),
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct UsageReported {
+    pub records: Vec<UsageRecord>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use firezone_relay::{AllocationId, ChannelUsage, Traffic, Usage};
+
+    #[test]
+    fn secret_rotated_deserialization() {
+        let message = r#"{"event":"secret_rotated","payload":{"secret":"4c98bf59c99b3e467ecd7cf9d6b3e5279645fca59be67bc5bb4af3cf653761ab"}}"#;
+
+        let actual = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            actual,
+            IngressMessages::SecretRotated(SecretRotated {
+                secret: SecretString::from(
+                    "4c98bf59c99b3e467ecd7cf9d6b3e5279645fca59be67bc5bb4af3cf653761ab".to_owned()
+                ),
+            })
+        );
+    }
+
+    #[test]
+    fn alternate_servers_updated_deserialization() {
+        let message = r#"{"event":"alternate_servers_updated","payload":{"servers":["203.0.113.2:3478","[2001:db8::2]:3478"]}}"#;
+
+        let actual = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            actual,
+            IngressMessages::AlternateServersUpdated(AlternateServersUpdated {
+                servers: vec![
+                    "203.0.113.2:3478".parse().unwrap(),
+                    "[2001:db8::2]:3478".parse().unwrap()
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn nonces_issued_deserialization() {
+        let message = r#"{"event":"nonces_issued","payload":{"nonces":["0b9a4a3e-2b52-4cbb-8d4e-2a8c3d4f3a29","8c1b0e1f-6d0a-4e6e-9d5b-45e1f1f0b3c2"]}}"#;
+
+        let actual = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            actual,
+            IngressMessages::NoncesIssued(NoncesIssued {
+                nonces: vec![
+                    "0b9a4a3e-2b52-4cbb-8d4e-2a8c3d4f3a29".parse().unwrap(),
+                    "8c1b0e1f-6d0a-4e6e-9d5b-45e1f1f0b3c2".parse().unwrap()
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn username_salt_revoked_deserialization() {
+        let message = r#"{"event":"username_salt_revoked","payload":{"username_salt":"3f4c1a","expires_at":1700000000}}"#;
+
+        let actual = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            actual,
+            IngressMessages::UsernameSaltRevoked(UsernameSaltRevoked {
+                username_salt: "3f4c1a".to_owned(),
+                expires_at: 1_700_000_000,
+            })
+        );
+    }
+
+    #[test]
+    fn drain_requested_deserialization() {
+        let message = r#"{"event":"drain_requested","payload":{}}"#;
+
+        let actual = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(actual, IngressMessages::DrainRequested(DrainRequested {}));
+    }
+
+    #[test]
+    fn config_updated_deserialization() {
+        let message = r#"{"event":"config_updated","payload":{"user_bandwidth_limit":1000000,"denied_peer_networks":["10.0.0.0/8","fd00::/8"]}}"#;
+
+        let actual = serde_json::from_str::<IngressMessages>(message).unwrap();
+
+        assert_eq!(
+            actual,
+            IngressMessages::ConfigUpdated(ConfigUpdated {
+                allocation_bandwidth_limit: None,
+                user_bandwidth_limit: Some(1_000_000),
+                allowed_peer_networks: None,
+                denied_peer_networks: Some(vec![
+                    "10.0.0.0/8".parse().unwrap(),
+                    "fd00::/8".parse().unwrap()
+                ]),
+            })
+        );
+    }
+
+    #[test]
+    fn config_updated_overrides_present_settings() {
+        let mut args = Args::parse_from([
+            "firezone-relay",
+            "--allocation-bandwidth-limit",
+            "500",
+            "--user-bandwidth-limit",
+            "1000",
+        ]);
+
+        ConfigUpdated {
+            allocation_bandwidth_limit: None,
+            user_bandwidth_limit: Some(2000),
+            allowed_peer_networks: None,
+            denied_peer_networks: None,
+        }
+        .apply(&mut args);
+
+        assert_eq!(args.allocation_bandwidth_limit, Some(500));
+        assert_eq!(args.user_bandwidth_limit, Some(2000));
+    }
+
+    #[test]
+    fn usage_reported_serialization() {
+        let message = EgressMessages::UsageReported(UsageReported {
+            records: vec![UsageRecord {
+                allocation: AllocationId::default(),
+                user: "salt".to_owned(),
+                usage: Usage {
+                    client_to_peer: Traffic {
+                        bytes: 100,
+                        packets: 1,
+                    },
+                    peer_to_client: Traffic {
+                        bytes: 0,
+                        packets: 0,
+                    },
+                },
+                channels: vec![ChannelUsage {
+                    number: 0x4000,
+                    peer: "203.0.113.2:3478".parse().unwrap(),
+                    usage: Usage {
+                        client_to_peer: Traffic {
+                            bytes: 100,
+                            packets: 1,
+                        },
+                        peer_to_client: Traffic {
+                            bytes: 0,
+                            packets: 0,
+                        },
+                    },
+                }],
+                is_final: true,
+            }],
+        });
+
+        let actual = serde_json::to_string(&message).unwrap();
+
+        assert_eq!(
+            actual,
+            r#"{"event":"usage_reported","payload":{"records":[{"allocation":0,"username_salt":"salt","client_to_peer":{"bytes":100,"packets":1},"peer_to_client":{"bytes":0,"packets":0},"channels":[{"number":16384,"peer":"203.0.113.2:3478","client_to_peer":{"bytes":100,"packets":1},"peer_to_client":{"bytes":0,"packets":0}}],"final":true}]}}"#
+        );
+    }
+}
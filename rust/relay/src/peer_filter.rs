@@ -0,0 +1,92 @@
+use ip_network::IpNetwork;
+use std::net::IpAddr;
+
+/// The networks clients may not relay to unless explicitly allowed.
+///
+/// These are the networks a relay can reach but the internet cannot:
+/// "this" network, loopback, link-local and the metadata endpoints of cloud providers that are not link-local.
+const DEFAULT_DENIED: &[&str] = &[
+    "0.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "100.100.100.200/32", // Alibaba Cloud metadata
+    "::/128",
+    "::1/128",
+    "fe80::/10",
+    "fd00:ec2::254/128", // AWS metadata over IPv6
+];
+
+/// Decides which peers clients may relay data to.
+///
+/// An address is allowed if it is in one of the allowed networks or in none of the denied ones.
+/// Thus, the allowed networks punch holes into the denied ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerFilter {
+    allowed: Vec<IpNetwork>,
+    denied: Vec<IpNetwork>,
+}
+
+impl PeerFilter {
+    pub fn new(allowed: Vec<IpNetwork>, denied: Vec<IpNetwork>) -> Self {
+        Self { allowed, denied }
+    }
+
+    /// The networks that are denied by default.
+    pub fn default_denied() -> Vec<IpNetwork> {
+        DEFAULT_DENIED
+            .iter()
+            .map(|network| network.parse().expect("default networks to be valid"))
+            .collect()
+    }
+
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        if self.allowed.iter().any(|network| network.contains(addr)) {
+            return true;
+        }
+
+        !self.denied.iter().any(|network| network.contains(addr))
+    }
+}
+
+impl Default for PeerFilter {
+    fn default() -> Self {
+        Self::new(Vec::new(), Self::default_denied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn denies_loopback_link_local_and_metadata_by_default() {
+        let filter = PeerFilter::default();
+
+        assert!(!filter.is_allowed(Ipv4Addr::LOCALHOST.into()));
+        assert!(!filter.is_allowed(Ipv6Addr::LOCALHOST.into()));
+        assert!(!filter.is_allowed(Ipv4Addr::new(169, 254, 169, 254).into()));
+        assert!(!filter.is_allowed("fe80::1".parse().unwrap()));
+        assert!(!filter.is_allowed("fd00:ec2::254".parse().unwrap()));
+    }
+
+    #[test]
+    fn allows_public_and_private_addresses_by_default() {
+        let filter = PeerFilter::default();
+
+        assert!(filter.is_allowed(Ipv4Addr::new(1, 1, 1, 1).into()));
+        assert!(filter.is_allowed(Ipv4Addr::new(10, 0, 0, 1).into()));
+        assert!(filter.is_allowed("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn allowed_networks_take_precedence() {
+        let filter = PeerFilter::new(
+            vec!["10.0.1.0/24".parse().unwrap()],
+            vec!["10.0.0.0/8".parse().unwrap()],
+        );
+
+        assert!(filter.is_allowed(Ipv4Addr::new(10, 0, 1, 1).into()));
+        assert!(!filter.is_allowed(Ipv4Addr::new(10, 0, 2, 1).into()));
+    }
+}
@@ -1,9 +1,10 @@
-use crate::Binding;
+use crate::{Binding, PeerFilter};
 use proptest::arbitrary::any;
 use proptest::collection::vec;
 use proptest::strategy::Just;
 use proptest::strategy::Strategy;
 use proptest::string::string_regex;
+use std::net::SocketAddrV4;
 use std::ops::Add;
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, RequestedTransport};
@@ -41,6 +42,13 @@ pub fn username_salt() -> impl Strategy<Value = String> {
     string_regex("[a-zA-Z0-9]{10}").unwrap()
 }
 
+/// A peer that the default [`PeerFilter`] allows relaying to.
+pub fn peer() -> impl Strategy<Value = SocketAddrV4> {
+    any::<SocketAddrV4>().prop_filter("peer must be allowed", |peer| {
+        PeerFilter::default().is_allowed((*peer.ip()).into())
+    })
+}
+
 pub fn nonce() -> impl Strategy<Value = Uuid> {
     any::<u128>().prop_map(Uuid::from_u128)
 }
@@ -0,0 +1,332 @@
+//! STUN attributes of [RFC 5780](https://www.rfc-editor.org/rfc/rfc5780) (NAT behaviour discovery).
+//!
+//! `stun-codec` does not implement these so we define them ourselves.
+//!
+//! A server that supports NAT behaviour discovery has a primary and an alternate IP address as well as a primary and an alternate port.
+//! Clients can ask it to respond from a combination other than the one they sent their Binding request to, see [`ChangeRequest`].
+//! By comparing the mapped addresses they learn from the different combinations, clients can tell how their NAT maps and filters.
+
+use crate::rfc8489::{invalid_data, ValueCodec, ValueDecoder, ValueEncoder};
+use crate::IpStack;
+use std::net::{IpAddr, SocketAddr};
+use stun_codec::rfc8656::attributes::AddressFamily;
+use stun_codec::{Attribute, AttributeType};
+
+/// The addresses and ports a relay answers NAT behaviour discovery requests on.
+#[derive(Debug, Clone, Copy)]
+pub struct NatDiscovery {
+    /// The port we accept STUN & TURN traffic on.
+    pub primary_port: u16,
+    pub alternate_port: u16,
+    /// Our alternate public addresses, if any.
+    ///
+    /// Without an alternate address, clients can only ask us to change the port.
+    pub alternate_address: Option<IpStack>,
+}
+
+impl NatDiscovery {
+    /// The public address of the given [`DiscoveryOrigin`] in the given [`AddressFamily`], if we have one.
+    pub(crate) fn address(
+        &self,
+        public_address: &IpStack,
+        family: AddressFamily,
+        origin: DiscoveryOrigin,
+    ) -> Option<SocketAddr> {
+        let ip = if origin.alternate_ip {
+            self.alternate_address?.for_family(family)?
+        } else {
+            public_address.for_family(family)?
+        };
+        let port = if origin.alternate_port {
+            self.alternate_port
+        } else {
+            self.primary_port
+        };
+
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+/// One of the up to four combinations of our primary and alternate IP address and port.
+///
+/// Identifies the socket a Binding request arrived on or that its response must be sent from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DiscoveryOrigin {
+    pub alternate_ip: bool,
+    pub alternate_port: bool,
+}
+
+impl DiscoveryOrigin {
+    /// Our primary address and port, where we accept all STUN & TURN traffic.
+    pub const PRIMARY: Self = Self {
+        alternate_ip: false,
+        alternate_port: false,
+    };
+
+    /// The origin to respond from if a request arriving at this origin carries the given [`ChangeRequest`].
+    pub(crate) fn change(self, request: ChangeRequest) -> Self {
+        Self {
+            alternate_ip: self.alternate_ip ^ request.change_ip(),
+            alternate_port: self.alternate_port ^ request.change_port(),
+        }
+    }
+
+    /// The origin that differs from this one in both, IP address and port.
+    ///
+    /// This is what we advertise in [`OtherAddress`].
+    pub(crate) fn other(self) -> Self {
+        self.change(ChangeRequest::new(true, true))
+    }
+}
+
+/// The CHANGE-REQUEST attribute.
+///
+/// Asks the server to send its response from a different IP address and / or port than the request was sent to.
+/// See <https://www.rfc-editor.org/rfc/rfc5780#section-7.2>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChangeRequest {
+    change_ip: bool,
+    change_port: bool,
+}
+
+impl ChangeRequest {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x0003;
+
+    const CHANGE_IP: u32 = 0x4;
+    const CHANGE_PORT: u32 = 0x2;
+
+    pub fn new(change_ip: bool, change_port: bool) -> Self {
+        Self {
+            change_ip,
+            change_port,
+        }
+    }
+
+    pub fn change_ip(&self) -> bool {
+        self.change_ip
+    }
+
+    pub fn change_port(&self) -> bool {
+        self.change_port
+    }
+}
+
+impl ValueCodec for ChangeRequest {
+    const CODEPOINT: u16 = ChangeRequest::CODEPOINT;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self> {
+        let flags: [u8; 4] = value
+            .try_into()
+            .map_err(|_| invalid_data("change request must be 4 bytes"))?;
+        let flags = u32::from_be_bytes(flags);
+
+        Ok(Self {
+            change_ip: flags & Self::CHANGE_IP != 0,
+            change_port: flags & Self::CHANGE_PORT != 0,
+        })
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        let mut flags = 0;
+
+        if self.change_ip {
+            flags |= Self::CHANGE_IP;
+        }
+        if self.change_port {
+            flags |= Self::CHANGE_PORT;
+        }
+
+        u32::to_be_bytes(flags).to_vec()
+    }
+}
+
+impl Attribute for ChangeRequest {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// The OTHER-ADDRESS attribute.
+///
+/// The address and port a response would be sent from if the client asked us to change both, see <https://www.rfc-editor.org/rfc/rfc5780#section-7.4>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OtherAddress(SocketAddr);
+
+impl OtherAddress {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x802C;
+
+    pub fn new(addr: SocketAddr) -> Self {
+        Self(addr)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.0
+    }
+}
+
+impl ValueCodec for OtherAddress {
+    const CODEPOINT: u16 = OtherAddress::CODEPOINT;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self> {
+        decode_address(&value).map(Self)
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        encode_address(self.0)
+    }
+}
+
+impl Attribute for OtherAddress {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// The RESPONSE-ORIGIN attribute.
+///
+/// The address and port a response was sent from, see <https://www.rfc-editor.org/rfc/rfc5780#section-7.3>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResponseOrigin(SocketAddr);
+
+impl ResponseOrigin {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x802B;
+
+    pub fn new(addr: SocketAddr) -> Self {
+        Self(addr)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.0
+    }
+}
+
+impl ValueCodec for ResponseOrigin {
+    const CODEPOINT: u16 = ResponseOrigin::CODEPOINT;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self> {
+        decode_address(&value).map(Self)
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        encode_address(self.0)
+    }
+}
+
+impl Attribute for ResponseOrigin {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+const FAMILY_IP4: u8 = 0x01;
+const FAMILY_IP6: u8 = 0x02;
+
+/// Decodes an address in the format of MAPPED-ADDRESS, see <https://www.rfc-editor.org/rfc/rfc8489#section-14.1>.
+fn decode_address(value: &[u8]) -> bytecodec::Result<SocketAddr> {
+    let [_, family, port_hi, port_lo, address @ ..] = value else {
+        return Err(invalid_data("address is too short"));
+    };
+    let port = u16::from_be_bytes([*port_hi, *port_lo]);
+
+    let ip = match (*family, address.len()) {
+        (FAMILY_IP4, 4) => IpAddr::from(<[u8; 4]>::try_from(address).expect("length checked")),
+        (FAMILY_IP6, 16) => IpAddr::from(<[u8; 16]>::try_from(address).expect("length checked")),
+        _ => return Err(invalid_data("unknown address family or bad address length")),
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Encodes an address in the format of MAPPED-ADDRESS, see <https://www.rfc-editor.org/rfc/rfc8489#section-14.1>.
+fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(20);
+
+    bytes.push(0);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes.push(FAMILY_IP4);
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+            bytes.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(FAMILY_IP6);
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+            bytes.extend_from_slice(&ip.octets());
+        }
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn change_request_flags() {
+        assert_eq!(
+            ChangeRequest::new(true, false).encode_value(),
+            hex!("00000004")
+        );
+        assert_eq!(
+            ChangeRequest::new(false, true).encode_value(),
+            hex!("00000002")
+        );
+        assert_eq!(
+            ChangeRequest::decode_value(hex!("00000006").to_vec()).unwrap(),
+            ChangeRequest::new(true, true)
+        );
+    }
+
+    #[test]
+    fn addresses_roundtrip() {
+        for addr in ["35.124.91.37:3479", "[2001:db8::1]:3479"] {
+            let addr = addr.parse().unwrap();
+
+            let value = ResponseOrigin::new(addr).encode_value();
+
+            assert_eq!(ResponseOrigin::decode_value(value).unwrap().address(), addr);
+        }
+    }
+
+    #[test]
+    fn ip4_address_matches_mapped_address_format() {
+        assert_eq!(
+            OtherAddress::new("192.0.2.1:32853".parse().unwrap()).encode_value(),
+            hex!("0001 8055 c0000201")
+        );
+    }
+
+    #[test]
+    fn other_origin_changes_ip_and_port() {
+        let origin = DiscoveryOrigin {
+            alternate_ip: false,
+            alternate_port: true,
+        };
+
+        assert_eq!(
+            origin.other(),
+            DiscoveryOrigin {
+                alternate_ip: true,
+                alternate_port: false,
+            }
+        );
+        assert_eq!(
+            DiscoveryOrigin::PRIMARY.change(ChangeRequest::new(false, true)),
+            origin
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! STUN methods, attributes and error codes of [RFC 6062](https://www.rfc-editor.org/rfc/rfc6062) (TURN extensions for TCP allocations).
+//!
+//! `stun-codec` does not implement these so we define them ourselves.
+
+use bytecodec::fixnum::{U32beDecoder, U32beEncoder};
+use bytecodec::{ByteCount, Decode, Encode, Eos, SizedEncode, TryTaggedDecode};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::{Attribute, AttributeType, Method};
+
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.1>.
+pub const TCP_TRANSPORT: u8 = 6;
+
+// TODO: Upstream RFC 6062 support to `stun-codec`.
+pub static CONNECT: Lazy<Method> = Lazy::new(|| Method::new(0x000A).unwrap());
+pub static CONNECTION_BIND: Lazy<Method> = Lazy::new(|| Method::new(0x000B).unwrap());
+pub static CONNECTION_ATTEMPT: Lazy<Method> = Lazy::new(|| Method::new(0x000C).unwrap());
+
+/// The CONNECTION-ID attribute.
+///
+/// Uniquely identifies a peer data connection of a TCP allocation.
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.2.1>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ConnectionId(u32);
+
+impl ConnectionId {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x002A;
+
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CID-{}", self.0)
+    }
+}
+
+impl Attribute for ConnectionId {
+    type Decoder = ConnectionIdDecoder;
+    type Encoder = ConnectionIdEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionIdDecoder(U32beDecoder);
+
+impl Decode for ConnectionIdDecoder {
+    type Item = ConnectionId;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.0.finish_decoding().map(ConnectionId)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for ConnectionIdDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attribute_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attribute_type.as_u16() == ConnectionId::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionIdEncoder(U32beEncoder);
+
+impl Encode for ConnectionIdEncoder {
+    type Item = ConnectionId;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.0.start_encoding(item.0)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for ConnectionIdEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
+
+/// The connection a CONNECTION-BIND request in the given STUN message wants to bind to.
+///
+/// Only looks at the header and attributes, thus this is cheap enough to do on the first message of every stream connection.
+/// Returns `None` for all other messages.
+pub fn connection_bind_target(message: &[u8]) -> Option<ConnectionId> {
+    const HEADER_LEN: usize = 20;
+    const MAGIC_COOKIE: [u8; 4] = 0x2112A442_u32.to_be_bytes();
+    // A request carries the method without any class bits.
+    const CONNECTION_BIND_REQUEST: [u8; 2] = 0x000B_u16.to_be_bytes();
+
+    if message.len() < HEADER_LEN
+        || message[0..2] != CONNECTION_BIND_REQUEST
+        || message[4..8] != MAGIC_COOKIE
+    {
+        return None;
+    }
+
+    let mut attributes = &message[HEADER_LEN..];
+
+    while attributes.len() >= 4 {
+        let attribute_type = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let length = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + length)?;
+
+        if attribute_type == ConnectionId::CODEPOINT {
+            return Some(ConnectionId(u32::from_be_bytes(value.try_into().ok()?)));
+        }
+
+        let padded_length = (length + 3) & !3;
+        attributes = attributes.get(4 + padded_length..)?;
+    }
+
+    None
+}
+
+/// `446`: "Connection Already Exists".
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.3>.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionAlreadyExists;
+
+impl ConnectionAlreadyExists {
+    pub const CODEPOINT: u16 = 446;
+}
+
+impl From<ConnectionAlreadyExists> for ErrorCode {
+    fn from(_: ConnectionAlreadyExists) -> Self {
+        ErrorCode::new(
+            ConnectionAlreadyExists::CODEPOINT,
+            "Connection Already Exists".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
+
+/// `447`: "Connection Timeout or Failure".
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.3>.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionTimeoutOrFailure;
+
+impl ConnectionTimeoutOrFailure {
+    pub const CODEPOINT: u16 = 447;
+}
+
+impl From<ConnectionTimeoutOrFailure> for ErrorCode {
+    fn from(_: ConnectionTimeoutOrFailure) -> Self {
+        ErrorCode::new(
+            ConnectionTimeoutOrFailure::CODEPOINT,
+            "Connection Timeout or Failure".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecodec::{DecodeExt, EncodeExt};
+
+    #[test]
+    fn connection_id_roundtrip() {
+        let mut encoder = ConnectionIdEncoder::default();
+        let bytes = encoder
+            .encode_into_bytes(ConnectionId::new(0xDEADBEEF))
+            .unwrap();
+
+        assert_eq!(bytes, vec![0xDE, 0xAD, 0xBE, 0xEF]);
+
+        let mut decoder = ConnectionIdDecoder::default();
+        let decoded = decoder.decode_from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded, ConnectionId::new(0xDEADBEEF));
+    }
+
+    #[test]
+    fn finds_connection_of_connection_bind_request() {
+        let mut message = vec![0x00, 0x0B, 0x00, 0x10, 0x21, 0x12, 0xA4, 0x42];
+        message.extend_from_slice(&[0; 12]); // Transaction ID
+        message.extend_from_slice(&[0x00, 0x06, 0x00, 0x03, b'f', b'o', b'o', 0x00]); // USERNAME with padding
+        message.extend_from_slice(&[0x00, 0x2A, 0x00, 0x04, 0xDE, 0xAD, 0xBE, 0xEF]);
+
+        assert_eq!(
+            connection_bind_target(&message),
+            Some(ConnectionId::new(0xDEADBEEF))
+        );
+
+        message[1] = 0x0A; // CONNECT
+
+        assert_eq!(connection_bind_target(&message), None);
+    }
+}
@@ -0,0 +1,125 @@
+//! STUN attributes and error codes of [RFC 8016](https://www.rfc-editor.org/rfc/rfc8016) (mobility with TURN).
+//!
+//! `stun-codec` does not implement these so we define them ourselves.
+
+use crate::rfc8489::{ValueCodec, ValueDecoder, ValueEncoder};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::{Attribute, AttributeType};
+
+/// The length of the tickets we issue.
+const TICKET_LEN: usize = 16;
+
+/// The MOBILITY-TICKET attribute.
+///
+/// Clients send an empty ticket in their allocate request to ask for mobility.
+/// We answer with an opaque ticket that allows them to refresh the allocation from a new 5-tuple, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.1>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MobilityTicket(Vec<u8>);
+
+impl MobilityTicket {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x8030;
+
+    pub fn new(ticket: Vec<u8>) -> Self {
+        Self(ticket)
+    }
+
+    /// The empty ticket clients use to ask for mobility.
+    pub fn empty() -> Self {
+        Self(Vec::new())
+    }
+
+    pub(crate) fn random(rng: &mut impl Rng) -> Self {
+        Self(rng.gen::<[u8; TICKET_LEN]>().to_vec())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl ValueCodec for MobilityTicket {
+    const CODEPOINT: u16 = MobilityTicket::CODEPOINT;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self> {
+        Ok(Self(value))
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+impl Attribute for MobilityTicket {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// `405`: "Mobility Forbidden".
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8016#section-3.4>.
+#[derive(Debug, Clone, Copy)]
+pub struct MobilityForbidden;
+
+impl MobilityForbidden {
+    pub const CODEPOINT: u16 = 405;
+}
+
+impl From<MobilityForbidden> for ErrorCode {
+    fn from(_: MobilityForbidden) -> Self {
+        ErrorCode::new(
+            MobilityForbidden::CODEPOINT,
+            "Mobility Forbidden".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Attribute;
+    use bytecodec::{DecodeExt, EncodeExt};
+    use stun_codec::rfc5766::methods::ALLOCATE;
+    use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+
+    #[test]
+    fn empty_ticket_roundtrip() {
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, ALLOCATE, TransactionId::new([0; 12]));
+        message.add_attribute(MobilityTicket::empty());
+
+        let bytes = MessageEncoder::default()
+            .encode_into_bytes(message)
+            .unwrap();
+        let message = MessageDecoder::<Attribute>::default()
+            .decode_from_bytes(&bytes)
+            .unwrap()
+            .unwrap();
+
+        assert!(message
+            .get_attribute::<MobilityTicket>()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn random_tickets_differ() {
+        let mut rng = rand::thread_rng();
+
+        assert_ne!(
+            MobilityTicket::random(&mut rng),
+            MobilityTicket::random(&mut rng)
+        );
+    }
+}
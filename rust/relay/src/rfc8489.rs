@@ -0,0 +1,662 @@
+//! STUN attributes of [RFC 8489](https://www.rfc-editor.org/rfc/rfc8489) that upgrade the long-term credential mechanism from MD5 and SHA-1 to SHA-256.
+//!
+//! `stun-codec` does not implement these so we define them ourselves.
+//!
+//! Unlike the RFC mandates, usernames, realms and passwords are not processed with the "OpaqueString" profile of PRECIS.
+//! This is consistent with how `stun-codec` computes MESSAGE-INTEGRITY and irrelevant for the ASCII credentials we hand out.
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::{ByteCount, Decode, Encode, EncodeExt, Eos, SizedEncode, TryTaggedDecode};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::marker::PhantomData;
+use stun_codec::rfc5389::attributes::{ErrorCode, Realm, Username};
+use stun_codec::rfc5389::errors::Unauthorized;
+use stun_codec::{Attribute, AttributeType, Message, MessageEncoder};
+
+/// The prefix of nonces issued by servers that support the security features of RFC 8489.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-9.2>.
+const NONCE_COOKIE: &str = "obMatJos2";
+
+/// The length of the base64-encoded [`SecurityFeatures`] following the [`NONCE_COOKIE`].
+const ENCODED_SECURITY_FEATURES_LEN: usize = 4;
+
+const MESSAGE_HEADER_LEN: usize = 20;
+const ATTRIBUTE_HEADER_LEN: usize = 4;
+
+/// The password algorithms we offer to clients, in order of preference.
+pub const PASSWORD_ALGORITHMS: [PasswordAlgorithm; 2] =
+    [PasswordAlgorithm::Sha256, PasswordAlgorithm::Md5];
+
+/// The security features a server advertises in the nonces it issues.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-18.1>.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecurityFeatures {
+    /// Clients may pick one of our [`PASSWORD_ALGORITHMS`].
+    pub password_algorithms: bool,
+    /// Clients may identify themselves with a [`Userhash`] instead of their username.
+    pub username_anonymity: bool,
+}
+
+impl SecurityFeatures {
+    // Bit 0 is the most significant bit of the 24-bit field.
+    const PASSWORD_ALGORITHMS: u32 = 1 << 23;
+    const USERNAME_ANONYMITY: u32 = 1 << 22;
+
+    /// Prefixes a nonce with the nonce cookie and these features.
+    pub fn prefix_nonce(&self, nonce: &str) -> String {
+        let mut bits = 0;
+
+        if self.password_algorithms {
+            bits |= Self::PASSWORD_ALGORITHMS;
+        }
+        if self.username_anonymity {
+            bits |= Self::USERNAME_ANONYMITY;
+        }
+
+        let features = BASE64_STANDARD.encode(&bits.to_be_bytes()[1..]);
+
+        format!("{NONCE_COOKIE}{features}{nonce}")
+    }
+
+    /// Splits a nonce into the features it advertises and the remaining nonce.
+    ///
+    /// Nonces without the nonce cookie advertise no features.
+    pub fn split_nonce(nonce: &str) -> (Self, &str) {
+        let Some(features_and_nonce) = nonce.strip_prefix(NONCE_COOKIE) else {
+            return (Self::default(), nonce);
+        };
+        let (Some(features), Some(nonce)) = (
+            features_and_nonce.get(..ENCODED_SECURITY_FEATURES_LEN),
+            features_and_nonce.get(ENCODED_SECURITY_FEATURES_LEN..),
+        ) else {
+            return (Self::default(), nonce);
+        };
+        let Ok([a, b, c]) = BASE64_STANDARD
+            .decode(features)
+            .map_err(|_| ())
+            .and_then(|bytes| <[u8; 3]>::try_from(bytes).map_err(|_| ()))
+        else {
+            return (Self::default(), nonce);
+        };
+
+        let bits = u32::from_be_bytes([0, a, b, c]);
+
+        let features = Self {
+            password_algorithms: bits & Self::PASSWORD_ALGORITHMS != 0,
+            username_anonymity: bits & Self::USERNAME_ANONYMITY != 0,
+        };
+
+        (features, nonce)
+    }
+}
+
+/// The algorithm to derive the key of the long-term credential mechanism with.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-18.5>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PasswordAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl PasswordAlgorithm {
+    /// The codepoint of the type of the PASSWORD-ALGORITHM attribute.
+    pub const CODEPOINT: u16 = 0x001D;
+
+    fn number(&self) -> u16 {
+        match self {
+            PasswordAlgorithm::Md5 => 0x0001,
+            PasswordAlgorithm::Sha256 => 0x0002,
+        }
+    }
+
+    fn from_number(number: u16) -> Option<Self> {
+        match number {
+            0x0001 => Some(PasswordAlgorithm::Md5),
+            0x0002 => Some(PasswordAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Derives the key of the long-term credential mechanism.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.2>.
+    pub fn key(&self, username: &str, realm: &str, password: &str) -> Vec<u8> {
+        let input = format!("{username}:{realm}:{password}");
+
+        match self {
+            PasswordAlgorithm::Md5 => md5::Md5::digest(input).to_vec(),
+            PasswordAlgorithm::Sha256 => Sha256::digest(input).to_vec(),
+        }
+    }
+
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.number().to_be_bytes());
+        bytes.extend_from_slice(&0u16.to_be_bytes()); // Neither MD5 nor SHA-256 have parameters.
+    }
+
+    /// Decodes a password algorithm and its parameters, returning the number of bytes consumed.
+    fn decode_from(bytes: &[u8]) -> bytecodec::Result<(Self, usize)> {
+        let &[a, b, c, d, ..] = bytes else {
+            return Err(invalid_data("password algorithm is too short"));
+        };
+
+        let algorithm = Self::from_number(u16::from_be_bytes([a, b]))
+            .ok_or_else(|| invalid_data("unknown password algorithm"))?;
+        let parameters_len = u16::from_be_bytes([c, d]) as usize;
+        let padded_parameters_len = (parameters_len + 3) / 4 * 4;
+        let consumed = 4 + padded_parameters_len;
+
+        if bytes.len() < consumed {
+            return Err(invalid_data("password algorithm parameters are too short"));
+        }
+
+        Ok((algorithm, consumed))
+    }
+}
+
+impl ValueCodec for PasswordAlgorithm {
+    const CODEPOINT: u16 = PasswordAlgorithm::CODEPOINT;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self> {
+        let (algorithm, _) = Self::decode_from(&value)?;
+
+        Ok(algorithm)
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4);
+        self.encode_into(&mut bytes);
+
+        bytes
+    }
+}
+
+impl Attribute for PasswordAlgorithm {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// The PASSWORD-ALGORITHMS attribute.
+///
+/// Lists the [`PasswordAlgorithm`]s a server supports.
+/// Clients echo it back to protect against downgrade attacks, see <https://www.rfc-editor.org/rfc/rfc8489#section-14.11>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PasswordAlgorithms(Vec<PasswordAlgorithm>);
+
+impl PasswordAlgorithms {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x8002;
+
+    pub fn new(algorithms: Vec<PasswordAlgorithm>) -> Self {
+        Self(algorithms)
+    }
+
+    pub fn algorithms(&self) -> &[PasswordAlgorithm] {
+        &self.0
+    }
+}
+
+impl ValueCodec for PasswordAlgorithms {
+    const CODEPOINT: u16 = PasswordAlgorithms::CODEPOINT;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self> {
+        let mut algorithms = Vec::new();
+        let mut remaining = value.as_slice();
+
+        while !remaining.is_empty() {
+            let (algorithm, consumed) = PasswordAlgorithm::decode_from(remaining)?;
+
+            algorithms.push(algorithm);
+            remaining = &remaining[consumed..];
+        }
+
+        Ok(Self(algorithms))
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.0.len() * 4);
+
+        for algorithm in &self.0 {
+            algorithm.encode_into(&mut bytes);
+        }
+
+        bytes
+    }
+}
+
+impl Attribute for PasswordAlgorithms {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// The USERHASH attribute.
+///
+/// Identifies a user by a hash of their username and the realm instead of the plain username, see <https://www.rfc-editor.org/rfc/rfc8489#section-14.4>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Userhash([u8; 32]);
+
+impl Userhash {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x001E;
+
+    pub fn new(username: &str, realm: &Realm) -> Self {
+        Self(Sha256::digest(format!("{username}:{}", realm.text())).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl ValueCodec for Userhash {
+    const CODEPOINT: u16 = Userhash::CODEPOINT;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self> {
+        let hash = value
+            .try_into()
+            .map_err(|_| invalid_data("userhash must be 32 bytes"))?;
+
+        Ok(Self(hash))
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+impl Attribute for Userhash {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+/// The MESSAGE-INTEGRITY-SHA256 attribute.
+///
+/// The HMAC-SHA256 of the message up to this attribute, keyed with the long-term credential, see <https://www.rfc-editor.org/rfc/rfc8489#section-14.6>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageIntegritySha256 {
+    hmac: Vec<u8>,
+    preceding_message_bytes: Vec<u8>,
+}
+
+impl MessageIntegritySha256 {
+    /// The codepoint of the type of the attribute.
+    pub const CODEPOINT: u16 = 0x001C;
+
+    const MIN_LEN: usize = 16;
+    const MAX_LEN: usize = 32;
+
+    /// Computes the MESSAGE-INTEGRITY-SHA256 to append to `message`.
+    pub fn new_long_term_credential<A: Attribute>(
+        message: &Message<A>,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> bytecodec::Result<Self> {
+        let preceding_message_bytes = preceding_message_bytes(message, Self::MAX_LEN)?;
+        let key = algorithm.key(username.name(), realm.text(), password);
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+        mac.update(&preceding_message_bytes);
+
+        Ok(Self {
+            hmac: mac.finalize().into_bytes().to_vec(),
+            preceding_message_bytes,
+        })
+    }
+
+    /// Checks the HMAC against the long-term credential of the given user.
+    ///
+    /// Clients may truncate the HMAC, in which case we only compare its leftmost bytes.
+    pub fn check_long_term_credential(
+        &self,
+        username: &Username,
+        realm: &Realm,
+        password: &str,
+        algorithm: PasswordAlgorithm,
+    ) -> Result<(), ErrorCode> {
+        let key = algorithm.key(username.name(), realm.text(), password);
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+        mac.update(&self.preceding_message_bytes);
+
+        mac.verify_truncated_left(&self.hmac)
+            .map_err(|_| ErrorCode::from(Unauthorized))
+    }
+
+    pub fn hmac_sha256(&self) -> &[u8] {
+        &self.hmac
+    }
+}
+
+impl ValueCodec for MessageIntegritySha256 {
+    const CODEPOINT: u16 = MessageIntegritySha256::CODEPOINT;
+
+    fn decode_value(hmac: Vec<u8>) -> bytecodec::Result<Self> {
+        if !(Self::MIN_LEN..=Self::MAX_LEN).contains(&hmac.len()) || hmac.len() % 4 != 0 {
+            return Err(invalid_data("invalid length of HMAC-SHA256"));
+        }
+
+        Ok(Self {
+            hmac,
+            preceding_message_bytes: Vec::new(), // Filled in by `after_decode`.
+        })
+    }
+
+    fn encode_value(&self) -> Vec<u8> {
+        self.hmac.clone()
+    }
+}
+
+impl Attribute for MessageIntegritySha256 {
+    type Decoder = ValueDecoder<Self>;
+    type Encoder = ValueEncoder<Self>;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+
+    fn after_decode<A: Attribute>(&mut self, message: &Message<A>) -> bytecodec::Result<()> {
+        self.preceding_message_bytes = preceding_message_bytes(message, self.hmac.len())?;
+
+        Ok(())
+    }
+}
+
+/// Encodes the attributes of `message` that precede a MESSAGE-INTEGRITY-SHA256 attribute.
+///
+/// As mandated by the RFC, the length in the header includes the MESSAGE-INTEGRITY-SHA256 attribute itself.
+fn preceding_message_bytes<A: Attribute>(
+    message: &Message<A>,
+    hmac_len: usize,
+) -> bytecodec::Result<Vec<u8>> {
+    let mut bytes = MessageEncoder::default().encode_into_bytes(message.clone())?;
+
+    let adjusted_len = bytes.len() - MESSAGE_HEADER_LEN + ATTRIBUTE_HEADER_LEN + hmac_len;
+    bytes[2..4].copy_from_slice(&(adjusted_len as u16).to_be_bytes());
+
+    Ok(bytes)
+}
+
+pub(crate) fn invalid_data(message: &'static str) -> bytecodec::Error {
+    bytecodec::Error::from(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// An attribute whose value is decoded from and encoded to a contiguous buffer.
+pub trait ValueCodec: Sized {
+    const CODEPOINT: u16;
+
+    fn decode_value(value: Vec<u8>) -> bytecodec::Result<Self>;
+    fn encode_value(&self) -> Vec<u8>;
+}
+
+#[derive(Debug)]
+pub struct ValueDecoder<T> {
+    inner: RemainingBytesDecoder,
+    _attribute: PhantomData<T>,
+}
+
+impl<T> Default for ValueDecoder<T> {
+    fn default() -> Self {
+        Self {
+            inner: RemainingBytesDecoder::default(),
+            _attribute: PhantomData,
+        }
+    }
+}
+
+impl<T: ValueCodec> Decode for ValueDecoder<T> {
+    type Item = T;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.inner.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        T::decode_value(self.inner.finish_decoding()?)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+impl<T: ValueCodec> TryTaggedDecode for ValueDecoder<T> {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attribute_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attribute_type.as_u16() == T::CODEPOINT)
+    }
+}
+
+#[derive(Debug)]
+pub struct ValueEncoder<T> {
+    inner: BytesEncoder<Vec<u8>>,
+    _attribute: PhantomData<T>,
+}
+
+impl<T> Default for ValueEncoder<T> {
+    fn default() -> Self {
+        Self {
+            inner: BytesEncoder::default(),
+            _attribute: PhantomData,
+        }
+    }
+}
+
+impl<T: ValueCodec> Encode for ValueEncoder<T> {
+    type Item = T;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.inner.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.inner.start_encoding(item.encode_value())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+impl<T: ValueCodec> SizedEncode for ValueEncoder<T> {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.inner.exact_requiring_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Attribute;
+    use bytecodec::DecodeExt;
+    use hex_literal::hex;
+    use sha1::Sha1;
+    use stun_codec::rfc5389::attributes::Nonce;
+    use stun_codec::rfc5389::methods::BINDING;
+    use stun_codec::{MessageClass, MessageDecoder, TransactionId};
+
+    const USERNAME: &str = "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}";
+    const PASSWORD: &str = "TheMatrIX";
+    const REALM: &str = "example.org";
+
+    /// The sample request of <https://www.rfc-editor.org/rfc/rfc8489#appendix-B.1>.
+    const RFC8489_SAMPLE_REQUEST: [u8; 164] = hex!(
+        "00010090 2112a442 78ad3433 c6ad72c0 29da412e"
+        "001e0020 4a3cf38f ef6992bd a952c678 0417da0f 24819415 569e60b2 05c46e41 407f1704"
+        "00150029 6f624d61 744a6f73 32414141 43662f2f 3439396b 39353464 364f4c33 346f4c39 46535476 79363473 41000000"
+        "0014000b 6578616d 706c652e 6f726700"
+        "001d0004 00020000"
+        "001c0020 b5c7bf00 5b6c52a2 1c51c5e8 92f81924 136296cb 927c4314 9309278c c6518e65"
+    );
+
+    #[test]
+    fn userhash_matches_rfc8489_test_vector() {
+        let userhash = Userhash::new(USERNAME, &realm());
+
+        assert_eq!(
+            userhash.as_bytes(),
+            &hex!("4a3cf38fef6992bda952c6780417da0f24819415569e60b205c46e41407f1704")
+        );
+    }
+
+    #[test]
+    fn md5_key_matches_rfc5769_test_vector() {
+        // The sample request with long-term authentication of <https://www.rfc-editor.org/rfc/rfc5769#section-2.4>, up to MESSAGE-INTEGRITY.
+        let preceding_message_bytes = hex!(
+            "00010060 2112a442 78ad3433 c6ad72c0 29da412e"
+            "00060012 e3839ee3 8388e383 aae38383 e382afe3 82b90000"
+            "0015001c 662f2f34 39396b39 35346436 4f4c3334 6f4c3946 53547679 36347341"
+            "0014000b 6578616d 706c652e 6f726700"
+        );
+
+        let key = PasswordAlgorithm::Md5.key(USERNAME, REALM, PASSWORD);
+        let mut mac = Hmac::<Sha1>::new_from_slice(&key).unwrap();
+        mac.update(&preceding_message_bytes);
+
+        assert_eq!(
+            mac.finalize().into_bytes().as_slice(),
+            hex!("f67024656dd64a3e02b8e0712e85c9a28ca89666")
+        );
+    }
+
+    #[test]
+    fn encodes_rfc8489_sample_request() {
+        let message = rfc8489_sample_request();
+
+        let bytes = MessageEncoder::default()
+            .encode_into_bytes(message)
+            .unwrap();
+
+        assert_eq!(bytes, RFC8489_SAMPLE_REQUEST);
+    }
+
+    #[test]
+    fn decoded_message_integrity_sha256_checks_long_term_credential() {
+        let message = MessageDecoder::<Attribute>::default()
+            .decode_from_bytes(&RFC8489_SAMPLE_REQUEST)
+            .unwrap()
+            .unwrap();
+        let message_integrity = message.get_attribute::<MessageIntegritySha256>().unwrap();
+
+        let username = Username::new(USERNAME.to_owned()).unwrap();
+
+        assert!(message_integrity
+            .check_long_term_credential(&username, &realm(), PASSWORD, PasswordAlgorithm::Sha256)
+            .is_ok());
+        assert!(message_integrity
+            .check_long_term_credential(&username, &realm(), "guess", PasswordAlgorithm::Sha256)
+            .is_err());
+        assert!(message_integrity
+            .check_long_term_credential(&username, &realm(), PASSWORD, PasswordAlgorithm::Md5)
+            .is_err());
+        assert_eq!(
+            message.get_attribute::<PasswordAlgorithm>(),
+            Some(&PasswordAlgorithm::Sha256)
+        );
+    }
+
+    #[test]
+    fn split_nonce_strips_cookie_and_security_features() {
+        let (_, nonce) = SecurityFeatures::split_nonce("obMatJos2AAACf//499k954d6OL34oL9FSTvy64sA");
+
+        assert_eq!(nonce, "f//499k954d6OL34oL9FSTvy64sA");
+    }
+
+    #[test]
+    fn security_features_roundtrip_through_nonce() {
+        let features = SecurityFeatures {
+            password_algorithms: true,
+            username_anonymity: false,
+        };
+
+        let nonce = features.prefix_nonce("f//499k954d6OL34oL9FSTvy64sA");
+
+        assert_eq!(nonce, "obMatJos2gAAAf//499k954d6OL34oL9FSTvy64sA");
+        assert_eq!(
+            SecurityFeatures::split_nonce(&nonce),
+            (features, "f//499k954d6OL34oL9FSTvy64sA")
+        );
+    }
+
+    #[test]
+    fn nonce_without_cookie_has_no_security_features() {
+        let nonce = "4c98bf59-c99b-3e46-7ecd-7cf9d6b3e527";
+
+        assert_eq!(
+            SecurityFeatures::split_nonce(nonce),
+            (SecurityFeatures::default(), nonce)
+        );
+    }
+
+    #[test]
+    fn password_algorithms_roundtrip() {
+        let algorithms = PasswordAlgorithms::new(PASSWORD_ALGORITHMS.to_vec());
+
+        let bytes = algorithms.encode_value();
+
+        assert_eq!(bytes, hex!("00020000 00010000"));
+        assert_eq!(PasswordAlgorithms::decode_value(bytes).unwrap(), algorithms);
+    }
+
+    fn rfc8489_sample_request() -> Message<Attribute> {
+        let mut message = Message::<Attribute>::new(
+            MessageClass::Request,
+            BINDING,
+            TransactionId::new(hex!("78ad3433c6ad72c029da412e")),
+        );
+        message.add_attribute(Userhash::new(USERNAME, &realm()));
+        message.add_attribute(
+            Nonce::new("obMatJos2AAACf//499k954d6OL34oL9FSTvy64sA".to_owned()).unwrap(),
+        );
+        message.add_attribute(realm());
+        message.add_attribute(PasswordAlgorithm::Sha256);
+
+        let message_integrity = MessageIntegritySha256::new_long_term_credential(
+            &message,
+            &Username::new(USERNAME.to_owned()).unwrap(),
+            &realm(),
+            PASSWORD,
+            PasswordAlgorithm::Sha256,
+        )
+        .unwrap();
+        message.add_attribute(message_integrity);
+
+        message
+    }
+
+    fn realm() -> Realm {
+        Realm::new(REALM.to_owned()).unwrap()
+    }
+}
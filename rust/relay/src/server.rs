@@ -1,14 +1,33 @@
 mod channel_data;
 mod client_message;
+mod snapshot;
 
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
-    Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
+    Allocate, Binding, ChannelBind, ClientMessage, Connect, ConnectionBind, CreatePermission,
+    CredentialExtensions, Refresh, SendIndication,
 };
-
-use crate::auth::{MessageIntegrityExt, Nonces, FIREZONE};
-use crate::net_ext::IpAddrExt;
-use crate::{IpStack, TimeEvents};
+pub use crate::server::snapshot::Snapshot;
+
+use crate::admin::{AllocationInfo, ChannelInfo, PermissionInfo};
+use crate::auth::{self, Authenticator, Firezone, Integrity, Nonces};
+use crate::bandwidth::{BandwidthLimits, TokenBucket};
+use crate::client_socket::ClientSocket;
+use crate::load_shedding::LoadShedding;
+use crate::net_ext::{IpAddrExt, SocketAddrExt};
+use crate::rfc5780::{ChangeRequest, DiscoveryOrigin, NatDiscovery, OtherAddress, ResponseOrigin};
+use crate::rfc6062::{
+    ConnectionAlreadyExists, ConnectionId, ConnectionTimeoutOrFailure, CONNECT, CONNECTION_ATTEMPT,
+    CONNECTION_BIND, TCP_TRANSPORT,
+};
+use crate::rfc8016::{MobilityForbidden, MobilityTicket};
+use crate::rfc8489::{
+    MessageIntegritySha256, PasswordAlgorithm, PasswordAlgorithms, SecurityFeatures, Userhash,
+    PASSWORD_ALGORITHMS,
+};
+use crate::shard::Shard;
+use crate::usage::{ChannelUsage, Usage, UsageRecord};
+use crate::{IpStack, PeerFilter, TimeEvents};
 use anyhow::Result;
 use bytecodec::EncodeExt;
 use core::fmt;
@@ -16,45 +35,60 @@ use opentelemetry::metrics::{Counter, Unit, UpDownCounter};
 use opentelemetry::KeyValue;
 use rand::Rng;
 use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
+use std::iter;
+use std::mem;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5389::attributes::{
-    ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+    AlternateServer, ErrorCode, Fingerprint, MessageIntegrity, Nonce, Realm, Software,
+    UnknownAttributes, Username, XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{
+    BadRequest, StaleNonce, TryAlternate, Unauthorized, UnknownAttribute,
 };
-use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, Unauthorized};
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
+    ChannelNumber, Data, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
 };
-use stun_codec::rfc5766::errors::{AllocationMismatch, InsufficientCapacity};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::errors::{
+    AllocationMismatch, Forbidden, InsufficientCapacity, WrongCredentials,
+};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
 use stun_codec::rfc8656::errors::{AddressFamilyNotSupported, PeerAddressFamilyMismatch};
-use stun_codec::{Message, MessageClass, MessageEncoder, Method, TransactionId};
+use stun_codec::{AttributeType, Message, MessageClass, MessageEncoder, Method, TransactionId};
 use tracing::{field, log, Span};
 use uuid::Uuid;
 
 /// A sans-IO STUN & TURN server.
 ///
-/// A [`Server`] is bound to an IPv4 address and assumes to only operate on UDP.
-/// Thus, 3 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
-/// we can index data simply by the sender's [`SocketAddr`].
+/// A [`Server`] is bound to an IPv4 address and serves clients via UDP, TCP and TLS.
+/// Thus, 2 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
+/// we can index data by the sender's [`ClientSocket`], i.e. its address and the transport it uses.
+///
+/// It is the caller's responsibility to split streams into individual messages (see [`StreamFramer`](crate::StreamFramer))
+/// and route [`Command::SendMessage`] back to the UDP socket or stream of the recipient.
 ///
-/// Additionally, we assume to have complete ownership over the port range `lowest_port` - `highest_port`.
+/// Additionally, we assume to have complete ownership over the port range `lowest_port` - `highest_port`, both inclusive.
 pub struct Server<R> {
     decoder: client_message::Decoder,
     encoder: MessageEncoder<Attribute>,
 
     public_address: IpStack,
 
-    /// All client allocations, indexed by client's socket address.
-    allocations: HashMap<SocketAddr, Allocation>,
-    clients_by_allocation: HashMap<AllocationId, SocketAddr>,
+    /// All client allocations, indexed by client's socket.
+    allocations: HashMap<ClientSocket, Allocation>,
+    clients_by_allocation: HashMap<AllocationId, ClientSocket>,
     allocations_by_port: HashMap<u16, AllocationId>,
+    /// The current [`MobilityTicket`] of each allocation that has one.
+    allocations_by_mobility_ticket: HashMap<MobilityTicket, AllocationId>,
 
     lowest_port: u16,
     highest_port: u16,
@@ -62,12 +96,39 @@ pub struct Server<R> {
     channels_by_number: HashMap<u16, Channel>,
     channel_numbers_by_peer: HashMap<SocketAddr, u16>,
 
+    /// The peer data connections of all TCP allocations.
+    tcp_connections: HashMap<ConnectionId, TcpConnection>,
+
+    bandwidth_limits: BandwidthLimits,
+    peer_filter: PeerFilter,
+    load_shedding: LoadShedding,
+    /// The SOFTWARE attribute we add to our responses, if any.
+    software: Option<Software>,
+    /// Whether channel bindings are mirrored to a fast path outside of the [`Server`], see [`Server::enable_fast_path`].
+    fast_path: bool,
+    /// How often we report the usage of each allocation, if at all.
+    usage_report_interval: Option<Duration>,
+    /// Where we answer NAT behaviour discovery requests, if at all.
+    nat_discovery: Option<NatDiscovery>,
+    /// Whether clients may move their allocations to a new 5-tuple, see [`Server::enable_mobility`].
+    mobility: bool,
+    /// The bandwidth budget of each user, as identified by the [`Authenticator`].
+    user_buckets: HashMap<String, TokenBucket>,
+    /// Users whose credentials we no longer accept and until when, see [`Server::revoke_user`].
+    revoked_users: HashMap<String, SystemTime>,
+    /// Whether we refuse new allocations, see [`Server::start_draining`].
+    draining: bool,
+
     pending_commands: VecDeque<Command>,
+    shard: Shard,
     next_allocation_id: AllocationId,
 
     rng: R,
 
     auth_secret: SecretString,
+    /// The secret before the last rotation and until when credentials derived from it are accepted.
+    previous_auth_secret: Option<(SecretString, SystemTime)>,
+    authenticator: Box<dyn Authenticator>,
 
     nonces: Nonces,
 
@@ -75,7 +136,12 @@ pub struct Server<R> {
 
     allocations_up_down_counter: UpDownCounter<i64>,
     data_relayed_counter: Counter<u64>,
+    data_dropped_counter: Counter<u64>,
     responses_counter: Counter<u64>,
+    requests_counter: Counter<u64>,
+    auth_failures_counter: Counter<u64>,
+    /// The number of ports in use, shared with the callback of the `port_pool_utilization` gauge.
+    allocated_ports: Arc<AtomicUsize>,
 }
 
 /// The commands returned from a [`Server`].
@@ -84,8 +150,16 @@ pub struct Server<R> {
 #[derive(Debug, PartialEq)]
 pub enum Command {
     SendMessage {
+        payload: Vec<u8>,
+        recipient: ClientSocket,
+    },
+    /// Send a message from one of our alternate addresses or ports instead of the primary one.
+    ///
+    /// Only emitted in response to NAT behaviour discovery requests, see [`Server::enable_nat_discovery`], thus always via UDP.
+    SendMessageFrom {
         payload: Vec<u8>,
         recipient: SocketAddr,
+        origin: DiscoveryOrigin,
     },
     /// Listen for traffic on the provided port [AddressFamily].
     ///
@@ -97,29 +171,89 @@ pub enum Command {
         family: AddressFamily,
         port: u16,
     },
+    /// Listen for TCP connections from peers on the provided port [AddressFamily].
+    ///
+    /// This is the TCP equivalent of [`Command::CreateAllocation`], see <https://www.rfc-editor.org/rfc/rfc6062>.
+    /// Incoming connections should be handed to the [`Server`] via [`Server::handle_peer_connection_accepted`].
+    /// TCP allocations are freed with [`Command::FreeAllocation`] as well.
+    CreateTcpAllocation {
+        id: AllocationId,
+        family: AddressFamily,
+        port: u16,
+    },
     /// Free the allocation associated with the given [`AllocationId`] and [AddressFamily]
     FreeAllocation {
         id: AllocationId,
         family: AddressFamily,
     },
+    /// Open a TCP connection to the peer from the relay address of the given TCP allocation.
+    ///
+    /// The outcome must be reported back via [`Server::handle_peer_connection_established`] or [`Server::handle_peer_connection_failed`].
+    ConnectToPeer {
+        connection: ConnectionId,
+        allocation: AllocationId,
+        peer: SocketAddr,
+    },
+    /// Splice the peer connection with the given data connection of the client.
+    ///
+    /// From now on, all bytes received on one of these connections must be forwarded verbatim to the other one.
+    BindConnection {
+        connection: ConnectionId,
+        client: ClientSocket,
+    },
+    /// Close the peer connection and, if already bound, the data connection of the client.
+    CloseConnection { connection: ConnectionId },
+    /// Stop splicing the data connection of the client until the given point in time.
+    ///
+    /// Emitted when spliced data exceeded a bandwidth limit, see [`Server::set_bandwidth_limits`].
+    PauseConnection {
+        client: ClientSocket,
+        until: SystemTime,
+    },
 
     ForwardData {
         id: AllocationId,
         data: Vec<u8>,
         receiver: SocketAddr,
     },
+    /// Relay channel data between the client and the peer of this channel binding without handing it to the [`Server`].
+    ///
+    /// Data from the client arrives on our listen port, data from the peer on the given port of the client's allocation.
+    /// Only emitted if the fast path is enabled, see [`Server::enable_fast_path`].
+    /// A binding may be created again when it is refreshed.
+    CreateChannelBinding {
+        allocation: AllocationId,
+        client: SocketAddr,
+        channel: u16,
+        peer: SocketAddr,
+        port: u16,
+    },
+    /// Stop relaying the channel data of this channel binding outside of the [`Server`].
+    DeleteChannelBinding {
+        allocation: AllocationId,
+        client: SocketAddr,
+        channel: u16,
+        peer: SocketAddr,
+        port: u16,
+    },
+    /// Report the data relayed through an allocation, e.g. to the portal.
+    ///
+    /// Only emitted if usage reports are enabled, see [`Server::enable_usage_reports`].
+    /// If the fast path is enabled, the record lacks the channel data relayed by it and may be empty.
+    ReportUsage(UsageRecord),
     /// At the latest, the [`Server`] needs to be woken at the specified deadline to execute time-based actions correctly.
     Wake { deadline: SystemTime },
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct AllocationId(u64);
 
 impl AllocationId {
-    fn next(&mut self) -> Self {
+    /// Returns this ID and advances to the next one of the same [`Shard`].
+    fn next(&mut self, shard: Shard) -> Self {
         let id = self.0;
 
-        self.0 += 1;
+        self.0 += shard.count() as u64;
 
         AllocationId(id)
     }
@@ -139,6 +273,21 @@ const UDP_TRANSPORT: u8 = 17;
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
 const CHANNEL_BINDING_DURATION: Duration = Duration::from_secs(600);
 
+/// How long a peer data connection waits for the client to bind it.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.3>.
+const CONNECTION_BIND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The lifetime of a permission.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-permissions>.
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+
+/// For how long credentials derived from the previous auth secret are accepted after a rotation.
+///
+/// This gives clients time to fetch new credentials from the portal.
+pub const AUTH_SECRET_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
 impl<R> Server<R>
 where
     R: Rng,
@@ -152,13 +301,35 @@ where
     /// - Users might already have other services deployed on the same machine that overlap with the ports the RFC recommends.
     /// - Docker Desktop struggles with forwarding large port ranges to the host with the default networking mode.
     pub fn new(
+        public_address: impl Into<IpStack>,
+        rng: R,
+        lowest_port: u16,
+        highest_port: u16,
+    ) -> Self {
+        Self::new_shard(
+            public_address,
+            rng,
+            lowest_port,
+            highest_port,
+            Shard::default(),
+        )
+    }
+
+    /// Constructs a [`Server`] that serves one [`Shard`] of a relay.
+    ///
+    /// The port range is that of the entire relay, the server only allocates ports from the part that belongs to its shard.
+    /// All shards of a relay must share the same auth secret, see [`Server::set_auth_secret`].
+    pub fn new_shard(
         public_address: impl Into<IpStack>,
         mut rng: R,
         lowest_port: u16,
         highest_port: u16,
+        shard: Shard,
     ) -> Self {
         // TODO: Validate that local IP isn't multicast / loopback etc.
 
+        let (lowest_port, highest_port) = shard.port_range(lowest_port, highest_port);
+
         let meter = opentelemetry_api::global::meter("relay");
 
         let allocations_up_down_counter = meter
@@ -169,10 +340,45 @@ where
             .u64_counter("responses_total")
             .with_description("The number of responses")
             .init();
+        let requests_counter = meter
+            .u64_counter("requests_total")
+            .with_description("The number of requests")
+            .init();
+        let auth_failures_counter = meter
+            .u64_counter("auth_failures_total")
+            .with_description("The number of requests that failed authentication")
+            .init();
+
+        let allocated_ports = Arc::new(AtomicUsize::new(0));
+        let port_pool_utilization = meter
+            .f64_observable_gauge("port_pool_utilization")
+            .with_description("The fraction of the port range that is used by allocations")
+            .init();
+        let max_available_ports = num_ports(lowest_port, highest_port) as f64;
+        if let Err(e) = meter.register_callback(&[port_pool_utilization.as_any()], {
+            let allocated_ports = allocated_ports.clone();
+
+            move |observer| {
+                let allocated_ports = allocated_ports.load(Ordering::Relaxed) as f64;
+
+                observer.observe_f64(
+                    &port_pool_utilization,
+                    allocated_ports / max_available_ports,
+                    &[KeyValue::new("shard", shard.index() as i64)],
+                )
+            }
+        }) {
+            tracing::warn!("Failed to register port pool utilization callback: {e}");
+        }
         let data_relayed_counter = meter
             .u64_counter("data_relayed_bytes")
             .with_description("The number of bytes relayed")
-            .with_unit(Unit::new("kB"))
+            .with_unit(Unit::new("By"))
+            .init();
+        let data_dropped_counter = meter
+            .u64_counter("data_dropped_bytes")
+            .with_description("The number of bytes dropped because a bandwidth limit was exceeded")
+            .with_unit(Unit::new("By"))
             .init();
 
         Self {
@@ -181,27 +387,236 @@ where
             public_address: public_address.into(),
             allocations: Default::default(),
             clients_by_allocation: Default::default(),
+            allocations_by_mobility_ticket: Default::default(),
             allocations_by_port: Default::default(),
             lowest_port,
             highest_port,
             channels_by_number: Default::default(),
             channel_numbers_by_peer: Default::default(),
+            tcp_connections: Default::default(),
+            bandwidth_limits: Default::default(),
+            peer_filter: Default::default(),
+            load_shedding: Default::default(),
+            software: None,
+            fast_path: false,
+            usage_report_interval: None,
+            nat_discovery: None,
+            mobility: false,
+            user_buckets: Default::default(),
+            revoked_users: Default::default(),
+            draining: false,
             pending_commands: Default::default(),
-            next_allocation_id: AllocationId(1),
+            shard,
+            next_allocation_id: AllocationId(1 + shard.index() as u64),
             auth_secret: SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
+            previous_auth_secret: None,
+            authenticator: Box::new(Firezone),
             rng,
             time_events: TimeEvents::default(),
             nonces: Default::default(),
             allocations_up_down_counter,
             responses_counter,
             data_relayed_counter,
+            data_dropped_counter,
+            requests_counter,
+            auth_failures_counter,
+            allocated_ports,
+        }
+    }
+
+    /// Sets the [`BandwidthLimits`] to enforce on relayed data.
+    ///
+    /// The budgets of existing allocations and users are reset to the new limits.
+    /// Rate-limited allocations are taken off the fast path, the kernel cannot enforce their limits.
+    pub fn set_bandwidth_limits(&mut self, limits: BandwidthLimits, now: SystemTime) {
+        // Which channels are part of the fast path depends on the limits.
+        let channels = if limits != self.bandwidth_limits {
+            self.bound_channels()
+        } else {
+            Vec::new()
+        };
+
+        for chan in &channels {
+            self.remove_from_fast_path(*chan);
+        }
+
+        self.bandwidth_limits = limits;
+
+        for allocation in self.allocations.values_mut() {
+            allocation.bucket = limits
+                .per_allocation
+                .map(|limit| TokenBucket::new(limit, now));
+        }
+
+        self.user_buckets.clear();
+        if let Some(limit) = limits.per_user {
+            for allocation in self.allocations.values() {
+                self.user_buckets
+                    .entry(allocation.user.clone())
+                    .or_insert_with(|| TokenBucket::new(limit, now));
+            }
+        }
+
+        for chan in channels {
+            self.add_to_fast_path(chan);
+        }
+    }
+
+    /// Replaces the [`BandwidthLimits`] and [`PeerFilter`] while the relay is running, e.g. on request of the portal.
+    pub fn reconfigure(&mut self, limits: BandwidthLimits, filter: PeerFilter, now: SystemTime) {
+        self.set_bandwidth_limits(limits, now);
+        self.set_peer_filter(filter);
+        self.revoke_denied_peers(now);
+
+        tracing::info!(target: "relay", "Applied new configuration");
+    }
+
+    /// Removes the permissions, channels and connections to peers that the current [`PeerFilter`] denies.
+    ///
+    /// Like expired ones, denied channels are only unbound right away and deleted once their number may be reused.
+    fn revoke_denied_peers(&mut self, now: SystemTime) {
+        let denied_channels = self
+            .channels_by_number
+            .iter()
+            .filter(|(_, channel)| {
+                channel.bound && !self.peer_filter.is_allowed(channel.peer_address.ip())
+            })
+            .map(|(chan, _)| *chan)
+            .collect::<Vec<_>>();
+
+        for chan in denied_channels {
+            tracing::info!(target: "relay", "Unbinding channel {chan} because its peer is denied by the peer filter");
+
+            self.remove_from_fast_path(chan);
+            if let Some(channel) = self.channels_by_number.get_mut(&chan) {
+                channel.bound = false;
+            }
+
+            let wake_deadline = self.time_events.add(
+                now + Duration::from_secs(5 * 60),
+                TimedAction::DeleteChannel(chan),
+            );
+            self.pending_commands.push_back(Command::Wake {
+                deadline: wake_deadline,
+            });
+        }
+
+        // Only now that the denied channels are off the fast path, see [`Server::fast_path_binding`].
+        for allocation in self.allocations.values_mut() {
+            let id = allocation.id;
+
+            allocation.permissions.retain(|peer, _| {
+                let allowed = self.peer_filter.is_allowed(*peer);
+                if !allowed {
+                    tracing::info!(target: "relay", allocation = %id, %peer, "Removed permission denied by peer filter");
+                }
+
+                allowed
+            });
         }
+
+        let denied_connections = self
+            .tcp_connections
+            .iter()
+            .filter(|(_, connection)| !self.peer_filter.is_allowed(connection.peer_address.ip()))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in denied_connections {
+            tracing::info!(target: "relay", "Closing connection {id} because its peer is denied by the peer filter");
+
+            self.delete_tcp_connection(id);
+        }
+    }
+
+    /// Sets the [`PeerFilter`] that decides which peers clients may relay data to.
+    ///
+    /// Existing permissions, channel bindings and connections are not affected, see [`Server::reconfigure`] for that.
+    pub fn set_peer_filter(&mut self, filter: PeerFilter) {
+        self.peer_filter = filter;
+    }
+
+    /// Sets the [`LoadShedding`] policy that decides when to redirect new allocations to sibling relays.
+    pub fn set_load_shedding(&mut self, load_shedding: LoadShedding) {
+        self.load_shedding = load_shedding;
+    }
+
+    /// Replaces the sibling relays that clients are redirected to, keeping the configured threshold.
+    pub fn set_alternate_servers(&mut self, alternate_servers: Vec<SocketAddr>) {
+        self.load_shedding.set_alternate_servers(alternate_servers);
+    }
+
+    /// Sets the SOFTWARE attribute to add to all responses, see <https://www.rfc-editor.org/rfc/rfc8489#section-14.14>.
+    ///
+    /// By default, responses do not identify the software of the relay.
+    pub fn set_software(&mut self, software: Software) {
+        self.software = Some(software);
+    }
+
+    /// Mirrors all channel bindings via [`Command::CreateChannelBinding`] and [`Command::DeleteChannelBinding`].
+    ///
+    /// This allows the caller to relay channel data without involving the [`Server`], e.g. with an XDP program.
+    /// Channel data relayed that way bypasses bandwidth limits.
+    pub fn enable_fast_path(&mut self) {
+        self.fast_path = true;
+    }
+
+    /// Reports the data relayed through each allocation via [`Command::ReportUsage`].
+    ///
+    /// Allocations that relayed data are reported every `interval`, once more when they are deleted.
+    /// If the fast path is enabled, all allocations are reported every `interval` so the caller can add the channel data relayed by the fast path.
+    pub fn enable_usage_reports(&mut self, interval: Duration) {
+        self.usage_report_interval = Some(interval);
+    }
+
+    /// Answers Binding requests as per [RFC 5780](https://www.rfc-editor.org/rfc/rfc5780), allowing clients to discover the behaviour of their NAT.
+    ///
+    /// Binding responses carry OTHER-ADDRESS and RESPONSE-ORIGIN and honor CHANGE-REQUEST via [`Command::SendMessageFrom`].
+    /// Requests arriving on one of the alternate addresses or ports must be handed to [`Server::handle_nat_discovery_input`].
+    pub fn enable_nat_discovery(&mut self, nat_discovery: NatDiscovery) {
+        self.nat_discovery = Some(nat_discovery);
+    }
+
+    /// Issues a [`MobilityTicket`] for UDP allocations whose clients ask for one, see [RFC 8016](https://www.rfc-editor.org/rfc/rfc8016).
+    ///
+    /// With the ticket, a client can refresh its allocation from a new 5-tuple, e.g. after roaming from Wi-Fi to LTE.
+    /// The allocation, its permissions and channels then belong to the new 5-tuple.
+    pub fn enable_mobility(&mut self) {
+        self.mobility = true;
+    }
+
+    /// Sets the [`Authenticator`] to verify the credentials of requests with.
+    ///
+    /// Defaults to [`Firezone`].
+    pub fn set_authenticator(&mut self, authenticator: impl Authenticator + 'static) {
+        self.authenticator = Box::new(authenticator);
     }
 
     pub fn auth_secret(&self) -> &SecretString {
         &self.auth_secret
     }
 
+    /// Replaces the auth secret with a new one.
+    ///
+    /// Credentials derived from the current secret remain valid for another [`AUTH_SECRET_GRACE_PERIOD`].
+    /// A secret that has been rotated out before is forgotten immediately.
+    pub fn rotate_auth_secret(&mut self, secret: SecretString, now: SystemTime) {
+        let previous = std::mem::replace(&mut self.auth_secret, secret);
+
+        self.previous_auth_secret = Some((previous, now + AUTH_SECRET_GRACE_PERIOD));
+
+        tracing::info!(target: "relay", "Rotated auth secret");
+    }
+
+    /// Replaces the auth secret, without accepting credentials derived from the previous one any longer.
+    ///
+    /// Meant for sharing one secret between all shards of a relay before they serve clients.
+    /// Afterwards, use [`Server::rotate_auth_secret`].
+    pub fn set_auth_secret(&mut self, secret: SecretString) {
+        self.auth_secret = secret;
+        self.previous_auth_secret = None;
+    }
+
     /// Registers a new, valid nonce.
     ///
     /// Each nonce is valid for 10 requests.
@@ -209,11 +624,141 @@ where
         self.nonces.add_new(nonce);
     }
 
+    /// Frees all allocations of the given user and rejects its credentials until the given time.
+    ///
+    /// The user is the one identified by the [`Authenticator`], i.e. the username salt for [`Firezone`] credentials.
+    /// `until` is when the last of its credentials expire, afterwards we forget about the revocation.
+    /// Returns the number of freed allocations.
+    pub fn revoke_user(&mut self, user: &str, until: SystemTime) -> usize {
+        let allocations = self
+            .allocations
+            .values()
+            .filter(|allocation| allocation.user == user)
+            .map(|allocation| allocation.id)
+            .collect::<Vec<_>>();
+
+        for id in &allocations {
+            self.delete_allocation(*id);
+        }
+
+        let until = *self
+            .revoked_users
+            .entry(user.to_owned())
+            .and_modify(|revoked_until| *revoked_until = (*revoked_until).max(until))
+            .or_insert(until);
+
+        let wake_deadline = self
+            .time_events
+            .add(until, TimedAction::ForgetRevocation(user.to_owned()));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        tracing::info!(target: "relay", %user, "Revoked user and freed {} allocations", allocations.len());
+
+        allocations.len()
+    }
+
+    /// Reports the usage of all allocations one last time, e.g. because the relay shuts down without handing them over.
+    pub fn report_final_usage(&mut self) {
+        if self.usage_report_interval.is_none() {
+            return;
+        }
+
+        let allocations = self
+            .allocations
+            .values()
+            .map(|allocation| allocation.id)
+            .collect::<Vec<_>>();
+
+        for id in allocations {
+            self.report_usage(id, true);
+        }
+    }
+
+    /// Stops accepting new allocations, e.g. before taking the relay out of rotation.
+    ///
+    /// Clients are redirected to an alternate server if we know one or rejected with `508 Insufficient Capacity` otherwise.
+    /// Existing allocations keep being served.
+    pub fn start_draining(&mut self) {
+        if !self.draining {
+            tracing::info!(target: "relay", "Draining, no longer accepting new allocations");
+        }
+
+        self.draining = true;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Whether we are draining and all allocations are gone.
+    pub fn is_drained(&self) -> bool {
+        self.draining && self.allocations.is_empty()
+    }
+
+    /// Lists all current allocations, i.e. for the admin API.
+    pub fn allocations(&self) -> Vec<AllocationInfo> {
+        self.allocations
+            .iter()
+            .map(|(client, allocation)| AllocationInfo {
+                id: allocation.id,
+                client: client.addr,
+                client_transport: client.transport.as_str(),
+                relay_addresses: iter::once(allocation.first_relay_addr)
+                    .chain(allocation.second_relay_addr)
+                    .map(|ip| SocketAddr::new(ip, allocation.port))
+                    .collect(),
+                transport: match allocation.transport {
+                    Transport::Udp => "udp",
+                    Transport::Tcp => "tcp",
+                },
+                channels: self
+                    .channels_by_number
+                    .iter()
+                    .filter(|(_, channel)| channel.allocation == allocation.id)
+                    .map(|(number, channel)| ChannelInfo {
+                        number: *number,
+                        peer: channel.peer_address,
+                        bound: channel.bound,
+                        expires_at: unix_timestamp(channel.expiry),
+                    })
+                    .collect(),
+                permissions: allocation
+                    .permissions
+                    .iter()
+                    .map(|(peer, expiry)| PermissionInfo {
+                        peer: *peer,
+                        expires_at: unix_timestamp(*expiry),
+                    })
+                    .collect(),
+                client_to_peer_bytes: allocation.client_to_peer_bytes,
+                peer_to_client_bytes: allocation.peer_to_client_bytes,
+                expires_at: unix_timestamp(allocation.expires_at),
+            })
+            .collect()
+    }
+
+    /// Forcibly frees an allocation, regardless of its lifetime.
+    ///
+    /// Returns `false` if the allocation does not exist.
+    pub fn free_allocation(&mut self, id: AllocationId) -> bool {
+        if !self.clients_by_allocation.contains_key(&id) {
+            return false;
+        }
+
+        tracing::info!(target: "relay", allocation = %id, "Freeing allocation on request of an admin");
+
+        self.delete_allocation(id);
+
+        true
+    }
+
     /// Process the bytes received from a client.
     ///
     /// After calling this method, you should call [`Server::next_command`] until it returns `None`.
     #[tracing::instrument(skip_all, fields(transaction_id, %sender), level = "error")]
-    pub fn handle_client_input(&mut self, bytes: &[u8], sender: SocketAddr, now: SystemTime) {
+    pub fn handle_client_input(&mut self, bytes: &[u8], sender: ClientSocket, now: SystemTime) {
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(bytes);
             tracing::trace!(target: "wire", %hex_bytes, "receiving bytes");
@@ -238,6 +783,9 @@ where
             Err(client_message::Error::DecodeStun(ref error)) => {
                 tracing::debug!(%error, "failed to decode stun packet")
             }
+            Err(client_message::Error::BadFingerprint) => {
+                tracing::debug!("FINGERPRINT of stun packet does not match, dropping it")
+            }
             Err(client_message::Error::UnknownMessageType(t)) => {
                 tracing::debug!(r#type = %t, "unknown STUN message type")
             }
@@ -247,12 +795,55 @@ where
         };
     }
 
+    /// Process the bytes received on one of our alternate addresses or ports, see [`Server::enable_nat_discovery`].
+    ///
+    /// We only answer Binding requests there, everything else is dropped.
+    #[tracing::instrument(skip_all, fields(transaction_id, %sender, ?origin), level = "error")]
+    pub fn handle_nat_discovery_input(
+        &mut self,
+        bytes: &[u8],
+        sender: SocketAddr,
+        origin: DiscoveryOrigin,
+    ) {
+        if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
+            let hex_bytes = hex::encode(bytes);
+            tracing::trace!(target: "wire", %hex_bytes, "receiving bytes");
+        }
+
+        match self.decoder.decode(bytes) {
+            Ok(Ok(ClientMessage::Binding(request))) => {
+                Span::current().record(
+                    "transaction_id",
+                    hex::encode(request.transaction_id().as_bytes()),
+                );
+
+                self.requests_counter
+                    .add(1, &[KeyValue::new("message_type", "binding")]);
+                self.handle_binding_request(request, ClientSocket::udp(sender), origin);
+            }
+            Ok(Ok(_)) => {
+                tracing::debug!("Only Binding requests are served on alternate addresses")
+            }
+            Ok(Err(error_response)) => {
+                self.send_message_from(error_response, ClientSocket::udp(sender), origin);
+            }
+            Err(error) => {
+                tracing::debug!(?error, "failed to decode message")
+            }
+        }
+    }
+
     pub fn handle_client_message(
         &mut self,
         message: ClientMessage,
-        sender: SocketAddr,
+        sender: ClientSocket,
         now: SystemTime,
     ) {
+        if let Some(message_type) = message_type(&message) {
+            self.requests_counter
+                .add(1, &[KeyValue::new("message_type", message_type)]);
+        }
+
         let result = match message {
             ClientMessage::Allocate(request) => self.handle_allocate_request(request, sender, now),
             ClientMessage::Refresh(request) => self.handle_refresh_request(request, sender, now),
@@ -262,14 +853,22 @@ where
             ClientMessage::CreatePermission(request) => {
                 self.handle_create_permission_request(request, sender, now)
             }
+            ClientMessage::Connect(request) => self.handle_connect_request(request, sender, now),
+            ClientMessage::ConnectionBind(request) => {
+                self.handle_connection_bind_request(request, sender, now)
+            }
             ClientMessage::Binding(request) => {
-                self.handle_binding_request(request, sender);
+                self.handle_binding_request(request, sender, DiscoveryOrigin::PRIMARY);
                 return;
             }
             ClientMessage::ChannelData(msg) => {
                 self.handle_channel_data_message(msg, sender, now);
                 return;
             }
+            ClientMessage::SendIndication(indication) => {
+                self.handle_send_indication(indication, sender, now);
+                return;
+            }
         };
 
         let Err(error_response) = result else {
@@ -279,8 +878,13 @@ where
         self.queue_error_response(sender, error_response)
     }
 
-    fn queue_error_response(&mut self, sender: SocketAddr, mut error_response: Message<Attribute>) {
+    fn queue_error_response(
+        &mut self,
+        sender: ClientSocket,
+        mut error_response: Message<Attribute>,
+    ) {
         // In case of a 401 or 438 response, attach a realm and nonce.
+        // The nonce advertises the security features of RFC 8489 that we support, see <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.4>.
         if error_response
             .get_attribute::<ErrorCode>()
             .map_or(false, |error| {
@@ -288,11 +892,19 @@ where
             })
         {
             let new_nonce = Uuid::from_u128(self.rng.gen());
+            let security_features = SecurityFeatures {
+                password_algorithms: true,
+                username_anonymity: self.authenticator.supports_userhash(),
+            };
 
-            self.add_nonce(new_nonce);
+            self.nonces
+                .add_new_with_features(new_nonce, security_features);
 
-            error_response.add_attribute(Nonce::new(new_nonce.to_string()).unwrap());
-            error_response.add_attribute((*FIREZONE).clone());
+            error_response.add_attribute(
+                Nonce::new(security_features.prefix_nonce(&new_nonce.to_string())).unwrap(),
+            );
+            error_response.add_attribute(self.authenticator.realm().clone());
+            error_response.add_attribute(PasswordAlgorithms::new(PASSWORD_ALGORITHMS.to_vec()));
         }
 
         self.send_message(error_response, sender);
@@ -305,6 +917,7 @@ where
         bytes: &[u8],
         sender: SocketAddr,
         allocation_id: AllocationId,
+        now: SystemTime,
     ) {
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(bytes);
@@ -318,33 +931,52 @@ where
 
         Span::current().record("recipient", field::display(&recipient));
 
-        let Some(channel_number) = self.channel_numbers_by_peer.get(&sender) else {
-            tracing::debug!(target: "relay", "no active channel, refusing to relay {} bytes", bytes.len());
+        let recipient = *recipient;
+
+        // A channel binding installs a permission, but the permission expires earlier unless the client refreshes it.
+        if !self
+            .get_allocation(&allocation_id)
+            .map_or(false, |allocation| {
+                allocation.has_permission(sender.ip(), now)
+            })
+        {
+            tracing::debug!(target: "relay", "no permission, refusing to relay {} bytes", bytes.len());
             return;
-        };
+        }
 
-        Span::current().record("channel", channel_number);
+        // Prefer a channel and fall back to a Data indication if the client only installed a permission for this peer.
+        let channel_number = self.bound_channel(sender, allocation_id);
+        let data = match channel_number {
+            Some(channel_number) => {
+                Span::current().record("channel", channel_number);
 
-        let Some(channel) = self.channels_by_number.get(channel_number) else {
-            debug_assert!(false, "unknown channel {}", channel_number);
-            return;
-        };
+                ChannelData::new(channel_number, bytes).to_bytes()
+            }
+            None => {
+                let Some(data) = self.data_indication(sender, bytes) else {
+                    return;
+                };
 
-        if !channel.bound {
-            tracing::debug!(target: "relay", "channel existed but is unbound");
-            return;
-        }
+                data
+            }
+        };
 
-        if channel.allocation != allocation_id {
-            tracing::debug!(target: "relay", "channel is not associated with allocation");
+        if !self.consume_bandwidth(allocation_id, bytes.len(), now) {
             return;
         }
 
         tracing::debug!(target: "relay", "Relaying {} bytes", bytes.len());
 
         self.data_relayed_counter.add(bytes.len() as u64, &[]);
-
-        let data = ChannelData::new(*channel_number, bytes).to_bytes();
+        if let Some(allocation) = self.get_allocation_mut(&allocation_id) {
+            allocation.peer_to_client_bytes += bytes.len() as u64;
+            allocation
+                .unreported_usage
+                .record_peer_to_client(bytes.len());
+        }
+        if let Some(channel) = channel_number.and_then(|n| self.channels_by_number.get_mut(&n)) {
+            channel.unreported_usage.record_peer_to_client(bytes.len());
+        }
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(&data);
@@ -353,7 +985,7 @@ where
 
         self.pending_commands.push_back(Command::SendMessage {
             payload: data,
-            recipient: *recipient,
+            recipient,
         })
     }
 
@@ -383,6 +1015,7 @@ where
                         tracing::info!(target: "relay", "Channel {chan} is now expired");
 
                         channel.bound = false;
+                        self.remove_from_fast_path(chan);
 
                         self.time_events.add(
                             now + Duration::from_secs(5 * 60),
@@ -393,10 +1026,275 @@ where
                 TimedAction::DeleteChannel(chan) => {
                     self.delete_channel_binding(chan);
                 }
+                TimedAction::ExpirePermission(id, peer) => {
+                    if self
+                        .get_allocation(&id)
+                        .map_or(true, |allocation| allocation.has_permission(peer, now))
+                    {
+                        continue;
+                    }
+
+                    // The fast path doesn't check permissions, thus channels to the peer must not be part of it anymore.
+                    for chan in self.bound_channels_to(id, peer) {
+                        self.remove_from_fast_path(chan);
+                    }
+
+                    let Some(allocation) = self.get_allocation_mut(&id) else {
+                        continue;
+                    };
+
+                    if allocation.expire_permission(peer, now) {
+                        tracing::info!(target: "relay", allocation = %id, %peer, "Permission expired");
+                    }
+                }
+                TimedAction::ForgetRevocation(user) => {
+                    if self
+                        .revoked_users
+                        .get(&user)
+                        .map_or(false, |until| *until <= now)
+                    {
+                        tracing::debug!(target: "relay", %user, "All revoked credentials expired, forgetting revocation");
+
+                        self.revoked_users.remove(&user);
+                    }
+                }
+                TimedAction::ReportUsage(id) => {
+                    let (Some(interval), Some(allocation)) =
+                        (self.usage_report_interval, self.get_allocation(&id))
+                    else {
+                        continue;
+                    };
+
+                    if self.fast_path || !allocation.unreported_usage.is_empty() {
+                        self.report_usage(id, false);
+                    }
+
+                    let wake_deadline = self
+                        .time_events
+                        .add(now + interval, TimedAction::ReportUsage(id));
+                    self.pending_commands.push_back(Command::Wake {
+                        deadline: wake_deadline,
+                    });
+                }
+                TimedAction::ExpireConnection(id) => {
+                    let Some(connection) = self.tcp_connections.get(&id) else {
+                        continue;
+                    };
+
+                    if connection.is_expired(now) {
+                        tracing::info!(target: "relay", "Connection {id} was not bound in time");
+
+                        self.delete_tcp_connection(id);
+                    }
+                }
             }
         }
     }
 
+    /// The TCP or TLS connection of a client has been closed.
+    ///
+    /// If it was the control connection of an allocation, the allocation is deleted.
+    /// If it was a data connection, the corresponding peer connection is closed.
+    #[tracing::instrument(skip(self), level = "error")]
+    pub fn handle_stream_closed(&mut self, client: ClientSocket) {
+        if let Some(id) = self.allocations.get(&client).map(|a| a.id) {
+            self.delete_allocation(id);
+        }
+
+        let bound_connections = self
+            .tcp_connections
+            .iter()
+            .filter(|(_, c)| c.state == TcpConnectionState::Bound { client })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in bound_connections {
+            self.delete_tcp_connection(id);
+        }
+    }
+
+    /// The given number of bytes have been spliced from a client's data connection to its peer connection.
+    ///
+    /// Spliced data bypasses the server, thus it is only accounted for here.
+    pub fn handle_spliced_client_data(
+        &mut self,
+        client: ClientSocket,
+        num_bytes: usize,
+        now: SystemTime,
+    ) {
+        let Some(allocation_id) = self.allocation_of_data_connection(client) else {
+            return;
+        };
+        let Some(allocation) = self.get_allocation_mut(&allocation_id) else {
+            return;
+        };
+
+        allocation.client_to_peer_bytes += num_bytes as u64;
+        allocation.unreported_usage.record_client_to_peer(num_bytes);
+        self.data_relayed_counter.add(num_bytes as u64, &[]);
+
+        self.consume_spliced_bandwidth(client, allocation_id, num_bytes, now);
+    }
+
+    /// The given number of bytes have been spliced from a peer connection to the bound data connection of the client.
+    ///
+    /// Spliced data bypasses the server, thus it is only accounted for here.
+    pub fn handle_spliced_peer_data(
+        &mut self,
+        client: ClientSocket,
+        num_bytes: usize,
+        now: SystemTime,
+    ) {
+        let Some(allocation_id) = self.allocation_of_data_connection(client) else {
+            return;
+        };
+        let Some(allocation) = self.get_allocation_mut(&allocation_id) else {
+            return;
+        };
+
+        allocation.peer_to_client_bytes += num_bytes as u64;
+        allocation.unreported_usage.record_peer_to_client(num_bytes);
+        self.data_relayed_counter.add(num_bytes as u64, &[]);
+
+        self.consume_spliced_bandwidth(client, allocation_id, num_bytes, now);
+    }
+
+    fn allocation_of_data_connection(&self, client: ClientSocket) -> Option<AllocationId> {
+        let connection = self
+            .tcp_connections
+            .values()
+            .find(|c| c.state == TcpConnectionState::Bound { client })?;
+
+        Some(connection.allocation)
+    }
+
+    /// A peer connected to the relay address of a TCP allocation.
+    ///
+    /// Returns the [`ConnectionId`] of the new peer connection or `None` if the connection should be refused.
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.3>.
+    #[tracing::instrument(skip(self, now), fields(%allocation_id, %peer), level = "error")]
+    pub fn handle_peer_connection_accepted(
+        &mut self,
+        allocation_id: AllocationId,
+        peer: SocketAddr,
+        now: SystemTime,
+    ) -> Option<ConnectionId> {
+        let Some(client) = self.clients_by_allocation.get(&allocation_id).copied() else {
+            tracing::debug!(target: "relay", "Unknown allocation");
+            return None;
+        };
+        let allocation = self.allocations.get(&client)?;
+
+        if allocation.transport != Transport::Tcp
+            || !allocation.can_relay_to(peer)
+            || !self.peer_filter.is_allowed(peer.ip())
+        {
+            tracing::debug!(target: "relay", "Refusing peer connection");
+            return None;
+        }
+
+        let id = self.new_connection_id();
+        let expires_at = now + CONNECTION_BIND_TIMEOUT;
+
+        self.tcp_connections.insert(
+            id,
+            TcpConnection {
+                allocation: allocation_id,
+                peer_address: peer,
+                state: TcpConnectionState::AwaitingBind { expires_at },
+            },
+        );
+
+        let wake_deadline = self
+            .time_events
+            .add(expires_at, TimedAction::ExpireConnection(id));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        let mut message = Message::new(
+            MessageClass::Indication,
+            *CONNECTION_ATTEMPT,
+            TransactionId::new(self.rng.gen()),
+        );
+        message.add_attribute(id);
+        message.add_attribute(XorPeerAddress::new(peer));
+
+        self.send_message(message, client);
+
+        tracing::info!(target: "relay", connection = %id, "Peer connected, waiting for client to bind connection");
+
+        Some(id)
+    }
+
+    /// A connection requested via [`Command::ConnectToPeer`] has been established.
+    #[tracing::instrument(skip(self, now), fields(%connection), level = "error")]
+    pub fn handle_peer_connection_established(
+        &mut self,
+        connection: ConnectionId,
+        now: SystemTime,
+    ) {
+        let Some(tcp_connection) = self.tcp_connections.get_mut(&connection) else {
+            tracing::debug!(target: "relay", "Unknown connection");
+            return;
+        };
+        let TcpConnectionState::Connecting { transaction_id } = tcp_connection.state else {
+            tracing::debug!(target: "relay", "Connection is not being established");
+            return;
+        };
+        let Some(client) = self
+            .clients_by_allocation
+            .get(&tcp_connection.allocation)
+            .copied()
+        else {
+            tracing::debug!(target: "relay", "Unknown allocation");
+            return;
+        };
+
+        let expires_at = now + CONNECTION_BIND_TIMEOUT;
+        tcp_connection.state = TcpConnectionState::AwaitingBind { expires_at };
+
+        let wake_deadline = self
+            .time_events
+            .add(expires_at, TimedAction::ExpireConnection(connection));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        let mut message = Message::new(MessageClass::SuccessResponse, *CONNECT, transaction_id);
+        message.add_attribute(connection);
+
+        self.send_message(message, client);
+
+        tracing::info!(target: "relay", "Connected to peer, waiting for client to bind connection");
+    }
+
+    /// A connection requested via [`Command::ConnectToPeer`] could not be established.
+    #[tracing::instrument(skip(self), fields(%connection), level = "error")]
+    pub fn handle_peer_connection_failed(&mut self, connection: ConnectionId) {
+        let Some(tcp_connection) = self.tcp_connections.remove(&connection) else {
+            tracing::debug!(target: "relay", "Unknown connection");
+            return;
+        };
+        let TcpConnectionState::Connecting { transaction_id } = tcp_connection.state else {
+            return;
+        };
+        let Some(client) = self
+            .clients_by_allocation
+            .get(&tcp_connection.allocation)
+            .copied()
+        else {
+            return;
+        };
+
+        let mut message = Message::new(MessageClass::ErrorResponse, *CONNECT, transaction_id);
+        message.add_attribute(ErrorCode::from(ConnectionTimeoutOrFailure));
+
+        self.send_message(message, client);
+
+        tracing::info!(target: "relay", "Failed to connect to peer");
+    }
+
     /// An allocation failed.
     #[tracing::instrument(skip(self), fields(%allocation_id), level = "error")]
     pub fn handle_allocation_failed(&mut self, allocation_id: AllocationId) {
@@ -414,15 +1312,56 @@ where
         self.pending_commands.pop_front()
     }
 
-    fn handle_binding_request(&mut self, message: Binding, sender: SocketAddr) {
+    /// Handle a STUN binding request that arrived at the given [`DiscoveryOrigin`].
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc5780#section-6.1> for how we handle NAT behaviour discovery.
+    fn handle_binding_request(
+        &mut self,
+        request: Binding,
+        sender: ClientSocket,
+        origin: DiscoveryOrigin,
+    ) {
         let mut message = Message::new(
             MessageClass::SuccessResponse,
             BINDING,
-            message.transaction_id(),
+            request.transaction_id(),
         );
-        message.add_attribute(XorMappedAddress::new(sender));
+        message.add_attribute(XorMappedAddress::new(sender.addr));
 
-        self.send_message(message, sender);
+        // NAT behaviour discovery only works over UDP, see <https://www.rfc-editor.org/rfc/rfc5780#section-7.2>.
+        let nat_discovery = self.nat_discovery.filter(|_| !sender.is_stream());
+
+        let Some(nat_discovery) = nat_discovery else {
+            if request.change_request().is_some() {
+                self.send_message(unknown_change_request(&request), sender);
+                return;
+            }
+
+            self.send_message(message, sender);
+            return;
+        };
+
+        let family = sender.addr.family();
+        let response_origin = request
+            .change_request()
+            .map_or(origin, |change| origin.change(change));
+
+        // We can only change the IP if we have an alternate address in the family of the client.
+        let Some(response_address) =
+            nat_discovery.address(&self.public_address, family, response_origin)
+        else {
+            self.send_message_from(unknown_change_request(&request), sender, origin);
+            return;
+        };
+
+        if let Some(other_address) =
+            nat_discovery.address(&self.public_address, family, origin.other())
+        {
+            message.add_attribute(OtherAddress::new(other_address));
+        }
+        message.add_attribute(ResponseOrigin::new(response_address));
+
+        self.send_message_from(message, sender, response_origin);
     }
 
     /// Handle a TURN allocate request.
@@ -432,23 +1371,42 @@ where
     fn handle_allocate_request(
         &mut self,
         request: Allocate,
-        sender: SocketAddr,
+        sender: ClientSocket,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+        let user = self.verify_auth(&request, now)?;
 
         if self.allocations.contains_key(&sender) {
             return Err(error_response(AllocationMismatch, &request));
         }
 
-        if self.allocations_by_port.len() == self.max_available_ports() as usize {
-            return Err(error_response(InsufficientCapacity, &request));
+        // While draining, we behave as if we were fully loaded.
+        let load = if self.draining {
+            1.0
+        } else {
+            self.port_pool_utilization()
+        };
+
+        if let Some(alternate_server) = self.load_shedding.alternate_server(sender.addr, load) {
+            tracing::info!(target: "relay", %alternate_server, "Redirecting client to alternate server");
+
+            let mut message = error_response(TryAlternate, &request);
+            message.add_attribute(AlternateServer::new(alternate_server));
+
+            return Err(message);
         }
 
-        if request.requested_transport().protocol() != UDP_TRANSPORT {
-            return Err(error_response(BadRequest, &request));
+        if self.draining || self.is_port_pool_exhausted() {
+            return Err(error_response(InsufficientCapacity, &request));
         }
 
+        let transport = match request.requested_transport().protocol() {
+            UDP_TRANSPORT => Transport::Udp,
+            // TCP allocations need to be requested via TCP or TLS, see <https://www.rfc-editor.org/rfc/rfc6062#section-5.1>.
+            TCP_TRANSPORT if sender.is_stream() => Transport::Tcp,
+            _ => return Err(error_response(BadRequest, &request)),
+        };
+
         let (first_relay_address, maybe_second_relay_addr) = derive_relay_addresses(
             self.public_address,
             request.requested_address_family(),
@@ -460,13 +1418,28 @@ where
         // TODO: Do we need to handle EVEN/ODD-PORT?
         let effective_lifetime = request.effective_lifetime();
 
-        let allocation = self.create_new_allocation(
+        let mut allocation = self.create_new_allocation(
             now,
             &effective_lifetime,
+            transport,
+            user,
             first_relay_address,
             maybe_second_relay_addr,
         );
 
+        // Mobility is only defined for UDP, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.1>.
+        if self.mobility
+            && request.mobility_ticket().is_some()
+            && transport == Transport::Udp
+            && !sender.is_stream()
+        {
+            let ticket = MobilityTicket::random(&mut self.rng);
+
+            self.allocations_by_mobility_ticket
+                .insert(ticket.clone(), allocation.id);
+            allocation.mobility_ticket = Some(ticket);
+        }
+
         let mut message = Message::new(
             MessageClass::SuccessResponse,
             ALLOCATE,
@@ -486,9 +1459,16 @@ where
             )));
         }
 
-        message.add_attribute(XorMappedAddress::new(sender));
+        message.add_attribute(XorMappedAddress::new(sender.addr));
         message.add_attribute(effective_lifetime.clone());
+        if let Some(ticket) = &allocation.mobility_ticket {
+            message.add_attribute(ticket.clone());
+        }
 
+        if let Some(interval) = self.usage_report_interval {
+            self.time_events
+                .add(now + interval, TimedAction::ReportUsage(allocation.id));
+        }
         let wake_deadline = self.time_events.add(
             allocation.expires_at,
             TimedAction::ExpireAllocation(allocation.id),
@@ -496,16 +1476,13 @@ where
         self.pending_commands.push_back(Command::Wake {
             deadline: wake_deadline,
         });
-        self.pending_commands.push_back(Command::CreateAllocation {
-            id: allocation.id,
-            family: first_relay_address.family(),
-            port,
-        });
-        if let Some(second_relay_addr) = maybe_second_relay_addr {
-            self.pending_commands.push_back(Command::CreateAllocation {
-                id: allocation.id,
-                family: second_relay_addr.family(),
-                port,
+        for relay_addr in iter::once(first_relay_address).chain(maybe_second_relay_addr) {
+            let id = allocation.id;
+            let family = relay_addr.family();
+
+            self.pending_commands.push_back(match transport {
+                Transport::Udp => Command::CreateAllocation { id, family, port },
+                Transport::Tcp => Command::CreateTcpAllocation { id, family, port },
             });
         }
         self.send_message(message, sender);
@@ -539,15 +1516,24 @@ where
     fn handle_refresh_request(
         &mut self,
         request: Refresh,
-        sender: SocketAddr,
+        sender: ClientSocket,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+        let user = self.verify_auth(&request, now)?;
+
+        // We only move the allocation once we accepted the entire refresh.
+        let client = match request
+            .mobility_ticket()
+            .filter(|ticket| !ticket.is_empty())
+        {
+            Some(ticket) => self.mobility_ticket_client(ticket, &user, sender, &request)?,
+            None => sender,
+        };
 
         // TODO: Verify that this is the correct error code.
         let allocation = self
             .allocations
-            .get_mut(&sender)
+            .get_mut(&client)
             .ok_or(error_response(AllocationMismatch, &request))?;
 
         let effective_lifetime = request.effective_lifetime();
@@ -579,14 +1565,93 @@ where
         self.pending_commands.push_back(Command::Wake {
             deadline: wake_deadline,
         });
-        self.send_message(
-            refresh_success_response(effective_lifetime, request.transaction_id()),
-            sender,
-        );
+
+        let mut message = refresh_success_response(effective_lifetime, request.transaction_id());
+
+        // Every refresh invalidates the previous ticket, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.3>.
+        if let Some(previous_ticket) = allocation.mobility_ticket.take() {
+            let ticket = MobilityTicket::random(&mut self.rng);
+
+            self.allocations_by_mobility_ticket.remove(&previous_ticket);
+            self.allocations_by_mobility_ticket
+                .insert(ticket.clone(), allocation.id);
+            allocation.mobility_ticket = Some(ticket.clone());
+            message.add_attribute(ticket);
+        }
+
+        if client != sender {
+            self.move_allocation(client, sender);
+        }
+
+        self.send_message(message, sender);
 
         Ok(())
     }
 
+    /// The current client of the allocation of the given [`MobilityTicket`], if `sender` may move it to its 5-tuple.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8016#section-3.4>.
+    fn mobility_ticket_client(
+        &self,
+        ticket: &MobilityTicket,
+        user: &str,
+        sender: ClientSocket,
+        request: &Refresh,
+    ) -> Result<ClientSocket, Message<Attribute>> {
+        if !self.mobility || sender.is_stream() {
+            return Err(error_response(MobilityForbidden, request));
+        }
+
+        let Some((client, allocation)) = self
+            .allocations_by_mobility_ticket
+            .get(ticket)
+            .and_then(|id| self.clients_by_allocation.get(id))
+            .and_then(|client| Some((*client, self.allocations.get(client)?)))
+        else {
+            return Err(error_response(MobilityForbidden, request));
+        };
+
+        if allocation.user != user {
+            return Err(error_response(WrongCredentials, request));
+        }
+
+        if client != sender && self.allocations.contains_key(&sender) {
+            return Err(error_response(AllocationMismatch, request));
+        }
+
+        Ok(client)
+    }
+
+    /// Moves the allocation of `previous_client` to the 5-tuple of `sender`.
+    fn move_allocation(&mut self, previous_client: ClientSocket, sender: ClientSocket) {
+        let id = self.allocations[&previous_client].id;
+
+        let channels = self
+            .channels_by_number
+            .iter()
+            .filter(|(_, c)| c.allocation == id && c.bound)
+            .map(|(number, _)| *number)
+            .collect::<Vec<_>>();
+
+        // The fast path identifies channels by the address of the client, thus we need to re-create them.
+        for channel in &channels {
+            self.remove_from_fast_path(*channel);
+        }
+
+        let allocation = self
+            .allocations
+            .remove(&previous_client)
+            .expect("internal state mismatch");
+        self.allocations.insert(sender, allocation);
+        self.clients_by_allocation.insert(id, sender);
+
+        for channel in channels {
+            self.add_to_fast_path(channel);
+        }
+
+        tracing::info!(target: "relay", allocation = %id, %previous_client, "Moved allocation to new 5-tuple");
+    }
+
     /// Handle a TURN channel bind request.
     ///
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-channelbind-req> for details.
@@ -594,7 +1659,7 @@ where
     fn handle_channel_bind_request(
         &mut self,
         request: ChannelBind,
-        sender: SocketAddr,
+        sender: ClientSocket,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
         self.verify_auth(&request, now)?;
@@ -606,6 +1671,11 @@ where
 
         Span::current().record("allocation", allocation.id.to_string());
 
+        // Channels are not supported for TCP allocations, see <https://www.rfc-editor.org/rfc/rfc6062#section-5.5>.
+        if allocation.transport != Transport::Udp {
+            return Err(error_response(BadRequest, &request));
+        }
+
         // Note: `channel_number` is enforced to be in the correct range.
         let requested_channel = request.channel_number().value();
         let peer_address = request.xor_peer_address().address();
@@ -615,6 +1685,10 @@ where
             return Err(error_response(PeerAddressFamilyMismatch, &request));
         }
 
+        if !self.peer_filter.is_allowed(peer_address.ip()) {
+            return Err(error_response(Forbidden, &request));
+        }
+
         // Ensure the same address isn't already bound to a different channel.
         if let Some(number) = self.channel_numbers_by_peer.get(&peer_address) {
             if number != &requested_channel {
@@ -622,22 +1696,35 @@ where
             }
         }
 
-        // Ensure the channel is not already bound to a different address.
+        // Ensure the channel is not already bound to a different address or by a different allocation.
         if let Some(channel) = self.channels_by_number.get_mut(&requested_channel) {
-            if channel.peer_address != peer_address {
+            if channel.peer_address != peer_address || channel.allocation != allocation.id {
                 return Err(error_response(BadRequest, &request));
             }
 
             // Binding requests for existing channels act as a refresh for the binding.
 
             channel.refresh(now);
+            let permission_expiry = allocation.install_permission(peer_address.ip(), now);
+            let allocation_id = allocation.id;
 
             tracing::info!(target: "relay", "Refreshed channel binding");
 
+            let bound = channel.bound;
             self.time_events.add(
                 channel.expiry,
                 TimedAction::UnbindChannel(requested_channel),
             );
+            let wake_deadline = self.time_events.add(
+                permission_expiry,
+                TimedAction::ExpirePermission(allocation_id, peer_address.ip()),
+            );
+            self.pending_commands.push_back(Command::Wake {
+                deadline: wake_deadline,
+            });
+            if bound {
+                self.add_to_fast_path(requested_channel);
+            }
             self.send_message(
                 channel_bind_success_response(request.transaction_id()),
                 sender,
@@ -651,7 +1738,17 @@ where
         // TODO: Any additional validations would go here.
         // TODO: Capacity checking would go here.
 
+        // A channel binding also installs a permission for the peer, see <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-channelbind-req>.
+        let permission_expiry = allocation.install_permission(peer_address.ip(), now);
+
         let allocation_id = allocation.id;
+        let wake_deadline = self.time_events.add(
+            permission_expiry,
+            TimedAction::ExpirePermission(allocation_id, peer_address.ip()),
+        );
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
         self.create_channel_binding(requested_channel, peer_address, allocation_id, now);
         self.send_message(
             channel_bind_success_response(request.transaction_id()),
@@ -667,21 +1764,198 @@ where
     ///
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-createpermissio> for details.
     ///
-    /// This TURN server implementation does not support relaying data other than through channels.
-    /// Thus, creating a permission is a no-op that always succeeds.
-    #[tracing::instrument(skip(self, message, now), fields(%sender), level = "error")]
+    /// A permission allows the client to exchange data with a peer via Send and Data indications.
+    /// Permissions are installed per peer IP and expire after 5 minutes unless refreshed by another request.
+    #[tracing::instrument(skip(self, message, now), fields(%sender, allocation), level = "error")]
     fn handle_create_permission_request(
         &mut self,
         message: CreatePermission,
-        sender: SocketAddr,
+        sender: ClientSocket,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
         self.verify_auth(&message, now)?;
 
+        let allocation = self
+            .allocations
+            .get_mut(&sender)
+            .ok_or(error_response(AllocationMismatch, &message))?;
+
+        Span::current().record("allocation", allocation.id.to_string());
+
+        if message.xor_peer_addresses().is_empty() {
+            return Err(error_response(BadRequest, &message));
+        }
+
+        for peer in message.xor_peer_addresses() {
+            if !allocation.can_relay_to(peer.address()) {
+                return Err(error_response(PeerAddressFamilyMismatch, &message));
+            }
+
+            if !self.peer_filter.is_allowed(peer.address().ip()) {
+                return Err(error_response(Forbidden, &message));
+            }
+        }
+
+        // Only install the permissions once we know that all of them are valid.
+        // Installing an existing permission refreshes it.
+        let id = allocation.id;
+        let mut peers = Vec::new();
+
+        for peer in message.xor_peer_addresses() {
+            let peer = peer.address().ip();
+            let expiry = allocation.install_permission(peer, now);
+
+            self.time_events
+                .add(expiry, TimedAction::ExpirePermission(id, peer));
+            peers.push(peer);
+        }
+
+        if let Some(deadline) = self.time_events.next_trigger() {
+            self.pending_commands.push_back(Command::Wake { deadline });
+        }
+
+        // Channels whose permission expired before are taken back onto the fast path.
+        for peer in peers {
+            for chan in self.bound_channels_to(id, peer) {
+                self.add_to_fast_path(chan);
+            }
+        }
+
+        tracing::info!(target: "relay", "Installed permissions");
+
+        self.send_message(
+            create_permission_success_response(message.transaction_id()),
+            sender,
+        );
+
+        Ok(())
+    }
+
+    /// Handle a TURN connect request.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.2> for details.
+    #[tracing::instrument(skip(self, request, now), fields(%sender, peer_address = %request.xor_peer_address().address(), allocation), level = "error")]
+    fn handle_connect_request(
+        &mut self,
+        request: Connect,
+        sender: ClientSocket,
+        now: SystemTime,
+    ) -> Result<(), Message<Attribute>> {
+        self.verify_auth(&request, now)?;
+
+        let allocation = self
+            .allocations
+            .get(&sender)
+            .ok_or(error_response(AllocationMismatch, &request))?;
+
+        Span::current().record("allocation", allocation.id.to_string());
+
+        if allocation.transport != Transport::Tcp {
+            return Err(error_response(BadRequest, &request));
+        }
+
+        let peer_address = request.xor_peer_address().address();
+
+        if !allocation.can_relay_to(peer_address) {
+            return Err(error_response(PeerAddressFamilyMismatch, &request));
+        }
+
+        if !self.peer_filter.is_allowed(peer_address.ip()) {
+            return Err(error_response(Forbidden, &request));
+        }
+
+        let allocation_id = allocation.id;
+
+        if self
+            .tcp_connections
+            .values()
+            .any(|c| c.allocation == allocation_id && c.peer_address == peer_address)
+        {
+            return Err(error_response(ConnectionAlreadyExists, &request));
+        }
+
+        let id = self.new_connection_id();
+
+        self.tcp_connections.insert(
+            id,
+            TcpConnection {
+                allocation: allocation_id,
+                peer_address,
+                state: TcpConnectionState::Connecting {
+                    transaction_id: request.transaction_id(),
+                },
+            },
+        );
+        self.pending_commands.push_back(Command::ConnectToPeer {
+            connection: id,
+            allocation: allocation_id,
+            peer: peer_address,
+        });
+
+        tracing::info!(target: "relay", connection = %id, "Connecting to peer");
+
+        Ok(())
+    }
+
+    /// Handle a TURN connection bind request.
+    ///
+    /// This request arrives on a new TCP connection of the client which, if successful, becomes the data connection for the given peer connection.
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.4> for details.
+    #[tracing::instrument(skip(self, request, now), fields(%sender, connection = %request.connection_id()), level = "error")]
+    fn handle_connection_bind_request(
+        &mut self,
+        request: ConnectionBind,
+        sender: ClientSocket,
+        now: SystemTime,
+    ) -> Result<(), Message<Attribute>> {
+        let user = self.verify_auth(&request, now)?;
+
+        // The data connection must be a fresh TCP connection, i.e. neither a control connection nor already bound.
+        if !sender.is_stream()
+            || self.allocations.contains_key(&sender)
+            || self
+                .tcp_connections
+                .values()
+                .any(|c| c.state == TcpConnectionState::Bound { client: sender })
+        {
+            return Err(error_response(BadRequest, &request));
+        }
+
+        let connection_id = request.connection_id();
+
+        let connection = self
+            .tcp_connections
+            .get_mut(&connection_id)
+            .ok_or(error_response(BadRequest, &request))?;
+
+        if !matches!(connection.state, TcpConnectionState::AwaitingBind { .. }) {
+            return Err(error_response(BadRequest, &request));
+        }
+
+        // Only the user that owns the allocation may take over its peer connections.
+        let owner = self
+            .clients_by_allocation
+            .get(&connection.allocation)
+            .and_then(|client| self.allocations.get(client))
+            .map(|allocation| allocation.user.as_str());
+        if owner != Some(user.as_str()) {
+            tracing::info!(target: "relay", %connection_id, "Refusing to bind connection of another user");
+
+            return Err(error_response(Forbidden, &request));
+        }
+
+        connection.state = TcpConnectionState::Bound { client: sender };
+
         self.send_message(
-            create_permission_success_response(message.transaction_id()),
+            connection_bind_success_response(request.transaction_id()),
             sender,
         );
+        self.pending_commands.push_back(Command::BindConnection {
+            connection: connection_id,
+            client: sender,
+        });
+
+        tracing::info!(target: "relay", "Successfully bound connection");
 
         Ok(())
     }
@@ -690,8 +1964,8 @@ where
     fn handle_channel_data_message(
         &mut self,
         message: ChannelData,
-        sender: SocketAddr,
-        _: SystemTime,
+        sender: ClientSocket,
+        now: SystemTime,
     ) {
         let channel_number = message.channel();
         let data = message.data();
@@ -710,11 +1984,25 @@ where
         }
 
         let recipient = channel.peer_address;
+        let allocation_id = channel.allocation;
         Span::current().record("recipient", field::display(&recipient));
 
+        if !self.consume_bandwidth(allocation_id, data.len(), now) {
+            return;
+        }
+
         tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
 
         self.data_relayed_counter.add(data.len() as u64, &[]);
+        if let Some(allocation) = self.get_allocation_mut(&allocation_id) {
+            allocation.client_to_peer_bytes += data.len() as u64;
+            allocation
+                .unreported_usage
+                .record_client_to_peer(data.len());
+        }
+        if let Some(channel) = self.channels_by_number.get_mut(&channel_number) {
+            channel.unreported_usage.record_client_to_peer(data.len());
+        }
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(data);
@@ -722,59 +2010,238 @@ where
         }
 
         self.pending_commands.push_back(Command::ForwardData {
-            id: channel.allocation,
+            id: allocation_id,
+            data: data.to_vec(),
+            receiver: recipient,
+        });
+    }
+
+    /// Handle a TURN send indication.
+    ///
+    /// Indications are never answered, thus the data is silently discarded if it cannot be relayed.
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-send-indication> for details.
+    #[tracing::instrument(skip(self, indication, now), fields(%sender, recipient = %indication.xor_peer_address().address(), allocation), level = "error")]
+    fn handle_send_indication(
+        &mut self,
+        indication: SendIndication,
+        sender: ClientSocket,
+        now: SystemTime,
+    ) {
+        let Some(allocation) = self.allocations.get(&sender) else {
+            tracing::debug!(target: "relay", "No allocation, discarding send indication");
+            return;
+        };
+
+        Span::current().record("allocation", allocation.id.to_string());
+
+        let recipient = indication.xor_peer_address().address();
+
+        if allocation.transport != Transport::Udp
+            || !allocation.can_relay_to(recipient)
+            || !self.peer_filter.is_allowed(recipient.ip())
+        {
+            tracing::debug!(target: "relay", "Cannot relay to peer, discarding send indication");
+            return;
+        }
+
+        if !allocation.has_permission(recipient.ip(), now) {
+            tracing::debug!(target: "relay", "No permission for peer, discarding send indication");
+            return;
+        }
+
+        let allocation_id = allocation.id;
+        let data = indication.data();
+
+        if !self.consume_bandwidth(allocation_id, data.len(), now) {
+            return;
+        }
+
+        tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
+
+        self.data_relayed_counter.add(data.len() as u64, &[]);
+        if let Some(allocation) = self.allocations.get_mut(&sender) {
+            allocation.client_to_peer_bytes += data.len() as u64;
+            allocation
+                .unreported_usage
+                .record_client_to_peer(data.len());
+        }
+
+        self.pending_commands.push_back(Command::ForwardData {
+            id: allocation_id,
             data: data.to_vec(),
             receiver: recipient,
         });
     }
 
+    /// Encodes a Data indication, carrying data from the given peer to the client.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-send-indication>.
+    fn data_indication(&mut self, peer: SocketAddr, bytes: &[u8]) -> Option<Vec<u8>> {
+        let mut message = Message::new(
+            MessageClass::Indication,
+            DATA,
+            TransactionId::new(self.rng.gen()),
+        );
+        message.add_attribute(XorPeerAddress::new(peer));
+        message.add_attribute(Data::new(bytes.to_vec()).ok()?);
+
+        self.encode_message(message)
+    }
+
+    /// Encodes a message to send to a client.
+    ///
+    /// Every message ends with a FINGERPRINT, allowing clients to tell it apart from other protocols multiplexed on the same port.
+    /// See <https://www.rfc-editor.org/rfc/rfc8489#section-7>.
+    fn encode_message(&mut self, mut message: Message<Attribute>) -> Option<Vec<u8>> {
+        let Ok(fingerprint) = Fingerprint::new(&message) else {
+            debug_assert!(false, "Computing the fingerprint should never fail");
+            return None;
+        };
+        message.add_attribute(fingerprint);
+
+        let Ok(bytes) = self.encoder.encode_into_bytes(message) else {
+            debug_assert!(false, "Encoding should never fail");
+            return None;
+        };
+
+        Some(bytes)
+    }
+
+    /// The number of the channel that is bound to the given peer on the given allocation, if any.
+    fn bound_channel(&self, peer: SocketAddr, allocation_id: AllocationId) -> Option<u16> {
+        let number = self.channel_numbers_by_peer.get(&peer)?;
+        let channel = self.channels_by_number.get(number)?;
+
+        (channel.bound && channel.allocation == allocation_id).then_some(*number)
+    }
+
     fn verify_auth(
         &mut self,
         request: &(impl StunRequest + ProtectedRequest),
         now: SystemTime,
-    ) -> Result<(), Message<Attribute>> {
-        let message_integrity = request
-            .message_integrity()
-            .map_err(|e| error_response(e, request))?;
-        let username = request.username().map_err(|e| error_response(e, request))?;
-        let nonce = request
-            .nonce()
-            .map_err(|e| error_response(e, request))?
-            .value()
-            .parse::<Uuid>()
+    ) -> Result<String, Message<Attribute>> {
+        let extensions = request.credential_extensions();
+        let (security_features, nonce) = SecurityFeatures::split_nonce(
+            request
+                .nonce()
+                .map_err(|e| error_response(e, request))?
+                .value(),
+        );
+        let nonce = nonce.parse::<Uuid>().map_err(|e| {
+            log::debug!("failed to parse nonce: {e}");
+
+            error_response(Unauthorized, request)
+        })?;
+
+        // Check the nonce before trusting the features it advertises.
+        self.nonces
+            .handle_nonce_used(nonce, security_features)
+            .map_err(|e| {
+                self.record_auth_failure(&e);
+
+                error_response(StaleNonce, request)
+            })?;
+
+        // Only clients that received a nonce advertising the password algorithms may pick one.
+        let password_algorithm = if security_features.password_algorithms {
+            extensions
+                .password_algorithm()
+                .ok_or_else(|| error_response(BadRequest, request))?
+        } else {
+            PasswordAlgorithm::Md5
+        };
+
+        let message_integrity = match (
+            extensions.message_integrity_sha256(),
+            request.message_integrity(),
+        ) {
+            (Some(message_integrity), _) => {
+                Integrity::Sha256(message_integrity, password_algorithm)
+            }
+            // MESSAGE-INTEGRITY is always keyed with the MD5 of the long-term credential.
+            (None, Some(message_integrity)) if password_algorithm == PasswordAlgorithm::Md5 => {
+                Integrity::Sha1(message_integrity)
+            }
+            (None, Some(_)) => return Err(error_response(BadRequest, request)),
+            (None, None) => return Err(error_response(Unauthorized, request)),
+        };
+
+        let username = match (request.username(), extensions.userhash()) {
+            (Some(username), _) => username.name().to_owned(),
+            (None, Some(userhash)) => {
+                self.authenticator
+                    .resolve_userhash(userhash)
+                    .ok_or_else(|| {
+                        self.record_auth_failure(&auth::Error::InvalidUsername);
+
+                        error_response(Unauthorized, request)
+                    })?
+            }
+            (None, None) => return Err(error_response(Unauthorized, request)),
+        };
+
+        let user = self
+            .authenticator
+            .verify(&message_integrity, &username, &self.auth_secret, now)
+            .or_else(|e| match &self.previous_auth_secret {
+                Some((previous, valid_until))
+                    if e == auth::Error::InvalidPassword && now <= *valid_until =>
+                {
+                    self.authenticator
+                        .verify(&message_integrity, &username, previous, now)
+                }
+                _ => Err(e),
+            })
             .map_err(|e| {
-                log::debug!("failed to parse nonce: {e}");
+                self.record_auth_failure(&e);
 
                 error_response(Unauthorized, request)
             })?;
 
-        self.nonces
-            .handle_nonce_used(nonce)
-            .map_err(|_| error_response(StaleNonce, request))?;
+        if self
+            .revoked_users
+            .get(&user)
+            .map_or(false, |until| now <= *until)
+        {
+            self.auth_failures_counter
+                .add(1, &[KeyValue::new("reason", "revoked")]);
+
+            return Err(error_response(Forbidden, request));
+        }
 
-        message_integrity
-            .verify(&self.auth_secret, username.name(), now)
-            .map_err(|_| error_response(Unauthorized, request))?;
+        Ok(user)
+    }
 
-        Ok(())
+    fn record_auth_failure(&self, error: &auth::Error) {
+        let reason = match error {
+            auth::Error::Expired => "expired",
+            auth::Error::InvalidPassword => "invalid_password",
+            auth::Error::InvalidUsername => "invalid_username",
+            auth::Error::InvalidNonce => "invalid_nonce",
+        };
+
+        self.auth_failures_counter
+            .add(1, &[KeyValue::new("reason", reason)]);
     }
 
     fn create_new_allocation(
         &mut self,
         now: SystemTime,
         lifetime: &Lifetime,
+        transport: Transport,
+        user: String,
         first_relay_addr: IpAddr,
         second_relay_addr: Option<IpAddr>,
     ) -> Allocation {
         // First, find an unused port.
 
         assert!(
-            self.allocations_by_port.len() < self.max_available_ports() as usize,
+            self.allocations_by_port.len() < self.max_available_ports(),
             "No more ports available; this would loop forever"
         );
 
         let port = loop {
-            let candidate = self.rng.gen_range(self.lowest_port..self.highest_port);
+            let candidate = self.rng.gen_range(self.lowest_port..=self.highest_port);
 
             if !self.allocations_by_port.contains_key(&candidate) {
                 break candidate;
@@ -782,21 +2249,143 @@ where
         };
 
         // Second, grab a new allocation ID.
-        let id = self.next_allocation_id.next();
+        let id = self.next_allocation_id.next(self.shard);
 
         self.allocations_by_port.insert(port, id);
+        self.allocated_ports
+            .store(self.allocations_by_port.len(), Ordering::Relaxed);
+
+        if let Some(limit) = self.bandwidth_limits.per_user {
+            self.user_buckets
+                .entry(user.clone())
+                .or_insert_with(|| TokenBucket::new(limit, now));
+        }
 
         Allocation {
             id,
             port,
             expires_at: now + lifetime.lifetime(),
+            transport,
+            bucket: self
+                .bandwidth_limits
+                .per_allocation
+                .map(|limit| TokenBucket::new(limit, now)),
+            user,
+            permissions: HashMap::new(),
+            client_to_peer_bytes: 0,
+            peer_to_client_bytes: 0,
+            unreported_usage: Usage::default(),
+            mobility_ticket: None,
             first_relay_addr,
             second_relay_addr,
         }
     }
 
-    fn max_available_ports(&self) -> u16 {
-        self.highest_port - self.lowest_port
+    /// Consumes spliced `bytes` from the bandwidth budgets of the given allocation and its user.
+    ///
+    /// Spliced data has already been relayed and cannot be dropped.
+    /// Instead, the data connection is paused until the allocation and its user are within their limits again.
+    fn consume_spliced_bandwidth(
+        &mut self,
+        client: ClientSocket,
+        allocation_id: AllocationId,
+        bytes: usize,
+        now: SystemTime,
+    ) {
+        let Some(allocation) = self
+            .clients_by_allocation
+            .get(&allocation_id)
+            .and_then(|client| self.allocations.get_mut(client))
+        else {
+            return;
+        };
+        let user_bucket = self.user_buckets.get_mut(&allocation.user);
+
+        let Some(until) = [allocation.bucket.as_mut(), user_bucket]
+            .into_iter()
+            .flatten()
+            .filter_map(|bucket| bucket.consume_overdrawn(bytes, now))
+            .max()
+        else {
+            return;
+        };
+
+        tracing::debug!(target: "relay", %client, "Data connection exceeded its bandwidth limit, pausing it until {until:?}");
+
+        self.pending_commands
+            .push_back(Command::PauseConnection { client, until });
+    }
+
+    /// Consumes `bytes` from the bandwidth budgets of the given allocation and its user.
+    ///
+    /// Returns `false` if any of the limits would be exceeded, in which case the data must be dropped.
+    fn consume_bandwidth(
+        &mut self,
+        allocation_id: AllocationId,
+        bytes: usize,
+        now: SystemTime,
+    ) -> bool {
+        let Some(allocation) = self
+            .clients_by_allocation
+            .get(&allocation_id)
+            .and_then(|client| self.allocations.get_mut(client))
+        else {
+            return true;
+        };
+        let mut user_bucket = self.user_buckets.get_mut(&allocation.user);
+
+        if let Some(bucket) = allocation.bucket.as_mut() {
+            if !bucket.has_capacity(bytes, now) {
+                tracing::debug!(target: "relay", "Allocation exceeded its bandwidth limit, dropping {bytes} bytes");
+                self.data_dropped_counter
+                    .add(bytes as u64, &[KeyValue::new("limit", "allocation")]);
+
+                return false;
+            }
+        }
+
+        if let Some(bucket) = user_bucket.as_mut() {
+            if !bucket.has_capacity(bytes, now) {
+                tracing::debug!(target: "relay", "User exceeded their bandwidth limit, dropping {bytes} bytes");
+                self.data_dropped_counter
+                    .add(bytes as u64, &[KeyValue::new("limit", "user")]);
+
+                return false;
+            }
+        }
+
+        if let Some(bucket) = allocation.bucket.as_mut() {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = user_bucket {
+            bucket.consume(bytes);
+        }
+
+        true
+    }
+
+    fn new_connection_id(&mut self) -> ConnectionId {
+        loop {
+            let candidate = self.shard.connection_id(self.rng.gen());
+
+            if !self.tcp_connections.contains_key(&candidate) {
+                break candidate;
+            }
+        }
+    }
+
+    /// Whether all ports are in use, i.e. we cannot accept any new allocations.
+    pub fn is_port_pool_exhausted(&self) -> bool {
+        self.allocations_by_port.len() >= self.max_available_ports()
+    }
+
+    /// The fraction of the port range that is in use by allocations.
+    fn port_pool_utilization(&self) -> f64 {
+        self.allocations_by_port.len() as f64 / self.max_available_ports() as f64
+    }
+
+    fn max_available_ports(&self) -> usize {
+        num_ports(self.lowest_port, self.highest_port)
     }
 
     fn create_channel_binding(
@@ -813,19 +2402,113 @@ where
                 peer_address,
                 allocation: id,
                 bound: true,
+                unreported_usage: Usage::default(),
             },
         );
         self.channel_numbers_by_peer
             .insert(peer_address, requested_channel);
+
+        self.add_to_fast_path(requested_channel);
+    }
+
+    fn bound_channels(&self) -> Vec<u16> {
+        self.channels_by_number
+            .iter()
+            .filter(|(_, c)| c.bound)
+            .map(|(number, _)| *number)
+            .collect()
+    }
+
+    fn bound_channels_to(&self, id: AllocationId, peer: IpAddr) -> Vec<u16> {
+        self.channels_by_number
+            .iter()
+            .filter(|(_, c)| c.allocation == id && c.bound && c.peer_address.ip() == peer)
+            .map(|(number, _)| *number)
+            .collect()
+    }
+
+    fn add_to_fast_path(&mut self, chan: u16) {
+        if let Some((allocation, client, peer, port)) = self.fast_path_binding(chan) {
+            self.pending_commands
+                .push_back(Command::CreateChannelBinding {
+                    allocation,
+                    client,
+                    channel: chan,
+                    peer,
+                    port,
+                });
+        }
+    }
+
+    fn remove_from_fast_path(&mut self, chan: u16) {
+        if let Some((allocation, client, peer, port)) = self.fast_path_binding(chan) {
+            self.pending_commands
+                .push_back(Command::DeleteChannelBinding {
+                    allocation,
+                    client,
+                    channel: chan,
+                    peer,
+                    port,
+                });
+        }
+    }
+
+    /// The allocation, client, peer and allocation port of the given channel if we mirror channel bindings to a fast path.
+    ///
+    /// Only bound channels are part of the fast path, it is up to the caller to check that.
+    /// Clients connected via TCP or TLS never are: the fast path only sees UDP datagrams.
+    /// Neither are rate-limited allocations and channels to peers without a permission, the fast path enforces neither.
+    /// Thus, callers must take channels off the fast path before they change the limits or remove the permission.
+    fn fast_path_binding(&self, chan: u16) -> Option<(AllocationId, SocketAddr, SocketAddr, u16)> {
+        if !self.fast_path {
+            return None;
+        }
+
+        let channel = self.channels_by_number.get(&chan)?;
+        let client = self
+            .clients_by_allocation
+            .get(&channel.allocation)
+            .filter(|client| !client.is_stream())?;
+        let allocation = self.allocations.get(client)?;
+
+        if allocation.bucket.is_some()
+            || self.bandwidth_limits.per_user.is_some()
+            || !allocation
+                .permissions
+                .contains_key(&channel.peer_address.ip())
+        {
+            return None;
+        }
+
+        Some((
+            channel.allocation,
+            client.addr,
+            channel.peer_address,
+            allocation.port,
+        ))
     }
 
-    fn send_message(&mut self, message: Message<Attribute>, recipient: SocketAddr) {
+    fn send_message(&mut self, message: Message<Attribute>, recipient: ClientSocket) {
+        self.send_message_from(message, recipient, DiscoveryOrigin::PRIMARY)
+    }
+
+    fn send_message_from(
+        &mut self,
+        mut message: Message<Attribute>,
+        recipient: ClientSocket,
+        origin: DiscoveryOrigin,
+    ) {
         let method = message.method();
         let class = message.class();
         tracing::trace!(target: "relay",  method = %message.method(), class = %message.class(), "Sending message");
 
-        let Ok(bytes) = self.encoder.encode_into_bytes(message) else {
-            debug_assert!(false, "Encoding should never fail");
+        if let (Some(software), MessageClass::SuccessResponse | MessageClass::ErrorResponse) =
+            (&self.software, class)
+        {
+            message.add_attribute(software.clone());
+        }
+
+        let Some(bytes) = self.encode_message(message) else {
             return;
         };
 
@@ -834,10 +2517,18 @@ where
             tracing::trace!(target: "wire", %hex_bytes, "sending bytes");
         }
 
-        self.pending_commands.push_back(Command::SendMessage {
-            payload: bytes,
-            recipient,
-        });
+        if origin == DiscoveryOrigin::PRIMARY {
+            self.pending_commands.push_back(Command::SendMessage {
+                payload: bytes,
+                recipient,
+            });
+        } else {
+            self.pending_commands.push_back(Command::SendMessageFrom {
+                payload: bytes,
+                recipient: recipient.addr,
+                origin,
+            });
+        }
 
         // record metrics
         let response_class = match class {
@@ -851,6 +2542,8 @@ where
             REFRESH => "refresh",
             CHANNEL_BIND => "channelbind",
             CREATE_PERMISSION => "createpermission",
+            method if method == *CONNECT => "connect",
+            method if method == *CONNECTION_BIND => "connectionbind",
             _ => return,
         };
         self.responses_counter.add(
@@ -868,7 +2561,29 @@ where
             .and_then(|client| self.allocations.get(client))
     }
 
+    fn get_allocation_mut(&mut self, id: &AllocationId) -> Option<&mut Allocation> {
+        self.clients_by_allocation
+            .get(id)
+            .and_then(|client| self.allocations.get_mut(client))
+    }
+
     fn delete_allocation(&mut self, id: AllocationId) {
+        let channels = self
+            .channels_by_number
+            .iter()
+            .filter(|(_, c)| c.allocation == id && c.bound)
+            .map(|(number, _)| *number)
+            .collect::<Vec<_>>();
+
+        // The relay port of the allocation is about to be freed, the fast path must not use it anymore.
+        for channel in channels {
+            self.remove_from_fast_path(channel);
+        }
+
+        if self.usage_report_interval.is_some() {
+            self.report_usage(id, true);
+        }
+
         let Some(client) = self.clients_by_allocation.remove(&id) else {
             tracing::debug!("Unknown allocation");
 
@@ -882,6 +2597,15 @@ where
         let port = allocation.port;
 
         self.allocations_by_port.remove(&port);
+        self.allocated_ports
+            .store(self.allocations_by_port.len(), Ordering::Relaxed);
+        if let Some(ticket) = &allocation.mobility_ticket {
+            self.allocations_by_mobility_ticket.remove(ticket);
+        }
+
+        if !self.allocations.values().any(|a| a.user == allocation.user) {
+            self.user_buckets.remove(&allocation.user);
+        }
 
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
@@ -895,9 +2619,58 @@ where
             })
         }
 
+        let connections = self
+            .tcp_connections
+            .iter()
+            .filter(|(_, c)| c.allocation == id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for connection in connections {
+            self.delete_tcp_connection(connection);
+        }
+
         tracing::info!(target: "relay", %port, "Deleted allocation");
     }
 
+    /// Reports the usage of the given allocation and its channels since the previous report.
+    fn report_usage(&mut self, id: AllocationId, is_final: bool) {
+        let channels = self
+            .channels_by_number
+            .iter_mut()
+            .filter(|(_, channel)| channel.allocation == id)
+            .filter(|(_, channel)| !channel.unreported_usage.is_empty())
+            .map(|(number, channel)| ChannelUsage {
+                number: *number,
+                peer: channel.peer_address,
+                usage: mem::take(&mut channel.unreported_usage),
+            })
+            .collect();
+        let Some(allocation) = self.get_allocation_mut(&id) else {
+            return;
+        };
+
+        let record = UsageRecord {
+            allocation: id,
+            user: allocation.user.clone(),
+            usage: mem::take(&mut allocation.unreported_usage),
+            channels,
+            is_final,
+        };
+
+        self.pending_commands
+            .push_back(Command::ReportUsage(record));
+    }
+
+    fn delete_tcp_connection(&mut self, id: ConnectionId) {
+        if self.tcp_connections.remove(&id).is_none() {
+            return;
+        }
+
+        self.pending_commands
+            .push_back(Command::CloseConnection { connection: id });
+    }
+
     fn delete_channel_binding(&mut self, chan: u16) {
         let Some(channel) = self.channels_by_number.get(&chan) else {
             return;
@@ -905,11 +2678,43 @@ where
 
         let addr = channel.peer_address;
 
+        // Unbound channels have already been removed from the fast path.
+        if channel.bound {
+            self.remove_from_fast_path(chan);
+        }
+
         self.channel_numbers_by_peer.remove(&addr);
         self.channels_by_number.remove(&chan);
     }
 }
 
+/// The label of a request in our metrics, `None` for channel data.
+fn message_type(message: &ClientMessage) -> Option<&'static str> {
+    let message_type = match message {
+        ClientMessage::Binding(_) => "binding",
+        ClientMessage::Allocate(_) => "allocate",
+        ClientMessage::Refresh(_) => "refresh",
+        ClientMessage::ChannelBind(_) => "channelbind",
+        ClientMessage::CreatePermission(_) => "createpermission",
+        ClientMessage::Connect(_) => "connect",
+        ClientMessage::ConnectionBind(_) => "connectionbind",
+        ClientMessage::ChannelData(_) | ClientMessage::SendIndication(_) => return None,
+    };
+
+    Some(message_type)
+}
+
+/// The number of ports in the range `lowest_port` - `highest_port`, both inclusive.
+fn num_ports(lowest_port: u16, highest_port: u16) -> usize {
+    usize::from(highest_port - lowest_port) + 1
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 fn refresh_success_response(
     effective_lifetime: Lifetime,
     transaction_id: TransactionId,
@@ -923,6 +2728,14 @@ fn channel_bind_success_response(transaction_id: TransactionId) -> Message<Attri
     Message::new(MessageClass::SuccessResponse, CHANNEL_BIND, transaction_id)
 }
 
+fn connection_bind_success_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::new(
+        MessageClass::SuccessResponse,
+        *CONNECTION_BIND,
+        transaction_id,
+    )
+}
+
 fn create_permission_success_response(transaction_id: TransactionId) -> Message<Attribute> {
     Message::new(
         MessageClass::SuccessResponse,
@@ -932,16 +2745,73 @@ fn create_permission_success_response(transaction_id: TransactionId) -> Message<
 }
 
 /// Represents an allocation of a client.
+#[derive(Clone, Serialize, Deserialize)]
 struct Allocation {
     id: AllocationId,
-    /// Data arriving on this port will be forwarded to the client iff there is an active data channel.
+    /// Data arriving on this port will be forwarded to the client iff there is an active data channel or permission.
     port: u16,
     expires_at: SystemTime,
+    transport: Transport,
+
+    /// The user that created this allocation, as identified by the [`Authenticator`].
+    #[serde(alias = "username_salt")]
+    user: String,
+    /// The bandwidth budget of this allocation, if limited.
+    #[serde(skip)]
+    bucket: Option<TokenBucket>,
+    /// The IP addresses of the peers that the client installed a permission for, together with when the permission expires.
+    #[serde(default)]
+    permissions: HashMap<IpAddr, SystemTime>,
+
+    #[serde(default)]
+    client_to_peer_bytes: u64,
+    #[serde(default)]
+    peer_to_client_bytes: u64,
+    /// The data relayed since the last [`UsageRecord`] of this allocation.
+    #[serde(default)]
+    unreported_usage: Usage,
+    /// The ticket that allows the client to move this allocation to a new 5-tuple, if it asked for mobility.
+    #[serde(default)]
+    mobility_ticket: Option<MobilityTicket>,
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
 }
 
+/// The transport protocol between the relay and the peers of an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// A TCP connection between the relay and a peer of a TCP allocation.
+struct TcpConnection {
+    allocation: AllocationId,
+    peer_address: SocketAddr,
+    state: TcpConnectionState,
+}
+
+#[derive(Debug, PartialEq)]
+enum TcpConnectionState {
+    /// We are connecting to the peer on behalf of a client's connect request.
+    Connecting { transaction_id: TransactionId },
+    /// The connection to the peer is established and waits for the client to bind it to a data connection.
+    AwaitingBind { expires_at: SystemTime },
+    /// The connection is bound to the given data connection of the client.
+    Bound { client: ClientSocket },
+}
+
+impl TcpConnection {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        match self.state {
+            TcpConnectionState::AwaitingBind { expires_at } => expires_at <= now,
+            TcpConnectionState::Connecting { .. } | TcpConnectionState::Bound { .. } => false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct Channel {
     /// When the channel expires.
     expiry: SystemTime,
@@ -962,6 +2832,10 @@ struct Channel {
     ///
     /// With the data structure still existing while the channel is unbound, our existing validations cover the above requirement.
     bound: bool,
+
+    /// The data relayed through this channel since the last [`UsageRecord`] of its allocation.
+    #[serde(default)]
+    unreported_usage: Usage,
 }
 
 impl Channel {
@@ -979,6 +2853,31 @@ impl Allocation {
         // Currently, we only support IPv4, thus any IPv6 address is invalid.
         addr.is_ipv4()
     }
+
+    /// Whether the client has a permission for the given peer.
+    ///
+    /// Expired permissions are only removed once their [`TimedAction::ExpirePermission`] fires, thus we need to check the expiry here too.
+    fn has_permission(&self, peer: IpAddr, now: SystemTime) -> bool {
+        self.permissions
+            .get(&peer)
+            .map_or(false, |expiry| *expiry > now)
+    }
+
+    /// Installs or refreshes the permission for the given peer, returning when it expires.
+    fn install_permission(&mut self, peer: IpAddr, now: SystemTime) -> SystemTime {
+        let expiry = now + PERMISSION_LIFETIME;
+        self.permissions.insert(peer, expiry);
+
+        expiry
+    }
+
+    fn expire_permission(&mut self, peer: IpAddr, now: SystemTime) -> bool {
+        if self.has_permission(peer, now) {
+            return false;
+        }
+
+        self.permissions.remove(&peer).is_some()
+    }
 }
 
 impl Allocation {
@@ -987,11 +2886,27 @@ impl Allocation {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 enum TimedAction {
     ExpireAllocation(AllocationId),
     UnbindChannel(u16),
     DeleteChannel(u16),
+    ExpireConnection(ConnectionId),
+    ExpirePermission(AllocationId, IpAddr),
+    ReportUsage(AllocationId),
+    ForgetRevocation(String),
+}
+
+/// A 420 (Unknown Attribute) error response for a Binding request whose CHANGE-REQUEST we cannot honor.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc5780#section-6.1>.
+fn unknown_change_request(request: &Binding) -> Message<Attribute> {
+    let mut message = error_response(UnknownAttribute, request);
+    message.add_attribute(UnknownAttributes::new(vec![AttributeType::new(
+        ChangeRequest::CODEPOINT,
+    )]));
+
+    message
 }
 
 fn error_response(
@@ -1065,32 +2980,42 @@ macro_rules! impl_stun_request_for {
     };
 }
 
+impl_stun_request_for!(Binding, BINDING);
 impl_stun_request_for!(Allocate, ALLOCATE);
 impl_stun_request_for!(ChannelBind, CHANNEL_BIND);
 impl_stun_request_for!(CreatePermission, CREATE_PERMISSION);
 impl_stun_request_for!(Refresh, REFRESH);
+impl_stun_request_for!(Connect, *CONNECT);
+impl_stun_request_for!(ConnectionBind, *CONNECTION_BIND);
 
 /// Private helper trait to make [`Server::verify_auth`] more ergonomic to use.
+///
+/// Requests may use the MESSAGE-INTEGRITY-SHA256 and USERHASH of the [`CredentialExtensions`] instead, thus those are optional.
 trait ProtectedRequest {
-    fn message_integrity(&self) -> Result<&MessageIntegrity, Unauthorized>;
-    fn username(&self) -> Result<&Username, Unauthorized>;
+    fn message_integrity(&self) -> Option<&MessageIntegrity>;
+    fn username(&self) -> Option<&Username>;
     fn nonce(&self) -> Result<&Nonce, Unauthorized>;
+    fn credential_extensions(&self) -> &CredentialExtensions;
 }
 
 macro_rules! impl_protected_request_for {
     ($t:ty) => {
         impl ProtectedRequest for $t {
-            fn message_integrity(&self) -> Result<&MessageIntegrity, Unauthorized> {
-                self.message_integrity().ok_or(Unauthorized)
+            fn message_integrity(&self) -> Option<&MessageIntegrity> {
+                self.message_integrity()
             }
 
-            fn username(&self) -> Result<&Username, Unauthorized> {
-                self.username().ok_or(Unauthorized)
+            fn username(&self) -> Option<&Username> {
+                self.username()
             }
 
             fn nonce(&self) -> Result<&Nonce, Unauthorized> {
                 self.nonce().ok_or(Unauthorized)
             }
+
+            fn credential_extensions(&self) -> &CredentialExtensions {
+                self.credential_extensions()
+            }
         }
     };
 }
@@ -1099,6 +3024,8 @@ impl_protected_request_for!(Allocate);
 impl_protected_request_for!(ChannelBind);
 impl_protected_request_for!(CreatePermission);
 impl_protected_request_for!(Refresh);
+impl_protected_request_for!(Connect);
+impl_protected_request_for!(ConnectionBind);
 
 // Define an enum of all attributes that we care about for our server.
 stun_codec::define_attribute_enums!(
@@ -1107,24 +3034,39 @@ stun_codec::define_attribute_enums!(
     AttributeEncoder,
     [
         MessageIntegrity,
+        MessageIntegritySha256,
+        PasswordAlgorithm,
+        PasswordAlgorithms,
+        Userhash,
         XorMappedAddress,
         ErrorCode,
+        AlternateServer,
+        Fingerprint,
+        Software,
         RequestedTransport,
         XorRelayAddress,
         Lifetime,
         ChannelNumber,
         XorPeerAddress,
+        Data,
         Nonce,
         Realm,
         Username,
         RequestedAddressFamily,
-        AdditionalAddressFamily
+        AdditionalAddressFamily,
+        ConnectionId,
+        MobilityTicket,
+        ChangeRequest,
+        OtherAddress,
+        ResponseOrigin,
+        UnknownAttributes
     ]
 );
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::mock::StepRng;
     use std::net::{Ipv4Addr, Ipv6Addr};
 
     // Tests for requirements listed in https://www.rfc-editor.org/rfc/rfc8656#name-receiving-an-allocate-reque.
@@ -1182,4 +3124,11 @@ mod tests {
 
         assert_eq!(error_code.code(), BadRequest::CODEPOINT)
     }
+
+    #[test]
+    fn port_range_includes_highest_port() {
+        let server = Server::new(Ipv4Addr::LOCALHOST, StepRng::new(0, 0), 49152, 65535);
+
+        assert_eq!(server.max_available_ports(), 16384);
+    }
 }
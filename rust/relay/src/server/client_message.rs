@@ -1,4 +1,11 @@
 use crate::auth::{generate_password, split_username, systemtime_from_unix, FIREZONE};
+use crate::rfc5780::ChangeRequest;
+use crate::rfc6062::{ConnectionId, CONNECT, CONNECTION_BIND, TCP_TRANSPORT};
+use crate::rfc8016::MobilityTicket;
+use crate::rfc8489::{
+    MessageIntegritySha256, PasswordAlgorithm, PasswordAlgorithms, SecurityFeatures, Userhash,
+    PASSWORD_ALGORITHMS,
+};
 use crate::server::channel_data::ChannelData;
 use crate::server::UDP_TRANSPORT;
 use crate::Attribute;
@@ -10,9 +17,9 @@ use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, Nonce, Userna
 use stun_codec::rfc5389::errors::BadRequest;
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
-    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress,
+    ChannelNumber, Data, Lifetime, RequestedTransport, XorPeerAddress,
 };
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH, SEND};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
 };
@@ -27,6 +34,15 @@ const MAX_ALLOCATION_LIFETIME: Duration = Duration::from_secs(3600);
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-allocations-2>.
 const DEFAULT_ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
 
+const MESSAGE_HEADER_LEN: usize = 20;
+const ATTRIBUTE_HEADER_LEN: usize = 4;
+
+/// The type of the FINGERPRINT attribute.
+const FINGERPRINT: u16 = 0x8028;
+
+/// The length of the FINGERPRINT attribute, including its header.
+const FINGERPRINT_ATTRIBUTE_LEN: usize = ATTRIBUTE_HEADER_LEN + 4;
+
 #[derive(Default)]
 pub struct Decoder {
     stun_message_decoder: stun_codec::MessageDecoder<Attribute>,
@@ -40,8 +56,15 @@ impl Decoder {
         // De-multiplex as per <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
         match input.first() {
             Some(0..=3) => {
-                let message = match self.stun_message_decoder.decode_from_bytes(input)? {
+                let decoded = match self.stun_message_decoder.decode_from_bytes(input) {
+                    Ok(decoded) => decoded,
+                    Err(_) if has_bad_fingerprint(input) => return Err(Error::BadFingerprint),
+                    Err(error) => return Err(Error::DecodeStun(error)),
+                };
+
+                let message = match decoded {
                     Ok(message) => message,
+                    Err(_) if has_bad_fingerprint(input) => return Err(Error::BadFingerprint),
                     Err(broken_message) => {
                         let method = broken_message.method();
                         let transaction_id = broken_message.transaction_id();
@@ -49,6 +72,11 @@ impl Decoder {
 
                         tracing::debug!(transaction_id = %hex::encode(transaction_id.as_bytes()), %method, %error, "Failed to decode attributes of message");
 
+                        // Indications are never answered, not even with an error.
+                        if broken_message.class() == MessageClass::Indication {
+                            return Err(Error::DecodeStun(error));
+                        }
+
                         let error_code = ErrorCode::from(error);
 
                         return Ok(Err(error_response(method, transaction_id, error_code)));
@@ -68,7 +96,16 @@ impl Decoder {
                     (CREATE_PERMISSION, Request) => Ok(Ok(ClientMessage::CreatePermission(
                         CreatePermission::parse(&message),
                     ))),
+                    (method, Request) if method == *CONNECT => {
+                        Ok(Connect::parse(&message).map(ClientMessage::Connect))
+                    }
+                    (method, Request) if method == *CONNECTION_BIND => {
+                        Ok(ConnectionBind::parse(&message).map(ClientMessage::ConnectionBind))
+                    }
                     (_, Request) => Ok(Err(bad_request(&message))),
+                    // Indications are never answered, thus a malformed one is a decoding error.
+                    (SEND, Indication) => SendIndication::parse(&message)
+                        .map(|indication| Ok(ClientMessage::SendIndication(indication))),
                     (method, class) => {
                         Err(Error::DecodeStun(bytecodec::Error::from(io::Error::new(
                             io::ErrorKind::Unsupported,
@@ -95,6 +132,9 @@ pub enum ClientMessage<'a> {
     Refresh(Refresh),
     ChannelBind(ChannelBind),
     CreatePermission(CreatePermission),
+    Connect(Connect),
+    ConnectionBind(ConnectionBind),
+    SendIndication(SendIndication),
 }
 
 impl<'a> ClientMessage<'a> {
@@ -105,6 +145,9 @@ impl<'a> ClientMessage<'a> {
             ClientMessage::Refresh(request) => Some(request.transaction_id),
             ClientMessage::ChannelBind(request) => Some(request.transaction_id),
             ClientMessage::CreatePermission(request) => Some(request.transaction_id),
+            ClientMessage::Connect(request) => Some(request.transaction_id),
+            ClientMessage::ConnectionBind(request) => Some(request.transaction_id),
+            ClientMessage::SendIndication(indication) => Some(indication.transaction_id),
             ClientMessage::ChannelData(_) => None,
         }
     }
@@ -113,22 +156,45 @@ impl<'a> ClientMessage<'a> {
 #[derive(Debug)]
 pub struct Binding {
     transaction_id: TransactionId,
+    change_request: Option<ChangeRequest>,
 }
 
 impl Binding {
     pub fn new(transaction_id: TransactionId) -> Self {
-        Self { transaction_id }
+        Self {
+            transaction_id,
+            change_request: None,
+        }
+    }
+
+    /// A Binding request that asks for the response to be sent from a different address and / or port, see [`ChangeRequest`].
+    pub fn new_with_change_request(
+        transaction_id: TransactionId,
+        change_request: ChangeRequest,
+    ) -> Self {
+        Self {
+            transaction_id,
+            change_request: Some(change_request),
+        }
     }
 
     pub fn parse(message: &Message<Attribute>) -> Self {
         let transaction_id = message.transaction_id();
+        let change_request = message.get_attribute::<ChangeRequest>().copied();
 
-        Binding { transaction_id }
+        Binding {
+            transaction_id,
+            change_request,
+        }
     }
 
     pub fn transaction_id(&self) -> TransactionId {
         self.transaction_id
     }
+
+    pub fn change_request(&self) -> Option<ChangeRequest> {
+        self.change_request
+    }
 }
 
 pub struct Allocate {
@@ -138,8 +204,10 @@ pub struct Allocate {
     lifetime: Option<Lifetime>,
     username: Option<Username>,
     nonce: Option<Nonce>,
+    credential_extensions: CredentialExtensions,
     requested_address_family: Option<RequestedAddressFamily>,
     additional_address_family: Option<AdditionalAddressFamily>,
+    mobility_ticket: Option<MobilityTicket>,
 }
 
 impl Allocate {
@@ -152,10 +220,13 @@ impl Allocate {
     ) -> Self {
         let (requested_transport, nonce, message_integrity) = Self::make_attributes(
             transaction_id,
+            UDP_TRANSPORT,
             &lifetime,
             &username,
             relay_secret,
             nonce,
+            SecurityFeatures::default(),
+            None,
             None,
         );
 
@@ -166,8 +237,43 @@ impl Allocate {
             lifetime,
             username: Some(username),
             nonce: Some(nonce),
+            credential_extensions: Default::default(),
             requested_address_family: None, // IPv4 is the default.
             additional_address_family: None,
+            mobility_ticket: None,
+        }
+    }
+
+    pub fn new_authenticated_tcp_implicit_ip4(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            TCP_TRANSPORT,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            SecurityFeatures::default(),
+            None,
+            None,
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            credential_extensions: Default::default(),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            mobility_ticket: None,
         }
     }
 
@@ -182,11 +288,14 @@ impl Allocate {
 
         let (requested_transport, nonce, message_integrity) = Self::make_attributes(
             transaction_id,
+            UDP_TRANSPORT,
             &lifetime,
             &username,
             relay_secret,
             nonce,
+            SecurityFeatures::default(),
             Some(requested_address_family.clone()),
+            None,
         );
 
         Self {
@@ -196,8 +305,81 @@ impl Allocate {
             lifetime,
             username: Some(username),
             nonce: Some(nonce),
+            credential_extensions: Default::default(),
             requested_address_family: Some(requested_address_family),
             additional_address_family: None,
+            mobility_ticket: None,
+        }
+    }
+
+    /// An allocate request for a UDP allocation that asks for mobility, see <https://www.rfc-editor.org/rfc/rfc8016>.
+    pub fn new_authenticated_udp_with_mobility(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let mobility_ticket = MobilityTicket::empty();
+
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            UDP_TRANSPORT,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            SecurityFeatures::default(),
+            None,
+            Some(&mobility_ticket),
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            credential_extensions: Default::default(),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            mobility_ticket: Some(mobility_ticket),
+        }
+    }
+
+    /// An allocate request that presents a nonce we issued together with the given [`SecurityFeatures`].
+    pub fn new_authenticated_udp_with_security_features(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+        security_features: SecurityFeatures,
+    ) -> Self {
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            UDP_TRANSPORT,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            security_features,
+            None,
+            None,
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            credential_extensions: Default::default(),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            mobility_ticket: None,
         }
     }
 
@@ -222,21 +404,32 @@ impl Allocate {
             lifetime,
             username: None,
             nonce: None,
+            credential_extensions: Default::default(),
             requested_address_family: None,
             additional_address_family: None,
+            mobility_ticket: None,
         }
     }
 
     fn make_attributes(
         transaction_id: TransactionId,
+        protocol: u8,
         lifetime: &Option<Lifetime>,
         username: &Username,
         relay_secret: &SecretString,
         nonce: Uuid,
+        security_features: SecurityFeatures,
         requested_address_family: Option<RequestedAddressFamily>,
+        mobility_ticket: Option<&MobilityTicket>,
     ) -> (RequestedTransport, Nonce, MessageIntegrity) {
-        let requested_transport = RequestedTransport::new(UDP_TRANSPORT);
-        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+        let requested_transport = RequestedTransport::new(protocol);
+        let nonce = nonce.as_hyphenated().to_string();
+        let nonce = if security_features == SecurityFeatures::default() {
+            Nonce::new(nonce)
+        } else {
+            Nonce::new(security_features.prefix_nonce(&nonce))
+        }
+        .expect("len(uuid) < 128");
 
         let mut message =
             Message::<Attribute>::new(MessageClass::Request, ALLOCATE, transaction_id);
@@ -248,6 +441,10 @@ impl Allocate {
             message.add_attribute(requested_address_family);
         }
 
+        if let Some(mobility_ticket) = mobility_ticket {
+            message.add_attribute(mobility_ticket.clone());
+        }
+
         if let Some(lifetime) = &lifetime {
             message.add_attribute(lifetime.clone());
         }
@@ -275,6 +472,7 @@ impl Allocate {
         let username = message.get_attribute::<Username>().cloned();
         let requested_address_family = message.get_attribute::<RequestedAddressFamily>().cloned();
         let additional_address_family = message.get_attribute::<AdditionalAddressFamily>().cloned();
+        let mobility_ticket = message.get_attribute::<MobilityTicket>().cloned();
 
         Ok(Allocate {
             transaction_id,
@@ -283,8 +481,10 @@ impl Allocate {
             lifetime,
             username,
             nonce,
+            credential_extensions: CredentialExtensions::parse(message),
             requested_address_family,
             additional_address_family,
+            mobility_ticket,
         })
     }
 
@@ -312,6 +512,10 @@ impl Allocate {
         self.nonce.as_ref()
     }
 
+    pub fn credential_extensions(&self) -> &CredentialExtensions {
+        &self.credential_extensions
+    }
+
     pub fn requested_address_family(&self) -> Option<&RequestedAddressFamily> {
         self.requested_address_family.as_ref()
     }
@@ -319,6 +523,10 @@ impl Allocate {
     pub fn additional_address_family(&self) -> Option<&AdditionalAddressFamily> {
         self.additional_address_family.as_ref()
     }
+
+    pub fn mobility_ticket(&self) -> Option<&MobilityTicket> {
+        self.mobility_ticket.as_ref()
+    }
 }
 
 pub struct Refresh {
@@ -327,6 +535,8 @@ pub struct Refresh {
     lifetime: Option<Lifetime>,
     username: Option<Username>,
     nonce: Option<Nonce>,
+    credential_extensions: CredentialExtensions,
+    mobility_ticket: Option<MobilityTicket>,
 }
 
 impl Refresh {
@@ -336,6 +546,27 @@ impl Refresh {
         username: Username,
         relay_secret: &SecretString,
         nonce: Uuid,
+    ) -> Self {
+        Self::new_with_mobility_ticket(
+            transaction_id,
+            lifetime,
+            None,
+            username,
+            relay_secret,
+            nonce,
+        )
+    }
+
+    /// A refresh request that carries the [`MobilityTicket`] of an allocation, e.g. to move it to a new 5-tuple.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8016#section-3.3>.
+    pub fn new_with_mobility_ticket(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        mobility_ticket: Option<MobilityTicket>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
     ) -> Self {
         let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
 
@@ -347,6 +578,10 @@ impl Refresh {
             message.add_attribute(lifetime.clone());
         }
 
+        if let Some(mobility_ticket) = &mobility_ticket {
+            message.add_attribute(mobility_ticket.clone());
+        }
+
         let (expiry, salt) = split_username(username.name()).expect("a valid username");
         let expiry_systemtime = systemtime_from_unix(expiry);
 
@@ -362,6 +597,8 @@ impl Refresh {
             lifetime,
             username: Some(username),
             nonce: Some(nonce),
+            credential_extensions: Default::default(),
+            mobility_ticket,
         }
     }
 
@@ -371,6 +608,7 @@ impl Refresh {
         let nonce = message.get_attribute::<Nonce>().cloned();
         let lifetime = message.get_attribute::<Lifetime>().cloned();
         let username = message.get_attribute::<Username>().cloned();
+        let mobility_ticket = message.get_attribute::<MobilityTicket>().cloned();
 
         Refresh {
             transaction_id,
@@ -378,9 +616,15 @@ impl Refresh {
             lifetime,
             username,
             nonce,
+            credential_extensions: CredentialExtensions::parse(message),
+            mobility_ticket,
         }
     }
 
+    pub fn mobility_ticket(&self) -> Option<&MobilityTicket> {
+        self.mobility_ticket.as_ref()
+    }
+
     pub fn transaction_id(&self) -> TransactionId {
         self.transaction_id
     }
@@ -400,6 +644,10 @@ impl Refresh {
     pub fn nonce(&self) -> Option<&Nonce> {
         self.nonce.as_ref()
     }
+
+    pub fn credential_extensions(&self) -> &CredentialExtensions {
+        &self.credential_extensions
+    }
 }
 
 pub struct ChannelBind {
@@ -407,6 +655,7 @@ pub struct ChannelBind {
     channel_number: ChannelNumber,
     message_integrity: Option<MessageIntegrity>,
     nonce: Option<Nonce>,
+    credential_extensions: CredentialExtensions,
     xor_peer_address: XorPeerAddress,
     username: Option<Username>,
 }
@@ -445,6 +694,7 @@ impl ChannelBind {
             xor_peer_address,
             username: Some(username),
             nonce: Some(nonce),
+            credential_extensions: Default::default(),
         }
     }
 
@@ -467,6 +717,7 @@ impl ChannelBind {
             channel_number,
             message_integrity,
             nonce,
+            credential_extensions: CredentialExtensions::parse(message),
             xor_peer_address,
             username,
         })
@@ -495,6 +746,10 @@ impl ChannelBind {
     pub fn nonce(&self) -> Option<&Nonce> {
         self.nonce.as_ref()
     }
+
+    pub fn credential_extensions(&self) -> &CredentialExtensions {
+        &self.credential_extensions
+    }
 }
 
 pub struct CreatePermission {
@@ -502,20 +757,67 @@ pub struct CreatePermission {
     message_integrity: Option<MessageIntegrity>,
     username: Option<Username>,
     nonce: Option<Nonce>,
+    credential_extensions: CredentialExtensions,
+    xor_peer_addresses: Vec<XorPeerAddress>,
 }
 
 impl CreatePermission {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_addresses: Vec<XorPeerAddress>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, CREATE_PERMISSION, transaction_id);
+        message.add_attribute(username.clone());
+        for xor_peer_address in &xor_peer_addresses {
+            message.add_attribute(xor_peer_address.clone());
+        }
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            username: Some(username),
+            nonce: Some(nonce),
+            credential_extensions: Default::default(),
+            xor_peer_addresses,
+        }
+    }
+
     pub fn parse(message: &Message<Attribute>) -> Self {
         let transaction_id = message.transaction_id();
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
         let username = message.get_attribute::<Username>().cloned();
         let nonce = message.get_attribute::<Nonce>().cloned();
+        let xor_peer_addresses = message
+            .attributes()
+            .filter_map(|attribute| match attribute {
+                Attribute::XorPeerAddress(address) => Some(address.clone()),
+                _ => None,
+            })
+            .collect();
 
         CreatePermission {
             transaction_id,
             message_integrity,
             username,
             nonce,
+            credential_extensions: CredentialExtensions::parse(message),
+            xor_peer_addresses,
         }
     }
 
@@ -534,6 +836,293 @@ impl CreatePermission {
     pub fn nonce(&self) -> Option<&Nonce> {
         self.nonce.as_ref()
     }
+
+    pub fn credential_extensions(&self) -> &CredentialExtensions {
+        &self.credential_extensions
+    }
+
+    /// The peers to install permissions for, a request may contain several.
+    pub fn xor_peer_addresses(&self) -> &[XorPeerAddress] {
+        &self.xor_peer_addresses
+    }
+}
+
+/// A Send indication, carrying data from the client to a peer.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-send-and-data-methods>.
+pub struct SendIndication {
+    transaction_id: TransactionId,
+    xor_peer_address: XorPeerAddress,
+    data: Data,
+}
+
+impl SendIndication {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        data: Data,
+    ) -> Self {
+        Self {
+            transaction_id,
+            xor_peer_address,
+            data,
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Error> {
+        let transaction_id = message.transaction_id();
+        let xor_peer_address = message
+            .get_attribute::<XorPeerAddress>()
+            .cloned()
+            .ok_or_else(|| missing_attribute("XOR-PEER-ADDRESS"))?;
+        let data = message
+            .get_attribute::<Data>()
+            .cloned()
+            .ok_or_else(|| missing_attribute("DATA"))?;
+
+        Ok(SendIndication {
+            transaction_id,
+            xor_peer_address,
+            data,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn xor_peer_address(&self) -> &XorPeerAddress {
+        &self.xor_peer_address
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data.data()
+    }
+}
+
+pub struct Connect {
+    transaction_id: TransactionId,
+    message_integrity: Option<MessageIntegrity>,
+    nonce: Option<Nonce>,
+    credential_extensions: CredentialExtensions,
+    xor_peer_address: XorPeerAddress,
+    username: Option<Username>,
+}
+
+impl Connect {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, *CONNECT, transaction_id);
+        message.add_attribute(username.clone());
+        message.add_attribute(xor_peer_address.clone());
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            xor_peer_address,
+            username: Some(username),
+            nonce: Some(nonce),
+            credential_extensions: Default::default(),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
+        let transaction_id = message.transaction_id();
+        let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let nonce = message.get_attribute::<Nonce>().cloned();
+        let username = message.get_attribute::<Username>().cloned();
+        let xor_peer_address = message
+            .get_attribute::<XorPeerAddress>()
+            .ok_or(bad_request(message))?
+            .clone();
+
+        Ok(Connect {
+            transaction_id,
+            message_integrity,
+            nonce,
+            credential_extensions: CredentialExtensions::parse(message),
+            xor_peer_address,
+            username,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
+        self.message_integrity.as_ref()
+    }
+
+    pub fn xor_peer_address(&self) -> &XorPeerAddress {
+        &self.xor_peer_address
+    }
+
+    pub fn username(&self) -> Option<&Username> {
+        self.username.as_ref()
+    }
+
+    pub fn nonce(&self) -> Option<&Nonce> {
+        self.nonce.as_ref()
+    }
+
+    pub fn credential_extensions(&self) -> &CredentialExtensions {
+        &self.credential_extensions
+    }
+}
+
+pub struct ConnectionBind {
+    transaction_id: TransactionId,
+    connection_id: ConnectionId,
+    message_integrity: Option<MessageIntegrity>,
+    nonce: Option<Nonce>,
+    credential_extensions: CredentialExtensions,
+    username: Option<Username>,
+}
+
+impl ConnectionBind {
+    pub fn new(
+        transaction_id: TransactionId,
+        connection_id: ConnectionId,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, *CONNECTION_BIND, transaction_id);
+        message.add_attribute(username.clone());
+        message.add_attribute(connection_id);
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            connection_id,
+            message_integrity: Some(message_integrity),
+            username: Some(username),
+            nonce: Some(nonce),
+            credential_extensions: Default::default(),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
+        let transaction_id = message.transaction_id();
+        let connection_id = message
+            .get_attribute::<ConnectionId>()
+            .copied()
+            .ok_or(bad_request(message))?;
+        let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let nonce = message.get_attribute::<Nonce>().cloned();
+        let username = message.get_attribute::<Username>().cloned();
+
+        Ok(ConnectionBind {
+            transaction_id,
+            connection_id,
+            message_integrity,
+            nonce,
+            credential_extensions: CredentialExtensions::parse(message),
+            username,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
+        self.message_integrity.as_ref()
+    }
+
+    pub fn username(&self) -> Option<&Username> {
+        self.username.as_ref()
+    }
+
+    pub fn nonce(&self) -> Option<&Nonce> {
+        self.nonce.as_ref()
+    }
+
+    pub fn credential_extensions(&self) -> &CredentialExtensions {
+        &self.credential_extensions
+    }
+}
+
+/// The attributes of the long-term credential mechanism that were added in RFC 8489.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-9.2>.
+#[derive(Debug, Clone, Default)]
+pub struct CredentialExtensions {
+    message_integrity_sha256: Option<MessageIntegritySha256>,
+    password_algorithm: Option<PasswordAlgorithm>,
+    password_algorithms: Option<PasswordAlgorithms>,
+    userhash: Option<Userhash>,
+}
+
+impl CredentialExtensions {
+    fn parse(message: &Message<Attribute>) -> Self {
+        Self {
+            message_integrity_sha256: message.get_attribute::<MessageIntegritySha256>().cloned(),
+            password_algorithm: message.get_attribute::<PasswordAlgorithm>().copied(),
+            password_algorithms: message.get_attribute::<PasswordAlgorithms>().cloned(),
+            userhash: message.get_attribute::<Userhash>().cloned(),
+        }
+    }
+
+    pub fn message_integrity_sha256(&self) -> Option<&MessageIntegritySha256> {
+        self.message_integrity_sha256.as_ref()
+    }
+
+    pub fn userhash(&self) -> Option<&Userhash> {
+        self.userhash.as_ref()
+    }
+
+    /// The password algorithm picked by the client.
+    ///
+    /// Clients that don't pick one use MD5.
+    /// Returns `None` if the client did not echo our PASSWORD-ALGORITHMS or picked an algorithm that is not part of it, see <https://www.rfc-editor.org/rfc/rfc8489#section-9.2.4>.
+    pub fn password_algorithm(&self) -> Option<PasswordAlgorithm> {
+        match (self.password_algorithm, &self.password_algorithms) {
+            (None, None) => Some(PasswordAlgorithm::Md5),
+            (Some(algorithm), Some(algorithms))
+                if algorithms.algorithms() == PASSWORD_ALGORITHMS
+                    && algorithms.algorithms().contains(&algorithm) =>
+            {
+                Some(algorithm)
+            }
+            _ => None,
+        }
+    }
 }
 
 /// Computes the effective lifetime of an allocation.
@@ -570,10 +1159,52 @@ fn error_response(
 pub enum Error {
     BadChannelData(io::Error),
     DecodeStun(bytecodec::Error),
+    /// The FINGERPRINT does not match the message, thus it is not actually STUN.
+    BadFingerprint,
     UnknownMessageType(u8),
     Eof,
 }
 
+/// Checks whether a message failed to decode only because of its FINGERPRINT.
+///
+/// stun_codec verifies the FINGERPRINT while decoding but reports a mismatch like any other malformed attribute.
+/// Unlike those, a mismatching FINGERPRINT means the message must not be processed at all.
+/// See <https://www.rfc-editor.org/rfc/rfc8489#section-7.3>.
+///
+/// The FINGERPRINT is always the last attribute, thus we decode the message again without it:
+/// if that succeeds, the FINGERPRINT was the culprit.
+fn has_bad_fingerprint(input: &[u8]) -> bool {
+    let Some(len) = input.len().checked_sub(FINGERPRINT_ATTRIBUTE_LEN) else {
+        return false;
+    };
+    let Some(attributes_len) = len
+        .checked_sub(MESSAGE_HEADER_LEN)
+        .and_then(|len| u16::try_from(len).ok())
+    else {
+        return false;
+    };
+    let (message, fingerprint) = input.split_at(len);
+
+    if fingerprint[..2] != FINGERPRINT.to_be_bytes() {
+        return false;
+    }
+
+    let mut message = message.to_vec();
+    message[2..4].copy_from_slice(&attributes_len.to_be_bytes());
+
+    matches!(
+        stun_codec::MessageDecoder::<Attribute>::default().decode_from_bytes(&message),
+        Ok(Ok(_))
+    )
+}
+
+fn missing_attribute(name: &str) -> Error {
+    Error::DecodeStun(bytecodec::Error::from(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Send indication is missing the {name} attribute"),
+    )))
+}
+
 impl From<bytecodec::Error> for Error {
     fn from(error: bytecodec::Error) -> Self {
         Error::DecodeStun(error)
@@ -589,6 +1220,9 @@ impl From<io::Error> for Error {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytecodec::EncodeExt;
+    use stun_codec::rfc5389::attributes::{Fingerprint, Software};
+    use stun_codec::MessageEncoder;
 
     #[test]
     fn requested_lifetime_is_capped_at_max_lifetime() {
@@ -598,4 +1232,51 @@ mod tests {
 
         assert_eq!(effective_lifetime.lifetime(), MAX_ALLOCATION_LIFETIME)
     }
+
+    #[test]
+    fn accepts_message_with_valid_fingerprint() {
+        let bytes = encode_binding_request(|message| {
+            let fingerprint = Fingerprint::new(message).unwrap();
+            message.add_attribute(fingerprint);
+        });
+
+        assert!(matches!(
+            Decoder::default().decode(&bytes),
+            Ok(Ok(ClientMessage::Binding(_)))
+        ));
+    }
+
+    #[test]
+    fn accepts_message_without_fingerprint() {
+        let bytes = encode_binding_request(|_| {});
+
+        assert!(matches!(
+            Decoder::default().decode(&bytes),
+            Ok(Ok(ClientMessage::Binding(_)))
+        ));
+    }
+
+    #[test]
+    fn rejects_message_with_mismatching_fingerprint() {
+        let mut bytes = encode_binding_request(|message| {
+            let fingerprint = Fingerprint::new(message).unwrap();
+            message.add_attribute(fingerprint);
+        });
+        *bytes.last_mut().unwrap() ^= 0xff;
+
+        assert!(matches!(
+            Decoder::default().decode(&bytes),
+            Err(Error::BadFingerprint)
+        ));
+    }
+
+    fn encode_binding_request(finish: impl FnOnce(&mut Message<Attribute>)) -> Vec<u8> {
+        let mut message = Message::new(MessageClass::Request, BINDING, TransactionId::new([0; 12]));
+        message.add_attribute(Software::new("test".to_owned()).unwrap());
+        finish(&mut message);
+
+        MessageEncoder::default()
+            .encode_into_bytes(message)
+            .unwrap()
+    }
 }
@@ -0,0 +1,167 @@
+use crate::auth::Nonces;
+use crate::client_socket::{ClientSocket, ClientTransport};
+use crate::net_ext::IpAddrExt;
+use crate::server::{Allocation, AllocationId, Channel, Command, Server, TimedAction};
+use rand::Rng;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::iter;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+/// A serializable snapshot of the state of a [`Server`].
+///
+/// Restoring a snapshot in a new relay process allows it to continue where an old one stopped, without dropping the allocations of clients.
+/// The snapshot includes the auth secret, thus it must be kept as private as the secret itself.
+/// Only allocations of clients talking to us via UDP are included: TCP and TLS connections belong to the old process and cannot survive it.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    auth_secret: String,
+    #[serde(default)]
+    previous_auth_secret: Option<(String, SystemTime)>,
+    next_allocation_id: AllocationId,
+    allocations: Vec<(SocketAddr, Allocation)>,
+    channels: Vec<(u16, Channel)>,
+    nonces: Nonces,
+    time_events: Vec<(SystemTime, TimedAction)>,
+    #[serde(default)]
+    revoked_users: Vec<(String, SystemTime)>,
+}
+
+impl<R> Server<R>
+where
+    R: Rng,
+{
+    /// Takes a [`Snapshot`] of the current state.
+    pub fn snapshot(&self) -> Snapshot {
+        let allocations = self
+            .allocations
+            .iter()
+            .filter(|(client, _)| client.transport == ClientTransport::Udp)
+            .map(|(client, allocation)| (client.addr, allocation.clone()))
+            .collect::<Vec<_>>();
+        let allocation_ids = allocations
+            .iter()
+            .map(|(_, allocation)| allocation.id)
+            .collect::<HashSet<_>>();
+
+        let channels = self
+            .channels_by_number
+            .iter()
+            .filter(|(_, channel)| allocation_ids.contains(&channel.allocation))
+            .map(|(number, channel)| (*number, channel.clone()))
+            .collect();
+        let time_events = self
+            .time_events
+            .iter()
+            .filter(|(_, action)| match action {
+                TimedAction::ExpireAllocation(id)
+                | TimedAction::ExpirePermission(id, _)
+                | TimedAction::ReportUsage(id) => allocation_ids.contains(id),
+                TimedAction::UnbindChannel(_) | TimedAction::DeleteChannel(_) => true,
+                TimedAction::ExpireConnection(_) => false,
+                TimedAction::ForgetRevocation(_) => true,
+            })
+            .map(|(time, action)| (time, action.clone()))
+            .collect();
+
+        Snapshot {
+            auth_secret: self.auth_secret.expose_secret().clone(),
+            previous_auth_secret: self
+                .previous_auth_secret
+                .as_ref()
+                .map(|(secret, valid_until)| (secret.expose_secret().clone(), *valid_until)),
+            next_allocation_id: self.next_allocation_id,
+            allocations,
+            channels,
+            nonces: self.nonces.clone(),
+            time_events,
+            revoked_users: self
+                .revoked_users
+                .iter()
+                .map(|(user, until)| (user.clone(), *until))
+                .collect(),
+        }
+    }
+
+    /// Restores the state of a [`Snapshot`].
+    ///
+    /// This is meant to be called on a freshly constructed [`Server`], any existing state is replaced.
+    /// Afterwards, the server emits a [`Command::CreateAllocation`] for each restored allocation
+    /// and, if the fast path is enabled, a [`Command::CreateChannelBinding`] for each bound channel.
+    pub fn restore(&mut self, snapshot: Snapshot, now: SystemTime) {
+        self.auth_secret = SecretString::from(snapshot.auth_secret);
+        self.previous_auth_secret = snapshot
+            .previous_auth_secret
+            .map(|(secret, valid_until)| (SecretString::from(secret), valid_until));
+        self.next_allocation_id = snapshot.next_allocation_id;
+        self.nonces = snapshot.nonces;
+        self.revoked_users = snapshot.revoked_users.into_iter().collect();
+
+        self.allocations.clear();
+        self.clients_by_allocation.clear();
+        self.allocations_by_port.clear();
+        self.allocations_by_mobility_ticket.clear();
+        self.channels_by_number.clear();
+        self.channel_numbers_by_peer.clear();
+        self.time_events = Default::default();
+
+        for (client, allocation) in snapshot.allocations {
+            let client = ClientSocket::udp(client);
+
+            for relay_addr in
+                iter::once(allocation.first_relay_addr).chain(allocation.second_relay_addr)
+            {
+                self.pending_commands.push_back(Command::CreateAllocation {
+                    id: allocation.id,
+                    family: relay_addr.family(),
+                    port: allocation.port,
+                });
+            }
+
+            self.clients_by_allocation.insert(allocation.id, client);
+            self.allocations_by_port
+                .insert(allocation.port, allocation.id);
+            if let Some(ticket) = allocation.mobility_ticket.clone() {
+                self.allocations_by_mobility_ticket
+                    .insert(ticket, allocation.id);
+            }
+            self.allocations.insert(client, allocation);
+            self.allocations_up_down_counter.add(1, &[]);
+        }
+        self.allocated_ports
+            .store(self.allocations_by_port.len(), Ordering::Relaxed);
+
+        for (number, channel) in snapshot.channels {
+            let bound = channel.bound;
+
+            self.channel_numbers_by_peer
+                .insert(channel.peer_address, number);
+            self.channels_by_number.insert(number, channel);
+
+            if bound {
+                self.add_to_fast_path(number);
+            }
+        }
+
+        for (time, action) in snapshot.time_events {
+            self.time_events.add(time, action);
+        }
+
+        if let Some(deadline) = self.time_events.next_trigger() {
+            self.pending_commands.push_back(Command::Wake { deadline });
+        }
+
+        // Bandwidth budgets are not part of the snapshot, start over with the current limits.
+        self.set_bandwidth_limits(self.bandwidth_limits, now);
+
+        tracing::info!(
+            target: "relay",
+            "Restored {} allocations and {} channels from snapshot",
+            self.allocations.len(),
+            self.channels_by_number.len()
+        );
+    }
+}
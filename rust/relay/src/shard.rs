@@ -0,0 +1,123 @@
+use crate::ConnectionId;
+
+/// Identifies one of several [`Server`](crate::Server)s that together make up a relay.
+///
+/// Each shard serves the clients whose traffic the kernel hands to its sockets (see `SO_REUSEPORT`) and owns a disjoint part of the relay's port range.
+/// Allocation IDs are interleaved between shards to keep them unique across the relay.
+/// Connection IDs are interleaved the same way, which lets us route the data connection of a TCP allocation to the shard owning it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    index: usize,
+    count: usize,
+}
+
+impl Shard {
+    /// Creates the shard with the given index out of `count` shards.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is not less than `count`.
+    pub fn new(index: usize, count: usize) -> Self {
+        assert!(
+            index < count,
+            "shard index {index} out of range for {count} shards"
+        );
+
+        Self { index, count }
+    }
+
+    /// All shards of a relay made up of `count` shards.
+    pub fn all(count: usize) -> impl Iterator<Item = Self> {
+        (0..count).map(move |index| Self::new(index, count))
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The part of the port range `lowest_port` - `highest_port` that this shard allocates ports from.
+    ///
+    /// Both ends of the ranges are inclusive.
+    /// The ports are split evenly, the last shard also gets the remainder.
+    pub fn port_range(&self, lowest_port: u16, highest_port: u16) -> (u16, u16) {
+        let num_ports = usize::from(highest_port - lowest_port) + 1;
+        let ports_per_shard = (num_ports / self.count) as u16;
+        let lowest = lowest_port + ports_per_shard * self.index as u16;
+
+        if self.index + 1 == self.count {
+            return (lowest, highest_port);
+        }
+
+        (lowest, lowest + ports_per_shard - 1)
+    }
+
+    /// Derives a [`ConnectionId`] that identifies this shard from the given random value.
+    pub fn connection_id(&self, random: u32) -> ConnectionId {
+        let count = self.count as u32;
+
+        ConnectionId::new((random % (u32::MAX / count)) * count + self.index as u32)
+    }
+
+    /// The shard of our relay that created the given [`ConnectionId`], see [`Shard::connection_id`].
+    pub fn owner_of(&self, connection: ConnectionId) -> Self {
+        Self::new(connection.value() as usize % self.count, self.count)
+    }
+}
+
+impl Default for Shard {
+    /// The only shard of a relay that is not sharded.
+    fn default() -> Self {
+        Self::new(0, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_shard_owns_entire_port_range() {
+        assert_eq!(Shard::default().port_range(49152, 65535), (49152, 65535));
+    }
+
+    #[test]
+    fn shards_partition_port_range() {
+        let ranges = Shard::all(3)
+            .map(|shard| shard.port_range(49152, 65535))
+            .collect::<Vec<_>>();
+
+        assert_eq!(ranges, vec![(49152, 54612), (54613, 60073), (60074, 65535)]);
+    }
+
+    #[test]
+    fn last_shard_gets_remaining_ports() {
+        let ranges = Shard::all(4)
+            .map(|shard| shard.port_range(100, 110))
+            .collect::<Vec<_>>();
+
+        assert_eq!(ranges, vec![(100, 101), (102, 103), (104, 105), (106, 110)]);
+    }
+
+    #[test]
+    fn connection_ids_identify_their_shard() {
+        for shard in Shard::all(7) {
+            for random in [0, 1, 4242, u32::MAX - 1, u32::MAX] {
+                let connection = shard.connection_id(random);
+
+                assert_eq!(Shard::new(0, 7).owner_of(connection), shard);
+            }
+        }
+    }
+
+    #[test]
+    fn single_shard_keeps_random_connection_id() {
+        assert_eq!(
+            Shard::default().connection_id(4242),
+            ConnectionId::new(4242)
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{AddressFamily, IpAddrExt};
+use anyhow::{Context as _, Result};
+use bytes::BytesMut;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The length of the STUN message header.
+const STUN_HEADER_LEN: usize = 20;
+
+/// The length of the channel data header.
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// Splits a stream of bytes (i.e. from a TCP or TLS connection) into individual STUN and channel data messages.
+///
+/// Over streams, there is no datagram boundary that tells us where a message ends.
+/// STUN messages are self-delimiting thanks to the length field in their header.
+/// Channel data messages are too, except that they are padded to a multiple of 4 bytes.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-the-channeldata-message>.
+#[derive(Default)]
+pub struct StreamFramer {
+    buffer: BytesMut,
+}
+
+impl StreamFramer {
+    /// Appends bytes read from the stream to the internal buffer.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete message, if any.
+    ///
+    /// Channel data messages are returned including their padding.
+    /// An error indicates that the stream is corrupted and should be closed.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        let Some(frame_len) = frame_len(&self.buffer)? else {
+            return Ok(None);
+        };
+
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        Ok(Some(self.buffer.split_to(frame_len).to_vec()))
+    }
+
+    /// Takes all bytes that have not been returned as a frame yet.
+    ///
+    /// Useful when a stream stops carrying STUN messages, i.e. after a TCP data connection has been bound.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        self.buffer.split().to_vec()
+    }
+}
+
+/// Pads a message to be sent over a stream.
+///
+/// STUN messages are always a multiple of 4 bytes long, thus only channel data messages need to be padded.
+pub fn pad_for_stream(mut message: Vec<u8>) -> Vec<u8> {
+    if matches!(message.first(), Some(64..=79)) {
+        let padding = (4 - message.len() % 4) % 4;
+        message.resize(message.len() + padding, 0);
+    }
+
+    message
+}
+
+/// Creates a [`tokio::net::TcpListener`] on the given address.
+pub fn bind_tcp_listener(addr: IpAddr, port: u16) -> Result<tokio::net::TcpListener> {
+    let listener = bind_tcp_socket(addr, port)?
+        .listen(1024)
+        .with_context(|| format!("Failed to listen on TCP port {port} of {addr}"))?;
+
+    Ok(listener)
+}
+
+/// Creates a [`tokio::net::TcpSocket`] that is bound to the given address.
+///
+/// Like for our UDP sockets, this sets the `IPV6_V6ONLY` flag to allow binding IP4 and IP6 sockets to the same port.
+/// Additionally, we set `SO_REUSEPORT` which allows us to connect to peers from the same port that a TCP allocation is listening on.
+pub fn bind_tcp_socket(addr: IpAddr, port: u16) -> Result<tokio::net::TcpSocket> {
+    use socket2::*;
+
+    let family = addr.family();
+    let domain = match family {
+        AddressFamily::V4 => Domain::IPV4,
+        AddressFamily::V6 => Domain::IPV6,
+    };
+
+    let make_socket = || -> io::Result<std::net::TcpStream> {
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+        if family == AddressFamily::V6 {
+            socket.set_only_v6(true)?;
+        }
+
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SockAddr::from(SocketAddr::new(addr, port)))?;
+
+        Ok(socket.into())
+    };
+
+    let std_socket =
+        make_socket().with_context(|| format!("Failed to bind TCP socket to {addr}:{port}"))?;
+
+    Ok(tokio::net::TcpSocket::from_std_stream(std_socket))
+}
+
+/// Limits the number of concurrent TCP and TLS connections of clients across all our listeners.
+///
+/// Unlike UDP, every stream connection costs us a task and its buffers, thus we refuse new ones once we reach the limit.
+#[derive(Debug, Clone)]
+pub struct StreamConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Counts towards the [`StreamConnectionLimit`] until it is dropped.
+#[derive(Debug)]
+pub struct StreamConnectionPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl StreamConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Returns a permit for a new connection, unless we already reached the limit.
+    pub fn try_acquire(&self) -> Option<StreamConnectionPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max).then_some(active + 1)
+            })
+            .ok()?;
+
+        Some(StreamConnectionPermit {
+            active: self.active.clone(),
+        })
+    }
+}
+
+impl Drop for StreamConnectionPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn frame_len(buffer: &[u8]) -> Result<Option<usize>, io::Error> {
+    let Some(first) = buffer.first() else {
+        return Ok(None);
+    };
+
+    if buffer.len() < 4 {
+        return Ok(None);
+    }
+
+    let length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+
+    match first {
+        0..=3 => Ok(Some(STUN_HEADER_LEN + length)),
+        64..=79 => {
+            let padding = (4 - length % 4) % 4;
+
+            Ok(Some(CHANNEL_DATA_HEADER_LEN + length + padding))
+        }
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown message type {other} in stream"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returns_nothing_until_message_is_complete() {
+        let mut framer = StreamFramer::default();
+
+        framer.push(&[0x00, 0x01, 0x00, 0x00]);
+
+        assert_eq!(framer.next_frame().unwrap(), None);
+
+        framer.push(&[0u8; 16]);
+
+        assert_eq!(framer.next_frame().unwrap().unwrap().len(), 20);
+        assert_eq!(framer.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn splits_channel_data_including_padding() {
+        let mut framer = StreamFramer::default();
+
+        framer.push(&[0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0]);
+        framer.push(&[0x40, 0x01, 0x00, 0x04, 1, 2, 3, 4]);
+
+        assert_eq!(
+            framer.next_frame().unwrap().unwrap(),
+            vec![0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0]
+        );
+        assert_eq!(
+            framer.next_frame().unwrap().unwrap(),
+            vec![0x40, 0x01, 0x00, 0x04, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn unknown_message_type_is_an_error() {
+        let mut framer = StreamFramer::default();
+
+        framer.push(&[0xFF, 0x00, 0x00, 0x00]);
+
+        assert!(framer.next_frame().is_err());
+    }
+
+    #[test]
+    fn pads_channel_data_but_not_stun_messages() {
+        assert_eq!(
+            pad_for_stream(vec![0x40, 0x00, 0x00, 0x01, 1]),
+            vec![0x40, 0x00, 0x00, 0x01, 1, 0, 0, 0]
+        );
+        assert_eq!(pad_for_stream(vec![0x00; 20]), vec![0x00; 20]);
+    }
+
+    #[test]
+    fn refuses_connections_beyond_limit_until_permit_is_dropped() {
+        let limit = StreamConnectionLimit::new(2);
+
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+
+        assert!(limit.try_acquire().is_some());
+    }
+}
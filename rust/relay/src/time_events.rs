@@ -44,6 +44,11 @@ where
         events.into_iter().map(|event| event.action)
     }
 
+    /// Iterates over all scheduled actions, ordered by the time they are triggered at.
+    pub fn iter(&self) -> impl Iterator<Item = (SystemTime, &A)> {
+        self.events.iter().map(|event| (event.time, &event.action))
+    }
+
     /// The time at which the next action will be ready.
     pub fn next_trigger(&self) -> Option<SystemTime> {
         let first = self.events.first()?;
@@ -1,11 +1,25 @@
-use crate::AddressFamily;
+use crate::{AddressFamily, IpAddrExt};
 use anyhow::{Context as _, Result};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
 use std::task::{ready, Context, Poll};
-use tokio::io::ReadBuf;
+use tokio::io::{Interest, ReadBuf};
 
 const MAX_UDP_SIZE: usize = 65536;
 
+/// The size of the buffers in a [`BufferPool`].
+///
+/// This is the Ethernet MTU: Clients size their datagrams to the path MTU, larger ones are truncated by the kernel and dropped by [`UdpSocket::recv_batch`].
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// The maximum number of datagrams we receive or send with a single syscall.
+pub const MAX_BATCH_SIZE: usize = 32;
+
+/// How many unused buffers a [`BufferPool`] keeps around at most.
+const MAX_POOLED_BUFFERS: usize = 4 * MAX_BATCH_SIZE;
+
 /// A thin wrapper around [`tokio::net::UdpSocket`] that provides a slightly more convenient API.
 pub struct UdpSocket {
     inner: tokio::net::UdpSocket,
@@ -13,16 +27,34 @@ pub struct UdpSocket {
 }
 
 impl UdpSocket {
-    pub fn bind(family: AddressFamily, port: u16) -> Result<Self> {
-        let std_socket = make_wildcard_socket(family, port)
-            .with_context(|| format!("Failed to bind UDP socket for {family}"))?;
+    pub fn bind(addr: IpAddr, port: u16) -> Result<Self> {
+        let std_socket = make_socket(addr, port, false)
+            .with_context(|| format!("Failed to bind UDP socket to {addr}:{port}"))?;
+
+        Self::from_std(std_socket)
+    }
 
+    /// Binds one of several sockets that share the same address via `SO_REUSEPORT`.
+    ///
+    /// The kernel distributes incoming datagrams across these sockets by hashing their 4-tuple, thus all datagrams of a client arrive at the same socket.
+    /// The returned socket is not yet registered with a runtime, this allows binding all sockets upfront and in a stable order.
+    pub fn bind_shared(addr: IpAddr, port: u16) -> Result<std::net::UdpSocket> {
+        make_socket(addr, port, true)
+            .with_context(|| format!("Failed to bind shared UDP socket to {addr}:{port}"))
+    }
+
+    /// Registers a socket created by [`UdpSocket::bind_shared`] with the current runtime.
+    pub fn from_std(socket: std::net::UdpSocket) -> Result<Self> {
         Ok(Self {
-            inner: tokio::net::UdpSocket::from_std(std_socket)?,
+            inner: tokio::net::UdpSocket::from_std(socket)?,
             recv_buf: [0u8; MAX_UDP_SIZE],
         })
     }
 
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.local_addr()?)
+    }
+
     pub async fn recv(&mut self) -> Result<(&[u8], SocketAddr)> {
         let (length, sender) = self.inner.recv_from(&mut self.recv_buf).await?;
 
@@ -42,6 +74,77 @@ impl UdpSocket {
         Poll::Ready(Ok((buffer, sender)))
     }
 
+    /// Receives up to [`MAX_BATCH_SIZE`] datagrams at once, into buffers of the given pool.
+    ///
+    /// On Linux, this is a single `recvmmsg` syscall, elsewhere we receive one datagram at a time.
+    /// Datagrams larger than [`MAX_DATAGRAM_SIZE`] are dropped.
+    /// This function is cancel-safe: no datagram is lost if the returned future is dropped before completing.
+    pub async fn recv_batch(
+        &mut self,
+        pool: &BufferPool,
+    ) -> Result<Vec<(PooledBuffer, SocketAddr)>> {
+        loop {
+            self.inner.readable().await?;
+
+            let mut buffers = pool.take(MAX_BATCH_SIZE);
+
+            match self
+                .inner
+                .try_io(Interest::READABLE, || mmsg::recv(&self.inner, &mut buffers))
+            {
+                Ok(received) => {
+                    let mut datagrams = Vec::with_capacity(received.len());
+
+                    for (buffer, datagram) in buffers.drain(..received.len()).zip(received) {
+                        let Some((len, sender)) = datagram else {
+                            tracing::debug!(
+                                "Dropping datagram larger than {MAX_DATAGRAM_SIZE} bytes"
+                            );
+                            pool.put([buffer]);
+                            continue;
+                        };
+
+                        datagrams.push((pool.wrap(buffer, len), sender));
+                    }
+                    pool.put(buffers);
+
+                    return Ok(datagrams);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    pool.put(buffers);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Sends all given datagrams, batching up to [`MAX_BATCH_SIZE`] of them into one syscall.
+    ///
+    /// On Linux, this uses `sendmmsg`, elsewhere we send one datagram at a time.
+    pub async fn send_batch<B>(&mut self, datagrams: &[(B, SocketAddr)]) -> Result<()>
+    where
+        B: Deref<Target = [u8]>,
+    {
+        let mut remaining = datagrams;
+
+        while !remaining.is_empty() {
+            self.inner.writable().await?;
+
+            let batch = &remaining[..remaining.len().min(MAX_BATCH_SIZE)];
+
+            match self
+                .inner
+                .try_io(Interest::WRITABLE, || mmsg::send(&self.inner, batch))
+            {
+                Ok(num_sent) => remaining = &remaining[num_sent..],
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn try_send_to(
         &mut self,
         buf: &[u8],
@@ -57,28 +160,349 @@ impl UdpSocket {
     }
 }
 
+/// A pool of receive buffers, reused across datagrams instead of allocating a new buffer for each one.
+///
+/// Cloning a [`BufferPool`] yields a handle to the same pool.
+#[derive(Debug, Clone, Default)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    /// Takes `num` buffers from the pool, allocating new ones if it runs empty.
+    fn take(&self, num: usize) -> Vec<Vec<u8>> {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+        let num_pooled = buffers.len().min(num);
+
+        let mut taken = buffers.split_off(buffers.len() - num_pooled);
+        taken.resize_with(num, || vec![0u8; MAX_DATAGRAM_SIZE]);
+
+        taken
+    }
+
+    /// Returns buffers to the pool, dropping them if the pool is full.
+    fn put(&self, returned: impl IntoIterator<Item = Vec<u8>>) {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+        let free_slots = MAX_POOLED_BUFFERS.saturating_sub(buffers.len());
+
+        buffers.extend(returned.into_iter().take(free_slots));
+    }
+
+    fn wrap(&self, buffer: Vec<u8>, len: usize) -> PooledBuffer {
+        PooledBuffer {
+            buffer,
+            len,
+            pool: self.clone(),
+        }
+    }
+}
+
+/// A datagram received into a buffer of a [`BufferPool`].
+///
+/// Dereferences to the datagram's bytes and returns the buffer to the pool once dropped.
+/// The buffer always keeps its full size, thus reusing it does not need to zero it again.
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    len: usize,
+    pool: BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer[..self.len]
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put([std::mem::take(&mut self.buffer)]);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::ops::Deref;
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    /// Receives as many datagrams as there are buffers with a single `recvmmsg` call.
+    ///
+    /// Returns the length and sender of each received datagram, in the order of the buffers.
+    /// Datagrams that did not fit into their buffer are returned as `None`.
+    pub(super) fn recv(
+        socket: &impl AsRawFd,
+        buffers: &mut [Vec<u8>],
+    ) -> io::Result<Vec<Option<(usize, SocketAddr)>>> {
+        // SAFETY: All-zero is a valid bit-pattern for these C structs.
+        let mut addresses = vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; buffers.len()];
+        let mut iovecs = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut headers = iovecs
+            .iter_mut()
+            .zip(addresses.iter_mut())
+            .map(|(iovec, address)| {
+                // SAFETY: All-zero is a valid bit-pattern for these C structs.
+                let mut header = unsafe { mem::zeroed::<libc::mmsghdr>() };
+                header.msg_hdr.msg_name = (address as *mut libc::sockaddr_storage).cast();
+                header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as u32;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+
+                header
+            })
+            .collect::<Vec<_>>();
+
+        // SAFETY: All pointers in the headers point to buffers that outlive this call and have the advertised lengths.
+        let num_received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT as _,
+                ptr::null_mut(),
+            )
+        };
+        if num_received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        headers
+            .iter()
+            .zip(&addresses)
+            .take(num_received as usize)
+            .map(|(header, address)| {
+                if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                    return Ok(None);
+                }
+
+                Ok(Some((header.msg_len as usize, to_socket_addr(address)?)))
+            })
+            .collect()
+    }
+
+    /// Sends the given datagrams with a single `sendmmsg` call.
+    ///
+    /// Returns how many datagrams were sent, which may be less than given if the socket's send buffer is full.
+    pub(super) fn send<B>(socket: &impl AsRawFd, datagrams: &[(B, SocketAddr)]) -> io::Result<usize>
+    where
+        B: Deref<Target = [u8]>,
+    {
+        let addresses = datagrams
+            .iter()
+            .map(|(_, recipient)| socket2::SockAddr::from(*recipient))
+            .collect::<Vec<_>>();
+        let mut iovecs = datagrams
+            .iter()
+            .map(|(payload, _)| libc::iovec {
+                iov_base: payload.as_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut headers = iovecs
+            .iter_mut()
+            .zip(&addresses)
+            .map(|(iovec, address)| {
+                // SAFETY: All-zero is a valid bit-pattern for these C structs.
+                let mut header = unsafe { mem::zeroed::<libc::mmsghdr>() };
+                header.msg_hdr.msg_name = address.as_ptr() as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = address.len();
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+
+                header
+            })
+            .collect::<Vec<_>>();
+
+        // SAFETY: All pointers in the headers point to buffers that outlive this call and have the advertised lengths.
+        // `sendmmsg` does not write through `msg_name` or `iov_base`.
+        let num_sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT as _,
+            )
+        };
+        if num_sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(num_sent as usize)
+    }
+
+    fn to_socket_addr(address: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match address.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: The kernel wrote a `sockaddr_in` for this address family.
+                let address = unsafe { &*(address as *const _ as *const libc::sockaddr_in) };
+
+                Ok(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(address.sin_addr.s_addr.to_ne_bytes()),
+                    u16::from_be(address.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: The kernel wrote a `sockaddr_in6` for this address family.
+                let address = unsafe { &*(address as *const _ as *const libc::sockaddr_in6) };
+
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(address.sin6_addr.s6_addr),
+                    u16::from_be(address.sin6_port),
+                    address.sin6_flowinfo,
+                    address.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected address family {family}"),
+            )),
+        }
+    }
+}
+
+/// Fallback for platforms without `recvmmsg` and `sendmmsg`: Handle one datagram at a time.
+#[cfg(not(target_os = "linux"))]
+mod mmsg {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::ops::Deref;
+
+    pub(super) fn recv(
+        socket: &tokio::net::UdpSocket,
+        buffers: &mut [Vec<u8>],
+    ) -> io::Result<Vec<Option<(usize, SocketAddr)>>> {
+        let Some(buffer) = buffers.first_mut() else {
+            return Ok(Vec::new());
+        };
+
+        Ok(vec![Some(socket.try_recv_from(buffer)?)])
+    }
+
+    pub(super) fn send<B>(
+        socket: &tokio::net::UdpSocket,
+        datagrams: &[(B, SocketAddr)],
+    ) -> io::Result<usize>
+    where
+        B: Deref<Target = [u8]>,
+    {
+        let Some((payload, recipient)) = datagrams.first() else {
+            return Ok(0);
+        };
+
+        socket.try_send_to(payload, *recipient)?;
+
+        Ok(1)
+    }
+}
+
 /// Creates an [std::net::UdpSocket] via the [socket2] library that is configured for our needs.
 ///
 /// Most importantly, this sets the `IPV6_V6ONLY` flag to ensure we disallow IP4-mapped IPv6 addresses and can bind to IP4 and IP6 addresses on the same port.
-fn make_wildcard_socket(family: AddressFamily, port: u16) -> Result<std::net::UdpSocket> {
+fn make_socket(addr: IpAddr, port: u16, reuse_port: bool) -> Result<std::net::UdpSocket> {
     use socket2::*;
 
+    let family = addr.family();
     let domain = match family {
         AddressFamily::V4 => Domain::IPV4,
         AddressFamily::V6 => Domain::IPV6,
     };
-    let address = match family {
-        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
-        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
-    };
 
     let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
     if family == AddressFamily::V6 {
         socket.set_only_v6(true)?;
     }
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
 
     socket.set_nonblocking(true)?;
-    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+    socket.bind(&SockAddr::from(SocketAddr::new(addr, port)))?;
 
     Ok(socket.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn buffer_pool_reuses_returned_buffers() {
+        let pool = BufferPool::default();
+
+        let buffer = pool.take(1).remove(0);
+        let ptr = buffer.as_ptr();
+        drop(pool.wrap(buffer, 100));
+
+        assert_eq!(pool.take(1)[0].as_ptr(), ptr);
+    }
+
+    #[test]
+    fn buffer_pool_keeps_full_buffer_size() {
+        let pool = BufferPool::default();
+
+        let buffer = pool.wrap(pool.take(1).remove(0), 4);
+        assert_eq!(buffer.len(), 4);
+        drop(buffer);
+
+        assert_eq!(pool.take(1)[0].len(), MAX_DATAGRAM_SIZE);
+    }
+
+    #[tokio::test]
+    async fn sends_and_receives_batches() {
+        let mut sender = UdpSocket::bind(Ipv4Addr::LOCALHOST.into(), 0).unwrap();
+        let mut receiver = UdpSocket::bind(Ipv4Addr::LOCALHOST.into(), 0).unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let pool = BufferPool::default();
+
+        let datagrams = (0..3u8)
+            .map(|i| (vec![i; 10 + i as usize], receiver_addr))
+            .collect::<Vec<_>>();
+        sender.send_batch(&datagrams).await.unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            received.extend(receiver.recv_batch(&pool).await.unwrap());
+        }
+
+        let sender_addr = sender.local_addr().unwrap();
+        for ((payload, _), (buffer, from)) in datagrams.iter().zip(&received) {
+            assert_eq!(&buffer[..], payload.as_slice());
+            assert_eq!(*from, sender_addr);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn drops_datagrams_larger_than_buffers() {
+        let mut sender = UdpSocket::bind(Ipv4Addr::LOCALHOST.into(), 0).unwrap();
+        let mut receiver = UdpSocket::bind(Ipv4Addr::LOCALHOST.into(), 0).unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let pool = BufferPool::default();
+
+        let datagrams = [
+            (vec![1u8; MAX_DATAGRAM_SIZE + 1], receiver_addr),
+            (vec![2u8; 10], receiver_addr),
+        ];
+        sender.send_batch(&datagrams).await.unwrap();
+
+        let mut received = Vec::new();
+        while received.is_empty() {
+            received.extend(receiver.recv_batch(&pool).await.unwrap());
+        }
+
+        assert_eq!(received.len(), 1);
+        assert_eq!(&received[0].0[..], [2u8; 10].as_slice());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::AllocationId;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// The data relayed in one direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Traffic {
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+impl Traffic {
+    fn record(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.packets += 1;
+    }
+
+    fn add(&mut self, other: Traffic) {
+        self.bytes += other.bytes;
+        self.packets += other.packets;
+    }
+
+    fn since(self, earlier: Traffic) -> Traffic {
+        Traffic {
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+            packets: self.packets.saturating_sub(earlier.packets),
+        }
+    }
+}
+
+/// The data relayed through an allocation or one of its channels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub client_to_peer: Traffic,
+    pub peer_to_client: Traffic,
+}
+
+impl Usage {
+    pub(crate) fn record_client_to_peer(&mut self, bytes: usize) {
+        self.client_to_peer.record(bytes);
+    }
+
+    pub(crate) fn record_peer_to_client(&mut self, bytes: usize) {
+        self.peer_to_client.record(bytes);
+    }
+
+    pub(crate) fn add(&mut self, other: Usage) {
+        self.client_to_peer.add(other.client_to_peer);
+        self.peer_to_client.add(other.peer_to_client);
+    }
+
+    /// The usage between `earlier` and these totals of the same counters.
+    pub(crate) fn since(self, earlier: Usage) -> Usage {
+        Usage {
+            client_to_peer: self.client_to_peer.since(earlier.client_to_peer),
+            peer_to_client: self.peer_to_client.since(earlier.peer_to_client),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Usage::default()
+    }
+}
+
+/// The data relayed through an allocation since its previous [`UsageRecord`], see [`Server::enable_usage_reports`](crate::Server::enable_usage_reports).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub allocation: AllocationId,
+    /// The user that created the allocation, as identified by the [`Authenticator`](crate::Authenticator).
+    ///
+    /// For credentials issued by the portal, this is the salt of the username.
+    #[serde(rename = "username_salt")]
+    pub user: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// The part of `usage` that was relayed through each channel, omitting channels without traffic.
+    pub channels: Vec<ChannelUsage>,
+    /// Whether the allocation has been deleted, making this its last record.
+    #[serde(rename = "final")]
+    pub is_final: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelUsage {
+    pub number: u16,
+    pub peer: SocketAddr,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_bytes_and_packets_per_direction() {
+        let mut usage = Usage::default();
+
+        usage.record_client_to_peer(100);
+        usage.record_client_to_peer(50);
+        usage.record_peer_to_client(20);
+
+        assert_eq!(
+            usage,
+            Usage {
+                client_to_peer: Traffic {
+                    bytes: 150,
+                    packets: 2
+                },
+                peer_to_client: Traffic {
+                    bytes: 20,
+                    packets: 1
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn usage_is_empty_until_data_is_relayed() {
+        let mut usage = Usage::default();
+        assert!(usage.is_empty());
+
+        usage.record_peer_to_client(0);
+        assert!(!usage.is_empty());
+    }
+}
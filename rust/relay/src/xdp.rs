@@ -0,0 +1,624 @@
+use crate::{AllocationId, ChannelUsage, Traffic, Usage, UsageRecord};
+use anyhow::{bail, Context as _, Result};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+/// Where `ip` pins the maps of XDP programs it loads.
+const PIN_DIRECTORY: &str = "/sys/fs/bpf/xdp/globals";
+
+const CHANNELS_MAP: &str = "relay_channels";
+const PEERS_MAP: &str = "relay_peers";
+const CONFIG_MAP: &str = "relay_config";
+const USAGE_MAP: &str = "relay_usage";
+/// Counts the packets relayed by the XDP program, only read by tests.
+const STATS_MAP: &str = "relay_stats";
+
+const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_OBJ_GET: libc::c_long = 7;
+
+const BPF_ANY: u64 = 0;
+const BPF_NOEXIST: u64 = 1;
+
+/// Relays channel data in the kernel with the XDP program in `xdp/channel_data.c`.
+///
+/// The program is attached to a network interface with `ip`, which also pins the program's maps to the BPF filesystem.
+/// We keep these maps in sync with the channel bindings of all [`Server`](crate::Server)s, see [`Server::enable_fast_path`](crate::Server::enable_fast_path).
+/// Only channel data between IPv4 clients and peers is relayed in the kernel, everything else still reaches the [`Server`](crate::Server).
+///
+/// Cloning a [`FastPath`] yields a handle to the same maps.
+#[derive(Debug, Clone)]
+pub struct FastPath {
+    interface: String,
+    maps: Arc<Maps>,
+}
+
+#[derive(Debug)]
+struct Maps {
+    /// Maps a client and its channel number to the peer and the port of the allocation.
+    channels: Map,
+    /// Maps a peer and the port of the allocation to the client and its channel number.
+    peers: Map,
+    /// Maps a client and its channel number to the data relayed for this channel binding.
+    usage: Map,
+}
+
+impl FastPath {
+    /// Attaches the XDP program in `object` to `interface` and relays channel data arriving on `listen_port`.
+    ///
+    /// Fails if `interface` already has an XDP program attached, unless `replace` is set.
+    /// In that case, the program is replaced and our maps are re-created, e.g. after a relay process crashed without detaching.
+    pub fn attach(interface: &str, object: &Path, listen_port: u16, replace: bool) -> Result<Self> {
+        if !replace && is_xdp_attached(interface)? {
+            bail!("An XDP program is already attached to {interface}")
+        }
+
+        remove_pinned_maps()?;
+
+        let object = object.display().to_string();
+        let args = replace
+            .then_some("-force")
+            .into_iter()
+            .chain([
+                "link", "set", "dev", interface, "xdp", "obj", &object, "sec", "xdp",
+            ])
+            .collect::<Vec<_>>();
+
+        run_ip(&args).with_context(|| format!("Failed to attach XDP program to {interface}"))?;
+
+        let config = Map::open_pinned(CONFIG_MAP)?;
+        let mut config_value = [0u8; 4];
+        config_value[..2].copy_from_slice(&listen_port.to_be_bytes());
+        config
+            .update(&0u32.to_ne_bytes(), &config_value)
+            .context("Failed to configure XDP program")?;
+
+        tracing::info!(%interface, "Attached XDP program");
+
+        Ok(Self {
+            interface: interface.to_owned(),
+            maps: Arc::new(Maps {
+                channels: Map::open_pinned(CHANNELS_MAP)?,
+                peers: Map::open_pinned(PEERS_MAP)?,
+                usage: Map::open_pinned(USAGE_MAP)?,
+            }),
+        })
+    }
+
+    /// Starts relaying channel data of the given channel binding in the kernel.
+    ///
+    /// Refreshing a binding keeps its usage counters, see [`FastPath::channel_usage`].
+    pub fn create_channel_binding(
+        &self,
+        client: SocketAddr,
+        channel: u16,
+        peer: SocketAddr,
+        port: u16,
+    ) -> Result<()> {
+        let Some((client, peer)) = ip4_endpoints(client, peer) else {
+            return Ok(());
+        };
+
+        self.maps
+            .usage
+            .insert(&endpoint(client, channel), &[0u8; USAGE_LEN])?;
+        self.maps
+            .channels
+            .update(&endpoint(client, channel), &endpoint(peer, port))?;
+        self.maps
+            .peers
+            .update(&endpoint(peer, port), &endpoint(client, channel))?;
+
+        Ok(())
+    }
+
+    /// Stops relaying channel data of the given channel binding in the kernel.
+    ///
+    /// Returns the data relayed for this binding in total, its usage counters are removed.
+    pub fn delete_channel_binding(
+        &self,
+        client: SocketAddr,
+        channel: u16,
+        peer: SocketAddr,
+        port: u16,
+    ) -> Result<Usage> {
+        let Some((client, peer)) = ip4_endpoints(client, peer) else {
+            return Ok(Usage::default());
+        };
+
+        self.maps.channels.delete(&endpoint(client, channel))?;
+        self.maps.peers.delete(&endpoint(peer, port))?;
+
+        let usage = self.read_usage(client, channel)?;
+        self.maps.usage.delete(&endpoint(client, channel))?;
+
+        Ok(usage)
+    }
+
+    /// The data relayed in the kernel for the given channel binding since it was created.
+    pub fn channel_usage(&self, client: SocketAddr, channel: u16) -> Result<Usage> {
+        let SocketAddr::V4(client) = client else {
+            return Ok(Usage::default());
+        };
+
+        self.read_usage(client, channel)
+    }
+
+    fn read_usage(&self, client: SocketAddrV4, channel: u16) -> Result<Usage> {
+        let mut value = [0u8; USAGE_LEN];
+
+        if !self
+            .maps
+            .usage
+            .lookup(&endpoint(client, channel), &mut value)?
+        {
+            return Ok(Usage::default());
+        }
+
+        Ok(usage(value))
+    }
+
+    /// Detaches the XDP program and removes its maps.
+    pub fn detach(self) -> Result<()> {
+        run_ip(&["link", "set", "dev", &self.interface, "xdp", "off"])
+            .with_context(|| format!("Failed to detach XDP program from {}", self.interface))?;
+        remove_pinned_maps()?;
+
+        tracing::info!(interface = %self.interface, "Detached XDP program");
+
+        Ok(())
+    }
+}
+
+/// Adds the channel data relayed by a [`FastPath`] to the [`UsageRecord`]s of a [`Server`](crate::Server).
+///
+/// The XDP program only counts the data relayed for each channel binding in total.
+/// We remember how much of it we already reported and read the counters of an allocation's bindings whenever it is reported.
+#[derive(Debug, Default)]
+pub struct FastPathUsage {
+    bindings: HashMap<(SocketAddr, u16), BindingUsage>,
+}
+
+#[derive(Debug)]
+struct BindingUsage {
+    allocation: AllocationId,
+    peer: SocketAddr,
+    /// The totals of the binding's counters when we last read them.
+    last_totals: Usage,
+    /// What we read from the counters but did not report yet.
+    unreported: Usage,
+    /// Whether the binding is still installed in the XDP program.
+    active: bool,
+}
+
+impl FastPathUsage {
+    /// A channel binding was installed with [`FastPath::create_channel_binding`].
+    pub fn binding_created(
+        &mut self,
+        allocation: AllocationId,
+        client: SocketAddr,
+        channel: u16,
+        peer: SocketAddr,
+    ) {
+        let binding = self
+            .bindings
+            .entry((client, channel))
+            .or_insert_with(|| BindingUsage {
+                allocation,
+                peer,
+                last_totals: Usage::default(),
+                unreported: Usage::default(),
+                active: false,
+            });
+
+        // A refreshed binding keeps its counters, a re-created one starts from zero.
+        if !binding.active {
+            binding.last_totals = Usage::default();
+            binding.active = true;
+        }
+    }
+
+    /// A channel binding was removed with [`FastPath::delete_channel_binding`], which returned its final `totals`.
+    pub fn binding_deleted(&mut self, client: SocketAddr, channel: u16, totals: Usage) {
+        let Some(binding) = self.bindings.get_mut(&(client, channel)) else {
+            return;
+        };
+
+        binding.unreported.add(totals.since(binding.last_totals));
+        binding.last_totals = totals;
+        binding.active = false;
+    }
+
+    /// Adds the data relayed for the allocation of `record` since its previous record.
+    ///
+    /// The counters of bindings that are still installed are read with `read_totals`, see [`FastPath::channel_usage`].
+    pub fn add_to_record(
+        &mut self,
+        record: &mut UsageRecord,
+        mut read_totals: impl FnMut(SocketAddr, u16) -> Result<Usage>,
+    ) {
+        self.bindings.retain(|(client, channel), binding| {
+            if binding.allocation != record.allocation {
+                return true;
+            }
+
+            if binding.active {
+                match read_totals(*client, *channel) {
+                    Ok(totals) => {
+                        binding.unreported.add(totals.since(binding.last_totals));
+                        binding.last_totals = totals;
+                    }
+                    Err(e) => {
+                        tracing::warn!(%client, %channel, "Failed to read usage of channel binding from XDP program: {e:#}");
+                    }
+                }
+            }
+
+            let usage = std::mem::take(&mut binding.unreported);
+
+            if !usage.is_empty() {
+                record.usage.add(usage);
+
+                match record.channels.iter_mut().find(|c| c.number == *channel) {
+                    Some(channel_usage) => channel_usage.usage.add(usage),
+                    None => record.channels.push(ChannelUsage {
+                        number: *channel,
+                        peer: binding.peer,
+                        usage,
+                    }),
+                }
+            }
+
+            binding.active && !record.is_final
+        });
+    }
+}
+
+/// A BPF map, pinned to the BPF filesystem.
+#[derive(Debug)]
+struct Map {
+    fd: OwnedFd,
+}
+
+impl Map {
+    fn open_pinned(name: &str) -> Result<Self> {
+        #[repr(C)]
+        struct ObjGet {
+            pathname: u64,
+            bpf_fd: u32,
+            file_flags: u32,
+        }
+
+        let path = CString::new(format!("{PIN_DIRECTORY}/{name}"))?;
+        let fd = bpf(
+            BPF_OBJ_GET,
+            &ObjGet {
+                pathname: path.as_ptr() as u64,
+                bpf_fd: 0,
+                file_flags: 0,
+            },
+        )
+        .with_context(|| format!("Failed to open BPF map {name}"))?;
+
+        // Safety: The kernel just handed us this file descriptor.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        Ok(Self { fd })
+    }
+
+    fn update(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.update_elem(key, value, BPF_ANY)
+    }
+
+    /// Inserts the given entry, keeping the existing value if the key already exists.
+    fn insert(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        match self.update_elem(key, value, BPF_NOEXIST) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn update_elem(&self, key: &[u8], value: &[u8], flags: u64) -> io::Result<()> {
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &MapElem {
+                map_fd: self.fd.as_raw_fd() as u32,
+                padding: 0,
+                key: key.as_ptr() as u64,
+                value: value.as_ptr() as u64,
+                flags,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    /// Copies the value of the given key into `value`, returning `false` if the key doesn't exist.
+    fn lookup(&self, key: &[u8], value: &mut [u8]) -> io::Result<bool> {
+        let result = bpf(
+            BPF_MAP_LOOKUP_ELEM,
+            &MapElem {
+                map_fd: self.fd.as_raw_fd() as u32,
+                padding: 0,
+                key: key.as_ptr() as u64,
+                value: value.as_mut_ptr() as u64,
+                flags: 0,
+            },
+        );
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes the given key, succeeding if it doesn't exist.
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        let result = bpf(
+            BPF_MAP_DELETE_ELEM,
+            &MapElem {
+                map_fd: self.fd.as_raw_fd() as u32,
+                padding: 0,
+                key: key.as_ptr() as u64,
+                value: 0,
+                flags: 0,
+            },
+        );
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The part of `union bpf_attr` used by the commands on map elements.
+#[repr(C)]
+struct MapElem {
+    map_fd: u32,
+    padding: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[cfg(target_os = "linux")]
+fn bpf<T>(command: libc::c_long, attr: &T) -> io::Result<libc::c_int> {
+    // Safety: `attr` is the part of `union bpf_attr` that belongs to `command` and we pass its actual size.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            command,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(result as libc::c_int)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bpf<T>(_: libc::c_long, _: &T) -> io::Result<libc::c_int> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "XDP is only supported on Linux",
+    ))
+}
+
+/// The key or value of a map entry: An IPv4 endpoint plus either a channel number or a port, all in network byte order.
+///
+/// Must match `struct endpoint` of the XDP program.
+fn endpoint(addr: SocketAddrV4, extra: u16) -> [u8; 8] {
+    let mut endpoint = [0u8; 8];
+
+    endpoint[..4].copy_from_slice(&addr.ip().octets());
+    endpoint[4..6].copy_from_slice(&addr.port().to_be_bytes());
+    endpoint[6..].copy_from_slice(&extra.to_be_bytes());
+
+    endpoint
+}
+
+/// The size of `struct usage` of the XDP program.
+const USAGE_LEN: usize = 32;
+
+/// Decodes the value of a `relay_usage` entry: The bytes and packets relayed from the client to the peer and back, as native-endian 64 bit counters.
+///
+/// Must match `struct usage` of the XDP program.
+fn usage(value: [u8; USAGE_LEN]) -> Usage {
+    let counter = |i: usize| {
+        u64::from_ne_bytes(
+            value[i * 8..(i + 1) * 8]
+                .try_into()
+                .expect("slice to be 8 bytes"),
+        )
+    };
+
+    Usage {
+        client_to_peer: Traffic {
+            bytes: counter(0),
+            packets: counter(1),
+        },
+        peer_to_client: Traffic {
+            bytes: counter(2),
+            packets: counter(3),
+        },
+    }
+}
+
+fn ip4_endpoints(client: SocketAddr, peer: SocketAddr) -> Option<(SocketAddrV4, SocketAddrV4)> {
+    match (client, peer) {
+        (SocketAddr::V4(client), SocketAddr::V4(peer)) => Some((client, peer)),
+        _ => None,
+    }
+}
+
+fn remove_pinned_maps() -> Result<()> {
+    for name in [CHANNELS_MAP, PEERS_MAP, CONFIG_MAP, STATS_MAP, USAGE_MAP] {
+        match std::fs::remove_file(Path::new(PIN_DIRECTORY).join(name)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove BPF map {name}")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether any XDP program is attached to the given interface.
+fn is_xdp_attached(interface: &str) -> Result<bool> {
+    let output = run_ip(&["-json", "link", "show", "dev", interface])
+        .with_context(|| format!("Failed to query {interface}"))?;
+
+    has_xdp_program(&output)
+}
+
+/// Whether the output of `ip -json link show` lists an XDP program.
+fn has_xdp_program(output: &str) -> Result<bool> {
+    let links = serde_json::from_str::<Vec<serde_json::Value>>(output)
+        .context("Failed to parse output of `ip`")?;
+
+    Ok(links.iter().any(|link| link.get("xdp").is_some()))
+}
+
+/// Runs `ip` with the given arguments, returning its standard output.
+fn run_ip(args: &[&str]) -> Result<String> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .context("Failed to run `ip`")?;
+
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim())
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_is_in_network_byte_order() {
+        assert_eq!(
+            endpoint("10.0.0.1:3478".parse().unwrap(), 0x4001),
+            [10, 0, 0, 1, 0x0d, 0x96, 0x40, 0x01]
+        );
+    }
+
+    #[test]
+    fn usage_counters_are_in_declaration_order() {
+        let mut value = [0u8; USAGE_LEN];
+        for (i, counter) in [100u64, 2, 300, 4].into_iter().enumerate() {
+            value[i * 8..(i + 1) * 8].copy_from_slice(&counter.to_ne_bytes());
+        }
+
+        assert_eq!(usage(value), traffic(100, 2, 300, 4));
+    }
+
+    #[test]
+    fn fast_path_usage_is_reported_once() {
+        let client = "10.0.0.1:1000".parse().unwrap();
+        let peer = "10.0.0.2:2000".parse().unwrap();
+        let allocation = AllocationId::default();
+        let mut fast_path_usage = FastPathUsage::default();
+
+        fast_path_usage.binding_created(allocation, client, 0x4000, peer);
+
+        let mut record = empty_record(allocation, false);
+        fast_path_usage.add_to_record(&mut record, |_, _| Ok(traffic(100, 1, 50, 1)));
+        assert_eq!(record.usage, traffic(100, 1, 50, 1));
+        assert_eq!(record.channels.len(), 1);
+        assert_eq!(record.channels[0].peer, peer);
+
+        // Refreshing the binding keeps its counters.
+        fast_path_usage.binding_created(allocation, client, 0x4000, peer);
+
+        let mut record = empty_record(allocation, false);
+        fast_path_usage.add_to_record(&mut record, |_, _| Ok(traffic(100, 1, 50, 1)));
+        assert!(record.usage.is_empty());
+        assert!(record.channels.is_empty());
+
+        fast_path_usage.binding_deleted(client, 0x4000, traffic(120, 2, 50, 1));
+
+        let mut record = empty_record(allocation, true);
+        fast_path_usage.add_to_record(&mut record, |_, _| panic!("binding to be deleted"));
+        assert_eq!(record.usage, traffic(20, 1, 0, 0));
+        assert!(fast_path_usage.bindings.is_empty());
+    }
+
+    #[test]
+    fn recreated_binding_counts_from_zero() {
+        let client = "10.0.0.1:1000".parse().unwrap();
+        let peer = "10.0.0.2:2000".parse().unwrap();
+        let allocation = AllocationId::default();
+        let mut fast_path_usage = FastPathUsage::default();
+
+        fast_path_usage.binding_created(allocation, client, 0x4000, peer);
+        fast_path_usage.binding_deleted(client, 0x4000, traffic(100, 1, 0, 0));
+        fast_path_usage.binding_created(allocation, client, 0x4000, peer);
+
+        let mut record = empty_record(allocation, false);
+        fast_path_usage.add_to_record(&mut record, |_, _| Ok(traffic(30, 1, 0, 0)));
+        assert_eq!(record.usage, traffic(130, 2, 0, 0));
+    }
+
+    #[test]
+    fn detects_attached_xdp_program() {
+        assert!(has_xdp_program(
+            r#"[{"ifindex":2,"ifname":"eth0","xdp":{"mode":1,"prog":{"id":42}}}]"#
+        )
+        .unwrap());
+        assert!(!has_xdp_program(r#"[{"ifindex":2,"ifname":"eth0"}]"#).unwrap());
+    }
+
+    #[test]
+    fn only_ip4_bindings_are_relayed_in_kernel() {
+        assert!(ip4_endpoints(
+            "10.0.0.1:1000".parse().unwrap(),
+            "[2001:db8::1]:2000".parse().unwrap()
+        )
+        .is_none());
+    }
+
+    fn traffic(
+        client_to_peer_bytes: u64,
+        client_to_peer_packets: u64,
+        peer_to_client_bytes: u64,
+        peer_to_client_packets: u64,
+    ) -> Usage {
+        Usage {
+            client_to_peer: Traffic {
+                bytes: client_to_peer_bytes,
+                packets: client_to_peer_packets,
+            },
+            peer_to_client: Traffic {
+                bytes: peer_to_client_bytes,
+                packets: peer_to_client_packets,
+            },
+        }
+    }
+
+    fn empty_record(allocation: AllocationId, is_final: bool) -> UsageRecord {
+        UsageRecord {
+            allocation,
+            user: "user".to_owned(),
+            usage: Usage::default(),
+            channels: Vec::new(),
+            is_final,
+        }
+    }
+}
@@ -1,23 +1,44 @@
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
-    AddressFamily, Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, Command, IpStack, Refresh, Server,
+    AddressFamily, Allocate, AllocationId, Attribute, Authenticator, BandwidthLimit,
+    BandwidthLimits, Binding, ChangeRequest, ChannelBind, ChannelData, ChannelUsage, ClientMessage,
+    ClientSocket, Command, Connect, ConnectionBind, ConnectionId, CreatePermission,
+    DiscoveryOrigin, IpStack, LoadShedding, MobilityTicket, NatDiscovery, OtherAddress,
+    PasswordAlgorithm, PasswordAlgorithms, PeerFilter, Refresh, ResponseOrigin, SecurityFeatures,
+    SendIndication, Server, Shard, Snapshot, Traffic, TurnRestApi, Usage, UsageRecord,
+    AUTH_SECRET_GRACE_PERIOD, CONNECT, CONNECTION_ATTEMPT, CONNECTION_BIND,
 };
+use proptest::prop_assume;
 use rand::rngs::mock::StepRng;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret, SecretString};
 use std::collections::HashMap;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::time::{Duration, SystemTime};
-use stun_codec::rfc5389::attributes::{ErrorCode, Nonce, Realm, Username, XorMappedAddress};
-use stun_codec::rfc5389::errors::Unauthorized;
+use stun_codec::rfc5389::attributes::{
+    AlternateServer, ErrorCode, Fingerprint, Nonce, Realm, Software, UnknownAttributes, Username,
+    XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{
+    BadRequest, StaleNonce, TryAlternate, Unauthorized, UnknownAttribute,
+};
 use stun_codec::rfc5389::methods::BINDING;
-use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress, XorRelayAddress};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, REFRESH};
-use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+use stun_codec::rfc5766::attributes::{
+    ChannelNumber, Data, Lifetime, XorPeerAddress, XorRelayAddress,
+};
+use stun_codec::rfc5766::errors::{
+    AllocationMismatch, Forbidden, InsufficientCapacity, WrongCredentials,
+};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH};
+use stun_codec::{
+    AttributeType, Message, MessageClass, MessageDecoder, MessageEncoder, Method, TransactionId,
+};
 use test_strategy::proptest;
 use uuid::Uuid;
-use Output::{CreateAllocation, FreeAllocation, Wake};
+use Output::{
+    BindConnection, CloseConnection, ConnectToPeer, CreateAllocation, CreateChannelBinding,
+    CreateTcpAllocation, DeleteChannelBinding, FreeAllocation, PauseConnection, ReportUsage, Wake,
+};
 
 #[proptest]
 fn can_answer_stun_request_from_ip4_address(
@@ -39,6 +60,116 @@ fn can_answer_stun_request_from_ip4_address(
     );
 }
 
+#[proptest]
+fn responses_carry_configured_software(
+    #[strategy(firezone_relay::proptest::binding())] request: Binding,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+) {
+    let _ = env_logger::try_init();
+    let software = Software::new("firezone-relay/1.0.0".to_owned()).unwrap();
+    let mut server = TestServer::new(public_relay_addr).with_software(software.clone());
+
+    let transaction_id = request.transaction_id();
+    let mut response = binding_response(transaction_id, source);
+    response.add_attribute(software);
+
+    server.assert_commands(
+        from_client(source, request, SystemTime::now()),
+        [send_message(source, response)],
+    );
+}
+
+#[proptest]
+fn binding_responses_advertise_other_address_and_response_origin(
+    #[strategy(firezone_relay::proptest::binding())] request: Binding,
+    source: SocketAddrV4,
+) {
+    let mut server = TestServer::new(Ipv4Addr::new(35, 124, 91, 37))
+        .with_nat_discovery(nat_discovery(Some(Ipv4Addr::new(35, 124, 91, 38))));
+
+    let mut response = binding_response(request.transaction_id(), source);
+    response.add_attribute(OtherAddress::new("35.124.91.38:3479".parse().unwrap()));
+    response.add_attribute(ResponseOrigin::new("35.124.91.37:3478".parse().unwrap()));
+
+    server.assert_commands(
+        from_client(source, request, SystemTime::now()),
+        [send_message(source, response)],
+    );
+}
+
+#[proptest]
+fn change_request_is_answered_from_alternate_address(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    change_ip: bool,
+    change_port: bool,
+    source: SocketAddrV4,
+) {
+    prop_assume!(change_ip || change_port);
+
+    let mut server = TestServer::new(Ipv4Addr::new(35, 124, 91, 37))
+        .with_nat_discovery(nat_discovery(Some(Ipv4Addr::new(35, 124, 91, 38))));
+
+    let origin = DiscoveryOrigin {
+        alternate_ip: change_ip,
+        alternate_port: change_port,
+    };
+    let response_origin = SocketAddr::new(
+        Ipv4Addr::new(35, 124, 91, if change_ip { 38 } else { 37 }).into(),
+        if change_port { 3479 } else { 3478 },
+    );
+
+    let mut response = binding_response(transaction_id, source);
+    response.add_attribute(OtherAddress::new("35.124.91.38:3479".parse().unwrap()));
+    response.add_attribute(ResponseOrigin::new(response_origin));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(
+                transaction_id,
+                ChangeRequest::new(change_ip, change_port),
+            ),
+            SystemTime::now(),
+        ),
+        [send_message_from(source, response, origin)],
+    );
+}
+
+#[proptest]
+fn change_request_without_alternate_address_is_rejected(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    source: SocketAddrV4,
+) {
+    let mut server = TestServer::new(Ipv4Addr::new(35, 124, 91, 37));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(transaction_id, ChangeRequest::new(false, true)),
+            SystemTime::now(),
+        ),
+        [send_message(
+            source,
+            unknown_change_request_response(transaction_id),
+        )],
+    );
+
+    let mut server = server.with_nat_discovery(nat_discovery(None));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Binding::new_with_change_request(transaction_id, ChangeRequest::new(true, false)),
+            SystemTime::now(),
+        ),
+        [send_message(
+            source,
+            unknown_change_request_response(transaction_id),
+        )],
+    );
+}
+
 #[proptest]
 fn deallocate_once_time_expired(
     #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
@@ -110,12 +241,13 @@ fn unauthenticated_allocate_triggers_authentication(
     server.assert_commands(
         from_client(
             source,
-            Allocate::new_authenticated_udp_implicit_ip4(
+            Allocate::new_authenticated_udp_with_security_features(
                 transaction_id,
                 Some(lifetime.clone()),
                 valid_username(now, &username_salt),
                 &secret,
                 first_nonce,
+                ISSUED_SECURITY_FEATURES,
             ),
             now,
         ),
@@ -130,6 +262,53 @@ fn unauthenticated_allocate_triggers_authentication(
     );
 }
 
+#[proptest]
+fn nonce_without_issued_security_features_is_stale(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let first_nonce = Uuid::from_u128(0x0);
+
+    let mut server = TestServer::new(public_relay_addr);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_unauthenticated_udp(transaction_id, Some(lifetime.clone())),
+            now,
+        ),
+        [send_message(
+            source,
+            unauthorized_allocate_response(transaction_id, first_nonce),
+        )],
+    );
+
+    // An attacker stripped the security features from the nonce to bid down the client.
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                first_nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            stale_nonce_allocate_response(transaction_id, first_nonce),
+        )],
+    );
+}
+
 #[proptest]
 fn when_refreshed_in_time_allocation_does_not_expire(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -295,7 +474,7 @@ fn ping_pong_relay(
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
     #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
     source: SocketAddrV4,
-    peer: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
     public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
     peer_to_client_ping: [u8; 32],
@@ -335,6 +514,7 @@ fn ping_pong_relay(
         ],
     );
 
+    let allocation_expiry = now + lifetime.lifetime();
     let now = now + Duration::from_secs(1);
 
     server.assert_commands(
@@ -350,10 +530,10 @@ fn ping_pong_relay(
             ),
             now,
         ),
-        [send_message(
-            source,
-            channel_bind_response(channel_bind_transaction_id),
-        )],
+        [
+            Wake(allocation_expiry.min(now + Duration::from_secs(300))),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
     );
 
     let now = now + Duration::from_secs(1);
@@ -368,7 +548,7 @@ fn ping_pong_relay(
     );
 
     server.assert_commands(
-        from_peer(peer, peer_to_client_ping.as_ref(), 49152),
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
         [send_channel_data(
             source,
             ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
@@ -377,40 +557,49 @@ fn ping_pong_relay(
 }
 
 #[proptest]
-fn can_make_ipv6_allocation(
-    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+fn data_exceeding_allocation_bandwidth_limit_is_dropped(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
     #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
     #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
     source: SocketAddrV4,
-    public_relay_ip4_addr: Ipv4Addr,
-    public_relay_ip6_addr: Ipv6Addr,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
     #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    client_to_peer_ping: [u8; 32],
+    peer_to_client_ping: [u8; 32],
     #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
 ) {
-    let mut server =
-        TestServer::new((public_relay_ip4_addr, public_relay_ip6_addr)).with_nonce(nonce);
-    let secret = server.auth_secret();
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_bandwidth_limits(BandwidthLimits {
+            per_allocation: Some(BandwidthLimit::new(32)),
+            per_user: None,
+        });
+    let secret = server.auth_secret().to_owned();
 
     server.assert_commands(
         from_client(
             source,
-            Allocate::new_authenticated_udp_ip6(
-                transaction_id,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
                 Some(lifetime.clone()),
                 valid_username(now, &username_salt),
-                secret,
+                &secret,
                 nonce,
             ),
             now,
         ),
         [
             Wake(now + lifetime.lifetime()),
-            CreateAllocation(49152, AddressFamily::V6),
+            CreateAllocation(49152, AddressFamily::V4),
             send_message(
                 source,
                 allocate_response(
-                    transaction_id,
-                    public_relay_ip6_addr,
+                    allocate_transaction_id,
+                    public_relay_addr,
                     49152,
                     source,
                     &lifetime,
@@ -418,84 +607,2815 @@ fn can_make_ipv6_allocation(
             ),
         ],
     );
-}
-
-struct TestServer {
-    server: Server<StepRng>,
-    id_to_port: HashMap<u16, AllocationId>,
-}
-
-impl TestServer {
-    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
-        Self {
-            server: Server::new(relay_public_addr, StepRng::new(0, 0), 49152, 65535),
-            id_to_port: Default::default(),
-        }
-    }
-
-    fn with_nonce(mut self, nonce: Uuid) -> Self {
-        self.server.add_nonce(nonce);
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
 
-        self
-    }
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
 
-    fn auth_secret(&self) -> &SecretString {
-        self.server.auth_secret()
-    }
+    // The limit applies to both directions.
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
 
-    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
-        match input {
-            Input::Client(sender, message, now) => {
-                self.server.handle_client_message(message, sender, now);
-            }
-            Input::Time(now) => {
-                self.server.handle_deadline_reached(now);
-            }
-            Input::Peer(peer, data, port) => {
-                self.server
-                    .handle_relay_input(&data, peer, self.id_to_port[&port]);
-            }
-        }
+    let now = now + Duration::from_secs(1);
 
-        for expected_output in output {
-            let Some(actual_output) = self.server.next_command() else {
-                let msg = match expected_output {
-                    Output::SendMessage((recipient, msg)) => {
-                        format!("to send message {:?} to {recipient}", msg)
-                    }
-                    Wake(time) => format!("to be woken at {time:?}"),
-                    CreateAllocation(port, family) => {
-                        format!("to create allocation on port {port} for address family {family}")
-                    }
-                    FreeAllocation(port, family) => {
-                        format!("to free allocation on port {port} for address family {family}")
-                    }
-                    Output::SendChannelData((peer, _)) => {
-                        format!("to send channel data from {peer} to client")
-                    }
-                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
-                };
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+}
 
-                panic!("No commands produced but expected {msg}");
-            };
+#[proptest]
+fn restored_server_relays_data_of_existing_channels(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
 
-            match (expected_output, actual_output) {
-                (
-                    Output::SendMessage((to, message)),
-                    Command::SendMessage { payload, recipient },
-                ) => {
-                    let expected_bytes = MessageEncoder::new()
-                        .encode_into_bytes(message.clone())
-                        .unwrap();
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    let snapshot = serde_json::to_vec(&server.server.snapshot()).unwrap();
+    let snapshot = serde_json::from_slice::<Snapshot>(&snapshot).unwrap();
+
+    let mut restored_server = TestServer::new(public_relay_addr);
+
+    restored_server.assert_commands(
+        restore(snapshot, now),
+        [
+            CreateAllocation(49152, AddressFamily::V4),
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+        ],
+    );
+    assert_eq!(
+        restored_server.auth_secret().expose_secret(),
+        secret.expose_secret()
+    );
+
+    restored_server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+}
+
+#[proptest]
+fn restores_snapshot_of_first_version(
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let expires_at = now + Duration::from_secs(600);
+    let timestamp = |time: SystemTime| {
+        let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+
+        serde_json::json!({
+            "secs_since_epoch": since_epoch.as_secs(),
+            "nanos_since_epoch": since_epoch.subsec_nanos(),
+        })
+    };
+
+    // Written before allocations counted their relayed bytes and identified their user by the salt of its username.
+    let snapshot = serde_json::json!({
+        "auth_secret": "secret",
+        "next_allocation_id": 1,
+        "allocations": [[source.to_string(), {
+            "id": 0,
+            "port": 49152,
+            "expires_at": timestamp(expires_at),
+            "transport": "Udp",
+            "username_salt": "salt",
+            "first_relay_addr": public_relay_addr.to_string(),
+            "second_relay_addr": null,
+        }]],
+        "channels": [],
+        "nonces": { "inner": {} },
+        "time_events": [[timestamp(expires_at), { "ExpireAllocation": 0 }]],
+    });
+    let snapshot = serde_json::from_value::<Snapshot>(snapshot).unwrap();
+
+    let mut server = TestServer::new(public_relay_addr);
+
+    server.assert_commands(
+        restore(snapshot, now),
+        [CreateAllocation(49152, AddressFamily::V4), Wake(expires_at)],
+    );
+
+    let allocations = server.server.allocations();
+    assert_eq!(allocations[0].client, SocketAddr::from(source));
+    assert_eq!(allocations[0].client_to_peer_bytes, 0);
+
+    server.assert_commands(
+        revoke_user("salt", now + Duration::from_secs(3600)),
+        [FreeAllocation(49152, AddressFamily::V4), Wake(expires_at)],
+    );
+}
+
+#[proptest]
+fn admin_can_list_and_free_allocations(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    let allocations = server.server.allocations();
+
+    assert_eq!(allocations.len(), 1);
+    assert_eq!(allocations[0].client, SocketAddr::from(source));
+    assert_eq!(
+        allocations[0].relay_addresses,
+        vec![SocketAddr::new(public_relay_addr.into(), 49152)]
+    );
+
+    server.assert_commands(
+        free_allocation(49152),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+
+    assert!(server.server.allocations().is_empty());
+}
+
+#[proptest]
+fn channel_bindings_are_mirrored_to_fast_path(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_fast_path();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+            CreateChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    server.assert_commands(
+        free_allocation(49152),
+        [
+            DeleteChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            FreeAllocation(49152, AddressFamily::V4),
+        ],
+    );
+}
+
+#[proptest]
+fn channel_bindings_of_stream_clients_are_not_mirrored_to_fast_path(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_fast_path();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_stream_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+            send_stream_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    server.assert_commands(
+        free_allocation(49152),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+}
+
+#[proptest]
+fn allocation_moves_to_new_address_with_mobility_ticket(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    new_source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != new_source);
+
+    // Outlives the permission of the channel binding, which thus remains the next deadline.
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+    let ticket = MobilityTicket::new(vec![0; 16]); // `StepRng` always yields 0.
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_fast_path()
+        .with_mobility();
+    let secret = server.auth_secret().to_owned();
+
+    let mut response = allocate_response(
+        allocate_transaction_id,
+        public_relay_addr,
+        49152,
+        source,
+        &lifetime,
+    );
+    response.add_attribute(ticket.clone());
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_with_mobility(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(source, response),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(300)),
+            CreateChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    let mut response = refresh_response(refresh_transaction_id, lifetime.clone());
+    response.add_attribute(ticket.clone());
+
+    server.assert_commands(
+        from_client(
+            new_source,
+            Refresh::new_with_mobility_ticket(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                Some(ticket),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(300)),
+            DeleteChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            CreateChannelBinding(new_source.into(), channel.value(), peer.into(), 49152),
+            send_message(new_source, response),
+        ],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            new_source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+
+    server.assert_commands(
+        free_allocation(49152),
+        [
+            DeleteChannelBinding(new_source.into(), channel.value(), peer.into(), 49152),
+            FreeAllocation(49152, AddressFamily::V4),
+        ],
+    );
+}
+
+#[proptest]
+fn mobility_ticket_must_match_allocation_and_user(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    new_source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != new_source);
+
+    let ticket = MobilityTicket::new(vec![0; 16]);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_mobility();
+    let secret = server.auth_secret().to_owned();
+
+    let mut response = allocate_response(
+        allocate_transaction_id,
+        public_relay_addr,
+        49152,
+        source,
+        &lifetime,
+    );
+    response.add_attribute(ticket.clone());
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_with_mobility(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(source, response),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            new_source,
+            Refresh::new_with_mobility_ticket(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                Some(MobilityTicket::new(vec![1; 16])),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            new_source,
+            refresh_error_response(
+                refresh_transaction_id,
+                ErrorCode::new(405, "Mobility Forbidden".to_owned()).unwrap(),
+            ),
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            new_source,
+            Refresh::new_with_mobility_ticket(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                Some(ticket),
+                valid_username(now, &format!("{username_salt}-other")),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            new_source,
+            refresh_error_response(refresh_transaction_id, WrongCredentials.into()),
+        )],
+    );
+}
+
+#[proptest]
+fn usage_is_reported_periodically_and_on_deletion(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let interval = Duration::from_secs(60);
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_usage_reports(interval);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + interval),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + interval),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+
+    let usage = Usage {
+        client_to_peer: Traffic {
+            bytes: 32,
+            packets: 1,
+        },
+        peer_to_client: Traffic {
+            bytes: 32,
+            packets: 1,
+        },
+    };
+
+    server.assert_commands(
+        forward_time_to(now + interval),
+        [
+            ReportUsage(
+                49152,
+                UsageRecord {
+                    allocation: AllocationId::default(),
+                    user: username_salt.clone(),
+                    usage,
+                    channels: vec![ChannelUsage {
+                        number: channel.value(),
+                        peer: peer.into(),
+                        usage,
+                    }],
+                    is_final: false,
+                },
+            ),
+            Wake(now + interval * 2),
+        ],
+    );
+
+    // Idle allocations are not reported.
+    server.assert_commands(
+        forward_time_to(now + interval * 2),
+        [Wake(now + interval * 3)],
+    );
+
+    server.assert_commands(
+        free_allocation(49152),
+        [
+            ReportUsage(
+                49152,
+                UsageRecord {
+                    allocation: AllocationId::default(),
+                    user: username_salt,
+                    usage: Usage::default(),
+                    channels: Vec::new(),
+                    is_final: true,
+                },
+            ),
+            FreeAllocation(49152, AddressFamily::V4),
+        ],
+    );
+}
+
+#[proptest]
+fn idle_allocations_are_reported_with_fast_path(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let interval = Duration::from_secs(60);
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_fast_path()
+        .with_usage_reports(interval);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + interval),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // The fast path may have relayed data that the server doesn't know about.
+    server.assert_commands(
+        forward_time_to(now + interval),
+        [
+            ReportUsage(
+                49152,
+                UsageRecord {
+                    allocation: AllocationId::default(),
+                    user: username_salt,
+                    usage: Usage::default(),
+                    channels: Vec::new(),
+                    is_final: false,
+                },
+            ),
+            Wake(now + interval * 2),
+        ],
+    );
+}
+
+#[proptest]
+fn usage_of_all_allocations_is_reported_as_final_on_shutdown(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let interval = Duration::from_secs(60);
+    let lifetime = Lifetime::new(Duration::from_secs(600)).unwrap();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_usage_reports(interval);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + interval),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        report_final_usage(),
+        [ReportUsage(
+            49152,
+            UsageRecord {
+                allocation: AllocationId::default(),
+                user: username_salt,
+                usage: Usage::default(),
+                channels: Vec::new(),
+                is_final: true,
+            },
+        )],
+    );
+}
+
+#[proptest]
+fn shard_allocates_from_its_part_of_the_port_range(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new_shard(public_relay_addr, Shard::new(1, 2)).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(57344, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 57344, source, &lifetime),
+            ),
+        ],
+    );
+
+    let allocations = server.server.allocations();
+
+    // The first shard hands out odd allocation IDs, the second one even ones.
+    assert_eq!(allocations[0].id.to_string(), "AID-2");
+}
+
+#[proptest]
+fn previous_auth_secret_is_accepted_during_grace_period(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    second_source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != second_source);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let previous_secret = server.auth_secret().to_owned();
+    let after_grace_period = now + AUTH_SECRET_GRACE_PERIOD + Duration::from_secs(1);
+
+    server.assert_commands(rotate_auth_secret("new-secret", now), []);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &previous_secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            second_source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(after_grace_period, &username_salt),
+                &previous_secret,
+                nonce,
+            ),
+            after_grace_period,
+        ),
+        [send_message(
+            second_source,
+            unauthorized_allocate_response(transaction_id, Uuid::from_u128(0)),
+        )],
+    );
+}
+
+#[proptest]
+fn unauthenticated_allocate_is_challenged_with_configured_realm(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+) {
+    let realm = Realm::new("example.com".to_owned()).unwrap();
+    let mut server = TestServer::new(public_relay_addr)
+        .with_authenticator(TurnRestApi::new("north".parse().unwrap(), realm.clone()));
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_unauthenticated_udp(transaction_id, Some(lifetime)),
+            now,
+        ),
+        [send_message(
+            source,
+            authentication_error_allocate_response_in_realm(
+                transaction_id,
+                Unauthorized.into(),
+                Uuid::from_u128(0),
+                realm,
+            ),
+        )],
+    );
+}
+
+#[proptest]
+fn send_and_data_indications_require_permission(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    create_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] send_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // Without a permission, data is dropped in both directions.
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                Data::new(client_to_peer_ping.to_vec()).unwrap(),
+            ),
+            now,
+        ),
+        [],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+            send_message(
+                source,
+                create_permission_response(create_permission_transaction_id),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                Data::new(client_to_peer_ping.to_vec()).unwrap(),
+            ),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_message(
+            source,
+            data_indication(peer, &peer_to_client_ping),
+        )],
+    );
+}
+
+#[proptest]
+fn bound_channel_does_not_relay_once_its_permission_expired(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let lifetime = Lifetime::new(Duration::from_secs(3600)).unwrap();
+    let permission_expiry = now + Duration::from_secs(300);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(permission_expiry),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    server.assert_commands(forward_time_to(permission_expiry), []);
+
+    // The channel stays bound for another 5 minutes but the client did not refresh the permission.
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, permission_expiry),
+        [],
+    );
+}
+
+#[proptest]
+fn channel_is_removed_from_fast_path_while_its_permission_is_expired(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    create_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let lifetime = Lifetime::new(Duration::from_secs(3600)).unwrap();
+    let permission_expiry = now + Duration::from_secs(300);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_fast_path();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(permission_expiry),
+            CreateChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    // The channel stays bound for another 5 minutes but the fast path cannot check permissions.
+    server.assert_commands(
+        forward_time_to(permission_expiry),
+        [DeleteChannelBinding(
+            source.into(),
+            channel.value(),
+            peer.into(),
+            49152,
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            permission_expiry,
+        ),
+        [
+            Wake(now + Duration::from_secs(600)),
+            CreateChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            send_message(
+                source,
+                create_permission_response(create_permission_transaction_id),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn channels_of_rate_limited_allocations_are_not_mirrored_to_fast_path(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_bandwidth_limits(BandwidthLimits {
+            per_allocation: Some(BandwidthLimit::new(100)),
+            per_user: None,
+        })
+        .with_fast_path();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    // Without limits, the kernel may relay the channel data.
+    server.assert_commands(
+        reconfigure(PeerFilter::default(), now),
+        [CreateChannelBinding(
+            source.into(),
+            channel.value(),
+            peer.into(),
+            49152,
+        )],
+    );
+}
+
+#[proptest]
+fn cannot_refresh_channel_binding_of_another_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    other_allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    other_channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    other_source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != other_source);
+
+    let lifetime = Lifetime::new(Duration::from_secs(3600)).unwrap();
+
+    // The auth secret takes the first 32 random numbers, each further one advances the port by one.
+    let mut server =
+        TestServer::new_with_rng(public_relay_addr, StepRng::new(0, 1 << 18)).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    for (source, transaction_id, port) in [
+        (source, allocate_transaction_id, 49184),
+        (other_source, other_allocate_transaction_id, 49185),
+    ] {
+        server.assert_commands(
+            from_client(
+                source,
+                Allocate::new_authenticated_udp_implicit_ip4(
+                    transaction_id,
+                    Some(lifetime.clone()),
+                    valid_username(now, &username_salt),
+                    &secret,
+                    nonce,
+                ),
+                now,
+            ),
+            [
+                Wake(now + lifetime.lifetime()),
+                CreateAllocation(port, AddressFamily::V4),
+                send_message(
+                    source,
+                    allocate_response(transaction_id, public_relay_addr, port, source, &lifetime),
+                ),
+            ],
+        );
+    }
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(300)),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    // Binding the same channel to the same peer must not refresh the binding of the first allocation.
+    server.assert_commands(
+        from_client(
+            other_source,
+            ChannelBind::new(
+                other_channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            other_source,
+            bad_request_response(CHANNEL_BIND, other_channel_bind_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn permissions_expire_unless_refreshed(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    create_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    refresh_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] send_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(lifetime.lifetime() > Duration::from_secs(600));
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let refresh_at = now + Duration::from_secs(200);
+    let expired_at = refresh_at + Duration::from_secs(300);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(300)),
+            send_message(
+                source,
+                create_permission_response(create_permission_transaction_id),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                refresh_permission_transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
+                valid_username(refresh_at, &username_salt),
+                &secret,
+                nonce,
+            ),
+            refresh_at,
+        ),
+        [
+            Wake(expired_at),
+            send_message(
+                source,
+                create_permission_response(refresh_permission_transaction_id),
+            ),
+        ],
+    );
+
+    // The original permission would have expired by now but the refresh extended it.
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                Data::new(client_to_peer_ping.to_vec()).unwrap(),
+            ),
+            now + Duration::from_secs(400),
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+
+    server.assert_commands(forward_time_to(expired_at), []);
+
+    server.assert_commands(
+        from_client(
+            source,
+            SendIndication::new(
+                send_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                Data::new(client_to_peer_ping.to_vec()).unwrap(),
+            ),
+            expired_at,
+        ),
+        [],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, expired_at),
+        [],
+    );
+}
+
+#[proptest]
+fn busy_relay_redirects_to_alternate_server(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_server: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_load_shedding(LoadShedding::new(vec![alternate_server.into()], 0.0));
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            try_alternate_response(transaction_id, alternate_server),
+        )],
+    );
+}
+
+#[proptest]
+fn draining_relay_rejects_new_allocations_but_refreshes_existing_ones(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    second_allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    second_source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != second_source);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(start_draining(), []);
+
+    server.assert_commands(
+        from_client(
+            second_source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                second_allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            second_source,
+            insufficient_capacity_response(second_allocate_transaction_id),
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            send_message(
+                source,
+                refresh_response(refresh_transaction_id, lifetime.clone()),
+            ),
+        ],
+    );
+    assert!(!server.server.is_drained());
+
+    server.assert_commands(
+        forward_time_to(now + lifetime.lifetime() + Duration::from_secs(1)),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+    assert!(server.server.is_drained());
+}
+
+#[proptest]
+fn draining_relay_redirects_to_alternate_server(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_server: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_load_shedding(LoadShedding::new(vec![alternate_server.into()], 1.0));
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(start_draining(), []);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            try_alternate_response(transaction_id, alternate_server),
+        )],
+    );
+}
+
+#[proptest]
+fn revoked_user_loses_allocations_and_cannot_allocate_again(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    second_allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    third_allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // Allocation lifetimes are shorter than an hour, thus the stale expiry of the freed allocation is still scheduled first.
+    let revoked_until = now + Duration::from_secs(60 * 60);
+
+    server.assert_commands(
+        revoke_user(&username_salt, revoked_until),
+        [
+            FreeAllocation(49152, AddressFamily::V4),
+            Wake(now + lifetime.lifetime()),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                second_allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            forbidden_response(ALLOCATE, second_allocate_transaction_id),
+        )],
+    );
+
+    server.assert_commands(forward_time_to(revoked_until), []);
+
+    let later = revoked_until + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                third_allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(later, &username_salt),
+                &secret,
+                nonce,
+            ),
+            later,
+        ),
+        [
+            Wake(later + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    third_allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn denied_peers_are_forbidden(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    create_permission_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer_port: u16,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(SocketAddr::from((Ipv4Addr::LOCALHOST, peer_port))),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            forbidden_response(CHANNEL_BIND, channel_bind_transaction_id),
+        )],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                create_permission_transaction_id,
+                vec![XorPeerAddress::new(SocketAddr::from((
+                    Ipv4Addr::new(169, 254, 169, 254),
+                    peer_port,
+                )))],
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            forbidden_response(CREATE_PERMISSION, create_permission_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn reconfigured_peer_filter_revokes_channels_to_denied_peers(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+    peer_to_client_ping: [u8; 32],
+) {
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_fast_path();
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(300))),
+            CreateChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    let denied = format!("{}/32", peer.ip()).parse().unwrap();
+
+    server.assert_commands(
+        reconfigure(PeerFilter::new(Vec::new(), vec![denied]), now),
+        [
+            DeleteChannelBinding(source.into(), channel.value(), peer.into(), 49152),
+            Wake(now + lifetime.lifetime().min(Duration::from_secs(5 * 60))),
+        ],
+    );
+
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+}
+
+#[proptest]
+fn can_make_ipv6_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_ip4_addr: Ipv4Addr,
+    public_relay_ip6_addr: Ipv6Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server =
+        TestServer::new((public_relay_ip4_addr, public_relay_ip6_addr)).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_ip6(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V6),
+            send_message(
+                source,
+                allocate_response(
+                    transaction_id,
+                    public_relay_ip6_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn tcp_allocation_requires_stream_connection(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            bad_request_response(ALLOCATE, transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn can_connect_to_peer_and_bind_data_connection(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] connect_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    data_source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != data_source);
+
+    // Connection IDs are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let connection = ConnectionId::new(0);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_stream_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [ConnectToPeer(peer.into(), 49152)],
+    );
+
+    server.assert_commands(
+        peer_connection_established(connection, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_stream_message(source, connect_response(connect_transaction_id, connection)),
+        ],
+    );
+
+    server.assert_commands(
+        from_stream_client(
+            data_source,
+            ConnectionBind::new(
+                bind_transaction_id,
+                connection,
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            send_stream_message(data_source, connection_bind_response(bind_transaction_id)),
+            BindConnection(data_source.into()),
+        ],
+    );
+
+    server.assert_commands(spliced_client_data(data_source, 100, now), []);
+    server.assert_commands(spliced_peer_data(data_source, 250, now), []);
+
+    let allocations = server.server.allocations();
+    assert_eq!(allocations[0].client_to_peer_bytes, 100);
+    assert_eq!(allocations[0].peer_to_client_bytes, 250);
+
+    server.assert_commands(stream_closed(data_source), [CloseConnection]);
+}
+
+#[proptest]
+fn spliced_data_exceeding_allocation_bandwidth_limit_pauses_data_connection(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] connect_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    data_source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != data_source);
+
+    // Connection IDs are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let connection = ConnectionId::new(0);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_bandwidth_limits(BandwidthLimits {
+            per_allocation: Some(BandwidthLimit::new(100)),
+            per_user: None,
+        });
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_stream_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [ConnectToPeer(peer.into(), 49152)],
+    );
+
+    server.assert_commands(
+        peer_connection_established(connection, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_stream_message(source, connect_response(connect_transaction_id, connection)),
+        ],
+    );
+
+    server.assert_commands(
+        from_stream_client(
+            data_source,
+            ConnectionBind::new(
+                bind_transaction_id,
+                connection,
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            send_stream_message(data_source, connection_bind_response(bind_transaction_id)),
+            BindConnection(data_source.into()),
+        ],
+    );
+
+    server.assert_commands(spliced_client_data(data_source, 100, now), []);
+
+    // Spliced data has already been relayed, thus the connection is paused until the allocation is within its limit again.
+    server.assert_commands(
+        spliced_peer_data(data_source, 250, now),
+        [PauseConnection(
+            data_source.into(),
+            now + Duration::from_millis(2500),
+        )],
+    );
+}
+
+#[proptest]
+fn connection_id_routes_data_connection_to_owning_shard(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] connect_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let shard = Shard::new(1, 3);
+
+    // Our randomness is all zeros, thus the connection ID only consists of the shard index.
+    let connection = ConnectionId::new(1);
+
+    let mut server = TestServer::new_shard(public_relay_addr, shard).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(54613, AddressFamily::V4),
+            send_stream_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    54613,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [ConnectToPeer(peer.into(), 54613)],
+    );
+    server.assert_commands(
+        peer_connection_established(connection, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_stream_message(source, connect_response(connect_transaction_id, connection)),
+        ],
+    );
+
+    // Whichever shard accepts the data connection, it hands it to the one owning the peer connection.
+    for accepting_shard in Shard::all(3) {
+        assert_eq!(accepting_shard.owner_of(connection), shard);
+    }
+}
+
+#[proptest]
+fn cannot_bind_peer_connection_of_another_user(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] connect_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::username_salt())] attacker_username_salt: String,
+    source: SocketAddrV4,
+    attacker_source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    prop_assume!(source != attacker_source);
+    prop_assume!(username_salt != attacker_username_salt);
+
+    let connection = ConnectionId::new(0);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_stream_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [ConnectToPeer(peer.into(), 49152)],
+    );
+
+    server.assert_commands(
+        peer_connection_established(connection, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_stream_message(source, connect_response(connect_transaction_id, connection)),
+        ],
+    );
+
+    server.assert_commands(
+        from_stream_client(
+            attacker_source,
+            ConnectionBind::new(
+                bind_transaction_id,
+                connection,
+                valid_username(now, &attacker_username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_stream_message(
+            attacker_source,
+            forbidden_response(CONNECTION_BIND, bind_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn unbound_peer_connection_is_closed_after_30_seconds(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::peer())] peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_stream_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        peer_connection_accepted(peer, 49152, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_stream_message(
+                source,
+                connection_attempt_indication(ConnectionId::new(0), peer),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        forward_time_to(now + Duration::from_secs(30)),
+        [CloseConnection],
+    );
+}
+
+#[proptest]
+fn stream_client_does_not_share_allocation_of_udp_client_with_same_address(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    // A TCP connection from the same address is a different 5-tuple and thus has no allocation.
+    server.assert_commands(
+        from_stream_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_stream_message(
+            source,
+            refresh_error_response(refresh_transaction_id, AllocationMismatch.into()),
+        )],
+    );
+
+    // Closing it must not affect the allocation of the UDP client.
+    server.assert_commands(stream_closed(source), []);
+    server.assert_commands(
+        forward_time_to(now + lifetime.lifetime() + Duration::from_secs(1)),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+}
+
+struct TestServer {
+    server: Server<StepRng>,
+    id_to_port: HashMap<u16, AllocationId>,
+}
+
+impl TestServer {
+    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
+        Self {
+            server: Server::new(relay_public_addr, StepRng::new(0, 0), 49152, 65535),
+            id_to_port: Default::default(),
+        }
+    }
+
+    fn new_with_rng(relay_public_addr: impl Into<IpStack>, rng: StepRng) -> Self {
+        Self {
+            server: Server::new(relay_public_addr, rng, 49152, 65535),
+            id_to_port: Default::default(),
+        }
+    }
+
+    fn new_shard(relay_public_addr: impl Into<IpStack>, shard: Shard) -> Self {
+        Self {
+            server: Server::new_shard(relay_public_addr, StepRng::new(0, 0), 49152, 65535, shard),
+            id_to_port: Default::default(),
+        }
+    }
 
-                    if expected_bytes != payload {
-                        let expected_message = format!("{:?}", message);
-                        let actual_message = format!("{:?}", parse_message(&payload));
+    fn with_nonce(mut self, nonce: Uuid) -> Self {
+        self.server.add_nonce(nonce);
+
+        self
+    }
+
+    fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.server.set_authenticator(authenticator);
+
+        self
+    }
+
+    fn with_bandwidth_limits(mut self, limits: BandwidthLimits) -> Self {
+        self.server
+            .set_bandwidth_limits(limits, SystemTime::UNIX_EPOCH);
+
+        self
+    }
+
+    fn with_load_shedding(mut self, load_shedding: LoadShedding) -> Self {
+        self.server.set_load_shedding(load_shedding);
+
+        self
+    }
+
+    fn with_software(mut self, software: Software) -> Self {
+        self.server.set_software(software);
+
+        self
+    }
+
+    fn with_fast_path(mut self) -> Self {
+        self.server.enable_fast_path();
+
+        self
+    }
+
+    fn with_usage_reports(mut self, interval: Duration) -> Self {
+        self.server.enable_usage_reports(interval);
+
+        self
+    }
+
+    fn with_mobility(mut self) -> Self {
+        self.server.enable_mobility();
+
+        self
+    }
+
+    fn with_nat_discovery(mut self, nat_discovery: NatDiscovery) -> Self {
+        self.server.enable_nat_discovery(nat_discovery);
+
+        self
+    }
+
+    fn auth_secret(&self) -> &SecretString {
+        self.server.auth_secret()
+    }
+
+    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
+        match input {
+            Input::Client(sender, message, now) => {
+                self.server.handle_client_message(message, sender, now);
+            }
+            Input::Time(now) => {
+                self.server.handle_deadline_reached(now);
+            }
+            Input::Peer(peer, data, port, now) => {
+                self.server
+                    .handle_relay_input(&data, peer, self.id_to_port[&port], now);
+            }
+            Input::FreeAllocation(port) => {
+                assert!(self.server.free_allocation(self.id_to_port[&port]));
+            }
+            Input::Restore(snapshot, now) => {
+                self.server.restore(snapshot, now);
+            }
+            Input::StreamClosed(client) => {
+                self.server.handle_stream_closed(client);
+            }
+            Input::SplicedClientData(client, num_bytes, now) => {
+                self.server
+                    .handle_spliced_client_data(client, num_bytes, now);
+            }
+            Input::SplicedPeerData(client, num_bytes, now) => {
+                self.server.handle_spliced_peer_data(client, num_bytes, now);
+            }
+            Input::PeerConnectionAccepted(peer, port, now) => {
+                self.server
+                    .handle_peer_connection_accepted(self.id_to_port[&port], peer, now)
+                    .expect("peer connection to be accepted");
+            }
+            Input::PeerConnectionEstablished(connection, now) => {
+                self.server
+                    .handle_peer_connection_established(connection, now);
+            }
+            Input::RotateAuthSecret(secret, now) => {
+                self.server.rotate_auth_secret(secret, now);
+            }
+            Input::RevokeUser(user, until) => {
+                self.server.revoke_user(user, until);
+            }
+            Input::StartDraining => {
+                self.server.start_draining();
+            }
+            Input::ReportFinalUsage => {
+                self.server.report_final_usage();
+            }
+            Input::Reconfigure(filter, now) => {
+                self.server
+                    .reconfigure(BandwidthLimits::default(), filter, now);
+            }
+        }
 
-                        difference::assert_diff!(&expected_message, &actual_message, "\n", 0);
+        for expected_output in output {
+            let Some(actual_output) = self.server.next_command() else {
+                let msg = match expected_output {
+                    Output::SendMessage((recipient, msg)) => {
+                        format!("to send message {:?} to {recipient}", msg)
+                    }
+                    Output::SendMessageFrom((recipient, msg, origin)) => {
+                        format!("to send message {:?} to {recipient} from {origin:?}", msg)
+                    }
+                    Wake(time) => format!("to be woken at {time:?}"),
+                    CreateAllocation(port, family) => {
+                        format!("to create allocation on port {port} for address family {family}")
+                    }
+                    FreeAllocation(port, family) => {
+                        format!("to free allocation on port {port} for address family {family}")
+                    }
+                    CreateTcpAllocation(port, family) => {
+                        format!(
+                            "to create TCP allocation on port {port} for address family {family}"
+                        )
+                    }
+                    ConnectToPeer(peer, port) => {
+                        format!("to connect to peer {peer} from port {port}")
+                    }
+                    BindConnection(client) => {
+                        format!("to bind connection to data connection {client}")
+                    }
+                    CloseConnection => "to close connection".to_owned(),
+                    PauseConnection(client, until) => {
+                        format!("to pause data connection {client} until {until:?}")
+                    }
+                    CreateChannelBinding(_, channel, peer, port) => {
+                        format!("to create channel binding {channel} to {peer} on port {port}")
+                    }
+                    DeleteChannelBinding(_, channel, peer, port) => {
+                        format!("to delete channel binding {channel} to {peer} on port {port}")
                     }
+                    ReportUsage(port, _) => format!("to report usage of allocation on port {port}"),
+                    Output::SendChannelData((peer, _)) => {
+                        format!("to send channel data from {peer} to client")
+                    }
+                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
+                };
+
+                panic!("No commands produced but expected {msg}");
+            };
 
+            match (expected_output, actual_output) {
+                (
+                    Output::SendMessage((to, message)),
+                    Command::SendMessage { payload, recipient },
+                ) => {
+                    assert_message(message, &payload);
+                    assert_eq!(recipient, to);
+                }
+                (
+                    Output::SendMessageFrom((to, message, expected_origin)),
+                    Command::SendMessageFrom {
+                        payload,
+                        recipient,
+                        origin,
+                    },
+                ) => {
+                    assert_message(message, &payload);
                     assert_eq!(recipient, to);
+                    assert_eq!(origin, expected_origin);
                 }
                 (Wake(when), Command::Wake { deadline }) => {
                     assert_eq!(when, deadline);
@@ -512,6 +3432,83 @@ impl TestServer {
                     assert_eq!(expected_port, actual_port);
                     assert_eq!(expected_family, actual_family);
                 }
+                (
+                    CreateTcpAllocation(expected_port, expected_family),
+                    Command::CreateTcpAllocation {
+                        id,
+                        family: actual_family,
+                        port: actual_port,
+                    },
+                ) => {
+                    self.id_to_port.insert(actual_port, id);
+                    assert_eq!(expected_port, actual_port);
+                    assert_eq!(expected_family, actual_family);
+                }
+                (
+                    ConnectToPeer(expected_peer, port),
+                    Command::ConnectToPeer {
+                        allocation, peer, ..
+                    },
+                ) => {
+                    assert_eq!(expected_peer, peer);
+                    assert_eq!(self.id_to_port[&port], allocation);
+                }
+                (BindConnection(expected_client), Command::BindConnection { client, .. }) => {
+                    assert_eq!(ClientSocket::tcp(expected_client), client);
+                }
+                (CloseConnection, Command::CloseConnection { .. }) => {}
+                (
+                    PauseConnection(expected_client, expected_until),
+                    Command::PauseConnection { client, until },
+                ) => {
+                    assert_eq!(ClientSocket::tcp(expected_client), client);
+                    assert_eq!(expected_until, until);
+                }
+                (ReportUsage(port, expected), Command::ReportUsage(actual)) => {
+                    assert_eq!(
+                        UsageRecord {
+                            allocation: self.id_to_port[&port],
+                            ..expected
+                        },
+                        actual
+                    );
+                }
+                (
+                    CreateChannelBinding(
+                        expected_client,
+                        expected_channel,
+                        expected_peer,
+                        expected_port,
+                    ),
+                    Command::CreateChannelBinding {
+                        allocation,
+                        client,
+                        channel,
+                        peer,
+                        port,
+                    },
+                )
+                | (
+                    DeleteChannelBinding(
+                        expected_client,
+                        expected_channel,
+                        expected_peer,
+                        expected_port,
+                    ),
+                    Command::DeleteChannelBinding {
+                        allocation,
+                        client,
+                        channel,
+                        peer,
+                        port,
+                    },
+                ) => {
+                    assert_eq!(expected_client, client);
+                    assert_eq!(expected_channel, channel);
+                    assert_eq!(expected_peer, peer);
+                    assert_eq!(expected_port, port);
+                    assert_eq!(self.id_to_port[&expected_port], allocation);
+                }
                 (
                     FreeAllocation(port, family),
                     Command::FreeAllocation {
@@ -564,6 +3561,20 @@ impl TestServer {
     }
 }
 
+fn assert_message(expected: Message<Attribute>, payload: &[u8]) {
+    let message = with_fingerprint(expected);
+    let expected_bytes = MessageEncoder::new()
+        .encode_into_bytes(message.clone())
+        .unwrap();
+
+    if expected_bytes != payload {
+        let expected_message = format!("{:?}", message);
+        let actual_message = format!("{:?}", parse_message(payload));
+
+        difference::assert_diff!(&expected_message, &actual_message, "\n", 0);
+    }
+}
+
 fn valid_username(now: SystemTime, salt: &str) -> Username {
     let now_unix = now
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -586,6 +3597,26 @@ fn binding_response(
     message
 }
 
+fn unknown_change_request_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, BINDING, transaction_id);
+    message.add_attribute(ErrorCode::from(UnknownAttribute));
+    message.add_attribute(UnknownAttributes::new(vec![AttributeType::new(
+        ChangeRequest::CODEPOINT,
+    )]));
+
+    message
+}
+
+/// NAT discovery on the ports 3478 and 3479, optionally with the given alternate IPv4 address.
+fn nat_discovery(alternate_ip4: Option<Ipv4Addr>) -> NatDiscovery {
+    NatDiscovery {
+        primary_port: 3478,
+        alternate_port: 3479,
+        alternate_address: alternate_ip4.map(IpStack::Ip4),
+    }
+}
+
 fn allocate_response(
     transaction_id: TransactionId,
     public_relay_addr: impl Into<IpAddr>,
@@ -605,15 +3636,66 @@ fn allocate_response(
     message
 }
 
+/// The security features the server advertises with the nonces it issues.
+const ISSUED_SECURITY_FEATURES: SecurityFeatures = SecurityFeatures {
+    password_algorithms: true,
+    username_anonymity: false,
+};
+
 fn unauthorized_allocate_response(
     transaction_id: TransactionId,
     nonce: Uuid,
+) -> Message<Attribute> {
+    authentication_error_allocate_response(transaction_id, Unauthorized.into(), nonce)
+}
+
+fn stale_nonce_allocate_response(transaction_id: TransactionId, nonce: Uuid) -> Message<Attribute> {
+    authentication_error_allocate_response(transaction_id, StaleNonce.into(), nonce)
+}
+
+fn authentication_error_allocate_response(
+    transaction_id: TransactionId,
+    error: ErrorCode,
+    nonce: Uuid,
+) -> Message<Attribute> {
+    authentication_error_allocate_response_in_realm(
+        transaction_id,
+        error,
+        nonce,
+        Realm::new("firezone".to_owned()).unwrap(),
+    )
+}
+
+fn authentication_error_allocate_response_in_realm(
+    transaction_id: TransactionId,
+    error: ErrorCode,
+    nonce: Uuid,
+    realm: Realm,
+) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(error);
+    message.add_attribute(
+        Nonce::new(ISSUED_SECURITY_FEATURES.prefix_nonce(&nonce.as_hyphenated().to_string()))
+            .unwrap(),
+    );
+    message.add_attribute(realm);
+    message.add_attribute(PasswordAlgorithms::new(vec![
+        PasswordAlgorithm::Sha256,
+        PasswordAlgorithm::Md5,
+    ]));
+
+    message
+}
+
+fn try_alternate_response(
+    transaction_id: TransactionId,
+    alternate_server: impl Into<SocketAddr>,
 ) -> Message<Attribute> {
     let mut message =
         Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
-    message.add_attribute(ErrorCode::from(Unauthorized));
-    message.add_attribute(Nonce::new(nonce.as_hyphenated().to_string()).unwrap());
-    message.add_attribute(Realm::new("firezone".to_owned()).unwrap());
+    message.add_attribute(ErrorCode::from(TryAlternate));
+    message.add_attribute(AlternateServer::new(alternate_server.into()));
 
     message
 }
@@ -626,10 +3708,101 @@ fn refresh_response(transaction_id: TransactionId, lifetime: Lifetime) -> Messag
     message
 }
 
+fn refresh_error_response(transaction_id: TransactionId, error: ErrorCode) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, REFRESH, transaction_id);
+    message.add_attribute(error);
+
+    message
+}
+
+fn create_permission_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        CREATE_PERMISSION,
+        transaction_id,
+    )
+}
+
+fn data_indication(peer: impl Into<SocketAddr>, data: &[u8]) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::Indication,
+        DATA,
+        TransactionId::new([0u8; 12]),
+    );
+    message.add_attribute(XorPeerAddress::new(peer.into()));
+    message.add_attribute(Data::new(data.to_vec()).unwrap());
+
+    message
+}
+
 fn channel_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
     Message::<Attribute>::new(MessageClass::SuccessResponse, CHANNEL_BIND, transaction_id)
 }
 
+fn bad_request_response(method: Method, transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, method, transaction_id);
+    message.add_attribute(ErrorCode::from(BadRequest));
+
+    message
+}
+
+fn forbidden_response(method: Method, transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, method, transaction_id);
+    message.add_attribute(ErrorCode::from(Forbidden));
+
+    message
+}
+
+fn insufficient_capacity_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(InsufficientCapacity));
+
+    message
+}
+
+fn connect_response(transaction_id: TransactionId, connection: ConnectionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::SuccessResponse, *CONNECT, transaction_id);
+    message.add_attribute(connection);
+
+    message
+}
+
+fn connection_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        *CONNECTION_BIND,
+        transaction_id,
+    )
+}
+
+fn connection_attempt_indication(
+    connection: ConnectionId,
+    peer: impl Into<SocketAddr>,
+) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::Indication,
+        *CONNECTION_ATTEMPT,
+        TransactionId::new([0; 12]),
+    );
+    message.add_attribute(connection);
+    message.add_attribute(XorPeerAddress::new(peer.into()));
+
+    message
+}
+
+/// The relay appends a FINGERPRINT to every message it sends.
+fn with_fingerprint(mut message: Message<Attribute>) -> Message<Attribute> {
+    let fingerprint = Fingerprint::new(&message).unwrap();
+    message.add_attribute(fingerprint);
+
+    message
+}
+
 fn parse_message(message: &[u8]) -> Message<Attribute> {
     MessageDecoder::new()
         .decode_from_bytes(message)
@@ -638,9 +3811,21 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 }
 
 enum Input<'a> {
-    Client(SocketAddr, ClientMessage<'a>, SystemTime),
-    Peer(SocketAddr, Vec<u8>, u16),
+    Client(ClientSocket, ClientMessage<'a>, SystemTime),
+    Peer(SocketAddr, Vec<u8>, u16, SystemTime),
     Time(SystemTime),
+    FreeAllocation(u16),
+    Restore(Snapshot, SystemTime),
+    StreamClosed(ClientSocket),
+    SplicedClientData(ClientSocket, usize, SystemTime),
+    SplicedPeerData(ClientSocket, usize, SystemTime),
+    PeerConnectionAccepted(SocketAddr, u16, SystemTime),
+    PeerConnectionEstablished(ConnectionId, SystemTime),
+    RotateAuthSecret(SecretString, SystemTime),
+    RevokeUser(&'a str, SystemTime),
+    StartDraining,
+    Reconfigure(PeerFilter, SystemTime),
+    ReportFinalUsage,
 }
 
 fn from_client<'a>(
@@ -648,29 +3833,127 @@ fn from_client<'a>(
     message: impl Into<ClientMessage<'a>>,
     now: SystemTime,
 ) -> Input<'a> {
-    Input::Client(from.into(), message.into(), now)
+    Input::Client(ClientSocket::udp(from.into()), message.into(), now)
+}
+
+fn from_stream_client<'a>(
+    from: impl Into<SocketAddr>,
+    message: impl Into<ClientMessage<'a>>,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::Client(ClientSocket::tcp(from.into()), message.into(), now)
 }
 
-fn from_peer<'a>(from: impl Into<SocketAddr>, data: &[u8], port: u16) -> Input<'a> {
-    Input::Peer(from.into(), data.to_vec(), port)
+fn from_peer<'a>(
+    from: impl Into<SocketAddr>,
+    data: &[u8],
+    port: u16,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::Peer(from.into(), data.to_vec(), port, now)
 }
 
 fn forward_time_to<'a>(when: SystemTime) -> Input<'a> {
     Input::Time(when)
 }
 
+fn free_allocation<'a>(port: u16) -> Input<'a> {
+    Input::FreeAllocation(port)
+}
+
+fn restore<'a>(snapshot: Snapshot, now: SystemTime) -> Input<'a> {
+    Input::Restore(snapshot, now)
+}
+
+fn stream_closed<'a>(client: impl Into<SocketAddr>) -> Input<'a> {
+    Input::StreamClosed(ClientSocket::tcp(client.into()))
+}
+
+fn spliced_client_data<'a>(
+    client: impl Into<SocketAddr>,
+    num_bytes: usize,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::SplicedClientData(ClientSocket::tcp(client.into()), num_bytes, now)
+}
+
+fn spliced_peer_data<'a>(
+    client: impl Into<SocketAddr>,
+    num_bytes: usize,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::SplicedPeerData(ClientSocket::tcp(client.into()), num_bytes, now)
+}
+
+fn peer_connection_accepted<'a>(
+    peer: impl Into<SocketAddr>,
+    port: u16,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::PeerConnectionAccepted(peer.into(), port, now)
+}
+
+fn rotate_auth_secret<'a>(secret: &str, now: SystemTime) -> Input<'a> {
+    Input::RotateAuthSecret(SecretString::from(secret.to_owned()), now)
+}
+
+fn revoke_user(user: &str, until: SystemTime) -> Input<'_> {
+    Input::RevokeUser(user, until)
+}
+
+fn start_draining<'a>() -> Input<'a> {
+    Input::StartDraining
+}
+
+fn report_final_usage<'a>() -> Input<'a> {
+    Input::ReportFinalUsage
+}
+
+fn reconfigure<'a>(filter: PeerFilter, now: SystemTime) -> Input<'a> {
+    Input::Reconfigure(filter, now)
+}
+
+fn peer_connection_established<'a>(connection: ConnectionId, now: SystemTime) -> Input<'a> {
+    Input::PeerConnectionEstablished(connection, now)
+}
+
 #[derive(Debug)]
 enum Output<'a> {
-    SendMessage((SocketAddr, Message<Attribute>)),
+    SendMessage((ClientSocket, Message<Attribute>)),
+    SendMessageFrom((SocketAddr, Message<Attribute>, DiscoveryOrigin)),
     SendChannelData((SocketAddr, ChannelData<'a>)),
     Forward((SocketAddr, Vec<u8>, u16)),
     Wake(SystemTime),
     CreateAllocation(u16, AddressFamily),
     FreeAllocation(u16, AddressFamily),
+    CreateTcpAllocation(u16, AddressFamily),
+    ConnectToPeer(SocketAddr, u16),
+    BindConnection(SocketAddr),
+    CloseConnection,
+    PauseConnection(SocketAddr, SystemTime),
+    CreateChannelBinding(SocketAddr, u16, SocketAddr, u16),
+    DeleteChannelBinding(SocketAddr, u16, SocketAddr, u16),
+    /// The expected usage record of the allocation on the given port, its [`AllocationId`] is ignored.
+    ReportUsage(u16, UsageRecord),
 }
 
 fn send_message<'a>(source: impl Into<SocketAddr>, message: Message<Attribute>) -> Output<'a> {
-    Output::SendMessage((source.into(), message))
+    Output::SendMessage((ClientSocket::udp(source.into()), message))
+}
+
+fn send_stream_message<'a>(
+    source: impl Into<SocketAddr>,
+    message: Message<Attribute>,
+) -> Output<'a> {
+    Output::SendMessage((ClientSocket::tcp(source.into()), message))
+}
+
+fn send_message_from<'a>(
+    source: impl Into<SocketAddr>,
+    message: Message<Attribute>,
+    origin: DiscoveryOrigin,
+) -> Output<'a> {
+    Output::SendMessageFrom((source.into(), message, origin))
 }
 
 fn send_channel_data(source: impl Into<SocketAddr>, message: ChannelData) -> Output {
@@ -0,0 +1,319 @@
+// XDP fast path for relaying TURN channel data, see `src/xdp.rs`.
+//
+// Build with:
+//
+//     clang -O2 -g -target bpf -c xdp/channel_data.c -o channel_data.o
+//
+// The relay attaches this program with `ip`, which pins the maps below to `/sys/fs/bpf/xdp/globals`.
+// The relay keeps `relay_channels` and `relay_peers` in sync with its channel bindings.
+// `relay_stats` counts the packets relayed here, e.g. for tests to check that the fast path is taken.
+// `relay_usage` counts the data relayed here for each channel binding, which the relay adds to its usage reports.
+// Anything that cannot be relayed here is passed on to the relay process unmodified.
+
+#include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/in.h>
+#include <linux/ip.h>
+#include <linux/udp.h>
+#include <bpf/bpf_endian.h>
+#include <bpf/bpf_helpers.h>
+
+#define AF_INET 2
+#define CHANNEL_DATA_HEADER_LEN 4
+#define MAX_CHANNEL_BINDINGS 65536
+
+// An IPv4 address and port plus one more 16 bit value, all in network byte order.
+//
+// In `relay_channels`, the key is the client and its channel number, the value is the peer and the port of the allocation.
+// In `relay_peers`, the key is the peer and the port of the allocation, the value is the client and its channel number.
+struct endpoint {
+    __be32 ip;
+    __be16 port;
+    __be16 extra;
+};
+
+struct config {
+    // The port on which the relay receives channel data from clients.
+    __be16 listen_port;
+    __u16 padding;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_CHANNEL_BINDINGS);
+    __type(key, struct endpoint);
+    __type(value, struct endpoint);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} relay_channels SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_CHANNEL_BINDINGS);
+    __type(key, struct endpoint);
+    __type(value, struct endpoint);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} relay_peers SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, __u32);
+    __type(value, struct config);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} relay_config SEC(".maps");
+
+// The data relayed for a channel binding, keyed by the client and its channel number.
+//
+// Only bytes of application data count, i.e. without the channel data header.
+struct usage {
+    __u64 client_to_peer_bytes;
+    __u64 client_to_peer_packets;
+    __u64 peer_to_client_bytes;
+    __u64 peer_to_client_packets;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_CHANNEL_BINDINGS);
+    __type(key, struct endpoint);
+    __type(value, struct usage);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} relay_usage SEC(".maps");
+
+// The directions of relayed packets, indexing `relay_stats`.
+enum direction {
+    FROM_CLIENT,
+    FROM_PEER,
+    NUM_DIRECTIONS,
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, NUM_DIRECTIONS);
+    __type(key, __u32);
+    __type(value, __u64);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} relay_stats SEC(".maps");
+
+struct headers {
+    struct ethhdr eth;
+    struct iphdr ip;
+    struct udphdr udp;
+} __attribute__((packed));
+
+static __always_inline __u16 ip_checksum(struct iphdr *ip) {
+    __u16 *words = (__u16 *)ip;
+    __u32 sum = 0;
+
+    ip->check = 0;
+
+#pragma unroll
+    for (int i = 0; i < sizeof(*ip) / 2; i++) {
+        sum += words[i];
+    }
+
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+
+    return ~sum;
+}
+
+// Rewrites the headers of a packet that we send from the relay's address to `daddr`.
+//
+// Returns the interface to send the packet on or a negative value if the route or the next hop is unknown.
+// The kernel resolves the next hop once we pass such a packet on to the relay process.
+static __always_inline int rewrite(struct xdp_md *ctx, struct headers *hdr, __be32 daddr,
+                                   __be16 source, __be16 dest, int payload_delta) {
+    struct bpf_fib_lookup fib = {};
+
+    hdr->ip.saddr = hdr->ip.daddr;
+    hdr->ip.daddr = daddr;
+    hdr->ip.ttl = 64;
+    hdr->ip.tot_len = bpf_htons(bpf_ntohs(hdr->ip.tot_len) + payload_delta);
+    hdr->ip.check = ip_checksum(&hdr->ip);
+
+    hdr->udp.source = source;
+    hdr->udp.dest = dest;
+    hdr->udp.len = bpf_htons(bpf_ntohs(hdr->udp.len) + payload_delta);
+    hdr->udp.check = 0; // The UDP checksum is optional for IPv4.
+
+    fib.family = AF_INET;
+    fib.l4_protocol = IPPROTO_UDP;
+    fib.tot_len = bpf_ntohs(hdr->ip.tot_len);
+    fib.ipv4_src = hdr->ip.saddr;
+    fib.ipv4_dst = hdr->ip.daddr;
+    fib.ifindex = ctx->ingress_ifindex;
+
+    if (bpf_fib_lookup(ctx, &fib, sizeof(fib), 0) != BPF_FIB_LKUP_RET_SUCCESS) {
+        return -1;
+    }
+
+    __builtin_memcpy(hdr->eth.h_dest, fib.dmac, ETH_ALEN);
+    __builtin_memcpy(hdr->eth.h_source, fib.smac, ETH_ALEN);
+
+    return fib.ifindex;
+}
+
+static __always_inline void count_relayed(__u32 direction) {
+    __u64 *packets = bpf_map_lookup_elem(&relay_stats, &direction);
+
+    if (packets) {
+        __sync_fetch_and_add(packets, 1);
+    }
+}
+
+static __always_inline void count_usage(struct endpoint *client, __u32 direction, __u16 bytes) {
+    struct usage *usage = bpf_map_lookup_elem(&relay_usage, client);
+
+    if (!usage) {
+        return;
+    }
+
+    if (direction == FROM_CLIENT) {
+        __sync_fetch_and_add(&usage->client_to_peer_bytes, bytes);
+        __sync_fetch_and_add(&usage->client_to_peer_packets, 1);
+    } else {
+        __sync_fetch_and_add(&usage->peer_to_client_bytes, bytes);
+        __sync_fetch_and_add(&usage->peer_to_client_packets, 1);
+    }
+}
+
+static __always_inline int transmit(struct xdp_md *ctx, int ifindex) {
+    if (ifindex == ctx->ingress_ifindex) {
+        return XDP_TX;
+    }
+
+    return bpf_redirect(ifindex, 0);
+}
+
+// Channel data from a client: Strip the channel data header and send the data to the peer.
+static __always_inline int from_client(struct xdp_md *ctx, struct headers *hdr, void *data_end) {
+    __u8 *channel_data = (__u8 *)(hdr + 1);
+
+    if ((void *)(channel_data + CHANNEL_DATA_HEADER_LEN) > data_end) {
+        return XDP_PASS;
+    }
+
+    __be16 channel = *(__be16 *)channel_data;
+    __u16 length = bpf_ntohs(*(__be16 *)(channel_data + 2));
+
+    // Padded or truncated channel data is left to the relay process.
+    if (bpf_ntohs(hdr->udp.len) != sizeof(struct udphdr) + CHANNEL_DATA_HEADER_LEN + length) {
+        return XDP_PASS;
+    }
+
+    struct endpoint client = {.ip = hdr->ip.saddr, .port = hdr->udp.source, .extra = channel};
+    struct endpoint *peer = bpf_map_lookup_elem(&relay_channels, &client);
+
+    if (!peer) {
+        return XDP_PASS;
+    }
+
+    struct headers new_hdr = *hdr;
+    int ifindex = rewrite(ctx, &new_hdr, peer->ip, peer->extra, peer->port, -CHANNEL_DATA_HEADER_LEN);
+
+    if (ifindex < 0) {
+        return XDP_PASS;
+    }
+
+    if (bpf_xdp_adjust_head(ctx, CHANNEL_DATA_HEADER_LEN)) {
+        return XDP_PASS;
+    }
+
+    void *data = (void *)(long)ctx->data;
+    data_end = (void *)(long)ctx->data_end;
+
+    if (data + sizeof(new_hdr) > data_end) {
+        return XDP_DROP;
+    }
+
+    __builtin_memcpy(data, &new_hdr, sizeof(new_hdr));
+    count_relayed(FROM_CLIENT);
+    count_usage(&client, FROM_CLIENT, length);
+
+    return transmit(ctx, ifindex);
+}
+
+// Data from a peer: Prepend a channel data header and send it to the client.
+static __always_inline int from_peer(struct xdp_md *ctx, struct headers *hdr, struct config *config) {
+    struct endpoint peer = {.ip = hdr->ip.saddr, .port = hdr->udp.source, .extra = hdr->udp.dest};
+    struct endpoint *client = bpf_map_lookup_elem(&relay_peers, &peer);
+
+    if (!client) {
+        return XDP_PASS;
+    }
+
+    __u16 udp_len = bpf_ntohs(hdr->udp.len);
+
+    if (udp_len < sizeof(struct udphdr)) {
+        return XDP_PASS;
+    }
+
+    struct endpoint client_key = *client;
+    __be16 channel = client->extra;
+    __be16 length = bpf_htons(udp_len - sizeof(struct udphdr));
+
+    struct headers new_hdr = *hdr;
+    int ifindex = rewrite(ctx, &new_hdr, client->ip, config->listen_port, client->port, CHANNEL_DATA_HEADER_LEN);
+
+    if (ifindex < 0) {
+        return XDP_PASS;
+    }
+
+    if (bpf_xdp_adjust_head(ctx, -CHANNEL_DATA_HEADER_LEN)) {
+        return XDP_PASS;
+    }
+
+    void *data = (void *)(long)ctx->data;
+    void *data_end = (void *)(long)ctx->data_end;
+
+    if (data + sizeof(new_hdr) + CHANNEL_DATA_HEADER_LEN > data_end) {
+        return XDP_DROP;
+    }
+
+    __builtin_memcpy(data, &new_hdr, sizeof(new_hdr));
+
+    __be16 *channel_data = data + sizeof(new_hdr);
+    channel_data[0] = channel;
+    channel_data[1] = length;
+    count_relayed(FROM_PEER);
+    count_usage(&client_key, FROM_PEER, udp_len - sizeof(struct udphdr));
+
+    return transmit(ctx, ifindex);
+}
+
+SEC("xdp")
+int relay_channel_data(struct xdp_md *ctx) {
+    void *data = (void *)(long)ctx->data;
+    void *data_end = (void *)(long)ctx->data_end;
+    struct headers *hdr = data;
+
+    if ((void *)(hdr + 1) > data_end) {
+        return XDP_PASS;
+    }
+
+    if (hdr->eth.h_proto != bpf_htons(ETH_P_IP) || hdr->ip.ihl != 5 ||
+        hdr->ip.protocol != IPPROTO_UDP) {
+        return XDP_PASS;
+    }
+
+    // Fragments are reassembled by the kernel.
+    if (hdr->ip.frag_off & bpf_htons(0x3fff)) {
+        return XDP_PASS;
+    }
+
+    __u32 zero = 0;
+    struct config *config = bpf_map_lookup_elem(&relay_config, &zero);
+
+    if (!config || !config->listen_port) {
+        return XDP_PASS;
+    }
+
+    if (hdr->udp.dest == config->listen_port) {
+        return from_client(ctx, hdr, data_end);
+    }
+
+    return from_peer(ctx, hdr, config);
+}
+
+char LICENSE[] SEC("license") = "GPL";